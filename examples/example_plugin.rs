@@ -0,0 +1,61 @@
+//! A plugin with a net refusing every connection with its `message`.
+//!
+//! Built with `cargo build --example example_plugin`, and loaded by
+//! `Registry::load_plugin`.
+
+use rd_interface::{
+    async_trait,
+    registry::NetFactory,
+    schemars::{self, JsonSchema},
+    Address, Config, Context, Error, INet, Registry, Result, TcpListener, TcpStream, UdpSocket,
+};
+use serde_derive::Deserialize;
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct RejectNetConfig {
+    message: String,
+}
+
+pub struct RejectNet {
+    message: String,
+}
+
+impl RejectNet {
+    fn reject(&self) -> Error {
+        Error::Other(self.message.clone().into())
+    }
+}
+
+#[async_trait]
+impl INet for RejectNet {
+    async fn tcp_connect(&self, _ctx: &mut Context, _addr: Address) -> Result<TcpStream> {
+        Err(self.reject())
+    }
+
+    async fn tcp_bind(&self, _ctx: &mut Context, _addr: Address) -> Result<TcpListener> {
+        Err(self.reject())
+    }
+
+    async fn udp_bind(&self, _ctx: &mut Context, _addr: Address) -> Result<UdpSocket> {
+        Err(self.reject())
+    }
+}
+
+impl NetFactory for RejectNet {
+    const NAME: &'static str = "reject";
+    type Config = RejectNetConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        Ok(RejectNet {
+            message: config.message,
+        })
+    }
+}
+
+fn init(registry: &mut Registry) -> Result<()> {
+    registry.add_net::<RejectNet>();
+    Ok(())
+}
+
+rd_interface::export_plugin!(init);
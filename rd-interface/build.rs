@@ -0,0 +1,15 @@
+use std::{env, process::Command};
+
+fn main() {
+    // Plugins are checked against the compiler, there is no stable ABI.
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|v| v.trim().to_string())
+        .unwrap_or_else(|| "unknown rustc".to_string());
+    println!("cargo:rustc-env=RD_RUSTC_VERSION={}", version);
+    println!("cargo:rerun-if-env-changed=RUSTC");
+}
@@ -1,15 +1,27 @@
-use serde_derive::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     fmt,
     io::{Error, ErrorKind, Result},
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::PathBuf,
+    str::FromStr,
 };
+use thiserror::Error;
 
-/// Address can be IPv4, IPv6 address or a domain with port.
-#[derive(Debug, PartialEq, Clone, PartialOrd, Eq, Ord, Serialize, Deserialize)]
+/// Address can be IPv4, IPv6 address or a domain with port, or the path of
+/// a Unix domain socket.
+///
+/// It's displayed and serialized as `host:port`, which [FromStr] and
+/// [Deserialize] parse back, except for the flow info of IPv6 addresses.
+/// Unix sockets are only parsed by [Deserialize] and
+/// [`parse_config`](Address::parse_config), which read the config, never by
+/// [FromStr], which also reads what clients send.
+#[derive(Debug, PartialEq, Clone, PartialOrd, Eq, Ord, Hash)]
 pub enum Address {
     SocketAddr(SocketAddr),
     Domain(String, u16),
+    /// Written as `unix:/path/to/socket`, the path is absolute.
+    Unix(PathBuf),
 }
 
 /// Converts to address value.
@@ -23,14 +35,34 @@ pub trait IntoAddress: Send {
     }
 }
 
+/// Errors when parsing an [Address] from `"host:port"`.
+#[derive(Debug, Error, PartialEq, Eq, Clone)]
+pub enum AddressError {
+    #[error("Missing port")]
+    MissingPort,
+    #[error("Invalid port: {0:?}")]
+    InvalidPort(String),
+    #[error("Missing host")]
+    MissingHost,
+    #[error("Invalid host: {0:?}")]
+    InvalidHost(String),
+}
+
+impl From<AddressError> for Error {
+    fn from(e: AddressError) -> Self {
+        Error::new(ErrorKind::AddrNotAvailable, e)
+    }
+}
+
+const UNIX_PREFIX: &str = "unix:";
+
 fn no_addr() -> Error {
     ErrorKind::AddrNotAvailable.into()
 }
 
 fn strip_brackets(host: &str) -> &str {
     host.strip_prefix('[')
-        .map(|h| h.strip_suffix(']'))
-        .flatten()
+        .and_then(|h| h.strip_suffix(']'))
         .unwrap_or(host)
 }
 
@@ -44,16 +76,50 @@ fn host_to_address(host: &str, port: u16) -> Address {
     }
 }
 
+fn parse_port(port: &str) -> std::result::Result<u16, AddressError> {
+    if port.is_empty() {
+        return Err(AddressError::MissingPort);
+    }
+    port.parse()
+        .map_err(|_| AddressError::InvalidPort(port.to_string()))
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    /// Parses `host:port`, where host is an IPv4 address, a domain or an
+    /// IPv6 address in brackets, optionally with a `%scope_id`. It never
+    /// gives a Unix socket, `unix:80` is the host `unix`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix('[') {
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| AddressError::InvalidHost(s.to_string()))?;
+            let invalid_host = || AddressError::InvalidHost(host.to_string());
+            let (ip, scope_id) = match host.split_once('%') {
+                Some((ip, scope_id)) => (ip, scope_id.parse().map_err(|_| invalid_host())?),
+                None => (host, 0),
+            };
+            let ip: Ipv6Addr = ip.parse().map_err(|_| invalid_host())?;
+            let port = rest.strip_prefix(':').ok_or(AddressError::MissingPort)?;
+            return Ok(SocketAddrV6::new(ip, parse_port(port)?, 0, scope_id).into());
+        }
+
+        let (host, port) = s.rsplit_once(':').ok_or(AddressError::MissingPort)?;
+        if host.is_empty() {
+            return Err(AddressError::MissingHost);
+        }
+        // IPv6 addresses must be in brackets
+        if host.contains(':') {
+            return Err(AddressError::InvalidHost(host.to_string()));
+        }
+        Ok(host_to_address(host, parse_port(port)?))
+    }
+}
+
 impl IntoAddress for &str {
     fn into_address(self) -> Result<Address> {
-        let mut parts = self.rsplitn(2, ":");
-        let port: u16 = parts
-            .next()
-            .ok_or_else(no_addr)?
-            .parse()
-            .map_err(|_| no_addr())?;
-        let host = parts.next().ok_or_else(no_addr)?;
-        Ok(host_to_address(host, port))
+        Ok(self.parse()?)
     }
 }
 
@@ -76,12 +142,36 @@ impl IntoAddress for (IpAddr, u16) {
     }
 }
 
+impl IntoAddress for (Ipv4Addr, u16) {
+    fn into_address(self) -> Result<Address> {
+        Ok(self.into())
+    }
+}
+
+impl IntoAddress for (Ipv6Addr, u16) {
+    fn into_address(self) -> Result<Address> {
+        Ok(self.into())
+    }
+}
+
 impl IntoAddress for SocketAddr {
     fn into_address(self) -> Result<Address> {
         Ok(self.into())
     }
 }
 
+impl IntoAddress for SocketAddrV4 {
+    fn into_address(self) -> Result<Address> {
+        Ok(self.into())
+    }
+}
+
+impl IntoAddress for SocketAddrV6 {
+    fn into_address(self) -> Result<Address> {
+        Ok(self.into())
+    }
+}
+
 impl IntoAddress for Address {
     fn into_address(self) -> Result<Address> {
         Ok(self)
@@ -100,6 +190,30 @@ impl From<(IpAddr, u16)> for Address {
     }
 }
 
+impl From<(Ipv4Addr, u16)> for Address {
+    fn from((ip, port): (Ipv4Addr, u16)) -> Self {
+        (IpAddr::V4(ip), port).into()
+    }
+}
+
+impl From<(Ipv6Addr, u16)> for Address {
+    fn from((ip, port): (Ipv6Addr, u16)) -> Self {
+        (IpAddr::V6(ip), port).into()
+    }
+}
+
+impl From<SocketAddrV4> for Address {
+    fn from(addr: SocketAddrV4) -> Self {
+        Address::SocketAddr(addr.into())
+    }
+}
+
+impl From<SocketAddrV6> for Address {
+    fn from(addr: SocketAddrV6) -> Self {
+        Address::SocketAddr(addr.into())
+    }
+}
+
 impl Address {
     /// Converts to SocketAddr if Address can be convert to.
     /// Otherwise [AddrNotAvailable](std::io::ErrorKind::AddrNotAvailable) is returned.
@@ -110,6 +224,31 @@ impl Address {
         }
     }
 
+    /// Like [FromStr], and `unix:/path/to/socket` is a Unix domain socket.
+    /// Only for trusted text such as the config, clients must not choose to
+    /// connect to local sockets.
+    pub fn parse_config(s: &str) -> std::result::Result<Address, AddressError> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some(path) if path.starts_with('/') => Ok(Address::Unix(path.into())),
+            _ => s.parse(),
+        }
+    }
+
+    /// Parses comma separated addresses, e.g. `a.com:443,b.com:443`, as
+    /// passed to [`tcp_connect_any`](crate::INet::tcp_connect_any).
+    pub fn parse_list(s: &str) -> std::result::Result<Vec<Address>, AddressError> {
+        s.split(',').map(|i| i.trim().parse()).collect()
+    }
+
+    /// Returns the port, `None` for a Unix socket.
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Address::SocketAddr(s) => Some(s.port()),
+            Address::Domain(_, port) => Some(*port),
+            Address::Unix(_) => None,
+        }
+    }
+
     /// Resolve domain to SocketAddr using `f`.
     pub async fn resolve<Fut>(&self, f: impl FnOnce(String, u16) -> Fut) -> Result<SocketAddr>
     where
@@ -121,6 +260,7 @@ impl Address {
                 Ok(ip) => Ok(SocketAddr::new(ip, *p)),
                 Err(_) => f(d.to_string(), *p).await,
             },
+            Address::Unix(_) => Err(no_addr()),
         }
     }
 }
@@ -130,18 +270,31 @@ impl fmt::Display for Address {
         match self {
             Address::Domain(domain, port) => write!(f, "{}:{}", domain, port),
             Address::SocketAddr(s) => write!(f, "{}", s),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
         }
     }
 }
 
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Address::parse_config(&s).map_err(de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::{Ipv4Addr, Ipv6Addr};
 
     const IPV4_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
     const IPV6_ADDR: IpAddr = IpAddr::V6(Ipv6Addr::new(1, 2, 3, 4, 5, 6, 7, 8));
-    const DOMAIN: &'static str = "example.com";
+    const DOMAIN: &str = "example.com";
 
     #[test]
     fn test_address_convert() {
@@ -181,5 +334,186 @@ mod tests {
         // (IpAddr, u16)
         assert_eq!(ipv4_addr, (IPV4_ADDR, 1234).into_address().unwrap());
         assert_eq!(ipv6_addr, (IPV6_ADDR, 1234).into_address().unwrap());
+
+        let (ipv4, ipv6) = match (IPV4_ADDR, IPV6_ADDR) {
+            (IpAddr::V4(v4), IpAddr::V6(v6)) => (v4, v6),
+            _ => unreachable!(),
+        };
+        // (Ipv4Addr, u16), (Ipv6Addr, u16)
+        assert_eq!(ipv4_addr, (ipv4, 1234).into_address().unwrap());
+        assert_eq!(ipv6_addr, (ipv6, 1234).into_address().unwrap());
+        assert_eq!(ipv4_addr, Address::from((ipv4, 1234)));
+        assert_eq!(ipv6_addr, Address::from((ipv6, 1234)));
+
+        // SocketAddrV4, SocketAddrV6
+        let v4 = SocketAddrV4::new(ipv4, 1234);
+        let v6 = SocketAddrV6::new(ipv6, 1234, 0, 0);
+        assert_eq!(ipv4_addr, v4.into_address().unwrap());
+        assert_eq!(ipv6_addr, v6.into_address().unwrap());
+        assert_eq!(ipv4_addr, Address::from(v4));
+        assert_eq!(ipv6_addr, Address::from(v6));
+        assert_eq!(v4.into_socket_addr().unwrap(), SocketAddr::V4(v4));
+    }
+
+    #[test]
+    fn test_address_from_str() {
+        assert_eq!(
+            "example.com:443".parse(),
+            Ok(Address::Domain("example.com".to_string(), 443))
+        );
+        assert_eq!(
+            "[2001:db8::1]:8080".parse(),
+            Ok(Address::SocketAddr("[2001:db8::1]:8080".parse().unwrap()))
+        );
+        assert_eq!(
+            "127.0.0.1:1".parse(),
+            Ok(Address::SocketAddr("127.0.0.1:1".parse().unwrap()))
+        );
+        assert_eq!(
+            "[fe80::1%3]:80".parse(),
+            Ok(Address::SocketAddr("[fe80::1%3]:80".parse().unwrap()))
+        );
+
+        // the host `unix`
+        let domain = Address::Domain("unix".to_string(), 80);
+        assert_eq!("unix:80".parse(), Ok(domain.clone()));
+        assert_eq!(Address::parse_config("unix:80"), Ok(domain.clone()));
+        let json = serde_json::to_string(&domain).unwrap();
+        assert_eq!(serde_json::from_str::<Address>(&json).unwrap(), domain);
+    }
+
+    #[test]
+    fn test_address_unix() {
+        let unix = Address::Unix("/run/proxy.sock".into());
+        assert_eq!(
+            Address::parse_config("unix:/run/proxy.sock"),
+            Ok(unix.clone())
+        );
+        assert_eq!(unix.to_string(), "unix:/run/proxy.sock");
+        assert_eq!(unix.port(), None);
+        assert_eq!(
+            serde_json::from_value::<Address>("unix:/run/proxy.sock".into()).unwrap(),
+            unix
+        );
+        assert_eq!(serde_json::to_value(&unix).unwrap(), "unix:/run/proxy.sock");
+
+        // not from what clients send, nor relative
+        assert!("unix:/run/proxy.sock".parse::<Address>().is_err());
+        assert!(Address::parse_config("unix:run/proxy.sock").is_err());
+        assert_eq!(
+            Address::parse_config("unix:"),
+            Err(AddressError::MissingPort)
+        );
+    }
+
+    #[test]
+    fn test_address_display_ipv6() {
+        let addr: Address = (IPV6_ADDR, 1234).into();
+        assert_eq!(addr.to_string(), "[1:2:3:4:5:6:7:8]:1234");
+        let addr: Address = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 80, 0, 0).into();
+        assert_eq!(addr.to_string(), "[::1]:80");
+        let addr: Address = SocketAddrV6::new("fe80::1".parse().unwrap(), 80, 0, 3).into();
+        assert_eq!(addr.to_string(), "[fe80::1%3]:80");
+        assert_eq!(addr.to_string().parse(), Ok(addr.clone()));
+
+        assert_eq!(serde_json::to_value(&addr).unwrap(), "[fe80::1%3]:80");
+        assert_eq!(
+            serde_json::from_value::<Address>("[::1]:80".into()).unwrap(),
+            "[::1]:80".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_address_round_trip() {
+        // a small xorshift, so the test is reproducible
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let domain = |next: &mut dyn FnMut() -> u64| {
+            let labels = 1 + next() % 3;
+            let labels: Vec<String> = (0..labels)
+                .map(|_| {
+                    let len = 1 + next() % 10;
+                    (0..len)
+                        .map(|_| (b'a' + (next() % 26) as u8) as char)
+                        .collect()
+                })
+                .collect();
+            labels.join(".")
+        };
+
+        for _ in 0..1000 {
+            let port = next() as u16;
+            let addrs = vec![
+                Address::Domain(domain(&mut next), port),
+                (Ipv4Addr::from(next() as u32), port).into(),
+                (
+                    Ipv6Addr::from((next() as u128) << 64 | next() as u128),
+                    port,
+                )
+                    .into(),
+                SocketAddrV6::new(Ipv6Addr::from(next() as u128), port, 0, next() as u32).into(),
+            ];
+            for addr in addrs {
+                let s = addr.to_string();
+                assert_eq!(s.parse(), Ok(addr.clone()), "{}", s);
+
+                let json = serde_json::to_string(&addr).unwrap();
+                assert_eq!(serde_json::from_str::<Address>(&json).unwrap(), addr);
+            }
+        }
+    }
+
+    #[test]
+    fn test_address_from_str_error() {
+        let parse = |s: &str| s.parse::<Address>().unwrap_err();
+
+        assert_eq!(parse("host"), AddressError::MissingPort);
+        assert_eq!(parse("host:"), AddressError::MissingPort);
+        assert_eq!(parse("[::1]"), AddressError::MissingPort);
+        assert_eq!(parse(":80"), AddressError::MissingHost);
+        assert_eq!(
+            parse("host:http"),
+            AddressError::InvalidPort("http".to_string())
+        );
+        assert_eq!(
+            parse("host:65536"),
+            AddressError::InvalidPort("65536".to_string())
+        );
+        assert_eq!(
+            parse("::1:80"),
+            AddressError::InvalidHost("::1".to_string())
+        );
+        assert_eq!(
+            parse("[example.com]:80"),
+            AddressError::InvalidHost("example.com".to_string())
+        );
+
+        // the typed error is kept in the io error
+        let err = "host".into_address().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrNotAvailable);
+        assert_eq!(
+            err.into_inner().unwrap().downcast_ref::<AddressError>(),
+            Some(&AddressError::MissingPort)
+        );
+    }
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(
+            Address::parse_list("a.com:443, [::1]:80").unwrap(),
+            vec![
+                Address::Domain("a.com".to_string(), 443),
+                "[::1]:80".into_address().unwrap()
+            ]
+        );
+        assert_eq!(
+            Address::parse_list("a.com:443,"),
+            Err(AddressError::MissingPort)
+        );
     }
 }
@@ -1 +1,3 @@
 pub const UDP_BUFFER_SIZE: usize = 2048;
+/// Space reserved for each datagram received into a growable buffer.
+pub const MAX_DATAGRAM_SIZE: usize = 65536;
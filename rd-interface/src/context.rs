@@ -1,6 +1,12 @@
 use crate::Value;
 use serde::{de::DeserializeOwned, Serialize};
-use std::{collections::HashMap, fmt::Debug, net::SocketAddr};
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt::{self, Debug},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 
 /// Context error
@@ -18,12 +24,98 @@ pub trait CommonField: DeserializeOwned + Serialize + 'static {
     const KEY: &'static str;
 }
 
+trait AnyClone: Any + Send + Sync {
+    fn clone_box(&self) -> Box<dyn AnyClone>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Any + Clone + Send + Sync> AnyClone for T {
+    fn clone_box(&self) -> Box<dyn AnyClone> {
+        Box::new(self.clone())
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+/// A map holding one value of each type, for components to attach their
+/// own state to a [`Context`] without serializing it.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn AnyClone>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+    /// Inserts a value, returning the previous value of the type.
+    pub fn insert<T: Any + Clone + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|v| v.into_any().downcast().ok())
+            .map(|v| *v)
+    }
+    /// Returns the value of type `T`.
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|v| (**v).as_any().downcast_ref())
+    }
+    /// Returns a mutable reference to the value of type `T`.
+    pub fn get_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|v| (**v).as_any_mut().downcast_mut())
+    }
+    /// Removes the value of type `T`, returning it.
+    pub fn remove<T: Any>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|v| v.into_any().downcast().ok())
+            .map(|v| *v)
+    }
+}
+
+impl Clone for Extensions {
+    fn clone(&self) -> Self {
+        Extensions {
+            map: self
+                .map
+                .iter()
+                .map(|(k, v)| (*k, (**v).clone_box()))
+                .collect(),
+        }
+    }
+}
+
+impl Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
 /// A context stores a source endpoint, a process info and other any values
 /// during connecting.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Context {
     data: HashMap<String, Value>,
     net_list: Vec<String>,
+    resolved: HashMap<String, Vec<IpAddr>>,
+    source_addr: Option<SocketAddr>,
+    matched_rule: Option<String>,
+    deadline: Option<Instant>,
+    extensions: Extensions,
 }
 
 impl Context {
@@ -32,11 +124,17 @@ impl Context {
         Context {
             data: HashMap::new(),
             net_list: Vec::new(),
+            resolved: HashMap::new(),
+            source_addr: None,
+            matched_rule: None,
+            deadline: None,
+            extensions: Extensions::new(),
         }
     }
     /// new a context from socket addr
     pub fn from_socketaddr(addr: SocketAddr) -> Context {
         let mut ctx = Context::new();
+        ctx.set_source_addr(addr);
         ctx.insert_common(common_field::SourceAddress { addr })
             .unwrap();
         ctx
@@ -88,6 +186,71 @@ impl Context {
     pub fn net_list(&self) -> &Vec<String> {
         &self.net_list
     }
+    /// Caches the resolved IPs of a domain, so the following nets don't
+    /// need to resolve it again.
+    pub fn set_resolved(&mut self, domain: &str, ips: Vec<IpAddr>) {
+        self.resolved.insert(domain.to_ascii_lowercase(), ips);
+    }
+    /// Returns the cached IPs of a domain.
+    pub fn resolved(&self, domain: &str) -> Option<&[IpAddr]> {
+        self.resolved
+            .get(&domain.to_ascii_lowercase())
+            .map(Vec::as_slice)
+    }
+    /// Sets the address of the client the connection comes from.
+    pub fn set_source_addr(&mut self, addr: SocketAddr) {
+        self.source_addr = Some(addr);
+    }
+    /// Returns the address of the client, if the context is created by a
+    /// server.
+    pub fn source_addr(&self) -> Option<SocketAddr> {
+        self.source_addr
+    }
+    /// Sets the rule that routes the connection, or clears it with `None`.
+    pub fn set_matched_rule(&mut self, rule: Option<String>) {
+        self.matched_rule = rule;
+    }
+    /// Returns the rule that routed the connection, e.g. `#0 domain`.
+    pub fn matched_rule(&self) -> Option<&str> {
+        self.matched_rule.as_deref()
+    }
+    /// Sets the deadline of the connection. An earlier deadline set before
+    /// is kept.
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(match self.deadline {
+            Some(d) => d.min(deadline),
+            None => deadline,
+        });
+    }
+    /// Sets the deadline to `timeout` from now, unless it's already earlier.
+    pub fn with_timeout(&mut self, timeout: Duration) -> &mut Context {
+        self.set_deadline(Instant::now() + timeout);
+        self
+    }
+    /// Returns the deadline of the connection.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+    /// Returns the time left before the deadline, zero if it's passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+    /// Returns the smaller of `timeout` and the time left before the deadline.
+    pub fn timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        match (timeout, self.remaining()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+    /// Returns the typed values attached to the context.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+    /// Returns the typed values attached to the context, for changing them.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
 }
 
 /// Common context keys and types
@@ -112,4 +275,113 @@ pub mod common_field {
     impl CommonField for ProcessInfo {
         const KEY: &'static str = "process_info";
     }
+
+    /// The net the rule net sends the connection to.
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct RuleTarget {
+        pub net: String,
+    }
+
+    impl CommonField for RuleTarget {
+        const KEY: &'static str = "rule_target";
+    }
+
+    /// The destination host read from the first bytes of the connection,
+    /// i.e. the SNI of a TLS ClientHello or the `Host` of an HTTP request.
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct SniffedHost {
+        pub host: String,
+    }
+
+    impl CommonField for SniffedHost {
+        const KEY: &'static str = "sniffed_host";
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolved() {
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut ctx = Context::new();
+        assert_eq!(ctx.resolved("example.com"), None);
+
+        ctx.set_resolved("Example.com", vec![ip]);
+        assert_eq!(ctx.resolved("example.com"), Some(&[ip][..]));
+        assert_eq!(ctx.clone().resolved("EXAMPLE.COM"), Some(&[ip][..]));
+        assert_eq!(ctx.resolved("example.org"), None);
+    }
+
+    #[test]
+    fn test_source_addr() {
+        let addr: SocketAddr = "192.168.1.2:34567".parse().unwrap();
+        assert_eq!(Context::new().source_addr(), None);
+
+        let ctx = Context::from_socketaddr(addr);
+        assert_eq!(ctx.source_addr(), Some(addr));
+        assert_eq!(ctx.clone().source_addr(), Some(addr));
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut ctx = Context::new();
+        assert_eq!(ctx.remaining(), None);
+        assert_eq!(ctx.timeout(None), None);
+        let second = Duration::from_secs(1);
+        assert_eq!(ctx.timeout(Some(second)), Some(second));
+
+        ctx.with_timeout(Duration::from_secs(10));
+        let deadline = ctx.deadline().unwrap();
+        assert!(ctx.remaining().unwrap() > second);
+        assert_eq!(ctx.timeout(Some(second)), Some(second));
+        assert!(ctx.timeout(Some(Duration::from_secs(60))).unwrap() <= Duration::from_secs(10));
+
+        // a later deadline doesn't extend it
+        ctx.with_timeout(Duration::from_secs(20));
+        assert_eq!(ctx.clone().deadline(), Some(deadline));
+
+        ctx.set_deadline(Instant::now() - second);
+        assert_eq!(ctx.remaining(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_extensions() {
+        #[derive(Debug, Clone, PartialEq)]
+        struct User(String);
+        #[derive(Debug, Clone, PartialEq)]
+        struct Tags(Vec<&'static str>);
+
+        let mut ctx = Context::new();
+        assert_eq!(ctx.extensions().get::<User>(), None);
+
+        let ext = ctx.extensions_mut();
+        assert_eq!(ext.insert(User("alice".to_string())), None);
+        assert_eq!(ext.insert(Tags(vec!["a"])), None);
+        ext.get_mut::<Tags>().unwrap().0.push("b");
+
+        let cloned = ctx.clone();
+        assert_eq!(
+            cloned.extensions().get::<User>(),
+            Some(&User("alice".to_string()))
+        );
+        assert_eq!(
+            cloned.extensions().get::<Tags>(),
+            Some(&Tags(vec!["a", "b"]))
+        );
+
+        // the clone is independent
+        let ext = ctx.extensions_mut();
+        assert_eq!(
+            ext.insert(User("bob".to_string())),
+            Some(User("alice".to_string()))
+        );
+        assert_eq!(ext.remove::<Tags>(), Some(Tags(vec!["a", "b"])));
+        assert_eq!(ext.get::<Tags>(), None);
+        assert_eq!(
+            cloned.extensions().get::<User>(),
+            Some(&User("alice".to_string()))
+        );
+    }
 }
@@ -1,30 +1,65 @@
 use std::io;
 use thiserror::Error;
 
-/// Errors in this crate.
+/// Errors in this crate. The variants wrapping an error print it and have
+/// no `source()`, except for `Other` which stands for the error it holds.
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("IO error")]
-    IO(#[from] io::Error),
+    #[error("IO error: {0}")]
+    IO(io::Error),
     #[error("Not enabled in config")]
     NotEnabled,
     #[error("Not implemented")]
     NotImplemented,
     #[error("Config error: {0}")]
-    Config(#[from] serde_json::Error),
+    Config(serde_json::Error),
     #[error("Aborted by user")]
     AbortedByUser,
-    #[error("Context error: {0:?}")]
-    Context(#[from] crate::context::Error),
-    #[error("Not found")]
+    #[error("Context error: {0}")]
+    Context(crate::context::Error),
+    #[error("Not found: {0}")]
     NotFound(String),
-    #[error("{0:?}")]
+    /// The peer broke the protocol, e.g. sent a malformed reply.
+    #[error("{proto} protocol error: {detail}")]
+    Protocol { proto: &'static str, detail: String },
+    /// The peer rejected the credentials, or none of the auth methods it offers is supported.
+    #[error("Authentication failed: {0}")]
+    AuthFailed(String),
+    #[error(transparent)]
     Other(Box<dyn std::error::Error + Send + Sync + 'static>),
 }
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub const NOT_IMPLEMENTED: Error = Error::NotImplemented;
 pub const NOT_ENABLED: Error = Error::NotEnabled;
 
+impl Error {
+    pub fn protocol(proto: &'static str, detail: impl ToString) -> Error {
+        Error::Protocol {
+            proto,
+            detail: detail.to_string(),
+        }
+    }
+}
+
+// Not `#[from]`, which would make the wrapped error the source too.
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::IO(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Config(e)
+    }
+}
+
+impl From<crate::context::Error> for Error {
+    fn from(e: crate::context::Error) -> Self {
+        Error::Context(e)
+    }
+}
+
 pub fn map_other(e: impl std::error::Error + Send + Sync + 'static) -> Error {
     Error::Other(e.into())
 }
@@ -33,17 +68,16 @@ impl From<Error> for io::Error {
     fn from(e: Error) -> Self {
         match e {
             Error::IO(e) => e,
-            e => io::Error::new(io::ErrorKind::Other, e),
+            e @ Error::Protocol { .. } => io::Error::new(io::ErrorKind::InvalidData, e),
+            e @ Error::AuthFailed(_) => io::Error::new(io::ErrorKind::PermissionDenied, e),
+            e => io::Error::other(e),
         }
     }
 }
 
 impl Error {
     pub fn is_aborted(&self) -> bool {
-        match self {
-            Error::AbortedByUser => true,
-            _ => false,
-        }
+        matches!(self, Error::AbortedByUser)
     }
     pub fn is_addr_in_use(&self) -> bool {
         match self {
@@ -52,3 +86,67 @@ impl Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{error::Error as _, fmt};
+
+    #[derive(Debug)]
+    struct Outer(io::Error);
+
+    impl fmt::Display for Outer {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "outer")
+        }
+    }
+
+    impl std::error::Error for Outer {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    fn source_string(e: &Error) -> Option<String> {
+        e.source().map(ToString::to_string)
+    }
+
+    #[test]
+    fn test_source() {
+        let e = Error::from(io::Error::other("refused"));
+        assert_eq!(e.to_string(), "IO error: refused");
+        assert!(e.source().is_none());
+
+        let json = serde_json::from_str::<u8>("x").unwrap_err();
+        let json_msg = json.to_string();
+        let e = Error::from(json);
+        assert_eq!(e.to_string(), format!("Config error: {}", json_msg));
+        assert!(e.source().is_none());
+
+        let e = Error::from(crate::context::Error::NonExist);
+        assert_eq!(e.to_string(), "Context error: item not exists");
+        assert!(e.source().is_none());
+
+        let e = map_other(Outer(io::Error::other("inner")));
+        assert_eq!(e.to_string(), "outer");
+        assert_eq!(source_string(&e).unwrap(), "inner");
+        let e = Error::Other("plain".into());
+        assert_eq!(e.to_string(), "plain");
+        assert!(e.source().is_none());
+
+        assert_eq!(
+            Error::NotFound("net".to_string()).to_string(),
+            "Not found: net"
+        );
+        for e in [
+            NOT_ENABLED,
+            NOT_IMPLEMENTED,
+            Error::AbortedByUser,
+            Error::NotFound("net".to_string()),
+            Error::protocol("socks5", "bad version"),
+            Error::AuthFailed("wrong password".to_string()),
+        ] {
+            assert!(e.source().is_none(), "{:?}", e);
+        }
+    }
+}
@@ -1,6 +1,10 @@
+use bytes::BytesMut;
 use std::net::SocketAddr;
 
+use crate::constant::MAX_DATAGRAM_SIZE;
 pub use crate::Context;
+// `Error` is also re-exported by name at the crate root, which shadows this one
+#[allow(unused_imports)]
 pub use crate::{Address, Error, Result};
 pub use async_trait::async_trait;
 pub use std::sync::Arc;
@@ -52,6 +56,47 @@ pub trait IUdpSocket: Unpin + Send + Sync {
     async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)>;
     async fn send_to(&self, buf: &[u8], addr: Address) -> Result<usize>;
     async fn local_addr(&self) -> Result<SocketAddr>;
+    /// Restricts the socket to `addr`. Datagrams from other peers are dropped.
+    async fn connect(&self, _addr: SocketAddr) -> Result<()> {
+        Err(crate::NOT_IMPLEMENTED)
+    }
+    /// The peer set by [`connect()`](IUdpSocket::connect()).
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        Err(crate::NOT_IMPLEMENTED)
+    }
+    /// Sends to the connected peer.
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let peer = self.peer_addr().await?;
+        self.send_to(buf, peer.into()).await
+    }
+    /// Receives from the connected peer, skipping datagrams from the others.
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let peer = self.peer_addr().await?;
+        loop {
+            let (size, addr) = self.recv_from(buf).await?;
+            if addr == peer {
+                return Ok(size);
+            }
+        }
+    }
+    /// Receives a datagram appended to `buf`, which grows as needed.
+    async fn recv_from_buf(&self, buf: &mut BytesMut) -> Result<(usize, SocketAddr)> {
+        let start = buf.len();
+        buf.resize(start + MAX_DATAGRAM_SIZE, 0);
+        let result = self.recv_from(&mut buf[start..]).await;
+        let size = result.as_ref().map(|(size, _)| *size).unwrap_or_default();
+        buf.truncate(start + size);
+        result
+    }
+    /// Receives up to `max` datagrams appended to `buf`, waiting for the
+    /// first one only. Returns the size and the sender of each, in order.
+    /// Receives a single datagram by default.
+    async fn recv_batch(&self, buf: &mut BytesMut, max: usize) -> Result<Vec<(usize, SocketAddr)>> {
+        if max == 0 {
+            return Ok(Vec::new());
+        }
+        Ok(vec![self.recv_from_buf(buf).await?])
+    }
 }
 pub type UdpSocket = Arc<dyn IUdpSocket>;
 
@@ -70,6 +115,39 @@ pub trait INet: Unpin + Send + Sync {
     async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream>;
     async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener>;
     async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket>;
+    /// Resolves `addr` to the addresses this net would connect to. Nets that
+    /// don't resolve domains return
+    /// [`Error::NotImplemented`](crate::Error::NotImplemented) for them.
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        match addr {
+            Address::SocketAddr(addr) => Ok(vec![*addr]),
+            Address::Domain(..) | Address::Unix(_) => Err(crate::NOT_IMPLEMENTED),
+        }
+    }
+    /// Connects to each of `addrs` in order, returning the first connection
+    /// made or the last error.
+    async fn tcp_connect_any(&self, ctx: &mut Context, addrs: &[Address]) -> Result<TcpStream> {
+        let mut last_err = None;
+        for addr in addrs {
+            match self.tcp_connect(ctx, addr.clone()).await {
+                Ok(tcp) => return Ok(tcp),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| std::io::Error::from(std::io::ErrorKind::AddrNotAvailable).into()))
+    }
+    /// Identifies the net behind this one. Nets that only forward to
+    /// another net should return the id of that net.
+    fn id(&self) -> *const () {
+        self as *const Self as *const ()
+    }
+    /// Whether `other` is this net, so nets referred to more than once can
+    /// be told apart from different nets. Wrappers forwarding
+    /// [`id`](INet::id) are the same as the net they wrap.
+    fn is_same(&self, other: &Net) -> bool {
+        self.id() == other.id()
+    }
 }
 pub type Net = Arc<dyn INet>;
 
@@ -86,6 +164,22 @@ impl<T: INet> IntoDyn<Net> for T {
 #[async_trait]
 pub trait IServer: Unpin + Send + Sync {
     async fn start(&self) -> Result<()>;
+    /// Stops a running [`start()`](IServer::start()), which then releases the
+    /// listener and returns `Ok(())`.
+    async fn stop(&self) -> Result<()> {
+        Err(crate::NOT_IMPLEMENTED)
+    }
+    /// The address the server listens on, known after [`start()`](IServer::start())
+    /// binds it. Useful when the server binds to port 0.
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Err(crate::NOT_IMPLEMENTED)
+    }
+    /// Applies a new `config` and `net` to the running server, keeping its
+    /// listener. Servers that can't, e.g. when the bind address changes,
+    /// return an error and are restarted instead.
+    async fn reload(&self, _net: Net, _config: serde_json::Value) -> Result<()> {
+        Err(crate::NOT_IMPLEMENTED)
+    }
 }
 pub type Server = Box<dyn IServer>;
 
@@ -114,3 +208,125 @@ impl<T: IUdpChannel> crate::IntoDyn<UdpChannel> for T {
         Arc::new(self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_executor::block_on;
+    use std::{collections::VecDeque, sync::Mutex};
+
+    struct QueueUdp(Mutex<VecDeque<(Vec<u8>, SocketAddr)>>);
+
+    impl QueueUdp {
+        fn new() -> Self {
+            let addr: SocketAddr = "127.0.0.1:5353".parse().unwrap();
+            QueueUdp(Mutex::new(
+                vec![
+                    (b"first".to_vec(), addr),
+                    (vec![], addr),
+                    (vec![7u8; 3000], addr),
+                ]
+                .into(),
+            ))
+        }
+    }
+
+    #[async_trait]
+    impl IUdpSocket for QueueUdp {
+        async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+            let (data, addr) = self
+                .0
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or(crate::NOT_ENABLED)?;
+            buf[..data.len()].copy_from_slice(&data);
+            Ok((data.len(), addr))
+        }
+        async fn send_to(&self, _buf: &[u8], _addr: Address) -> Result<usize> {
+            Err(crate::NOT_IMPLEMENTED)
+        }
+        async fn local_addr(&self) -> Result<SocketAddr> {
+            Err(crate::NOT_IMPLEMENTED)
+        }
+    }
+
+    /// Connected to `1`.
+    struct ConnectedUdp(QueueUdp, SocketAddr);
+
+    #[async_trait]
+    impl IUdpSocket for ConnectedUdp {
+        async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+            self.0.recv_from(buf).await
+        }
+        async fn send_to(&self, buf: &[u8], addr: Address) -> Result<usize> {
+            self.0.send_to(buf, addr).await
+        }
+        async fn local_addr(&self) -> Result<SocketAddr> {
+            self.0.local_addr().await
+        }
+        async fn peer_addr(&self) -> Result<SocketAddr> {
+            Ok(self.1)
+        }
+    }
+
+    #[test]
+    fn test_recv_connected() {
+        block_on(async {
+            let peer: SocketAddr = "127.0.0.1:5353".parse().unwrap();
+            let other: SocketAddr = "127.0.0.2:5353".parse().unwrap();
+            let queue = QueueUdp(Mutex::new(
+                vec![
+                    (b"spoofed".to_vec(), other),
+                    (b"first".to_vec(), peer),
+                    (b"other".to_vec(), other),
+                    (b"second".to_vec(), peer),
+                ]
+                .into(),
+            ));
+            let mut buf = [0u8; 16];
+            assert!(matches!(
+                queue.recv(&mut buf).await,
+                Err(crate::Error::NotImplemented)
+            ));
+
+            let udp = ConnectedUdp(queue, peer);
+            let size = udp.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..size], b"first");
+            let size = udp.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..size], b"second");
+            assert!(udp.recv(&mut buf).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_recv_batch() {
+        block_on(async {
+            let single = QueueUdp::new();
+            let mut expected = Vec::new();
+            let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+            for _ in 0..3 {
+                let (size, addr) = single.recv_from(&mut buf).await.unwrap();
+                expected.push((buf[..size].to_vec(), addr));
+            }
+
+            let batched = QueueUdp::new();
+            let mut buf = BytesMut::from(&b"kept"[..]);
+            let mut received = Vec::new();
+            let mut start = 4;
+            while received.len() < expected.len() {
+                for (size, addr) in batched.recv_batch(&mut buf, 8).await.unwrap() {
+                    received.push((buf[start..start + size].to_vec(), addr));
+                    start += size;
+                }
+            }
+            assert_eq!(received, expected);
+            assert_eq!(&buf[..4], b"kept");
+            assert_eq!(buf.len(), start);
+
+            assert!(batched.recv_batch(&mut buf, 0).await.unwrap().is_empty());
+            assert!(batched.recv_from_buf(&mut buf).await.is_err());
+            assert_eq!(buf.len(), start);
+        });
+    }
+}
@@ -1,4 +1,4 @@
-pub use address::{Address, IntoAddress};
+pub use address::{Address, AddressError, IntoAddress};
 pub use context::Context;
 pub use error::{Error, Result, NOT_ENABLED, NOT_IMPLEMENTED};
 pub use interface::*;
@@ -6,7 +6,7 @@ pub use rd_derive::Config;
 pub use registry::Registry;
 pub use schemars;
 pub use serde_json::Value;
-pub use util::{CombineNet, NotImplementedNet};
+pub use util::{CombineNet, FallbackCombineNet, NotImplementedNet};
 
 mod address;
 pub mod constant;
@@ -14,5 +14,6 @@ pub mod context;
 pub mod error;
 mod interface;
 mod macros;
+pub mod plugin;
 pub mod registry;
 pub mod util;
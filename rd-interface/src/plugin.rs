@@ -0,0 +1,64 @@
+//! Nets and servers from a shared library, loaded at runtime.
+//!
+//! A plugin is a `cdylib` calling [`export_plugin!`](crate::export_plugin)
+//! with the function registering its nets and servers.
+//!
+//! There is no stable ABI. The registry and the nets cross the boundary as
+//! Rust types, whose layout may change with any compiler or `rd-interface`
+//! release, so a plugin has to be built with the same rustc and the same
+//! version of `rd-interface` as the program loading it. Both versions are
+//! checked before the plugin is initialized, anything else (e.g. different
+//! features or profiles) is up to whoever builds it.
+
+use crate::{Error, Registry};
+use std::os::raw::c_char;
+
+/// Returned by the [`PLUGIN_ABI_VERSION_SYMBOL`] of a plugin, the versions
+/// of `rd-interface` and of the rustc it's built with.
+pub const PLUGIN_ABI_VERSION: &str = concat!(
+    "rd-interface ",
+    env!("CARGO_PKG_VERSION"),
+    ", ",
+    env!("RD_RUSTC_VERSION"),
+    "\0"
+);
+/// `extern "C" fn() -> *const c_char`, the nul terminated
+/// [`PLUGIN_ABI_VERSION`] the plugin is built with.
+pub const PLUGIN_ABI_VERSION_SYMBOL: &[u8] = b"rd_plugin_abi_version\0";
+/// [`PluginInit`], registering the nets and servers of the plugin.
+pub const PLUGIN_INIT_SYMBOL: &[u8] = b"rd_plugin_init\0";
+
+pub type PluginAbiVersion = extern "C" fn() -> *const c_char;
+/// Returns a boxed error, or null on success.
+pub type PluginInit = unsafe extern "C" fn(registry: *mut Registry) -> *mut Error;
+
+/// Exports the init function of a plugin.
+///
+/// ```ignore
+/// fn init(registry: &mut Registry) -> Result<()> {
+///     registry.add_net::<MyNet>();
+///     Ok(())
+/// }
+///
+/// rd_interface::export_plugin!(init);
+/// ```
+#[macro_export]
+macro_rules! export_plugin {
+    ($init:path) => {
+        #[no_mangle]
+        pub extern "C" fn rd_plugin_abi_version() -> *const ::std::os::raw::c_char {
+            $crate::plugin::PLUGIN_ABI_VERSION.as_ptr() as *const ::std::os::raw::c_char
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn rd_plugin_init(
+            registry: *mut $crate::Registry,
+        ) -> *mut $crate::Error {
+            let init: fn(&mut $crate::Registry) -> $crate::Result<()> = $init;
+            match init(&mut *registry) {
+                Ok(()) => ::std::ptr::null_mut(),
+                Err(e) => ::std::boxed::Box::into_raw(::std::boxed::Box::new(e)),
+            }
+        }
+    };
+}
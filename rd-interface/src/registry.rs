@@ -1,6 +1,6 @@
 use std::{collections::HashMap, fmt};
 
-pub use self::net_ref::{NetRef, ResolveNetRef};
+pub use self::net_ref::{with_default_net, NetRef, ResolveNetRef};
 use crate::{INet, IServer, IntoDyn, Net, Result, Server};
 pub use schemars::JsonSchema;
 use schemars::{
@@ -14,6 +14,7 @@ pub type NetMap = HashMap<String, Net>;
 
 mod net_ref;
 
+#[derive(Default)]
 pub struct Registry {
     pub net: HashMap<String, NetResolver>,
     pub server: HashMap<String, ServerResolver>,
@@ -36,11 +37,19 @@ impl Registry {
         }
     }
     pub fn add_net<N: NetFactory>(&mut self) {
-        self.net.insert(N::NAME.into(), NetResolver::new::<N>());
+        self.add_net_as::<N>(N::NAME);
+    }
+    /// Like `add_net`, but under `name` instead of `N::NAME`, so a net can be
+    /// registered more than once.
+    pub fn add_net_as<N: NetFactory>(&mut self, name: &str) {
+        self.net.insert(name.into(), NetResolver::new::<N>());
     }
     pub fn add_server<S: ServerFactory>(&mut self) {
-        self.server
-            .insert(S::NAME.into(), ServerResolver::new::<S>());
+        self.add_server_as::<S>(S::NAME);
+    }
+    /// Like `add_server`, but under `name` instead of `S::NAME`.
+    pub fn add_server_as<S: ServerFactory>(&mut self, name: &str) {
+        self.server.insert(name.into(), ServerResolver::new::<S>());
     }
 }
 
@@ -137,7 +146,7 @@ impl ServerResolver {
 }
 
 #[derive(Debug, Default, serde_derive::Deserialize)]
-pub struct EmptyConfig(Value);
+pub struct EmptyConfig(#[allow(dead_code)] Value);
 
 impl ResolveNetRef for EmptyConfig {
     fn resolve(&mut self, _nets: &NetMap) -> Result<()> {
@@ -159,3 +168,64 @@ impl JsonSchema for EmptyConfig {
         .into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{async_trait, NotImplementedNet};
+
+    struct TestNet;
+
+    impl NetFactory for TestNet {
+        const NAME: &'static str = "test";
+        type Config = EmptyConfig;
+        type Net = NotImplementedNet;
+
+        fn new(_config: Self::Config) -> Result<Self::Net> {
+            Ok(NotImplementedNet)
+        }
+    }
+
+    struct TestServer;
+
+    #[async_trait]
+    impl IServer for TestServer {
+        async fn start(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ServerFactory for TestServer {
+        const NAME: &'static str = "test";
+        type Config = EmptyConfig;
+        type Server = Self;
+
+        fn new(_listen: Net, _net: Net, _config: Self::Config) -> Result<Self> {
+            Ok(TestServer)
+        }
+    }
+
+    #[test]
+    fn test_add_as() {
+        let mut registry = Registry::new();
+        registry.add_net::<TestNet>();
+        registry.add_net_as::<TestNet>("a");
+        registry.add_net_as::<TestNet>("b");
+        registry.add_server_as::<TestServer>("c");
+
+        let mut names: Vec<_> = registry.net.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, ["a", "b", "test"]);
+        for name in &names {
+            registry.net[name]
+                .build(&NetMap::new(), Value::Null)
+                .unwrap();
+        }
+
+        assert!(!registry.server.contains_key("test"));
+        let net = NotImplementedNet.into_dyn();
+        registry.server["c"]
+            .build(net.clone(), net, Value::Null)
+            .unwrap();
+    }
+}
@@ -6,14 +6,33 @@ use schemars::{
 };
 use serde::{de, ser};
 use std::{
+    cell::RefCell,
     collections::{BTreeMap, HashMap, LinkedList, VecDeque},
     fmt,
     ops::Deref,
     sync::Arc,
 };
 
+thread_local! {
+    static DEFAULT_NET: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with `name` as the default value of `NetRef` instead of `"local"`.
+/// Configs must be deserialized inside `f` to take it.
+pub fn with_default_net<R>(name: &str, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<String>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            DEFAULT_NET.with(|d| *d.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(DEFAULT_NET.with(|d| d.replace(Some(name.to_string()))));
+    f()
+}
+
 /// `NetRef` represents a reference to another `Net`. It is a string in the configuration file.
-/// The default value is `"local"`.
+/// The default value is `"local"`, or the name set by [`with_default_net`].
 #[derive(Clone)]
 pub struct NetRef {
     name: String,
@@ -39,10 +58,10 @@ impl fmt::Debug for NetRef {
 }
 
 fn default_net() -> NetRef {
-    NetRef {
-        name: "local".to_string(),
-        net: None,
-    }
+    let name = DEFAULT_NET
+        .with(|d| d.borrow().clone())
+        .unwrap_or_else(|| "local".to_string());
+    NetRef { name, net: None }
 }
 
 impl NetRef {
@@ -55,6 +74,13 @@ impl NetRef {
             .expect("Net must be resolved before used")
             .clone()
     }
+    /// Same as [`net()`](NetRef::net()), but returns [`Error::NotFound`] if
+    /// it's not resolved.
+    pub fn try_net(&self) -> Result<Net> {
+        self.net
+            .clone()
+            .ok_or_else(|| Error::NotFound(self.name.clone()))
+    }
 }
 
 impl Deref for NetRef {
@@ -190,7 +216,7 @@ macro_rules! impl_key_container_resolve {
     )*)
 }
 
-impl_empty_resolve! { String, u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, bool, f32, f64 }
+impl_empty_resolve! { String, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, bool, f32, f64 }
 impl_container_resolve! { Vec, Option, VecDeque, Result, LinkedList }
 impl_key_container_resolve! { HashMap, BTreeMap }
 
@@ -217,6 +243,63 @@ mod tests {
         net_map.insert("test".to_string(), noop.clone());
         test.net.resolve(&net_map).unwrap();
 
-        assert_eq!(Arc::as_ptr(&test.net[0]), Arc::as_ptr(&noop))
+        assert_eq!(Arc::as_ptr(&test.net[0]), Arc::as_ptr(&noop));
+        assert_eq!(
+            Arc::as_ptr(&test.net[0].try_net().unwrap()),
+            Arc::as_ptr(&noop)
+        );
+    }
+
+    #[test]
+    fn test_is_same() {
+        #[derive(Deserialize)]
+        struct TestConfig {
+            net: Vec<NetRef>,
+        }
+
+        let mut test: TestConfig = serde_json::from_str(r#"{ "net": ["a", "b", "c"] }"#).unwrap();
+        let mut net_map = NetMap::new();
+        let noop = NotImplementedNet.into_dyn();
+        net_map.insert("a".to_string(), noop.clone());
+        net_map.insert("b".to_string(), noop);
+        net_map.insert("c".to_string(), NotImplementedNet.into_dyn());
+        test.net.resolve(&net_map).unwrap();
+
+        let nets: Vec<Net> = test.net.iter().map(NetRef::net).collect();
+        assert!(nets[0].is_same(&nets[1]));
+        assert!(nets[1].is_same(&nets[0]));
+        assert!(!nets[0].is_same(&nets[2]));
+    }
+
+    #[test]
+    fn test_default_net() {
+        #[derive(Deserialize)]
+        struct TestConfig {
+            #[serde(default)]
+            net: NetRef,
+        }
+        let parse = || serde_json::from_str::<TestConfig>("{}").unwrap().net;
+
+        assert_eq!(parse().name(), "local");
+        with_default_net("base", || {
+            assert_eq!(parse().name(), "base");
+            with_default_net("inner", || assert_eq!(parse().name(), "inner"));
+            assert_eq!(parse().name(), "base");
+
+            // the dependency of an omitted field follows the default too
+            let mut config = parse();
+            assert_eq!(config.get_dependency().unwrap(), vec!["base".to_string()]);
+        });
+        assert_eq!(parse().name(), "local");
+    }
+
+    #[test]
+    fn test_try_net_unresolved() {
+        let net_ref = NetRef::from("test".to_string());
+
+        match net_ref.try_net() {
+            Err(Error::NotFound(name)) => assert_eq!(name, "test"),
+            _ => panic!("unresolved NetRef should be NotFound"),
+        }
     }
 }
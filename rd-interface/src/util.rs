@@ -5,17 +5,25 @@ use crate::{
     },
     Address, Context, Result, NOT_IMPLEMENTED,
 };
-use futures_util::future::try_join;
+use futures_util::{
+    future::{select, try_join, Either},
+    pin_mut,
+};
 use std::{
     collections::VecDeque,
     future::Future,
     io,
     net::SocketAddr,
     pin::Pin,
+    sync::Mutex,
     task::{self, Poll},
+    time::Duration,
 };
 pub use tokio::io::copy_bidirectional;
-use tokio::io::{AsyncReadExt, ReadBuf};
+use tokio::{
+    io::{AsyncReadExt, ReadBuf},
+    sync::watch,
+};
 pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
 /// Connect two `TcpStream`
@@ -41,7 +49,7 @@ impl AsyncRead for PeekableTcpStream {
         buf: &mut ReadBuf,
     ) -> Poll<io::Result<()>> {
         let (first, ..) = &self.buf.as_slices();
-        if first.len() > 0 {
+        if !first.is_empty() {
             let read = first.len().min(buf.remaining());
             let unfilled = buf.initialize_unfilled_to(read);
             unfilled[0..read].copy_from_slice(&first[0..read]);
@@ -92,13 +100,15 @@ impl PeekableTcpStream {
             buf: VecDeque::new(),
         }
     }
-    // Fill self.buf to size using self.tcp.read_exact
+    // Fill self.buf to size, keeping what's read if cancelled
     async fn fill_buf(&mut self, size: usize) -> crate::Result<()> {
-        if size > self.buf.len() {
-            let to_read = size - self.buf.len();
-            let mut buf = vec![0u8; to_read];
-            self.tcp.read_exact(&mut buf).await?;
-            self.buf.append(&mut buf.into());
+        while size > self.buf.len() {
+            let mut buf = vec![0u8; size - self.buf.len()];
+            let read = self.tcp.read(&mut buf).await?;
+            if read == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.buf.extend(&buf[..read]);
         }
         Ok(())
     }
@@ -109,6 +119,18 @@ impl PeekableTcpStream {
 
         Ok(())
     }
+    /// Reads once more from the stream into the peeked bytes. Returns the
+    /// number of bytes read, 0 at EOF.
+    pub async fn peek_more(&mut self, max: usize) -> crate::Result<usize> {
+        let mut buf = vec![0u8; max];
+        let read = self.tcp.read(&mut buf).await?;
+        self.buf.extend(&buf[..read]);
+        Ok(read)
+    }
+    /// Returns all the peeked bytes.
+    pub fn peeked(&mut self) -> &[u8] {
+        self.buf.make_contiguous()
+    }
     pub fn into_inner(self) -> (TcpStream, VecDeque<u8>) {
         (self.tcp, self.buf)
     }
@@ -175,6 +197,72 @@ impl INet for CombineNet {
     {
         self.udp_bind.udp_bind(ctx, addr)
     }
+
+    #[inline(always)]
+    fn lookup_host<'life0: 'a, 'life1: 'a, 'a>(
+        &'life0 self,
+        addr: &'life1 Address,
+    ) -> BoxFuture<'a, Result<Vec<SocketAddr>>>
+    where
+        Self: 'a,
+    {
+        self.tcp_connect.lookup_host(addr)
+    }
+}
+
+/// Like [`CombineNet`], but each operation tries its nets in order, until one
+/// of them succeeds. The error of the last net is returned if all failed.
+pub struct FallbackCombineNet {
+    pub tcp_connect: Vec<Net>,
+    pub tcp_bind: Vec<Net>,
+    pub udp_bind: Vec<Net>,
+}
+
+#[async_trait]
+impl INet for FallbackCombineNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        let mut last_err = NOT_IMPLEMENTED;
+        for net in &self.tcp_connect {
+            match net.tcp_connect(ctx, addr.clone()).await {
+                Ok(tcp) => return Ok(tcp),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        let mut last_err = NOT_IMPLEMENTED;
+        for net in &self.tcp_bind {
+            match net.tcp_bind(ctx, addr.clone()).await {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        let mut last_err = NOT_IMPLEMENTED;
+        for net in &self.udp_bind {
+            match net.udp_bind(ctx, addr.clone()).await {
+                Ok(udp) => return Ok(udp),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        let mut last_err = NOT_IMPLEMENTED;
+        for net in &self.tcp_connect {
+            match net.lookup_host(addr).await {
+                Ok(addrs) => return Ok(addrs),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
 }
 
 pub async fn connect_udp(udp_channel: UdpChannel, udp: UdpSocket) -> crate::Result<()> {
@@ -197,3 +285,93 @@ pub async fn connect_udp(udp_channel: UdpChannel, udp: UdpSocket) -> crate::Resu
     try_join(in_side, out_side).await?;
     Ok(())
 }
+
+/// Runs `fut` for at most `timeout`, usually from
+/// [`Context::timeout()`](crate::Context::timeout()). Fails with
+/// [`TimedOut`](io::ErrorKind::TimedOut) when it's up.
+pub async fn timeout<T>(
+    timeout: Option<Duration>,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+        None => fut.await,
+    }
+}
+
+/// Tells the accept loop of a server to stop.
+#[derive(Debug)]
+pub struct StopSignal(watch::Sender<bool>);
+
+impl Default for StopSignal {
+    fn default() -> Self {
+        StopSignal(watch::channel(false).0)
+    }
+}
+
+impl StopSignal {
+    pub fn new() -> StopSignal {
+        Self::default()
+    }
+    /// Stops the current and every later [`until()`](StopSignal::until()).
+    pub fn stop(&self) {
+        self.0.send_replace(true);
+    }
+    pub fn is_stopped(&self) -> bool {
+        *self.0.borrow()
+    }
+    /// Runs `fut` until it's done or stopped. `None` is returned if stopped.
+    pub async fn until<T>(&self, fut: impl Future<Output = T>) -> Option<T> {
+        let mut rx = self.0.subscribe();
+        let stopped = async { rx.wait_for(|stopped| *stopped).await.is_ok() };
+        pin_mut!(fut, stopped);
+        match select(fut, stopped).await {
+            Either::Left((r, _)) => Some(r),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+/// The address a server's listener is bound to.
+#[derive(Debug, Default)]
+pub struct BoundAddr(Mutex<Option<SocketAddr>>);
+
+impl BoundAddr {
+    pub fn new() -> BoundAddr {
+        Self::default()
+    }
+    pub fn set(&self, addr: SocketAddr) {
+        *self.0.lock().unwrap() = Some(addr);
+    }
+    /// Returns [`NotConnected`](io::ErrorKind::NotConnected) if it's not bound yet.
+    pub fn get(&self) -> Result<SocketAddr> {
+        self.0
+            .lock()
+            .unwrap()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_executor::block_on;
+    use futures_util::{future::pending, FutureExt};
+
+    #[test]
+    fn test_stop_signal() {
+        let signal = StopSignal::new();
+        assert_eq!(block_on(signal.until(async { 1 })), Some(1));
+
+        let running = signal.until(pending::<()>());
+        pin_mut!(running);
+        assert_eq!(running.as_mut().now_or_never(), None);
+
+        signal.stop();
+        assert!(signal.is_stopped());
+        assert_eq!(block_on(running), None);
+        assert_eq!(block_on(signal.until(pending::<()>())), None);
+    }
+}
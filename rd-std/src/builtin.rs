@@ -1,16 +1,28 @@
 use rd_interface::{Registry, Result};
 
 pub mod alias;
+pub mod block;
 pub mod combine;
+pub mod conn_rate_limit;
+pub mod family;
 pub mod forward;
 pub mod local;
 pub mod noop;
+pub mod pool;
+pub mod proxy_protocol;
+pub mod rate_limit;
 
 pub fn init(registry: &mut Registry) -> Result<()> {
     registry.add_net::<alias::AliasNet>();
+    registry.add_net::<block::BlockNet>();
     registry.add_net::<combine::CombineNet>();
+    registry.add_net::<conn_rate_limit::ConnRateLimitNet>();
+    registry.add_net::<family::FamilyNet>();
     registry.add_net::<local::LocalNet>();
     registry.add_net::<noop::NoopNet>();
+    registry.add_net::<pool::PoolNet>();
+    registry.add_net::<proxy_protocol::ProxyProtocolNet>();
+    registry.add_net::<rate_limit::RateLimitNet>();
 
     registry.add_server::<forward::ForwardNet>();
 
@@ -5,6 +5,7 @@ use rd_interface::{
     Address, Config, Context, INet, Result, TcpListener, TcpStream, UdpSocket,
 };
 use serde_derive::Deserialize;
+use std::net::SocketAddr;
 
 pub struct AliasNet(rd_interface::Net);
 
@@ -50,6 +51,22 @@ impl INet for AliasNet {
     {
         self.0.udp_bind(ctx, addr)
     }
+
+    #[inline(always)]
+    fn lookup_host<'life0: 'a, 'life1: 'a, 'a>(
+        &'life0 self,
+        addr: &'life1 Address,
+    ) -> BoxFuture<'a, Result<Vec<SocketAddr>>>
+    where
+        Self: 'a,
+    {
+        self.0.lookup_host(addr)
+    }
+
+    #[inline(always)]
+    fn id(&self) -> *const () {
+        self.0.id()
+    }
 }
 
 #[derive(Debug, Deserialize, Config, JsonSchema)]
@@ -63,6 +80,6 @@ impl NetFactory for AliasNet {
     type Net = Self;
 
     fn new(config: Self::Config) -> Result<Self> {
-        Ok(AliasNet::new(config.net.net()))
+        Ok(AliasNet::new(config.net.try_net()?))
     }
 }
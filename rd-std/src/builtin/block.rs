@@ -0,0 +1,187 @@
+use std::{error::Error as StdError, fmt, io, time::Duration};
+
+use rd_interface::{
+    async_trait,
+    registry::NetFactory,
+    schemars::{self, JsonSchema},
+    Address, Config, Context, INet, Result, TcpListener, TcpStream, UdpSocket,
+};
+use serde_derive::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Config, JsonSchema, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockMode {
+    /// Fails with `ConnectionRefused`.
+    #[default]
+    Drop,
+    /// Fails with `ConnectionReset`.
+    Reset,
+    /// Never completes, or fails with `TimedOut` after `timeout`.
+    Hang,
+}
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct Config {
+    #[serde(default)]
+    mode: BlockMode,
+    /// Milliseconds to hang before failing. Hangs forever if omitted.
+    timeout: Option<u64>,
+}
+
+/// The inner error of the IO errors returned by [BlockNet], see [is_blocked].
+#[derive(Debug)]
+pub struct Blocked;
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Blocked")
+    }
+}
+
+impl StdError for Blocked {}
+
+/// Returns true if the error comes from a [BlockNet].
+pub fn is_blocked(e: &rd_interface::Error) -> bool {
+    match e {
+        rd_interface::Error::IO(e) => e.get_ref().map(|e| e.is::<Blocked>()).unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Rejects every connection, for traffic routed away on purpose.
+pub struct BlockNet {
+    mode: BlockMode,
+    timeout: Option<Duration>,
+}
+
+impl BlockNet {
+    pub fn new(mode: BlockMode, timeout: Option<Duration>) -> BlockNet {
+        BlockNet { mode, timeout }
+    }
+    async fn block<T>(&self, ctx: &Context) -> Result<T> {
+        let kind = match self.mode {
+            BlockMode::Drop => io::ErrorKind::ConnectionRefused,
+            BlockMode::Reset => io::ErrorKind::ConnectionReset,
+            BlockMode::Hang => {
+                match ctx.timeout(self.timeout) {
+                    Some(timeout) => tokio::time::sleep(timeout).await,
+                    None => futures::future::pending().await,
+                }
+                io::ErrorKind::TimedOut
+            }
+        };
+        Err(io::Error::new(kind, Blocked).into())
+    }
+}
+
+#[async_trait]
+impl INet for BlockNet {
+    async fn tcp_connect(&self, ctx: &mut Context, _addr: Address) -> Result<TcpStream> {
+        self.block(ctx).await
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, _addr: Address) -> Result<TcpListener> {
+        self.block(ctx).await
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, _addr: Address) -> Result<UdpSocket> {
+        self.block(ctx).await
+    }
+}
+
+impl NetFactory for BlockNet {
+    const NAME: &'static str = "block";
+    type Config = Config;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        Ok(BlockNet::new(
+            config.mode,
+            config.timeout.map(Duration::from_millis),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rd_interface::IntoAddress;
+    use std::time::Instant;
+
+    async fn connect(net: &BlockNet) -> rd_interface::Error {
+        match net
+            .tcp_connect(&mut Context::new(), "1.2.3.4:80".into_address().unwrap())
+            .await
+        {
+            Err(e) => e,
+            Ok(_) => panic!("connect should be blocked"),
+        }
+    }
+
+    fn io_kind(e: &rd_interface::Error) -> io::ErrorKind {
+        match e {
+            rd_interface::Error::IO(e) => e.kind(),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_block_drop_reset() {
+        let e = connect(&BlockNet::new(BlockMode::Drop, None)).await;
+        assert_eq!(io_kind(&e), io::ErrorKind::ConnectionRefused);
+        assert!(is_blocked(&e));
+
+        let e = connect(&BlockNet::new(BlockMode::Reset, None)).await;
+        assert_eq!(io_kind(&e), io::ErrorKind::ConnectionReset);
+        assert!(is_blocked(&e));
+
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused).into();
+        assert!(!is_blocked(&refused));
+    }
+
+    #[tokio::test]
+    async fn test_block_hang() {
+        let start = Instant::now();
+        let e = connect(&BlockNet::new(
+            BlockMode::Hang,
+            Some(Duration::from_millis(50)),
+        ))
+        .await;
+        assert_eq!(io_kind(&e), io::ErrorKind::TimedOut);
+        assert!(is_blocked(&e));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let net = BlockNet::new(BlockMode::Hang, None);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), connect(&net))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_hang_deadline() {
+        let net = BlockNet::new(BlockMode::Hang, Some(Duration::from_secs(10)));
+        let mut ctx = Context::new();
+        ctx.with_timeout(Duration::from_millis(50));
+
+        let start = Instant::now();
+        let e = net
+            .tcp_connect(&mut ctx, "1.2.3.4:80".into_address().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(io_kind(&e), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_block_config() {
+        let config: Config = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert_eq!(config.mode, BlockMode::Drop);
+        let config: Config =
+            serde_json::from_value(serde_json::json!({ "mode": "hang", "timeout": 100 })).unwrap();
+        assert_eq!(config.mode, BlockMode::Hang);
+        assert_eq!(config.timeout, Some(100));
+    }
+}
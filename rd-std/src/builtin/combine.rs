@@ -2,9 +2,11 @@ use futures::future::BoxFuture;
 use rd_interface::{
     registry::{NetFactory, NetRef},
     schemars::{self, JsonSchema},
-    Address, Config, Context, INet, Net, Result, TcpListener, TcpStream, UdpSocket,
+    Address, Config, Context, FallbackCombineNet, INet, IntoDyn, Net, Result, TcpListener,
+    TcpStream, UdpSocket,
 };
 use serde_derive::Deserialize;
+use std::net::SocketAddr;
 
 pub struct CombineNet {
     tcp_connect: Net,
@@ -48,6 +50,17 @@ impl INet for CombineNet {
     {
         self.udp_bind.udp_bind(ctx, addr)
     }
+
+    #[inline(always)]
+    fn lookup_host<'life0: 'a, 'life1: 'a, 'a>(
+        &'life0 self,
+        addr: &'life1 Address,
+    ) -> BoxFuture<'a, Result<Vec<SocketAddr>>>
+    where
+        Self: 'a,
+    {
+        self.tcp_connect.lookup_host(addr)
+    }
 }
 
 #[derive(Debug, Deserialize, Config, JsonSchema)]
@@ -55,6 +68,32 @@ pub struct Config {
     tcp_connect: NetRef,
     tcp_bind: NetRef,
     udp_bind: NetRef,
+    /// Nets tried in order when `tcp_connect` fails.
+    #[serde(default)]
+    tcp_connect_fallback: Vec<NetRef>,
+    /// Nets tried in order when `tcp_bind` fails.
+    #[serde(default)]
+    tcp_bind_fallback: Vec<NetRef>,
+    /// Nets tried in order when `udp_bind` fails.
+    #[serde(default)]
+    udp_bind_fallback: Vec<NetRef>,
+}
+
+/// Returns `net` itself if there is no fallback.
+fn with_fallback(net: NetRef, fallback: Vec<NetRef>) -> Result<Net> {
+    let net = net.try_net()?;
+    if fallback.is_empty() {
+        return Ok(net);
+    }
+    let nets = std::iter::once(Ok(net))
+        .chain(fallback.into_iter().map(|n| n.try_net()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(FallbackCombineNet {
+        tcp_connect: nets.clone(),
+        tcp_bind: nets.clone(),
+        udp_bind: nets,
+    }
+    .into_dyn())
 }
 
 impl NetFactory for CombineNet {
@@ -67,12 +106,97 @@ impl NetFactory for CombineNet {
             tcp_connect,
             tcp_bind,
             udp_bind,
+            tcp_connect_fallback,
+            tcp_bind_fallback,
+            udp_bind_fallback,
         }: Self::Config,
     ) -> Result<Self> {
         Ok(CombineNet {
-            tcp_connect: tcp_connect.net(),
-            tcp_bind: tcp_bind.net(),
-            udp_bind: udp_bind.net(),
+            tcp_connect: with_fallback(tcp_connect, tcp_connect_fallback)?,
+            tcp_bind: with_fallback(tcp_bind, tcp_bind_fallback)?,
+            udp_bind: with_fallback(udp_bind, udp_bind_fallback)?,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        builtin::{
+            block::{is_blocked, BlockMode, BlockNet},
+            local::{LocalConfig, LocalNet},
+        },
+        tests::{assert_echo, spawn_echo_server},
+    };
+    use rd_interface::{
+        registry::{NetMap, ResolveNetRef},
+        IntoAddress, NotImplementedNet,
+    };
+
+    fn combine_net(nets: NetMap, config: serde_json::Value) -> Net {
+        let mut config: Config = serde_json::from_value(config).unwrap();
+        config.resolve(&nets).unwrap();
+        CombineNet::new(config).unwrap().into_dyn()
+    }
+
+    fn nets() -> NetMap {
+        let mut nets = NetMap::new();
+        nets.insert("noop".to_string(), NotImplementedNet.into_dyn());
+        nets.insert(
+            "block".to_string(),
+            BlockNet::new(BlockMode::Drop, None).into_dyn(),
+        );
+        nets.insert(
+            "local".to_string(),
+            LocalNet::new(LocalConfig::default()).into_dyn(),
+        );
+        nets
+    }
+
+    #[tokio::test]
+    async fn test_combine_fallback() {
+        let nets = nets();
+        spawn_echo_server(&nets["local"], "127.0.0.1:26678").await;
+
+        let net = combine_net(
+            nets,
+            serde_json::json!({
+                "tcp_connect": "noop",
+                "tcp_bind": "noop",
+                "udp_bind": "noop",
+                "tcp_connect_fallback": ["block", "local"],
+            }),
+        );
+        assert_echo(&net, "127.0.0.1:26678").await;
+    }
+
+    #[tokio::test]
+    async fn test_combine_fallback_all_failed() {
+        let net = combine_net(
+            nets(),
+            serde_json::json!({
+                "tcp_connect": "noop",
+                "tcp_bind": "noop",
+                "udp_bind": "noop",
+                "tcp_connect_fallback": ["block"],
+            }),
+        );
+        let err = net
+            .tcp_connect(
+                &mut Context::new(),
+                "127.0.0.1:26678".into_address().unwrap(),
+            )
+            .await
+            .err()
+            .unwrap();
+        assert!(is_blocked(&err));
+
+        let err = net
+            .udp_bind(&mut Context::new(), "0.0.0.0:0".into_address().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, rd_interface::Error::NotImplemented));
+    }
+}
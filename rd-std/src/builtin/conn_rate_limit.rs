@@ -0,0 +1,267 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rd_interface::{
+    async_trait,
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Address, Config, Context, INet, Net, Result, TcpListener, TcpStream, UdpSocket,
+};
+use serde_derive::Deserialize;
+use tokio::time::sleep;
+
+/// Hosts whose buckets are kept before the full ones are dropped.
+const MAX_HOSTS: usize = 4096;
+/// Hosts kept when none of the buckets is full, the ones used last.
+const KEPT_HOSTS: usize = MAX_HOSTS * 3 / 4;
+
+/// What to do with a connect over the rate.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Config, JsonSchema, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Exceed {
+    /// Wait until it's in the rate.
+    #[default]
+    Delay,
+    /// Fail it with `ConnectionRefused`.
+    Reject,
+}
+
+/// A token bucket of connects to each host. Delayed connects take their
+/// token ahead, leaving a debt the next one waits out.
+struct Buckets {
+    rate: f64,
+    capacity: f64,
+    hosts: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl Buckets {
+    fn new(rate: u32, capacity: u32) -> Buckets {
+        Buckets {
+            rate: rate as f64,
+            capacity: capacity as f64,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Takes a token of `host`, returning how long to wait before
+    /// connecting. `None` if there is none and `wait` is false.
+    fn take(&self, host: &str, wait: bool) -> Option<Duration> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let now = Instant::now();
+        if hosts.len() >= MAX_HOSTS && !hosts.contains_key(host) {
+            let (rate, capacity) = (self.rate, self.capacity);
+            hosts.retain(|_, (tokens, last)| {
+                *tokens + now.duration_since(*last).as_secs_f64() * rate < capacity
+            });
+            if hosts.len() >= MAX_HOSTS {
+                let mut lasts: Vec<Instant> = hosts.values().map(|(_, last)| *last).collect();
+                lasts.sort_unstable();
+                let oldest_kept = lasts[lasts.len() - KEPT_HOSTS];
+                hosts.retain(|_, (_, last)| *last > oldest_kept);
+            }
+        }
+        let (tokens, last) = hosts
+            .entry(host.to_string())
+            .or_insert((self.capacity, now));
+        *tokens =
+            (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.capacity);
+        *last = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Some(Duration::ZERO)
+        } else if wait {
+            let delay = Duration::from_secs_f64((1.0 - *tokens) / self.rate);
+            *tokens -= 1.0;
+            Some(delay)
+        } else {
+            None
+        }
+    }
+}
+
+/// Limits the new TCP connections to each destination host, whatever the
+/// port. Binding goes to `net` as is.
+pub struct ConnRateLimitNet {
+    net: Net,
+    buckets: Buckets,
+    exceed: Exceed,
+}
+
+impl ConnRateLimitNet {
+    pub fn new(net: Net, config: Config) -> Result<Self> {
+        if config.per_host_cps == 0 {
+            return Err(rd_interface::Error::Other(
+                "per_host_cps must be positive".into(),
+            ));
+        }
+        let burst = config.burst.unwrap_or(config.per_host_cps).max(1);
+        Ok(ConnRateLimitNet {
+            net,
+            buckets: Buckets::new(config.per_host_cps, burst),
+            exceed: config.on_exceed,
+        })
+    }
+}
+
+fn host(addr: &Address) -> String {
+    match addr {
+        Address::SocketAddr(addr) => addr.ip().to_string(),
+        Address::Domain(domain, _) => domain.to_ascii_lowercase(),
+        Address::Unix(path) => path.display().to_string(),
+    }
+}
+
+#[async_trait]
+impl INet for ConnRateLimitNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        let host = host(&addr);
+        match self.buckets.take(&host, self.exceed == Exceed::Delay) {
+            Some(delay) if delay.is_zero() => {}
+            Some(delay) => sleep(delay).await,
+            None => {
+                tracing::debug!("too many connections to {}, rejected", host);
+                return Err(io::Error::new(
+                    ErrorKind::ConnectionRefused,
+                    format!("too many connections to {}", host),
+                )
+                .into());
+            }
+        }
+        self.net.tcp_connect(ctx, addr).await
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        self.net.tcp_bind(ctx, addr).await
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        self.net.udp_bind(ctx, addr).await
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        self.net.lookup_host(addr).await
+    }
+}
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct Config {
+    #[serde(default)]
+    net: NetRef,
+    /// New connections per second to each host.
+    per_host_cps: u32,
+    /// Connections to a host that can go at once after being idle. One
+    /// second of connections by default.
+    #[serde(default)]
+    burst: Option<u32>,
+    /// What to do with the connections over the rate, `delay` by default.
+    #[serde(default)]
+    on_exceed: Exceed,
+}
+
+impl NetFactory for ConnRateLimitNet {
+    const NAME: &'static str = "conn_rate_limit";
+    type Config = Config;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        ConnRateLimitNet::new(config.net.try_net()?, config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::RecordNet;
+    use rd_interface::{IntoAddress, IntoDyn};
+    use std::sync::Arc;
+
+    fn conn_rate_limit_net(inner: &Arc<RecordNet>, config: serde_json::Value) -> ConnRateLimitNet {
+        ConnRateLimitNet::new(inner.clone(), serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    async fn connect(net: &ConnRateLimitNet, addr: &str) -> Result<TcpStream> {
+        net.tcp_connect(&mut Context::new(), addr.into_address().unwrap())
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_reject() {
+        let inner = Arc::new(RecordNet::default());
+        let net = conn_rate_limit_net(
+            &inner,
+            serde_json::json!({ "per_host_cps": 3, "on_exceed": "reject" }),
+        );
+
+        let mut rejected = 0;
+        for port in 1..=5 {
+            match connect(&net, &format!("example.com:{}", port)).await {
+                Err(rd_interface::Error::IO(e)) => {
+                    assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+                    rejected += 1;
+                }
+                Err(e) => assert!(matches!(e, rd_interface::Error::NotImplemented)),
+                Ok(_) => unreachable!(),
+            }
+        }
+        assert_eq!(rejected, 2);
+        // the other hosts have their own buckets
+        assert!(connect(&net, "EXAMPLE.org:80").await.is_err());
+        assert_eq!(inner.connected.lock().unwrap().len(), 4);
+
+        sleep(Duration::from_millis(400)).await;
+        connect(&net, "example.com:1").await.err().unwrap();
+        assert_eq!(inner.connected.lock().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_delay() {
+        let inner = Arc::new(RecordNet::default());
+        let net = conn_rate_limit_net(
+            &inner,
+            serde_json::json!({ "per_host_cps": 10, "burst": 2 }),
+        );
+
+        // 2 at once, then one per 100ms
+        let start = Instant::now();
+        for _ in 0..5 {
+            connect(&net, "127.0.0.1:80").await.err().unwrap();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(280), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(800), "{:?}", elapsed);
+        assert_eq!(inner.connected.lock().unwrap().len(), 5);
+
+        let start = Instant::now();
+        connect(&net, "127.0.0.2:80").await.err().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_max_hosts() {
+        let buckets = Buckets::new(1, 1);
+        // none of the buckets fills up again meanwhile
+        for i in 0..MAX_HOSTS {
+            buckets.take(&i.to_string(), false).unwrap();
+        }
+        assert_eq!(buckets.hosts.lock().unwrap().len(), MAX_HOSTS);
+
+        buckets.take("new", false).unwrap();
+        let hosts = buckets.hosts.lock().unwrap();
+        assert!(hosts.len() <= KEPT_HOSTS, "{}", hosts.len());
+        assert!(hosts.contains_key("new"));
+        assert!(hosts.contains_key(&(MAX_HOSTS - 1).to_string()));
+        assert!(!hosts.contains_key("0"));
+    }
+
+    #[test]
+    fn test_zero_rate() {
+        let config = serde_json::from_value(serde_json::json!({ "per_host_cps": 0 })).unwrap();
+        let result = ConnRateLimitNet::new(RecordNet::default().into_dyn(), config);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+};
+
+use rd_interface::{
+    async_trait,
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Address, Config, Context, INet, Net, Result, TcpListener, TcpStream, UdpSocket,
+};
+use serde_derive::Deserialize;
+
+/// The address family connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Config, JsonSchema, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    /// Either family.
+    #[default]
+    Auto,
+    /// IPv4 only.
+    V4,
+    /// IPv6 only.
+    V6,
+}
+
+impl Family {
+    pub fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            Family::Auto => true,
+            Family::V4 => addr.is_ipv4(),
+            Family::V6 => addr.is_ipv6(),
+        }
+    }
+    /// Fails if `addr` is not of the family.
+    pub fn check(self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        if self.matches(&addr) {
+            Ok(addr)
+        } else {
+            Err(self.not_found())
+        }
+    }
+    /// Keeps the addresses of the family, failing if none is left.
+    pub fn filter(self, addrs: Vec<SocketAddr>) -> io::Result<Vec<SocketAddr>> {
+        if self == Family::Auto {
+            return Ok(addrs);
+        }
+        let addrs: Vec<_> = addrs.into_iter().filter(|a| self.matches(a)).collect();
+        if addrs.is_empty() {
+            return Err(self.not_found());
+        }
+        Ok(addrs)
+    }
+    fn not_found(self) -> io::Error {
+        let family = match self {
+            Family::V6 => "IPv6",
+            _ => "IPv4",
+        };
+        io::Error::new(
+            ErrorKind::AddrNotAvailable,
+            format!("no {} address to connect to", family),
+        )
+    }
+}
+
+/// Connects to the addresses of one family only, resolving domains with
+/// `net` and trying the addresses in order. Binding goes to `net` as is.
+pub struct FamilyNet {
+    net: Net,
+    family: Family,
+}
+
+impl FamilyNet {
+    pub fn new(net: Net, family: Family) -> FamilyNet {
+        FamilyNet { net, family }
+    }
+}
+
+#[async_trait]
+impl INet for FamilyNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        if self.family == Family::Auto {
+            return self.net.tcp_connect(ctx, addr).await;
+        }
+        // never empty, `lookup_host` fails if none is of the family
+        let addrs: Vec<Address> = self
+            .lookup_host(&addr)
+            .await?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        self.net.tcp_connect_any(ctx, &addrs).await
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        self.net.tcp_bind(ctx, addr).await
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        self.net.udp_bind(ctx, addr).await
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        let addrs = self.net.lookup_host(addr).await?;
+        Ok(self.family.filter(addrs)?)
+    }
+}
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct Config {
+    #[serde(default)]
+    net: NetRef,
+    family: Family,
+}
+
+impl NetFactory for FamilyNet {
+    const NAME: &'static str = "family";
+    type Config = Config;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        Ok(FamilyNet::new(config.net.try_net()?, config.family))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::RecordNet;
+    use rd_interface::{IntoAddress, IntoDyn};
+    use std::sync::Arc;
+
+    async fn connect_with(family: Family, v6: bool) -> (Result<()>, Vec<String>) {
+        let inner = Arc::new(match v6 {
+            true => RecordNet::resolving(&["2001:db8::1", "192.0.2.1"]),
+            false => RecordNet::resolving(&["192.0.2.1"]),
+        });
+        let net = FamilyNet::new(inner.clone(), family);
+        let result = net
+            .tcp_connect(
+                &mut Context::new(),
+                "example.com:443".into_address().unwrap(),
+            )
+            .await
+            .map(drop);
+        (result, inner.take_connected())
+    }
+
+    #[tokio::test]
+    async fn test_family_net() {
+        let (_, connected) = connect_with(Family::V4, true).await;
+        assert_eq!(connected, ["192.0.2.1:443"]);
+        let (_, connected) = connect_with(Family::V6, true).await;
+        assert_eq!(connected, ["[2001:db8::1]:443"]);
+        let (_, connected) = connect_with(Family::Auto, true).await;
+        assert_eq!(connected, ["example.com:443"]);
+
+        let (result, connected) = connect_with(Family::V6, false).await;
+        assert!(connected.is_empty());
+        let err = result.err().unwrap();
+        assert!(err.to_string().contains("no IPv6 address"), "{}", err);
+
+        let net = FamilyNet::new(RecordNet::default().into_dyn(), Family::V6);
+        let result = net
+            .tcp_connect(&mut Context::new(), "192.0.2.1:80".into_address().unwrap())
+            .await;
+        assert!(result.is_err());
+    }
+}
@@ -4,21 +4,29 @@ use rd_interface::{
     async_trait,
     registry::ServerFactory,
     schemars::{self, JsonSchema},
-    util::connect_tcp,
+    util::{connect_tcp, StopSignal},
     Arc, Context, IServer, IntoAddress, Net, Result, TcpListener, TcpStream,
 };
 use serde_derive::Deserialize;
+use tracing::Instrument;
+
+use crate::sniff::sniff;
 
 #[derive(Debug, Deserialize, JsonSchema)]
 pub struct ForwardConfig {
     bind: String,
     target: String,
+    /// Sniffs the host the client connects to for the `host` rule. Only for
+    /// protocols where the client speaks first, like TLS and HTTP.
+    #[serde(default)]
+    sniff: bool,
 }
 
 pub struct ForwardNet {
     listen_net: Net,
     net: Net,
     cfg: Arc<ForwardConfig>,
+    stop: StopSignal,
 }
 
 impl ForwardNet {
@@ -27,6 +35,7 @@ impl ForwardNet {
             listen_net,
             net,
             cfg: Arc::new(cfg),
+            stop: StopSignal::new(),
         }
     }
 }
@@ -39,6 +48,10 @@ impl IServer for ForwardNet {
             .await?;
         self.serve_listener(listener).await
     }
+    async fn stop(&self) -> Result<()> {
+        self.stop.stop();
+        Ok(())
+    }
 }
 
 impl ForwardNet {
@@ -48,25 +61,34 @@ impl ForwardNet {
         net: Net,
         addr: SocketAddr,
     ) -> Result<()> {
+        let mut ctx = Context::from_socketaddr(addr);
+        let socket = if cfg.sniff {
+            sniff(socket, &mut ctx).await?
+        } else {
+            socket
+        };
         let target = net
-            .tcp_connect(
-                &mut Context::from_socketaddr(addr),
-                cfg.target.into_address()?,
-            )
+            .tcp_connect(&mut ctx, cfg.target.into_address()?)
             .await?;
         connect_tcp(socket, target).await?;
         Ok(())
     }
     pub async fn serve_listener(&self, listener: TcpListener) -> Result<()> {
         loop {
-            let (socket, addr) = listener.accept().await?;
+            let (socket, addr) = match self.stop.until(listener.accept()).await {
+                Some(r) => r?,
+                None => return Ok(()),
+            };
             let cfg = self.cfg.clone();
             let net = self.net.clone();
-            let _ = tokio::spawn(async move {
-                if let Err(e) = Self::serve_connection(cfg, socket, net, addr).await {
-                    tracing::error!("Error when serve_connection: {:?}", e);
+            tokio::spawn(
+                async move {
+                    if let Err(e) = Self::serve_connection(cfg, socket, net, addr).await {
+                        tracing::error!("Error when serve_connection: {:?}", e);
+                    }
                 }
-            });
+                .instrument(tracing::info_span!("serve_connection", src = %addr)),
+            );
         }
     }
 }
@@ -1,16 +1,32 @@
 use std::{
+    future::Future,
     io::{self, ErrorKind},
     net::SocketAddr,
+    time::Duration,
 };
 
+use bytes::BytesMut;
+use fast_open::{set_fast_open_connect, set_fast_open_listen};
+use futures::{
+    future::{join_all, select, Either},
+    stream::{FuturesUnordered, StreamExt},
+};
 use rd_interface::{
-    async_trait, impl_async_read_write,
+    async_trait,
+    constant::MAX_DATAGRAM_SIZE,
+    impl_async_read_write,
     registry::NetFactory,
     schemars::{self, JsonSchema},
-    Address, Config, INet, IntoDyn, Result, TcpListener, TcpStream, UdpSocket,
+    util, Address, Config, INet, IntoDyn, Result, TcpListener, TcpStream, UdpSocket,
 };
 use serde_derive::Deserialize;
-use tokio::net;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{net, time::sleep};
+
+use super::family::Family;
+
+mod fast_open;
+mod unix;
 
 #[derive(Debug, Deserialize, Config, JsonSchema, Clone, Default)]
 pub struct LocalConfig {
@@ -21,8 +37,48 @@ pub struct LocalConfig {
     /// set nodelay
     #[serde(default)]
     pub nodelay: Option<bool>,
+
+    /// seconds a connection is idle before TCP keepalive probes are sent,
+    /// keepalive is off if not set
+    #[serde(default)]
+    pub keepalive: Option<u64>,
+
+    /// enable TCP Fast Open on connect (Linux) and listen (Linux, macOS)
+    #[serde(default)]
+    pub tcp_fast_open: Option<bool>,
+
+    /// milliseconds an address is tried alone before the next one is raced
+    /// against it when connecting to a domain, 250 by default
+    #[serde(default)]
+    pub happy_eyeballs_delay: Option<u64>,
+
+    /// milliseconds to wait for a connection, shortened by the deadline of
+    /// the context
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+
+    /// connections a listening socket queues before they are accepted, 1024
+    /// by default
+    #[serde(default)]
+    pub listen_backlog: Option<u32>,
+
+    /// set SO_REUSEADDR on listening sockets, on by default except on Windows
+    #[serde(default)]
+    pub reuse_address: Option<bool>,
+
+    /// set SO_REUSEPORT on listening sockets, letting another process bind
+    /// the same address to take over without downtime (Unix)
+    #[serde(default)]
+    pub reuse_port: Option<bool>,
+
+    /// address family to connect to, `auto`, `v4` or `v6`
+    #[serde(default)]
+    pub family: Family,
 }
 
+const DEFAULT_HAPPY_EYEBALLS_DELAY: u64 = 250;
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
 pub struct LocalNet(LocalConfig);
 pub struct CompatTcp(pub(crate) net::TcpStream);
 pub struct Listener(net::TcpListener, LocalConfig);
@@ -32,6 +88,88 @@ impl LocalNet {
     pub fn new(config: LocalConfig) -> LocalNet {
         LocalNet(config)
     }
+
+    /// Resolves `addr` once and opens `count` connections to it concurrently.
+    pub async fn connect_many(
+        &self,
+        addr: Address,
+        count: usize,
+    ) -> Result<Vec<Result<TcpStream>>> {
+        let family = self.0.family;
+        let resolver = move |domain, port| async move {
+            Ok(family.filter(lookup_host_all(domain, port).await?)?[0])
+        };
+        self.connect_many_with(addr, count, resolver).await
+    }
+
+    async fn connect_many_with<Fut>(
+        &self,
+        addr: Address,
+        count: usize,
+        resolver: impl FnOnce(String, u16) -> Fut,
+    ) -> Result<Vec<Result<TcpStream>>>
+    where
+        Fut: Future<Output = io::Result<SocketAddr>>,
+    {
+        let addr = addr.resolve(resolver).await?;
+        Ok(join_all((0..count).map(|_| self.connect_addr(addr))).await)
+    }
+
+    /// Connects to `domain` with Happy Eyeballs (RFC 8305). The addresses are
+    /// tried IPv6 first, alternating between the families. Each attempt
+    /// starts when the previous one fails or after `happy_eyeballs_delay`,
+    /// and the first connected stream is returned.
+    async fn happy_eyeballs<Fut>(
+        &self,
+        domain: String,
+        port: u16,
+        resolver: impl FnOnce(String, u16) -> Fut,
+    ) -> Result<TcpStream>
+    where
+        Fut: Future<Output = io::Result<Vec<SocketAddr>>>,
+    {
+        let delay = Duration::from_millis(
+            self.0
+                .happy_eyeballs_delay
+                .unwrap_or(DEFAULT_HAPPY_EYEBALLS_DELAY),
+        );
+        let addrs = self.0.family.filter(resolver(domain, port).await?)?;
+        let mut addrs = interleave(addrs).into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = io::Error::from(ErrorKind::AddrNotAvailable).into();
+
+        loop {
+            if let Some(addr) = addrs.next() {
+                attempts.push(self.connect_addr(addr));
+            }
+            if attempts.is_empty() {
+                return Err(last_err);
+            }
+
+            match select(attempts.next(), Box::pin(sleep(delay))).await {
+                Either::Left((Some(Ok(tcp)), _)) => return Ok(tcp),
+                Either::Left((Some(Err(e)), _)) => last_err = e,
+                Either::Left((None, _)) | Either::Right(_) => {}
+            }
+        }
+    }
+
+    fn fast_open(&self) -> bool {
+        self.0.tcp_fast_open.unwrap_or(false)
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> Result<TcpStream> {
+        self.0.family.check(addr)?;
+        let tcp = if self.fast_open() {
+            let socket = new_socket(addr)?;
+            set_fast_open_connect(&socket)?;
+            socket.connect(addr).await?
+        } else {
+            net::TcpStream::connect(addr).await?
+        };
+        set_tcp_options(&tcp, &self.0)?;
+        Ok(CompatTcp::new(tcp).into_dyn())
+    }
 }
 
 async fn lookup_host(domain: String, port: u16) -> io::Result<SocketAddr> {
@@ -44,6 +182,60 @@ async fn lookup_host(domain: String, port: u16) -> io::Result<SocketAddr> {
         .ok_or(ErrorKind::AddrNotAvailable.into())
 }
 
+async fn lookup_host_all(domain: String, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let host = domain.trim_start_matches('[').trim_end_matches(']');
+    Ok(net::lookup_host((host, port)).await?.collect())
+}
+
+/// Sorts `addrs` to IPv6, IPv4, IPv6, ... keeping the order in each family.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut result = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return result,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Applies the options of `config` to a connected or accepted stream.
+fn set_tcp_options(tcp: &net::TcpStream, config: &LocalConfig) -> io::Result<()> {
+    if let Some(ttl) = config.ttl {
+        tcp.set_ttl(ttl)?;
+    }
+    if let Some(nodelay) = config.nodelay {
+        tcp.set_nodelay(nodelay)?;
+    }
+    if let Some(keepalive) = config.keepalive {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(keepalive));
+        SockRef::from(tcp).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+fn new_socket(addr: SocketAddr) -> io::Result<net::TcpSocket> {
+    match addr {
+        SocketAddr::V4(_) => net::TcpSocket::new_v4(),
+        SocketAddr::V6(_) => net::TcpSocket::new_v6(),
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &net::TcpSocket) -> io::Result<()> {
+    socket.set_reuseport(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &net::TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "reuse_port is not supported on this platform",
+    ))
+}
+
 impl_async_read_write!(CompatTcp, 0);
 
 #[async_trait]
@@ -65,12 +257,7 @@ impl CompatTcp {
 impl rd_interface::ITcpListener for Listener {
     async fn accept(&self) -> Result<(TcpStream, SocketAddr)> {
         let (socket, addr) = self.0.accept().await?;
-        if let Some(ttl) = self.1.ttl {
-            socket.set_ttl(ttl)?;
-        }
-        if let Some(nodelay) = self.1.nodelay {
-            socket.set_nodelay(nodelay)?;
-        }
+        set_tcp_options(&socket, &self.1)?;
         Ok((CompatTcp::new(socket).into_dyn(), addr))
     }
 
@@ -93,26 +280,68 @@ impl rd_interface::IUdpSocket for Udp {
     async fn local_addr(&self) -> Result<SocketAddr> {
         self.0.local_addr().map_err(Into::into)
     }
+
+    // the kernel drops datagrams from other peers once connected
+    async fn connect(&self, addr: SocketAddr) -> Result<()> {
+        self.0.connect(addr).await.map_err(Into::into)
+    }
+
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        self.0.peer_addr().map_err(Into::into)
+    }
+
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        self.0.send(buf).await.map_err(Into::into)
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        self.0.recv(buf).await.map_err(Into::into)
+    }
+
+    async fn recv_from_buf(&self, buf: &mut BytesMut) -> Result<(usize, SocketAddr)> {
+        buf.reserve(MAX_DATAGRAM_SIZE);
+        self.0.recv_buf_from(buf).await.map_err(Into::into)
+    }
+
+    // takes the datagrams already queued without waiting again
+    async fn recv_batch(&self, buf: &mut BytesMut, max: usize) -> Result<Vec<(usize, SocketAddr)>> {
+        let mut datagrams = Vec::new();
+        if max == 0 {
+            return Ok(datagrams);
+        }
+        datagrams.push(self.recv_from_buf(buf).await?);
+        while datagrams.len() < max {
+            buf.reserve(MAX_DATAGRAM_SIZE);
+            match self.0.try_recv_buf_from(buf) {
+                Ok(datagram) => datagrams.push(datagram),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(datagrams)
+    }
 }
 
 #[async_trait]
 impl INet for LocalNet {
     async fn tcp_connect(
         &self,
-        _ctx: &mut rd_interface::Context,
+        ctx: &mut rd_interface::Context,
         addr: Address,
     ) -> Result<TcpStream> {
         #[cfg(feature = "local_log")]
-        tracing::trace!("local::tcp_connect {:?} {:?}", _ctx, addr);
-        let addr = addr.resolve(lookup_host).await?;
-        let tcp = net::TcpStream::connect(addr).await?;
-        if let Some(ttl) = self.0.ttl {
-            tcp.set_ttl(ttl)?;
-        }
-        if let Some(nodelay) = self.0.nodelay {
-            tcp.set_nodelay(nodelay)?;
-        }
-        Ok(CompatTcp::new(tcp).into_dyn())
+        tracing::trace!("local::tcp_connect {:?} {:?}", ctx, addr);
+        let timeout = ctx.timeout(self.0.connect_timeout.map(Duration::from_millis));
+        util::timeout(timeout, async {
+            match addr {
+                Address::Domain(domain, port) => {
+                    self.happy_eyeballs(domain, port, lookup_host_all).await
+                }
+                Address::SocketAddr(addr) => self.connect_addr(addr).await,
+                Address::Unix(path) => unix::connect(&path).await,
+            }
+        })
+        .await
     }
 
     async fn tcp_bind(
@@ -122,8 +351,20 @@ impl INet for LocalNet {
     ) -> Result<TcpListener> {
         #[cfg(feature = "local_log")]
         tracing::trace!("local::tcp_bind {:?} {:?}", _ctx, addr);
+        if let Address::Unix(path) = &addr {
+            return unix::bind(path);
+        }
         let addr = addr.resolve(lookup_host).await?;
-        let listener = net::TcpListener::bind(addr).await?;
+        let socket = new_socket(addr)?;
+        socket.set_reuseaddr(self.0.reuse_address.unwrap_or(cfg!(not(windows))))?;
+        if self.0.reuse_port.unwrap_or_default() {
+            set_reuse_port(&socket)?;
+        }
+        socket.bind(addr)?;
+        if self.fast_open() {
+            set_fast_open_listen(&socket)?;
+        }
+        let listener = socket.listen(self.0.listen_backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG))?;
         if let Some(ttl) = self.0.ttl {
             listener.set_ttl(ttl)?;
         }
@@ -140,6 +381,17 @@ impl INet for LocalNet {
         }
         Ok(Udp(udp).into_dyn())
     }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        match addr {
+            Address::Domain(domain, port) => {
+                let addrs = lookup_host_all(domain.clone(), *port).await?;
+                Ok(self.0.family.filter(addrs)?)
+            }
+            Address::SocketAddr(addr) => Ok(vec![self.0.family.check(*addr)?]),
+            Address::Unix(_) => Err(rd_interface::NOT_IMPLEMENTED),
+        }
+    }
 }
 
 impl NetFactory for LocalNet {
@@ -151,3 +403,364 @@ impl NetFactory for LocalNet {
         Ok(LocalNet::new(config))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{assert_echo, spawn_echo_server};
+    use rd_interface::{Context, IntoAddress};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[tokio::test]
+    async fn test_tcp_connect_any() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        // nothing listens on the port once it's dropped
+        let refused = net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let net = LocalNet::new(LocalConfig::default());
+        let tcp = net
+            .tcp_connect_any(&mut Context::new(), &[refused.into(), target.into()])
+            .await
+            .unwrap();
+        assert_eq!(tcp.peer_addr().await.unwrap(), target);
+
+        let result = net
+            .tcp_connect_any(&mut Context::new(), &[refused.into()])
+            .await;
+        assert!(result.is_err());
+        let result = net.tcp_connect_any(&mut Context::new(), &[]).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_many_resolves_once() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                drop(socket);
+            }
+        });
+
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = {
+            let lookups = lookups.clone();
+            move |_: String, _: u16| async move {
+                lookups.fetch_add(1, Ordering::SeqCst);
+                Ok(target)
+            }
+        };
+
+        let net = LocalNet::new(LocalConfig::default());
+        let streams = net
+            .connect_many_with(
+                Address::Domain("bulk.test".to_string(), target.port()),
+                4,
+                resolver,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        assert_eq!(streams.len(), 4);
+        assert!(streams.iter().all(|s| s.is_ok()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port() {
+        let bind = |net: LocalNet, addr: SocketAddr| async move {
+            net.tcp_bind(&mut rd_interface::Context::new(), addr.into())
+                .await
+        };
+        let reuse = || {
+            LocalNet::new(LocalConfig {
+                reuse_port: Some(true),
+                listen_backlog: Some(16),
+                ..Default::default()
+            })
+        };
+
+        let first = bind(reuse(), "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = first.local_addr().await.unwrap();
+        let second = bind(reuse(), addr).await.unwrap();
+        assert_eq!(second.local_addr().await.unwrap(), addr);
+
+        let taken = bind(LocalNet::new(LocalConfig::default()), addr).await;
+        assert!(taken.err().unwrap().is_addr_in_use());
+    }
+
+    #[tokio::test]
+    async fn test_recv_batch() {
+        let net = LocalNet::new(LocalConfig::default());
+        let bind = || async {
+            net.udp_bind(
+                &mut rd_interface::Context::new(),
+                "127.0.0.1:0".into_address().unwrap(),
+            )
+            .await
+            .unwrap()
+        };
+        let (sender, single, batched) = (bind().await, bind().await, bind().await);
+        let from = sender.local_addr().await.unwrap();
+        let datagrams: [&[u8]; 3] = [b"first", b"", &[7u8; 3000]];
+        for udp in [&single, &batched] {
+            let to = udp.local_addr().await.unwrap();
+            for data in datagrams {
+                sender.send_to(data, to.into()).await.unwrap();
+            }
+        }
+
+        let mut expected = Vec::new();
+        let mut buf = [0u8; 4096];
+        for _ in datagrams {
+            let (size, addr) = single.recv_from(&mut buf).await.unwrap();
+            expected.push((buf[..size].to_vec(), addr));
+        }
+        assert!(expected.iter().all(|(_, addr)| *addr == from));
+
+        let mut buf = BytesMut::new();
+        let mut received = Vec::new();
+        while received.len() < expected.len() {
+            let start = buf.len();
+            let batch = batched.recv_batch(&mut buf, 8).await.unwrap();
+            let mut data = &buf[start..];
+            for (size, addr) in batch {
+                received.push((data[..size].to_vec(), addr));
+                data = &data[size..];
+            }
+        }
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_family() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                drop(socket);
+            }
+        });
+        // 100::/64 is a discard-only prefix, connecting to it never succeeds
+        let dead: SocketAddr = format!("[100::1]:{}", live.port()).parse().unwrap();
+        let dual_stack = move |_: String, _: u16| async move { Ok(vec![dead, live]) };
+        let v4_only = move |_: String, _: u16| async move { Ok(vec![live]) };
+        let local = |family| {
+            LocalNet::new(LocalConfig {
+                family,
+                // the IPv6 address is never raced
+                happy_eyeballs_delay: Some(60_000),
+                ..Default::default()
+            })
+        };
+
+        let tcp = tokio::time::timeout(
+            Duration::from_secs(2),
+            local(Family::V4).happy_eyeballs("dual.test".to_string(), live.port(), dual_stack),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(tcp.peer_addr().await.unwrap(), live);
+
+        let result = local(Family::V6)
+            .happy_eyeballs("v4.test".to_string(), live.port(), v4_only)
+            .await;
+        let err = result.err().unwrap();
+        assert!(err.to_string().contains("no IPv6 address"), "{}", err);
+
+        let result = local(Family::V6)
+            .tcp_connect(&mut rd_interface::Context::new(), live.into())
+            .await;
+        assert!(result.is_err());
+        let result = local(Family::V4)
+            .tcp_connect(&mut rd_interface::Context::new(), live.into())
+            .await;
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_interleave() {
+        let addrs = ["1.1.1.1:1", "2.2.2.2:1", "[::1]:1", "3.3.3.3:1", "[::2]:1"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let addrs: Vec<String> = interleave(addrs).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            addrs,
+            ["[::1]:1", "1.1.1.1:1", "[::2]:1", "2.2.2.2:1", "3.3.3.3:1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                drop(socket);
+            }
+        });
+        // 100::/64 is a discard-only prefix, connecting to it never succeeds
+        let dead: SocketAddr = format!("[100::1]:{}", live.port()).parse().unwrap();
+        let resolver = move |_: String, _: u16| async move { Ok(vec![live, dead]) };
+
+        let net = LocalNet::new(LocalConfig {
+            happy_eyeballs_delay: Some(100),
+            ..Default::default()
+        });
+        let start = std::time::Instant::now();
+        let tcp = tokio::time::timeout(
+            Duration::from_secs(2),
+            net.happy_eyeballs("dual.test".to_string(), live.port(), resolver),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(tcp.peer_addr().await.unwrap(), live);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_all_failed() {
+        let resolver = |_: String, _: u16| async { Ok(vec![]) };
+        let net = LocalNet::new(LocalConfig::default());
+        let err = net
+            .happy_eyeballs("none.test".to_string(), 80, resolver)
+            .await
+            .err()
+            .unwrap();
+        match err {
+            rd_interface::Error::IO(e) => assert_eq!(e.kind(), ErrorKind::AddrNotAvailable),
+            e => panic!("expected AddrNotAvailable, got {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_connect() {
+        use rd_interface::Context;
+
+        let net = LocalNet::new(LocalConfig::default());
+        let udp = net
+            .udp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        let udp_addr = udp.local_addr().await.unwrap();
+        let peer = net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other = net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        assert!(udp.send(b"x").await.is_err());
+        udp.connect(peer.local_addr().unwrap()).await.unwrap();
+        assert_eq!(udp.peer_addr().await.unwrap(), peer.local_addr().unwrap());
+
+        other.send_to(b"spoofed", udp_addr).await.unwrap();
+        peer.send_to(b"hello", udp_addr).await.unwrap();
+        let mut buf = [0u8; 16];
+        let size = udp.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"hello");
+
+        udp.send(b"world").await.unwrap();
+        let (size, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"world");
+        assert_eq!(from, udp_addr);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_host() {
+        let net = LocalNet::new(LocalConfig::default()).into_dyn();
+
+        let addrs = net
+            .lookup_host(&("localhost", 80).into_address().unwrap())
+            .await
+            .unwrap();
+        assert!(!addrs.is_empty());
+        assert!(addrs.iter().all(|a| a.ip().is_loopback() && a.port() == 80));
+
+        let addr = "127.0.0.1:80".into_address().unwrap();
+        assert_eq!(
+            net.lookup_host(&addr).await.unwrap(),
+            vec!["127.0.0.1:80".parse().unwrap()]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("rd-test-{}.sock", std::process::id()));
+        let addr = Address::parse_config(&format!("unix:{}", path.display())).unwrap();
+        assert_eq!(addr, Address::Unix(path.clone()));
+
+        let net = LocalNet::new(LocalConfig::default()).into_dyn();
+        let listener = net
+            .tcp_bind(&mut rd_interface::Context::new(), addr.clone())
+            .await
+            .unwrap();
+        assert!(path.exists());
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let (mut rx, mut tx) = tokio::io::split(tcp);
+            tokio::io::copy(&mut rx, &mut tx).await.unwrap();
+            // the socket file is removed with the listener
+            drop(listener);
+        });
+        assert_echo(&net, addr).await;
+
+        // wait for the listener to be dropped
+        for _ in 0..100 {
+            if !path.exists() {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the socket file is left");
+    }
+
+    #[tokio::test]
+    async fn test_fast_open_echo() {
+        let net = LocalNet::new(LocalConfig {
+            tcp_fast_open: Some(true),
+            ..Default::default()
+        })
+        .into_dyn();
+
+        spawn_echo_server(&net, "127.0.0.1:26667").await;
+        assert_echo(&net, "127.0.0.1:26667").await;
+    }
+
+    #[tokio::test]
+    async fn test_tcp_options() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connected = net::TcpStream::connect(addr).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+
+        let config = LocalConfig {
+            nodelay: Some(true),
+            keepalive: Some(30),
+            ..Default::default()
+        };
+        for tcp in [&connected, &accepted] {
+            let socket = SockRef::from(tcp);
+            assert!(!socket.keepalive().unwrap());
+
+            set_tcp_options(tcp, &config).unwrap();
+            assert!(socket.nodelay().unwrap());
+            assert!(socket.keepalive().unwrap());
+            #[cfg(not(windows))]
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        }
+    }
+}
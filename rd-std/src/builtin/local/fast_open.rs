@@ -0,0 +1,84 @@
+use std::io;
+use tokio::net::TcpSocket;
+
+/// Backlog of pending TFO requests on a listening socket.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+const FAST_OPEN_QUEUE_LEN: libc::c_int = 256;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn set_tcp_option(socket: &TcpSocket, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    use std::os::unix::prelude::AsRawFd;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Enables TCP Fast Open on a socket that is about to connect.
+/// The SYN is deferred until the first write, which is then carried by it.
+#[cfg(target_os = "linux")]
+pub fn set_fast_open_connect(socket: &TcpSocket) -> io::Result<()> {
+    set_tcp_option(socket, libc::TCP_FASTOPEN_CONNECT, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_fast_open_connect(_socket: &TcpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+/// Enables TCP Fast Open on a socket that is about to listen.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn set_fast_open_listen(socket: &TcpSocket) -> io::Result<()> {
+    set_tcp_option(socket, libc::TCP_FASTOPEN, FAST_OPEN_QUEUE_LEN)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn set_fast_open_listen(_socket: &TcpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::unix::prelude::AsRawFd;
+
+    fn get_tcp_option(socket: &TcpSocket, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                name,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        value
+    }
+
+    #[tokio::test]
+    async fn test_fast_open_options() {
+        let socket = TcpSocket::new_v4().unwrap();
+        set_fast_open_connect(&socket).unwrap();
+        assert_eq!(get_tcp_option(&socket, libc::TCP_FASTOPEN_CONNECT), 1);
+
+        let socket = TcpSocket::new_v4().unwrap();
+        set_fast_open_listen(&socket).unwrap();
+        assert_eq!(
+            get_tcp_option(&socket, libc::TCP_FASTOPEN),
+            FAST_OPEN_QUEUE_LEN
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Unix domain sockets, wrapped as TCP streams and listeners. They have no
+//! `SocketAddr`, `0.0.0.0:0` is given in place of one.
+
+use std::{net::SocketAddr, path::Path};
+
+use rd_interface::{Result, TcpListener, TcpStream};
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use rd_interface::{async_trait, impl_async_read_write, IntoDyn};
+    use std::path::PathBuf;
+    use tokio::net;
+
+    fn no_socket_addr() -> SocketAddr {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    }
+
+    pub struct CompatUnix(net::UnixStream);
+
+    impl_async_read_write!(CompatUnix, 0);
+
+    #[async_trait]
+    impl rd_interface::ITcpStream for CompatUnix {
+        async fn peer_addr(&self) -> Result<SocketAddr> {
+            Ok(no_socket_addr())
+        }
+        async fn local_addr(&self) -> Result<SocketAddr> {
+            Ok(no_socket_addr())
+        }
+    }
+
+    /// Removes the socket file when dropped.
+    pub struct UnixListener(net::UnixListener, PathBuf);
+
+    #[async_trait]
+    impl rd_interface::ITcpListener for UnixListener {
+        async fn accept(&self) -> Result<(TcpStream, SocketAddr)> {
+            let (socket, _) = self.0.accept().await?;
+            Ok((CompatUnix(socket).into_dyn(), no_socket_addr()))
+        }
+
+        async fn local_addr(&self) -> Result<SocketAddr> {
+            Ok(no_socket_addr())
+        }
+    }
+
+    impl Drop for UnixListener {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.1).ok();
+        }
+    }
+
+    pub async fn connect(path: &Path) -> Result<TcpStream> {
+        Ok(CompatUnix(net::UnixStream::connect(path).await?).into_dyn())
+    }
+
+    pub fn bind(path: &Path) -> Result<TcpListener> {
+        let listener = net::UnixListener::bind(path)?;
+        Ok(UnixListener(listener, path.to_path_buf()).into_dyn())
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+    use std::io;
+
+    fn unsupported() -> rd_interface::Error {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "Unix sockets are not supported on this platform",
+        )
+        .into()
+    }
+
+    pub async fn connect(_path: &Path) -> Result<TcpStream> {
+        Err(unsupported())
+    }
+
+    pub fn bind(_path: &Path) -> Result<TcpListener> {
+        Err(unsupported())
+    }
+}
+
+pub use imp::{bind, connect};
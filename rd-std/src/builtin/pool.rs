@@ -0,0 +1,338 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+
+use rd_interface::{
+    async_trait,
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Address, AsyncRead, AsyncWrite, Config, Context, INet, ITcpStream, IntoDyn, Net, ReadBuf,
+    Result, TcpListener, TcpStream, UdpSocket,
+};
+use serde_derive::Deserialize;
+
+struct Pool {
+    max_idle_per_host: usize,
+    idle_timeout: Duration,
+    idle: Mutex<HashMap<Address, VecDeque<(Instant, TcpStream)>>>,
+}
+
+impl Pool {
+    /// Takes the most recently returned stream of `addr`.
+    fn get(&self, addr: &Address) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.get_mut(addr)?;
+        let stream = loop {
+            match streams.pop_back() {
+                Some((since, stream)) if since.elapsed() < self.idle_timeout => break Some(stream),
+                Some(_) => continue,
+                None => break None,
+            }
+        };
+        if streams.is_empty() {
+            idle.remove(addr);
+        }
+        stream
+    }
+    fn put(&self, addr: Address, stream: TcpStream) {
+        if self.max_idle_per_host == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.entry(addr).or_default();
+        streams.retain(|(since, _)| since.elapsed() < self.idle_timeout);
+        if streams.len() >= self.max_idle_per_host {
+            streams.pop_front();
+        }
+        streams.push_back((Instant::now(), stream));
+    }
+}
+
+/// A stream from [`PoolNet`]. It goes back to the pool when dropped if it's
+/// marked reusable, otherwise it's closed.
+pub struct PooledTcpStream {
+    inner: Option<TcpStream>,
+    addr: Address,
+    pool: Arc<Pool>,
+    reusable: bool,
+}
+
+impl PooledTcpStream {
+    /// Marks the stream reusable. Only do this when the protocol on it is
+    /// done with the last request, e.g. an HTTP keep-alive response is
+    /// fully read.
+    pub fn set_reusable(&mut self, reusable: bool) {
+        self.reusable = reusable;
+    }
+    fn inner(&mut self) -> &mut TcpStream {
+        self.inner
+            .as_mut()
+            .expect("stream is taken only when dropped")
+    }
+}
+
+impl Drop for PooledTcpStream {
+    fn drop(&mut self) {
+        if let (true, Some(stream)) = (self.reusable, self.inner.take()) {
+            self.pool.put(self.addr.clone(), stream);
+        }
+    }
+}
+
+impl AsyncRead for PooledTcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(self.inner()).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PooledTcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(self.inner()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.inner()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        // a closed stream can't be reused
+        self.reusable = false;
+        Pin::new(self.inner()).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl ITcpStream for PooledTcpStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        match &self.inner {
+            Some(s) => s.peer_addr().await,
+            None => unreachable!(),
+        }
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        match &self.inner {
+            Some(s) => s.local_addr().await,
+            None => unreachable!(),
+        }
+    }
+}
+
+/// Keeps the reusable streams of `net` for later connects to the same address.
+pub struct PoolNet {
+    net: Net,
+    pool: Arc<Pool>,
+}
+
+impl PoolNet {
+    pub fn new(net: Net, max_idle_per_host: usize, idle_timeout: Duration) -> PoolNet {
+        PoolNet {
+            net,
+            pool: Arc::new(Pool {
+                max_idle_per_host,
+                idle_timeout,
+                idle: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+    /// Takes an idle stream to `addr`, or connects a new one.
+    pub async fn connect_pooled(
+        &self,
+        ctx: &mut Context,
+        addr: Address,
+    ) -> Result<PooledTcpStream> {
+        let stream = match self.pool.get(&addr) {
+            Some(stream) => stream,
+            None => self.net.tcp_connect(ctx, addr.clone()).await?,
+        };
+        Ok(PooledTcpStream {
+            inner: Some(stream),
+            addr,
+            pool: self.pool.clone(),
+            reusable: false,
+        })
+    }
+}
+
+#[async_trait]
+impl INet for PoolNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        Ok(self.connect_pooled(ctx, addr).await?.into_dyn())
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        self.net.tcp_bind(ctx, addr).await
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        self.net.udp_bind(ctx, addr).await
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        self.net.lookup_host(addr).await
+    }
+}
+
+fn default_max_idle_per_host() -> usize {
+    4
+}
+
+fn default_idle_timeout() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct Config {
+    #[serde(default)]
+    net: NetRef,
+    /// Idle streams kept for each address.
+    #[serde(default = "default_max_idle_per_host")]
+    max_idle_per_host: usize,
+    /// Seconds an idle stream is kept.
+    #[serde(default = "default_idle_timeout")]
+    idle_timeout: u64,
+}
+
+impl NetFactory for PoolNet {
+    const NAME: &'static str = "pool";
+    type Config = Config;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        Ok(PoolNet::new(
+            config.net.try_net()?,
+            config.max_idle_per_host,
+            Duration::from_secs(config.idle_timeout),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtin::local::{LocalConfig, LocalNet};
+    use rd_interface::IntoAddress;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::{
+        io::{self as tokio_io, AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time::sleep,
+    };
+
+    /// An echo server counting accepted connections.
+    async fn spawn_counting_echo() -> (Address, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().into_address().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = tokio_io::split(tcp);
+                    tokio_io::copy(&mut rx, &mut tx).await.ok();
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    fn pool_net(max_idle_per_host: usize, idle_timeout: Duration) -> PoolNet {
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        PoolNet::new(local, max_idle_per_host, idle_timeout)
+    }
+
+    async fn echo(stream: &mut PooledTcpStream) {
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    async fn connect(net: &PoolNet, addr: &Address, reusable: bool) {
+        let mut stream = net
+            .connect_pooled(&mut Context::new(), addr.clone())
+            .await
+            .unwrap();
+        echo(&mut stream).await;
+        stream.set_reusable(reusable);
+    }
+
+    #[tokio::test]
+    async fn test_pool_reuse() {
+        let (addr, accepted) = spawn_counting_echo().await;
+        let net = pool_net(4, Duration::from_secs(60));
+
+        connect(&net, &addr, true).await;
+        connect(&net, &addr, true).await;
+        // the generic tcp_connect takes from the pool too
+        let mut tcp = net
+            .tcp_connect(&mut Context::new(), addr.clone())
+            .await
+            .unwrap();
+        tcp.write_all(b"ping").await.unwrap();
+        tcp.read_exact(&mut [0u8; 4]).await.unwrap();
+
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pool_not_reusable() {
+        let (addr, accepted) = spawn_counting_echo().await;
+        let net = pool_net(4, Duration::from_secs(60));
+
+        connect(&net, &addr, false).await;
+        connect(&net, &addr, false).await;
+
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_idle_timeout() {
+        let (addr, accepted) = spawn_counting_echo().await;
+        let net = pool_net(4, Duration::from_millis(50));
+
+        connect(&net, &addr, true).await;
+        sleep(Duration::from_millis(100)).await;
+        connect(&net, &addr, true).await;
+
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_max_idle() {
+        let (addr, _) = spawn_counting_echo().await;
+        let net = pool_net(2, Duration::from_secs(60));
+
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            let mut stream = net
+                .connect_pooled(&mut Context::new(), addr.clone())
+                .await
+                .unwrap();
+            stream.set_reusable(true);
+            streams.push(stream);
+        }
+        drop(streams);
+
+        assert_eq!(net.pool.idle.lock().unwrap()[&addr].len(), 2);
+    }
+}
@@ -0,0 +1,305 @@
+use std::net::{IpAddr, SocketAddr};
+
+use rd_interface::{
+    async_trait,
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Address, Config, Context, INet, Net, Result, TcpListener, TcpStream, UdpSocket,
+};
+use serde_derive::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+/// Signature of a v2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2, PROXY command.
+const V2_VERSION_COMMAND: u8 = 0x21;
+/// Version 2, LOCAL command.
+const V2_VERSION_LOCAL: u8 = 0x20;
+const V2_UNSPEC: u8 = 0x00;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// Sends the PROXY protocol header with the client address, so the server
+/// `net` connects to sees it.
+pub struct ProxyProtocolNet {
+    net: Net,
+    version: u8,
+}
+
+/// Both addresses of a header must be in the same family, so IPv4 goes
+/// mapped to IPv6 if the other one is IPv6.
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    if src.is_ipv4() == dst.is_ipv4() {
+        (src, dst)
+    } else {
+        (to_v6(src), to_v6(dst))
+    }
+}
+
+/// Builds the header telling a connection comes from `src` to `dst`.
+pub fn header(version: u8, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src, dst) = same_family(src, dst);
+    if version == 1 {
+        let proto = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+        return format!(
+            "PROXY {} {} {} {} {}\r\n",
+            proto,
+            src.ip(),
+            dst.ip(),
+            src.port(),
+            dst.port()
+        )
+        .into_bytes();
+    }
+
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(V2_VERSION_COMMAND);
+    let addrs = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            header.push(V2_TCP4);
+            [&s.octets()[..], &d.octets()[..]].concat()
+        }
+        (s, d) => {
+            header.push(V2_TCP6);
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V6(ip) => ip.octets(),
+                IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            };
+            [&v6(s)[..], &v6(d)[..]].concat()
+        }
+    };
+    header.extend_from_slice(&(addrs.len() as u16 + 4).to_be_bytes());
+    header.extend_from_slice(&addrs);
+    header.extend_from_slice(&src.port().to_be_bytes());
+    header.extend_from_slice(&dst.port().to_be_bytes());
+    header
+}
+
+/// Builds the header for a connection whose addresses aren't known, so the
+/// server uses the ones of the connection itself.
+pub fn unknown_header(version: u8) -> Vec<u8> {
+    if version == 1 {
+        return b"PROXY UNKNOWN\r\n".to_vec();
+    }
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[V2_VERSION_LOCAL, V2_UNSPEC, 0, 0]);
+    header
+}
+
+#[async_trait]
+impl INet for ProxyProtocolNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        let mut tcp = self.net.tcp_connect(ctx, addr.clone()).await?;
+        // nothing to tell without the client address
+        let src = match ctx.source_addr() {
+            Some(src) => src,
+            None => return Ok(tcp),
+        };
+        let dst = match addr {
+            Address::SocketAddr(dst) => Some(dst),
+            // not every net knows where a domain went
+            _ => tcp.peer_addr().await.ok(),
+        };
+        let header = match dst {
+            Some(dst) => header(self.version, src, dst),
+            None => unknown_header(self.version),
+        };
+        tcp.write_all(&header).await?;
+        Ok(tcp)
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        self.net.tcp_bind(ctx, addr).await
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        self.net.udp_bind(ctx, addr).await
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        self.net.lookup_host(addr).await
+    }
+}
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct Config {
+    #[serde(default)]
+    net: NetRef,
+    /// 1 for the text header, 2 for the binary one.
+    version: u8,
+}
+
+impl NetFactory for ProxyProtocolNet {
+    const NAME: &'static str = "proxy_protocol";
+    type Config = Config;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        if config.version != 1 && config.version != 2 {
+            return Err(rd_interface::Error::Other(
+                format!("unknown PROXY protocol version {}", config.version).into(),
+            ));
+        }
+        Ok(ProxyProtocolNet {
+            net: config.net.try_net()?,
+            version: config.version,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryNet;
+    use rd_interface::{impl_async_read_write, ITcpStream, IntoAddress, IntoDyn, NOT_IMPLEMENTED};
+    use std::sync::Mutex;
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+
+    /// A stream without `peer_addr`, like the ones of http nets.
+    struct NoPeerTcp(DuplexStream);
+    impl_async_read_write!(NoPeerTcp, 0);
+
+    #[async_trait]
+    impl ITcpStream for NoPeerTcp {
+        async fn peer_addr(&self) -> Result<SocketAddr> {
+            Err(NOT_IMPLEMENTED)
+        }
+        async fn local_addr(&self) -> Result<SocketAddr> {
+            Err(NOT_IMPLEMENTED)
+        }
+    }
+
+    /// Connects to anything, keeping the other end of the streams.
+    #[derive(Default)]
+    struct NoPeerNet(Mutex<Vec<DuplexStream>>);
+
+    #[async_trait]
+    impl INet for NoPeerNet {
+        async fn tcp_connect(&self, _ctx: &mut Context, _addr: Address) -> Result<TcpStream> {
+            let (a, b) = duplex(1024);
+            self.0.lock().unwrap().push(b);
+            Ok(NoPeerTcp(a).into_dyn())
+        }
+        async fn tcp_bind(&self, _ctx: &mut Context, _addr: Address) -> Result<TcpListener> {
+            Err(NOT_IMPLEMENTED)
+        }
+        async fn udp_bind(&self, _ctx: &mut Context, _addr: Address) -> Result<UdpSocket> {
+            Err(NOT_IMPLEMENTED)
+        }
+    }
+
+    #[test]
+    fn test_header_v1() {
+        let src = "192.168.0.1:56324".parse().unwrap();
+        let dst = "192.168.0.11:443".parse().unwrap();
+        assert_eq!(
+            header(1, src, dst),
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n"
+        );
+
+        let src = "[2001:db8::1]:56324".parse().unwrap();
+        assert_eq!(
+            header(1, src, dst),
+            b"PROXY TCP6 2001:db8::1 ::ffff:192.168.0.11 56324 443\r\n"
+        );
+    }
+
+    #[test]
+    fn test_header_v2() {
+        let src = "192.168.0.1:56324".parse().unwrap();
+        let dst = "192.168.0.11:443".parse().unwrap();
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        expected.extend_from_slice(&[192, 168, 0, 1, 192, 168, 0, 11, 0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(header(2, src, dst), expected);
+
+        let src = "[2001:db8::1]:56324".parse().unwrap();
+        let dst = "[::1]:443".parse().unwrap();
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        expected.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        expected.extend_from_slice(&[0; 11]);
+        expected.push(1);
+        expected.extend_from_slice(&[0; 15]);
+        expected.push(1);
+        expected.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(header(2, src, dst), expected);
+    }
+
+    async fn received(version: u8, ctx: &mut Context) -> Vec<u8> {
+        let memory = MemoryNet::new();
+        let listener = memory
+            .tcp_bind(&mut Context::new(), "10.0.0.2:443".into_address().unwrap())
+            .await
+            .unwrap();
+        let net = ProxyProtocolNet {
+            net: memory.into_dyn(),
+            version,
+        };
+        let mut tcp = net
+            .tcp_connect(ctx, "10.0.0.2:443".into_address().unwrap())
+            .await
+            .unwrap();
+        tcp.write_all(b"data").await.unwrap();
+        drop(tcp);
+
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut buf = Vec::new();
+        accepted.read_to_end(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_net() {
+        let src = "10.0.0.1:1234".parse().unwrap();
+        assert_eq!(
+            received(1, &mut Context::from_socketaddr(src)).await,
+            b"PROXY TCP4 10.0.0.1 10.0.0.2 1234 443\r\ndata"
+        );
+        let mut expected = header(2, src, "10.0.0.2:443".parse().unwrap());
+        expected.extend_from_slice(b"data");
+        assert_eq!(
+            received(2, &mut Context::from_socketaddr(src)).await,
+            expected
+        );
+
+        // no source address, no header
+        assert_eq!(received(1, &mut Context::new()).await, b"data");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_destination() {
+        let src = "10.0.0.1:1234".parse().unwrap();
+        for (version, expected) in [
+            (1, b"PROXY UNKNOWN\r\n".to_vec()),
+            (2, b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00".to_vec()),
+        ] {
+            let inner = std::sync::Arc::new(NoPeerNet::default());
+            let net = ProxyProtocolNet {
+                net: inner.clone(),
+                version,
+            };
+            let tcp = net
+                .tcp_connect(
+                    &mut Context::from_socketaddr(src),
+                    "example.com:443".into_address().unwrap(),
+                )
+                .await
+                .unwrap();
+            drop(tcp);
+
+            let mut other = inner.0.lock().unwrap().pop().unwrap();
+            let mut buf = Vec::new();
+            other.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, expected);
+        }
+    }
+
+    #[test]
+    fn test_unknown_version() {
+        let config: Config = serde_json::from_value(serde_json::json!({ "version": 3 })).unwrap();
+        assert!(ProxyProtocolNet::new(config).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::ready;
+use rd_interface::{
+    async_trait,
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Address, AsyncRead, AsyncWrite, Config, Context, INet, ITcpStream, IUdpSocket, IntoDyn, Net,
+    ReadBuf, Result, TcpListener, TcpStream, UdpSocket,
+};
+use serde_derive::Deserialize;
+use tokio::time::{sleep, Sleep};
+
+/// A token bucket shared by every connection of a net. Taking more than
+/// there is leaves a debt, which the next taker waits out.
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: u64, capacity: u64) -> Arc<Bucket> {
+        Arc::new(Bucket {
+            rate: rate as f64,
+            capacity: capacity as f64,
+            state: Mutex::new((capacity as f64, Instant::now())),
+        })
+    }
+    /// How long to wait before taking again.
+    fn delay(&self) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens =
+            (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.capacity);
+        *last = now;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+    fn take(&self, bytes: usize) {
+        self.state.lock().unwrap().0 -= bytes as f64;
+    }
+    async fn wait(&self) {
+        loop {
+            match self.delay() {
+                d if d.is_zero() => return,
+                d => sleep(d).await,
+            }
+        }
+    }
+}
+
+/// One direction of a stream.
+struct Throttle {
+    bucket: Option<Arc<Bucket>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    fn new(bucket: Option<Arc<Bucket>>) -> Throttle {
+        Throttle {
+            bucket,
+            sleep: None,
+        }
+    }
+    fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<()> {
+        let bucket = match &self.bucket {
+            Some(bucket) => bucket,
+            None => return Poll::Ready(()),
+        };
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            match bucket.delay() {
+                d if d.is_zero() => return Poll::Ready(()),
+                d => self.sleep = Some(Box::pin(sleep(d))),
+            }
+        }
+    }
+    /// Caps a write, so a single one can't run up a large debt.
+    fn limit(&self, len: usize) -> usize {
+        match &self.bucket {
+            Some(bucket) => len.min(bucket.capacity.max(1.0) as usize),
+            None => len,
+        }
+    }
+    fn take(&self, bytes: usize) {
+        if let Some(bucket) = &self.bucket {
+            bucket.take(bytes)
+        }
+    }
+}
+
+pub struct RateLimitTcpStream {
+    inner: TcpStream,
+    up: Throttle,
+    down: Throttle,
+}
+
+impl AsyncRead for RateLimitTcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.down.poll_ready(cx));
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.down.take(buf.filled().len() - filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RateLimitTcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.up.poll_ready(cx));
+        let len = self.up.limit(buf.len());
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..len]))?;
+        self.up.take(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl ITcpStream for RateLimitTcpStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr().await
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr().await
+    }
+}
+
+pub struct RateLimitUdpSocket {
+    inner: UdpSocket,
+    up: Option<Arc<Bucket>>,
+    down: Option<Arc<Bucket>>,
+}
+
+#[async_trait]
+impl IUdpSocket for RateLimitUdpSocket {
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        if let Some(down) = &self.down {
+            down.wait().await;
+        }
+        let (size, addr) = self.inner.recv_from(buf).await?;
+        if let Some(down) = &self.down {
+            down.take(size);
+        }
+        Ok((size, addr))
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: Address) -> Result<usize> {
+        if let Some(up) = &self.up {
+            up.wait().await;
+        }
+        let size = self.inner.send_to(buf, addr).await?;
+        if let Some(up) = &self.up {
+            up.take(size);
+        }
+        Ok(size)
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr().await
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> Result<()> {
+        self.inner.connect(addr).await
+    }
+
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr().await
+    }
+}
+
+/// Caps the total throughput of all connections of `net`.
+pub struct RateLimitNet {
+    net: Net,
+    up: Option<Arc<Bucket>>,
+    down: Option<Arc<Bucket>>,
+}
+
+#[async_trait]
+impl INet for RateLimitNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        let inner = self.net.tcp_connect(ctx, addr).await?;
+        Ok(RateLimitTcpStream {
+            inner,
+            up: Throttle::new(self.up.clone()),
+            down: Throttle::new(self.down.clone()),
+        }
+        .into_dyn())
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        self.net.tcp_bind(ctx, addr).await
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        let inner = self.net.udp_bind(ctx, addr).await?;
+        Ok(RateLimitUdpSocket {
+            inner,
+            up: self.up.clone(),
+            down: self.down.clone(),
+        }
+        .into_dyn())
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        self.net.lookup_host(addr).await
+    }
+}
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct Config {
+    #[serde(default)]
+    net: NetRef,
+    /// Bytes sent per second. Unlimited if omitted.
+    #[serde(default)]
+    up_bytes_per_sec: Option<u64>,
+    /// Bytes received per second. Unlimited if omitted.
+    #[serde(default)]
+    down_bytes_per_sec: Option<u64>,
+    /// Bytes that can go at once after being idle. One second of traffic
+    /// by default.
+    #[serde(default)]
+    burst: Option<u64>,
+}
+
+impl NetFactory for RateLimitNet {
+    const NAME: &'static str = "rate_limit";
+    type Config = Config;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        if config.burst == Some(0) {
+            return Err(rd_interface::Error::Other(
+                "burst must be at least 1".into(),
+            ));
+        }
+        let bucket = |rate: Option<u64>| match rate {
+            Some(0) => Err(rd_interface::Error::Other(
+                "bytes_per_sec must be positive".into(),
+            )),
+            Some(rate) => Ok(Some(Bucket::new(rate, config.burst.unwrap_or(rate)))),
+            None => Ok(None),
+        };
+        let up = bucket(config.up_bytes_per_sec)?;
+        let down = bucket(config.down_bytes_per_sec)?;
+        Ok(RateLimitNet {
+            net: config.net.try_net()?,
+            up,
+            down,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        builtin::local::{LocalConfig, LocalNet},
+        tests::spawn_echo_server,
+    };
+    use futures::future::join;
+    use rd_interface::IntoAddress;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn rate_limit_net(up: Option<u64>, down: Option<u64>, burst: u64) -> RateLimitNet {
+        RateLimitNet {
+            net: LocalNet::new(LocalConfig::default()).into_dyn(),
+            up: up.map(|rate| Bucket::new(rate, burst)),
+            down: down.map(|rate| Bucket::new(rate, burst)),
+        }
+    }
+
+    async fn echo(net: &RateLimitNet, size: usize) {
+        let mut tcp = net
+            .tcp_connect(
+                &mut Context::new(),
+                "127.0.0.1:26679".into_address().unwrap(),
+            )
+            .await
+            .unwrap();
+        let data = vec![1u8; size];
+        let (mut rx, mut tx) = tokio::io::split(&mut tcp);
+        let write = tx.write_all(&data);
+        let mut buf = vec![0u8; size];
+        let read = rx.read_exact(&mut buf);
+        let (w, r) = join(write, read).await;
+        w.unwrap();
+        r.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        spawn_echo_server(
+            &LocalNet::new(LocalConfig::default()).into_dyn(),
+            "127.0.0.1:26679",
+        )
+        .await;
+
+        // 64 KiB in two connections through a shared 64 KiB/s limit
+        let net = rate_limit_net(Some(64 * 1024), None, 8 * 1024);
+        let start = Instant::now();
+        join(echo(&net, 32 * 1024), echo(&net, 32 * 1024)).await;
+        // the burst and the last write may go without waiting
+        assert!(start.elapsed() >= Duration::from_millis(700));
+
+        let net = rate_limit_net(None, None, 0);
+        let start = Instant::now();
+        echo(&net, 64 * 1024).await;
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_zero_rate() {
+        let config: Config =
+            serde_json::from_value(serde_json::json!({ "up_bytes_per_sec": 0 })).unwrap();
+        match RateLimitNet::new(config) {
+            Err(rd_interface::Error::Other(e)) => {
+                assert_eq!(e.to_string(), "bytes_per_sec must be positive")
+            }
+            _ => panic!("expected an error"),
+        }
+
+        let config: Config =
+            serde_json::from_value(serde_json::json!({ "up_bytes_per_sec": 1024, "burst": 0 }))
+                .unwrap();
+        match RateLimitNet::new(config) {
+            Err(rd_interface::Error::Other(e)) => {
+                assert_eq!(e.to_string(), "burst must be at least 1")
+            }
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_connect() {
+        let net = rate_limit_net(Some(1024), Some(1024), 1024);
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        let udp = net
+            .udp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        assert!(udp.peer_addr().await.is_err());
+        udp.connect(peer_addr).await.unwrap();
+        assert_eq!(udp.peer_addr().await.unwrap(), peer_addr);
+    }
+}
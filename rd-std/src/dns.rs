@@ -0,0 +1,97 @@
+pub use fakeip::FakeIpNet;
+pub use net::DnsNet;
+
+mod fakeip;
+mod message;
+mod net;
+mod resolver;
+#[cfg(test)]
+mod tests;
+
+use crate::rule::config::IpCidr;
+use rd_interface::{
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Config, Registry, Result,
+};
+use serde_derive::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Config, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProtocol {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+fn default_timeout() -> u64 {
+    5000
+}
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct DnsNetConfig {
+    /// Upstream DNS server, e.g. `8.8.8.8:53`.
+    server: String,
+    #[serde(default)]
+    protocol: DnsProtocol,
+    /// Timeout of a query in milliseconds.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// Net used to reach the DNS server.
+    #[serde(default)]
+    dns_net: NetRef,
+
+    #[serde(default)]
+    net: NetRef,
+}
+
+fn default_fake_ip_pool() -> IpCidr {
+    "198.18.0.0/15".parse().expect("valid cidr")
+}
+
+fn default_fake_ip_size() -> usize {
+    65536
+}
+
+/// Answers DNS queries sent through it with a fake IP from `pool` for each
+/// domain, and connects to the domain when a fake IP is connected to. For
+/// transparent proxying, where nets are only given IPs.
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct FakeIpNetConfig {
+    /// Where fake IPs are taken from, `198.18.0.0/15` by default.
+    #[serde(default = "default_fake_ip_pool")]
+    pool: IpCidr,
+    /// Domains kept at most. Past it, the least recently used one gives its
+    /// IP away.
+    #[serde(default = "default_fake_ip_size")]
+    size: usize,
+
+    #[serde(default)]
+    net: NetRef,
+}
+
+impl NetFactory for DnsNet {
+    const NAME: &'static str = "dns";
+    type Config = DnsNetConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        DnsNet::new(config.net.try_net()?, config.dns_net.try_net()?, config)
+    }
+}
+
+impl NetFactory for FakeIpNet {
+    const NAME: &'static str = "fakeip";
+    type Config = FakeIpNetConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        FakeIpNet::new(config.net.try_net()?, config)
+    }
+}
+
+pub fn init(registry: &mut Registry) -> Result<()> {
+    registry.add_net::<DnsNet>();
+    registry.add_net::<FakeIpNet>();
+    Ok(())
+}
@@ -0,0 +1,302 @@
+use super::{
+    message::{build_answer, parse_query, TYPE_A, TYPE_AAAA},
+    FakeIpNetConfig,
+};
+use crate::rule::config::IpCidr;
+use futures::{
+    future::{select, Either},
+    pin_mut,
+};
+use rd_interface::{
+    async_trait, Address, Context, Error, INet, IUdpSocket, IntoDyn, Net, Result, TcpListener,
+    TcpStream, UdpSocket, NOT_IMPLEMENTED,
+};
+use smoltcp::wire;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{self, mpsc};
+
+const DNS_PORT: u16 = 53;
+/// Fake IPs are only good while they are kept, so they aren't cached long.
+const FAKE_IP_TTL: u32 = 1;
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Fake IPs handed out to domains. Past `capacity` domains, the least
+/// recently used one gives its IP to the next.
+struct Pool {
+    cidr: IpCidr,
+    network: u128,
+    capacity: usize,
+    domains: HashMap<String, (IpAddr, u64)>,
+    ips: HashMap<IpAddr, String>,
+    recent: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Pool {
+    fn new(cidr: IpCidr, size: usize) -> Result<Pool> {
+        let (address, host_bits) = match &cidr.0 {
+            wire::IpCidr::Ipv4(c) => (
+                u32::from_be_bytes(c.address().0) as u128,
+                32 - c.prefix_len() as u32,
+            ),
+            wire::IpCidr::Ipv6(c) => (
+                u128::from_be_bytes(c.address().0),
+                128 - c.prefix_len() as u32,
+            ),
+            _ => {
+                return Err(Error::Other(
+                    format!("Invalid fake IP pool {}", cidr).into(),
+                ))
+            }
+        };
+        let host_mask = match host_bits {
+            128 => u128::MAX,
+            bits => (1 << bits) - 1,
+        };
+        // the first and the last addresses are left out
+        let capacity = host_mask.saturating_sub(1).min(size as u128) as usize;
+        if capacity == 0 {
+            return Err(Error::Other(
+                format!("The fake IP pool {} is too small", cidr).into(),
+            ));
+        }
+
+        Ok(Pool {
+            cidr,
+            network: address & !host_mask,
+            capacity,
+            domains: HashMap::new(),
+            ips: HashMap::new(),
+            recent: BTreeMap::new(),
+            clock: 0,
+        })
+    }
+
+    fn ip_at(&self, offset: u128) -> IpAddr {
+        match &self.cidr.0 {
+            wire::IpCidr::Ipv4(_) => Ipv4Addr::from((self.network + offset) as u32).into(),
+            _ => Ipv6Addr::from(self.network + offset).into(),
+        }
+    }
+
+    /// Whether queries of `qtype` are answered with a fake IP.
+    fn answers(&self, qtype: u16) -> bool {
+        match &self.cidr.0 {
+            wire::IpCidr::Ipv4(_) => qtype == TYPE_A,
+            _ => qtype == TYPE_AAAA,
+        }
+    }
+
+    fn touch(&mut self, domain: &str) -> IpAddr {
+        self.clock += 1;
+        let (ip, used) = self.domains.get_mut(domain).expect("domain is kept");
+        self.recent.remove(used);
+        *used = self.clock;
+        self.recent.insert(self.clock, domain.to_string());
+        *ip
+    }
+
+    /// The fake IP of `domain`, handing one out if it has none.
+    fn ip_of(&mut self, domain: &str) -> IpAddr {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if self.domains.contains_key(&domain) {
+            return self.touch(&domain);
+        }
+
+        let ip = if self.domains.len() < self.capacity {
+            self.ip_at(self.domains.len() as u128 + 1)
+        } else {
+            let oldest = *self.recent.keys().next().expect("the pool is full");
+            let evicted = self.recent.remove(&oldest).expect("key exists");
+            let (ip, _) = self.domains.remove(&evicted).expect("domain is kept");
+            self.ips.remove(&ip);
+            ip
+        };
+        self.ips.insert(ip, domain.clone());
+        self.domains.insert(domain.clone(), (ip, 0));
+        self.touch(&domain)
+    }
+
+    /// The domain `ip` is handed out to.
+    fn domain_of(&mut self, ip: IpAddr) -> Option<String> {
+        let domain = self.ips.get(&ip)?.clone();
+        self.touch(&domain);
+        Some(domain)
+    }
+
+    /// Puts the domain back in place of a fake IP.
+    fn translate(&mut self, addr: Address) -> Result<Address> {
+        match addr {
+            Address::SocketAddr(addr) if self.cidr.contains(addr.ip()) => {
+                match self.domain_of(addr.ip()) {
+                    Some(domain) => Ok(Address::Domain(domain, addr.port())),
+                    None => Err(Error::Other(
+                        format!("No domain for the fake IP {}", addr.ip()).into(),
+                    )),
+                }
+            }
+            addr => Ok(addr),
+        }
+    }
+}
+
+/// Answers DNS queries sent through it with fake IPs, and connects to the
+/// domain of a fake IP through `net`.
+pub struct FakeIpNet {
+    net: Net,
+    pool: Arc<Mutex<Pool>>,
+}
+
+impl FakeIpNet {
+    pub fn new(net: Net, config: FakeIpNetConfig) -> Result<Self> {
+        Ok(FakeIpNet {
+            net,
+            pool: Arc::new(Mutex::new(Pool::new(config.pool, config.size)?)),
+        })
+    }
+}
+
+/// Answers the DNS queries sent to port 53 for the records of the pool's
+/// family itself, and sends the rest through the inner socket.
+pub struct FakeIpUdpSocket {
+    inner: UdpSocket,
+    pool: Arc<Mutex<Pool>>,
+    answer_tx: mpsc::UnboundedSender<Datagram>,
+    answer_rx: sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+#[async_trait]
+impl IUdpSocket for FakeIpUdpSocket {
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let mut answer_rx = self.answer_rx.lock().await;
+        let (answer, from) = {
+            let answer = answer_rx.recv();
+            let inner = self.inner.recv_from(buf);
+            pin_mut!(answer, inner);
+            match select(answer, inner).await {
+                Either::Left((answer, _)) => answer.expect("the sender is kept"),
+                Either::Right((result, _)) => return result,
+            }
+        };
+        let len = answer.len().min(buf.len());
+        buf[..len].copy_from_slice(&answer[..len]);
+        Ok((len, from))
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: Address) -> Result<usize> {
+        if let Address::SocketAddr(server) = addr {
+            if server.port() == DNS_PORT {
+                if let Ok(query) = parse_query(buf) {
+                    let mut pool = self.pool.lock().unwrap();
+                    if pool.answers(query.qtype) {
+                        let ip = pool.ip_of(&query.domain);
+                        let answer = build_answer(buf, &query, &[ip], FAKE_IP_TTL);
+                        let _ = self.answer_tx.send((answer, server));
+                        return Ok(buf.len());
+                    }
+                }
+            }
+        }
+        let addr = self.pool.lock().unwrap().translate(addr)?;
+        self.inner.send_to(buf, addr).await
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr().await
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> Result<()> {
+        self.inner.connect(addr).await
+    }
+
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr().await
+    }
+}
+
+#[async_trait]
+impl INet for FakeIpNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        let addr = self.pool.lock().unwrap().translate(addr)?;
+        self.net.tcp_connect(ctx, addr).await
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        self.net.tcp_bind(ctx, addr).await
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        let inner = self.net.udp_bind(ctx, addr).await?;
+        let (answer_tx, answer_rx) = mpsc::unbounded_channel();
+        Ok(FakeIpUdpSocket {
+            inner,
+            pool: self.pool.clone(),
+            answer_tx,
+            answer_rx: sync::Mutex::new(answer_rx),
+        }
+        .into_dyn())
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        match addr {
+            Address::Domain(domain, port) => {
+                let ip = self.pool.lock().unwrap().ip_of(domain);
+                Ok(vec![SocketAddr::new(ip, *port)])
+            }
+            Address::SocketAddr(addr) => Ok(vec![*addr]),
+            Address::Unix(_) => Err(NOT_IMPLEMENTED),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(cidr: &str, size: usize) -> Pool {
+        Pool::new(cidr.parse().unwrap(), size).unwrap()
+    }
+
+    #[test]
+    fn test_pool() {
+        let mut pool = pool("198.18.0.0/15", 2);
+        let a = pool.ip_of("a.test");
+        assert_eq!(a, "198.18.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(pool.ip_of("A.test."), a);
+        let b = pool.ip_of("b.test");
+        assert_eq!(b, "198.18.0.2".parse::<IpAddr>().unwrap());
+
+        // a is used last, b gives its IP away
+        assert_eq!(pool.domain_of(a).as_deref(), Some("a.test"));
+        assert_eq!(pool.ip_of("c.test"), b);
+        assert_eq!(pool.domain_of(b).as_deref(), Some("c.test"));
+        assert_eq!(pool.ip_of("a.test"), a);
+
+        assert!(pool.translate(SocketAddr::new(a, 80).into()).is_ok());
+        let outside = "198.20.0.1:80".parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            pool.translate(outside.into()).unwrap(),
+            Address::SocketAddr(outside)
+        );
+        assert!(pool
+            .translate("198.18.0.3:80".parse::<SocketAddr>().unwrap().into())
+            .is_err());
+    }
+
+    #[test]
+    fn test_pool_size() {
+        assert_eq!(pool("10.0.0.0/30", 100).capacity, 2);
+        assert_eq!(pool("fc00::/64", 100).capacity, 100);
+        assert_eq!(
+            pool("fc00::/64", 1).ip_of("a.test"),
+            "fc00::1".parse::<IpAddr>().unwrap()
+        );
+        assert!(Pool::new("10.0.0.0/31".parse().unwrap(), 100).is_err());
+        assert!(Pool::new("10.0.0.0/8".parse().unwrap(), 0).is_err());
+    }
+}
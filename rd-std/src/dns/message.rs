@@ -0,0 +1,331 @@
+//! Just enough of the DNS wire format to ask for A and AAAA records, and to
+//! answer them.
+
+use std::{
+    convert::TryInto,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const RCODE_NXDOMAIN: u16 = 3;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Bad DNS message: {}", msg),
+    )
+}
+
+/// Records parsed from a response.
+#[derive(Debug, PartialEq)]
+pub struct Answer {
+    pub ips: Vec<IpAddr>,
+    /// The smallest TTL of the records, in seconds.
+    pub ttl: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    Answer(Answer),
+    /// The answer didn't fit in a UDP packet and should be asked over TCP.
+    Truncated,
+}
+
+/// The question of a query.
+#[derive(Debug, PartialEq)]
+pub struct Query {
+    pub id: u16,
+    pub domain: String,
+    pub qtype: u16,
+    /// Where the question ends in the query.
+    end: usize,
+}
+
+/// Builds a recursive query for `domain`.
+pub fn build_query(id: u16, domain: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(12 + domain.len() + 2 + 4);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&FLAG_RD.to_be_bytes());
+    // one question, no other records
+    buf.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid domain: {:?}", domain),
+            ));
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(buf)
+}
+
+/// Returns the id of a message, if it's long enough to have one.
+pub fn message_id(buf: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(buf.get(0..2)?.try_into().ok()?))
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid("unexpected end"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+    /// Reads a name without pointers, as in questions.
+    fn name(&mut self) -> io::Result<String> {
+        let mut labels = Vec::new();
+        loop {
+            match self.bytes(1)?[0] {
+                0 => return Ok(labels.join(".")),
+                l if l & 0xC0 != 0 => return Err(invalid("compressed question")),
+                l => {
+                    let label = self.bytes(l as usize)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                }
+            }
+        }
+    }
+    fn skip_name(&mut self) -> io::Result<()> {
+        loop {
+            let len = self.bytes(1)?[0];
+            match len {
+                0 => return Ok(()),
+                // a pointer ends the name
+                l if l & 0xC0 == 0xC0 => {
+                    self.bytes(1)?;
+                    return Ok(());
+                }
+                l => {
+                    self.bytes(l as usize)?;
+                }
+            }
+        }
+    }
+}
+
+/// Parses a query with one question.
+pub fn parse_query(buf: &[u8]) -> io::Result<Query> {
+    let mut reader = Reader { buf, pos: 0 };
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let qd_count = reader.u16()?;
+    reader.bytes(6)?;
+
+    if flags & FLAG_QR != 0 {
+        return Err(invalid("not a query"));
+    }
+    if qd_count != 1 {
+        return Err(invalid(&format!("{} questions", qd_count)));
+    }
+    let domain = reader.name()?;
+    let qtype = reader.u16()?;
+    let _class = reader.u16()?;
+
+    Ok(Query {
+        id,
+        domain,
+        qtype,
+        end: reader.pos,
+    })
+}
+
+/// Answers `query` with the addresses in `ips` of its type, which may be
+/// none.
+pub fn build_answer(query: &[u8], parsed: &Query, ips: &[IpAddr], ttl: u32) -> Vec<u8> {
+    let records: Vec<Vec<u8>> = ips
+        .iter()
+        .filter_map(|ip| match (parsed.qtype, ip) {
+            (TYPE_A, IpAddr::V4(ip)) => Some(ip.octets().to_vec()),
+            (TYPE_AAAA, IpAddr::V6(ip)) => Some(ip.octets().to_vec()),
+            _ => None,
+        })
+        .collect();
+
+    let mut buf = query[..parsed.end].to_vec();
+    // QR, RA and the RD of the query
+    let flags = FLAG_QR | 0x0080 | (u16::from_be_bytes([buf[2], buf[3]]) & FLAG_RD);
+    buf[2..4].copy_from_slice(&flags.to_be_bytes());
+    buf[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+    buf[8..12].copy_from_slice(&[0, 0, 0, 0]);
+
+    for data in records {
+        // the name of the question
+        buf.extend_from_slice(&[0xC0, 0x0C]);
+        buf.extend_from_slice(&parsed.qtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&ttl.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&data);
+    }
+    buf
+}
+
+/// Parses a response, keeping only the A and AAAA records.
+pub fn parse_response(buf: &[u8]) -> io::Result<Response> {
+    let mut reader = Reader { buf, pos: 0 };
+    let _id = reader.u16()?;
+    let flags = reader.u16()?;
+    let qd_count = reader.u16()?;
+    let an_count = reader.u16()?;
+    reader.bytes(4)?;
+
+    if flags & FLAG_QR == 0 {
+        return Err(invalid("not a response"));
+    }
+    if flags & FLAG_TC != 0 {
+        return Ok(Response::Truncated);
+    }
+    match flags & 0xF {
+        0 => {}
+        RCODE_NXDOMAIN => return Err(io::Error::new(io::ErrorKind::NotFound, "Domain not found")),
+        rcode => return Err(invalid(&format!("rcode {}", rcode))),
+    }
+
+    for _ in 0..qd_count {
+        reader.skip_name()?;
+        reader.bytes(4)?;
+    }
+
+    let mut ips = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..an_count {
+        reader.skip_name()?;
+        let rtype = reader.u16()?;
+        let _class = reader.u16()?;
+        let record_ttl = reader.u32()?;
+        let len = reader.u16()? as usize;
+        let data = reader.bytes(len)?;
+
+        let ip = match (rtype, len) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = data.try_into().unwrap();
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = data.try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            // CNAME and others
+            _ => continue,
+        };
+        ips.push(ip);
+        ttl = ttl.min(record_ttl);
+    }
+    if ips.is_empty() {
+        ttl = 0;
+    }
+
+    Ok(Response::Answer(Answer { ips, ttl }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_query() {
+        let query = build_query(0x1234, "example.com.", TYPE_AAAA).unwrap();
+        assert_eq!(
+            query,
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+              \x07example\x03com\x00\x00\x1c\x00\x01"
+        );
+        assert_eq!(message_id(&query), Some(0x1234));
+
+        assert!(build_query(0, "a..com", TYPE_A).is_err());
+        assert!(build_query(0, &"a".repeat(64), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut resp = build_query(1, "example.com", TYPE_A).unwrap();
+        // QR, RD, RA
+        resp[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+        // three answers
+        resp[7] = 3;
+        // CNAME, skipped
+        resp.extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x05\x00\x02\xc0\x0c");
+        resp.extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x01\x02\x03\x04");
+        resp.extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x1e\x00\x04\x05\x06\x07\x08");
+
+        assert_eq!(
+            parse_response(&resp).unwrap(),
+            Response::Answer(Answer {
+                ips: vec!["1.2.3.4".parse().unwrap(), "5.6.7.8".parse().unwrap()],
+                ttl: 30,
+            })
+        );
+
+        assert!(parse_response(&resp[..resp.len() - 1]).is_err());
+
+        resp[2..4].copy_from_slice(&0x8183u16.to_be_bytes());
+        assert_eq!(
+            parse_response(&resp).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        resp[2..4].copy_from_slice(&0x8380u16.to_be_bytes());
+        assert_eq!(parse_response(&resp).unwrap(), Response::Truncated);
+    }
+
+    #[test]
+    fn test_answer() {
+        let query = build_query(0x1234, "Example.com", TYPE_A).unwrap();
+        let parsed = parse_query(&query).unwrap();
+        assert_eq!(parsed.id, 0x1234);
+        assert_eq!(parsed.domain, "Example.com");
+        assert_eq!(parsed.qtype, TYPE_A);
+
+        let ips: Vec<IpAddr> = vec!["198.18.0.1".parse().unwrap(), "fc00::1".parse().unwrap()];
+        let answer = build_answer(&query, &parsed, &ips, 1);
+        assert_eq!(message_id(&answer), Some(0x1234));
+        assert_eq!(
+            parse_response(&answer).unwrap(),
+            Response::Answer(Answer {
+                ips: vec![ips[0]],
+                ttl: 1,
+            })
+        );
+
+        // no address of the type
+        let query = build_query(1, "example.com", TYPE_AAAA).unwrap();
+        let answer = build_answer(&query, &parse_query(&query).unwrap(), &ips[..1], 1);
+        assert_eq!(
+            parse_response(&answer).unwrap(),
+            Response::Answer(Answer {
+                ips: vec![],
+                ttl: 0,
+            })
+        );
+
+        assert!(parse_query(&answer).is_err());
+        assert!(parse_query(&query[..query.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use super::{resolver::Resolver, DnsNetConfig};
+use rd_interface::{
+    async_trait, Address, Context, INet, IntoAddress, Net, Result, TcpListener, TcpStream,
+    UdpSocket, NOT_IMPLEMENTED,
+};
+use std::{net::SocketAddr, time::Duration};
+
+/// Resolves domains with its own resolver before connecting through `net`.
+pub struct DnsNet {
+    net: Net,
+    resolver: Resolver,
+}
+
+impl DnsNet {
+    pub fn new(net: Net, dns_net: Net, config: DnsNetConfig) -> Result<Self> {
+        let server = config.server.as_str().into_address()?;
+        let timeout = Duration::from_millis(config.timeout);
+
+        Ok(DnsNet {
+            net,
+            resolver: Resolver::new(dns_net, server, config.protocol, timeout),
+        })
+    }
+}
+
+#[async_trait]
+impl INet for DnsNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        let addr = match addr {
+            Address::Domain(domain, port) => {
+                let ips = match ctx.resolved(&domain) {
+                    Some(ips) if !ips.is_empty() => ips.to_vec(),
+                    _ => {
+                        let ips = self.resolver.lookup(&domain).await?;
+                        ctx.set_resolved(&domain, ips.clone());
+                        ips
+                    }
+                };
+                SocketAddr::new(ips[0], port).into()
+            }
+            addr => addr,
+        };
+        self.net.tcp_connect(ctx, addr).await
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        self.net.tcp_bind(ctx, addr).await
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        self.net.udp_bind(ctx, addr).await
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        match addr {
+            Address::Domain(domain, port) => Ok(self
+                .resolver
+                .lookup(domain)
+                .await?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, *port))
+                .collect()),
+            Address::SocketAddr(addr) => Ok(vec![*addr]),
+            Address::Unix(_) => Err(NOT_IMPLEMENTED),
+        }
+    }
+}
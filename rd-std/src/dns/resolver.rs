@@ -0,0 +1,164 @@
+use super::{
+    message::{build_query, message_id, parse_response, Answer, Response, TYPE_A, TYPE_AAAA},
+    DnsProtocol,
+};
+use rd_interface::{Address, Context, IntoAddress, Net, Result};
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+struct CacheEntry {
+    ips: Vec<IpAddr>,
+    expire: Instant,
+}
+
+/// Resolves domains with an upstream DNS server reached through `net`.
+pub struct Resolver {
+    net: Net,
+    server: Address,
+    protocol: DnsProtocol,
+    timeout: Duration,
+    next_id: AtomicU16,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl Resolver {
+    pub fn new(net: Net, server: Address, protocol: DnsProtocol, timeout: Duration) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u16)
+            .unwrap_or_default();
+        Resolver {
+            net,
+            server,
+            protocol,
+            timeout,
+            next_id: AtomicU16::new(seed),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the IPv4 and IPv6 addresses of `domain`, IPv4 first.
+    pub async fn lookup(&self, domain: &str) -> Result<Vec<IpAddr>> {
+        let key = domain.to_ascii_lowercase();
+        if let Some(entry) = self.cache.lock().unwrap().get(&key) {
+            if entry.expire > Instant::now() {
+                return Ok(entry.ips.clone());
+            }
+        }
+
+        let (v4, v6) = futures::join!(self.query(&key, TYPE_A), self.query(&key, TYPE_AAAA));
+        let answers: Vec<Answer> = match (v4, v6) {
+            (Err(e), Err(_)) => return Err(e),
+            (v4, v6) => v4.into_iter().chain(v6).collect(),
+        };
+        // empty answers have no TTL of their own
+        let ttl = answers
+            .iter()
+            .filter(|a| !a.ips.is_empty())
+            .map(|a| a.ttl)
+            .min()
+            .unwrap_or_default();
+        let ips: Vec<IpAddr> = answers.into_iter().flat_map(|a| a.ips).collect();
+        if ips.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No address for {}", domain),
+            )
+            .into());
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, entry| entry.expire > Instant::now());
+        if ttl > 0 {
+            cache.insert(
+                key,
+                CacheEntry {
+                    ips: ips.clone(),
+                    expire: Instant::now() + Duration::from_secs(ttl as u64),
+                },
+            );
+        }
+
+        Ok(ips)
+    }
+
+    async fn query(&self, domain: &str, qtype: u16) -> Result<Answer> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let query = build_query(id, domain, qtype)?;
+
+        let exchange = async {
+            let response = match self.protocol {
+                DnsProtocol::Udp => match self.exchange_udp(id, &query).await? {
+                    Response::Truncated => self.exchange_tcp(&query).await?,
+                    response => response,
+                },
+                DnsProtocol::Tcp => self.exchange_tcp(&query).await?,
+            };
+            match response {
+                Response::Answer(answer) => Ok(answer),
+                Response::Truncated => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Truncated DNS response over TCP",
+                )
+                .into()),
+            }
+        };
+
+        match timeout(self.timeout, exchange).await {
+            Ok(r) => r,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "DNS query timed out").into()),
+        }
+    }
+
+    async fn exchange_udp(&self, id: u16, query: &[u8]) -> Result<Response> {
+        let bind = match self.server {
+            Address::SocketAddr(addr) if addr.is_ipv6() => "[::]:0",
+            _ => "0.0.0.0:0",
+        };
+        let socket = self
+            .net
+            .udp_bind(&mut Context::new(), bind.into_address()?)
+            .await?;
+        socket.send_to(query, self.server.clone()).await?;
+
+        let mut buf = [0u8; 4096];
+        loop {
+            let (len, _) = socket.recv_from(&mut buf).await?;
+            // ignore late responses to other queries
+            if message_id(&buf[..len]) == Some(id) {
+                return Ok(parse_response(&buf[..len])?);
+            }
+        }
+    }
+
+    async fn exchange_tcp(&self, query: &[u8]) -> Result<Response> {
+        let mut socket = self
+            .net
+            .tcp_connect(&mut Context::new(), self.server.clone())
+            .await?;
+
+        let mut packet = Vec::with_capacity(2 + query.len());
+        packet.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        packet.extend_from_slice(query);
+        socket.write_all(&packet).await?;
+        socket.flush().await?;
+
+        let len = socket.read_u16().await?;
+        let mut buf = vec![0u8; len as usize];
+        socket.read_exact(&mut buf).await?;
+
+        Ok(parse_response(&buf)?)
+    }
+}
@@ -0,0 +1,316 @@
+use super::*;
+use crate::builtin::local::{LocalConfig, LocalNet};
+use crate::tests::{assert_echo, get_registry, spawn_echo_server, RecordNet};
+use message::{build_query, parse_response, Response, TYPE_A, TYPE_AAAA};
+use rd_interface::{Address, Context, INet, IntoAddress, IntoDyn, Net};
+use resolver::Resolver;
+use std::{
+    io,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+};
+
+const DOMAIN: &str = "example.test";
+
+struct MockDns {
+    port: u16,
+    queries: Arc<AtomicUsize>,
+}
+
+impl MockDns {
+    fn queries(&self) -> usize {
+        self.queries.load(Ordering::SeqCst)
+    }
+}
+
+/// Answers `DOMAIN` with 127.0.0.1 and ::1, or no IPv6 address if `v6` is
+/// not set, and other domains with NXDOMAIN.
+fn answer(query: &[u8], ttl: u32, truncate: bool, v6: bool) -> Vec<u8> {
+    let mut pos = 12;
+    let mut labels = Vec::new();
+    while query[pos] != 0 {
+        let len = query[pos] as usize;
+        labels.push(String::from_utf8(query[pos + 1..pos + 1 + len].to_vec()).unwrap());
+        pos += 1 + len;
+    }
+    let qtype = u16::from_be_bytes([query[pos + 1], query[pos + 2]]);
+    let mut resp = query[..pos + 5].to_vec();
+
+    let mut flags = 0x8180u16;
+    if truncate {
+        flags |= 0x0200;
+    }
+    let records: Vec<IpAddr> = if labels.join(".") != DOMAIN {
+        flags |= 3;
+        vec![]
+    } else if truncate {
+        vec![]
+    } else if qtype == 1 {
+        vec!["127.0.0.1".parse().unwrap()]
+    } else if !v6 {
+        vec![]
+    } else {
+        vec!["::1".parse().unwrap()]
+    };
+    resp[2..4].copy_from_slice(&flags.to_be_bytes());
+    resp[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+
+    for ip in records {
+        resp.extend_from_slice(&[0xc0, 0x0c]);
+        resp.extend_from_slice(&qtype.to_be_bytes());
+        resp.extend_from_slice(&1u16.to_be_bytes());
+        resp.extend_from_slice(&ttl.to_be_bytes());
+        let octets = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        resp.extend_from_slice(&(octets.len() as u16).to_be_bytes());
+        resp.extend_from_slice(&octets);
+    }
+    resp
+}
+
+/// A DNS server on UDP and TCP with the same port. If `udp_truncate` is set,
+/// every UDP response is truncated.
+async fn spawn_dns_server(ttl: u32, udp_truncate: bool) -> MockDns {
+    spawn_dns_server_with(ttl, udp_truncate, true).await
+}
+
+async fn spawn_dns_server_with(ttl: u32, udp_truncate: bool, v6: bool) -> MockDns {
+    let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = tcp.local_addr().unwrap().port();
+    let udp = UdpSocket::bind(("127.0.0.1", port)).await.unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+
+    let counter = queries.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, addr) = udp.recv_from(&mut buf).await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let resp = answer(&buf[..len], ttl, udp_truncate, v6);
+            udp.send_to(&resp, addr).await.unwrap();
+        }
+    });
+
+    let counter = queries.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = tcp.accept().await.unwrap();
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let len = socket.read_u16().await.unwrap();
+                let mut query = vec![0u8; len as usize];
+                socket.read_exact(&mut query).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let resp = answer(&query, ttl, false, v6);
+                socket
+                    .write_all(&(resp.len() as u16).to_be_bytes())
+                    .await
+                    .unwrap();
+                socket.write_all(&resp).await.unwrap();
+            });
+        }
+    });
+
+    MockDns { port, queries }
+}
+
+fn local() -> Net {
+    LocalNet::new(LocalConfig::default()).into_dyn()
+}
+
+fn resolver(port: u16, protocol: DnsProtocol, timeout: Duration) -> Resolver {
+    let server = ("127.0.0.1", port).into_address().unwrap();
+    Resolver::new(local(), server, protocol, timeout)
+}
+
+fn expected_ips() -> Vec<IpAddr> {
+    vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()]
+}
+
+fn io_kind(e: rd_interface::Error) -> io::ErrorKind {
+    match e {
+        rd_interface::Error::IO(e) => e.kind(),
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn test_dns_smoke() {
+    let mut registry = get_registry();
+    super::init(&mut registry).unwrap();
+}
+
+#[tokio::test]
+async fn test_resolver_udp_cache() {
+    let server = spawn_dns_server(60, false).await;
+    let resolver = resolver(server.port, DnsProtocol::Udp, Duration::from_secs(5));
+
+    assert_eq!(resolver.lookup(DOMAIN).await.unwrap(), expected_ips());
+    assert_eq!(server.queries(), 2);
+
+    // served from the cache
+    assert_eq!(
+        resolver.lookup("Example.Test").await.unwrap(),
+        expected_ips()
+    );
+    assert_eq!(server.queries(), 2);
+}
+
+#[tokio::test]
+async fn test_resolver_cache_ipv4_only() {
+    let server = spawn_dns_server_with(60, false, false).await;
+    let resolver = resolver(server.port, DnsProtocol::Udp, Duration::from_secs(5));
+
+    let ipv4 = vec!["127.0.0.1".parse::<IpAddr>().unwrap()];
+    assert_eq!(resolver.lookup(DOMAIN).await.unwrap(), ipv4);
+    assert_eq!(resolver.lookup(DOMAIN).await.unwrap(), ipv4);
+    assert_eq!(server.queries(), 2);
+}
+
+#[tokio::test]
+async fn test_resolver_ttl_expired() {
+    let server = spawn_dns_server(0, false).await;
+    let resolver = resolver(server.port, DnsProtocol::Udp, Duration::from_secs(5));
+
+    resolver.lookup(DOMAIN).await.unwrap();
+    resolver.lookup(DOMAIN).await.unwrap();
+    assert_eq!(server.queries(), 4);
+}
+
+#[tokio::test]
+async fn test_resolver_tcp() {
+    let server = spawn_dns_server(60, false).await;
+    let resolver = resolver(server.port, DnsProtocol::Tcp, Duration::from_secs(5));
+
+    assert_eq!(resolver.lookup(DOMAIN).await.unwrap(), expected_ips());
+    assert_eq!(server.queries(), 2);
+}
+
+#[tokio::test]
+async fn test_resolver_truncated() {
+    let server = spawn_dns_server(60, true).await;
+    let resolver = resolver(server.port, DnsProtocol::Udp, Duration::from_secs(5));
+
+    assert_eq!(resolver.lookup(DOMAIN).await.unwrap(), expected_ips());
+    // each query is asked again over TCP
+    assert_eq!(server.queries(), 4);
+}
+
+#[tokio::test]
+async fn test_resolver_not_found() {
+    let server = spawn_dns_server(60, false).await;
+    let resolver = resolver(server.port, DnsProtocol::Udp, Duration::from_secs(5));
+
+    let err = resolver.lookup("missing.test").await.unwrap_err();
+    assert_eq!(io_kind(err), io::ErrorKind::NotFound);
+}
+
+#[tokio::test]
+async fn test_resolver_timeout() {
+    // never answers
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let port = silent.local_addr().unwrap().port();
+    let resolver = resolver(port, DnsProtocol::Udp, Duration::from_millis(100));
+
+    let err = resolver.lookup(DOMAIN).await.unwrap_err();
+    assert_eq!(io_kind(err), io::ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+async fn test_dns_net() {
+    let local = local();
+    spawn_echo_server(&local, "127.0.0.1:26672").await;
+    let server = spawn_dns_server(60, false).await;
+
+    let config = DnsNetConfig {
+        server: format!("127.0.0.1:{}", server.port),
+        protocol: DnsProtocol::Udp,
+        timeout: default_timeout(),
+        dns_net: Default::default(),
+        net: Default::default(),
+    };
+    let net = DnsNet::new(local.clone(), local, config).unwrap();
+
+    let mut ctx = Context::new();
+    let domain = (DOMAIN, 26672).into_address().unwrap();
+    net.tcp_connect(&mut ctx, domain).await.unwrap();
+    assert_eq!(ctx.resolved(DOMAIN), Some(&expected_ips()[..]));
+
+    assert_echo(&net.into_dyn(), (DOMAIN, 26672)).await;
+    assert_eq!(server.queries(), 2);
+}
+
+#[tokio::test]
+async fn test_fake_ip_net() {
+    let inner = Arc::new(RecordNet::default());
+    let config = serde_json::from_value(serde_json::json!({ "pool": "10.10.0.0/16" })).unwrap();
+    let net = FakeIpNet::new(inner.clone(), config).unwrap();
+
+    let socket = net
+        .udp_bind(&mut Context::new(), "0.0.0.0:0".into_address().unwrap())
+        .await
+        .unwrap();
+    let server = "10.0.0.53:53".into_address().unwrap();
+    socket
+        .send_to(&build_query(7, DOMAIN, TYPE_A).unwrap(), server.clone())
+        .await
+        .unwrap();
+    let mut buf = [0u8; 512];
+    let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+    assert_eq!(Address::SocketAddr(from), server);
+    let ip = match parse_response(&buf[..len]).unwrap() {
+        Response::Answer(answer) => answer.ips[0],
+        r => panic!("unexpected response {:?}", r),
+    };
+    assert_eq!(ip, "10.10.0.1".parse::<IpAddr>().unwrap());
+    assert!(inner.take_sent().is_empty());
+
+    // AAAA isn't in the pool's family, it goes to the server
+    socket
+        .send_to(&build_query(8, DOMAIN, TYPE_AAAA).unwrap(), server.clone())
+        .await
+        .unwrap();
+    assert_eq!(inner.take_sent(), [server.to_string()]);
+
+    // connected to the inner socket's peer
+    let peer = "127.0.0.1:53".parse().unwrap();
+    socket.connect(peer).await.unwrap();
+    assert_eq!(socket.peer_addr().await.unwrap(), peer);
+
+    // the same IP when looked up
+    let addr = (DOMAIN, 443).into_address().unwrap();
+    assert_eq!(
+        net.lookup_host(&addr).await.unwrap(),
+        vec![(ip, 443).into()]
+    );
+
+    net.tcp_connect(&mut Context::new(), (ip, 443).into())
+        .await
+        .ok();
+    assert_eq!(inner.take_connected(), [addr.to_string()]);
+
+    // not handed out yet
+    let err = net
+        .tcp_connect(&mut Context::new(), "10.10.0.2:443".into_address().unwrap())
+        .await;
+    assert!(err.is_err());
+    assert!(inner.take_connected().is_empty());
+
+    // other IPs are left alone
+    let other = "127.0.0.1:443".into_address().unwrap();
+    net.tcp_connect(&mut Context::new(), other.clone())
+        .await
+        .ok();
+    assert_eq!(inner.take_connected(), [other.to_string()]);
+}
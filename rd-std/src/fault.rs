@@ -0,0 +1,436 @@
+//! A net injecting faults into the connections of another, to test how
+//! retries, failover and timeouts cope with a bad network. It's for testing
+//! only, and built with the `fault` feature.
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use futures::ready;
+use rd_interface::{
+    async_trait,
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Address, AsyncRead, AsyncWrite, Config, Context, INet, ITcpStream, IUdpSocket, IntoDyn, Net,
+    ReadBuf, Registry, Result, TcpListener, TcpStream, UdpSocket,
+};
+use serde_derive::Deserialize;
+use tokio::time::{sleep, Sleep};
+
+/// Size of the chunks a stream is read in, each one meeting its fate.
+const CHUNK_SIZE: usize = 8192;
+
+/// SplitMix64, so a seed gives the same faults on every run.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+    /// In `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// What happens to a datagram or a chunk of a stream.
+enum Fate {
+    Drop,
+    Deliver(Option<Duration>),
+}
+
+/// The faults of a net, shared by its connections.
+struct Faults {
+    connect_failure: f64,
+    drop: f64,
+    corrupt: f64,
+    delay: f64,
+    max_delay: Duration,
+    rng: Mutex<Rng>,
+}
+
+impl Faults {
+    fn chance(&self, rng: &mut Rng, probability: f64) -> bool {
+        probability > 0.0 && rng.next_f64() < probability
+    }
+    fn connect_fails(&self) -> bool {
+        let mut rng = self.rng.lock().unwrap();
+        self.chance(&mut rng, self.connect_failure)
+    }
+    /// Decides the fate of `data`, corrupting it in place.
+    fn fate(&self, data: &mut [u8]) -> Fate {
+        let mut rng = self.rng.lock().unwrap();
+        if self.chance(&mut rng, self.drop) {
+            return Fate::Drop;
+        }
+        if !data.is_empty() && self.chance(&mut rng, self.corrupt) {
+            let i = (rng.next_u64() % data.len() as u64) as usize;
+            data[i] ^= 1 << (rng.next_u64() % 8);
+        }
+        if self.chance(&mut rng, self.delay) {
+            let delay = self.max_delay.mul_f64(rng.next_f64());
+            return Fate::Deliver(Some(delay));
+        }
+        Fate::Deliver(None)
+    }
+}
+
+/// Data read from the stream goes through the faults, what's written is
+/// left alone.
+pub struct FaultTcpStream {
+    inner: TcpStream,
+    faults: Arc<Faults>,
+    /// A chunk delivered, not read yet.
+    pending: BytesMut,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl AsyncRead for FaultTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            if !this.pending.is_empty() {
+                let n = this.pending.len().min(buf.remaining());
+                buf.put_slice(&this.pending.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0u8; CHUNK_SIZE];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            let data = chunk_buf.filled_mut();
+            if data.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            match this.faults.fate(data) {
+                Fate::Drop => continue,
+                Fate::Deliver(delay) => {
+                    this.pending.extend_from_slice(data);
+                    this.sleep = delay.map(|d| Box::pin(sleep(d)));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for FaultTcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl ITcpStream for FaultTcpStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr().await
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr().await
+    }
+}
+
+/// Datagrams go through the faults both ways.
+pub struct FaultUdpSocket {
+    inner: UdpSocket,
+    faults: Arc<Faults>,
+}
+
+#[async_trait]
+impl IUdpSocket for FaultUdpSocket {
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        loop {
+            let (size, addr) = self.inner.recv_from(buf).await?;
+            if let Fate::Deliver(delay) = self.faults.fate(&mut buf[..size]) {
+                if let Some(delay) = delay {
+                    sleep(delay).await;
+                }
+                return Ok((size, addr));
+            }
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: Address) -> Result<usize> {
+        let mut data = buf.to_vec();
+        match self.faults.fate(&mut data) {
+            // lost on the way
+            Fate::Drop => Ok(buf.len()),
+            Fate::Deliver(delay) => {
+                if let Some(delay) = delay {
+                    sleep(delay).await;
+                }
+                self.inner.send_to(&data, addr).await
+            }
+        }
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr().await
+    }
+}
+
+pub struct FaultInjectNet {
+    net: Net,
+    faults: Arc<Faults>,
+}
+
+impl FaultInjectNet {
+    pub fn new(net: Net, config: FaultInjectNetConfig) -> Result<Self> {
+        let probabilities = [
+            ("connect_failure", config.connect_failure),
+            ("drop", config.drop),
+            ("corrupt", config.corrupt),
+            ("delay", config.delay),
+        ];
+        for (name, probability) in probabilities {
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(rd_interface::Error::Other(
+                    format!("{} must be between 0 and 1", name).into(),
+                ));
+            }
+        }
+        let seed = match config.seed {
+            Some(seed) => seed,
+            None => {
+                let mut seed = [0u8; 8];
+                getrandom::getrandom(&mut seed).map_err(io::Error::from)?;
+                u64::from_le_bytes(seed)
+            }
+        };
+        Ok(FaultInjectNet {
+            net,
+            faults: Arc::new(Faults {
+                connect_failure: config.connect_failure,
+                drop: config.drop,
+                corrupt: config.corrupt,
+                delay: config.delay,
+                max_delay: Duration::from_millis(config.max_delay),
+                rng: Mutex::new(Rng(seed)),
+            }),
+        })
+    }
+}
+
+#[async_trait]
+impl INet for FaultInjectNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        if self.faults.connect_fails() {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "injected fault").into());
+        }
+        let inner = self.net.tcp_connect(ctx, addr).await?;
+        Ok(FaultTcpStream {
+            inner,
+            faults: self.faults.clone(),
+            pending: BytesMut::new(),
+            sleep: None,
+        }
+        .into_dyn())
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        self.net.tcp_bind(ctx, addr).await
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        let inner = self.net.udp_bind(ctx, addr).await?;
+        Ok(FaultUdpSocket {
+            inner,
+            faults: self.faults.clone(),
+        }
+        .into_dyn())
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        self.net.lookup_host(addr).await
+    }
+}
+
+/// Probabilities are from 0 to 1, and none of the faults happen by default.
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct FaultInjectNetConfig {
+    #[serde(default)]
+    net: NetRef,
+    /// Probability that `tcp_connect` fails.
+    #[serde(default)]
+    connect_failure: f64,
+    /// Probability that a datagram, or a chunk read from a stream, is lost.
+    #[serde(default)]
+    drop: f64,
+    /// Probability that a bit of a datagram or a chunk is flipped.
+    #[serde(default)]
+    corrupt: f64,
+    /// Probability that a datagram or a chunk is held back.
+    #[serde(default)]
+    delay: f64,
+    /// Milliseconds a datagram or a chunk is held back at most.
+    #[serde(default)]
+    max_delay: u64,
+    /// Seed of the faults, for the same faults on every run. Random if
+    /// omitted.
+    #[serde(default)]
+    seed: Option<u64>,
+}
+
+impl NetFactory for FaultInjectNet {
+    const NAME: &'static str = "fault_inject";
+    type Config = FaultInjectNetConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        FaultInjectNet::new(config.net.try_net()?, config)
+    }
+}
+
+pub fn init(registry: &mut Registry) -> Result<()> {
+    registry.add_net::<FaultInjectNet>();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryNet;
+    use rd_interface::IntoAddress;
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::timeout,
+    };
+
+    fn fault_net(memory: &MemoryNet, config: serde_json::Value) -> FaultInjectNet {
+        let config = serde_json::from_value(config).unwrap();
+        FaultInjectNet::new(memory.clone().into_dyn(), config).unwrap()
+    }
+
+    /// Sends 16 datagrams through `net`, and returns those received.
+    async fn datagrams(memory: &MemoryNet, net: &FaultInjectNet) -> Vec<Vec<u8>> {
+        let receiver = memory
+            .udp_bind(&mut Context::new(), "10.0.0.2:53".into_address().unwrap())
+            .await
+            .unwrap();
+        let sender = net
+            .udp_bind(&mut Context::new(), "10.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        for i in 0..16u8 {
+            let sent = sender
+                .send_to(&[i; 4], "10.0.0.2:53".into_address().unwrap())
+                .await
+                .unwrap();
+            assert_eq!(sent, 4);
+        }
+
+        let mut received = Vec::new();
+        let mut buf = [0u8; 64];
+        while let Ok(result) =
+            timeout(Duration::from_millis(100), receiver.recv_from(&mut buf)).await
+        {
+            let (size, _) = result.unwrap();
+            received.push(buf[..size].to_vec());
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn test_drop() {
+        let memory = MemoryNet::new();
+        let net = fault_net(&memory, json!({ "drop": 1.0 }));
+        assert!(datagrams(&memory, &net).await.is_empty());
+
+        let memory = MemoryNet::new();
+        let net = fault_net(&memory, json!({ "drop": 0.0 }));
+        let expected: Vec<_> = (0..16u8).map(|i| vec![i; 4]).collect();
+        assert_eq!(datagrams(&memory, &net).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_seed() {
+        let config = json!({ "drop": 0.5, "corrupt": 0.5, "seed": 42 });
+        let memory = MemoryNet::new();
+        let first = datagrams(&memory, &fault_net(&memory, config.clone())).await;
+        let memory = MemoryNet::new();
+        let second = datagrams(&memory, &fault_net(&memory, config)).await;
+        assert_eq!(first, second);
+        assert!(!first.is_empty() && first.len() < 16);
+    }
+
+    #[tokio::test]
+    async fn test_tcp() {
+        let memory = MemoryNet::new();
+        let listener = memory
+            .tcp_bind(&mut Context::new(), "10.0.0.2:80".into_address().unwrap())
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut tcp, _) = listener.accept().await.unwrap();
+                tcp.write_all(b"hello").await.unwrap();
+            }
+        });
+
+        let net = fault_net(&memory, json!({ "connect_failure": 1.0 }));
+        let result = net
+            .tcp_connect(&mut Context::new(), "10.0.0.2:80".into_address().unwrap())
+            .await;
+        assert!(result.is_err());
+
+        let net = fault_net(
+            &memory,
+            json!({ "corrupt": 1.0, "delay": 1.0, "max_delay": 10 }),
+        );
+        let mut tcp = net
+            .tcp_connect(&mut Context::new(), "10.0.0.2:80".into_address().unwrap())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 5];
+        tcp.read_exact(&mut buf).await.unwrap();
+        let flipped: u32 = buf
+            .iter()
+            .zip(b"hello")
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert_eq!(flipped, 1);
+    }
+
+    #[test]
+    fn test_probability() {
+        let config = serde_json::from_value(json!({ "drop": 1.5 })).unwrap();
+        match FaultInjectNet::new(MemoryNet::new().into_dyn(), config) {
+            Err(rd_interface::Error::Other(e)) => {
+                assert_eq!(e.to_string(), "drop must be between 0 and 1")
+            }
+            _ => panic!("expected an error"),
+        }
+    }
+}
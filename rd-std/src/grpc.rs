@@ -0,0 +1,42 @@
+pub use client::GrpcNet;
+
+mod client;
+#[cfg(test)]
+mod tests;
+
+use rd_interface::{
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Config, Registry, Result,
+};
+use serde_derive::Deserialize;
+
+/// Connects over a gRPC tunnel ("gun"), each connection being a stream of
+/// the `Tun` method on its own HTTP/2 connection. It goes under nets like
+/// `trojan` or `vmess`, to reach servers behind gRPC.
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct GrpcNetConfig {
+    /// The service, the path of the requests is `/{service_name}/Tun`.
+    service_name: String,
+    /// Authority of the requests, the address connected to by default.
+    #[serde(default)]
+    host: Option<String>,
+
+    #[serde(default)]
+    net: NetRef,
+}
+
+impl NetFactory for GrpcNet {
+    const NAME: &'static str = "grpc";
+    type Config = GrpcNetConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        Ok(GrpcNet::new(config.net.try_net()?, config))
+    }
+}
+
+pub fn init(registry: &mut Registry) -> Result<()> {
+    registry.add_net::<GrpcNet>();
+    Ok(())
+}
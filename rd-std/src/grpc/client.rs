@@ -0,0 +1,381 @@
+use super::GrpcNetConfig;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::Future;
+use h2::{
+    client::{self, ResponseFuture},
+    RecvStream, SendStream,
+};
+use hyper::{HeaderMap, Method, Request};
+use rd_interface::{
+    async_trait, error::map_other, Address, INet, ITcpStream, IntoDyn, Net, Result, TcpStream,
+    UdpSocket, NOT_IMPLEMENTED,
+};
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    task::{self, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Max size of the data in a message sent.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+/// Field 1 of `Hunk`, length delimited, holding the data.
+const HUNK_DATA_TAG: u8 = 0x0a;
+/// The compressed flag and the length before each message.
+const MESSAGE_HEADER_SIZE: usize = 5;
+/// Longer messages received are refused instead of buffered: the data and
+/// the tag and varint length of the `Hunk` around it.
+const MAX_RECEIVED_MESSAGE_SIZE: usize = MAX_MESSAGE_SIZE + 1 + 10;
+
+pub struct GrpcNet {
+    net: Net,
+    service_name: String,
+    host: Option<String>,
+}
+
+fn h2_err(e: h2::Error) -> io::Error {
+    if e.is_io() {
+        return e.into_io().expect("is io");
+    }
+    io::Error::other(e)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("gRPC: {}", msg))
+}
+
+/// Fails unless the `grpc-status` in `headers` is OK.
+fn check_status(headers: &HeaderMap) -> io::Result<()> {
+    let text = |name| {
+        headers
+            .get(name)
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+    };
+    match text("grpc-status").as_deref() {
+        Some("0") => Ok(()),
+        Some(status) => Err(io::Error::other(format!(
+            "gRPC: status {}: {}",
+            status,
+            text("grpc-message").unwrap_or_default()
+        ))),
+        None => Err(invalid_data("no grpc-status")),
+    }
+}
+
+/// A gRPC message holding a `Hunk` of `data`.
+pub fn encode_message(data: &[u8]) -> Vec<u8> {
+    let mut hunk = vec![HUNK_DATA_TAG];
+    let mut len = data.len();
+    while len >= 0x80 {
+        hunk.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    hunk.push(len as u8);
+    hunk.extend_from_slice(data);
+
+    // not compressed
+    let mut message = vec![0];
+    message.extend_from_slice(&(hunk.len() as u32).to_be_bytes());
+    message.extend(hunk);
+    message
+}
+
+/// Takes the message at the start of `buf`, and returns the data of its
+/// `Hunk`. `None` if the message is incomplete.
+pub fn decode_message(buf: &mut BytesMut) -> io::Result<Option<Bytes>> {
+    if buf.len() < MESSAGE_HEADER_SIZE {
+        return Ok(None);
+    }
+    if buf[0] != 0 {
+        return Err(invalid_data("compressed messages are not supported"));
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if len > MAX_RECEIVED_MESSAGE_SIZE {
+        return Err(invalid_data(&format!(
+            "message of {} bytes is too large",
+            len
+        )));
+    }
+    if buf.len() < MESSAGE_HEADER_SIZE + len {
+        return Ok(None);
+    }
+    buf.advance(MESSAGE_HEADER_SIZE);
+    let mut hunk = buf.split_to(len);
+
+    // the data is left out when it's empty
+    if hunk.is_empty() {
+        return Ok(Some(Bytes::new()));
+    }
+    if hunk.get_u8() != HUNK_DATA_TAG {
+        return Err(invalid_data("unexpected field"));
+    }
+    let mut data_len = 0usize;
+    for shift in (0..64).step_by(7) {
+        if !hunk.has_remaining() {
+            return Err(invalid_data("truncated length"));
+        }
+        let b = hunk.get_u8();
+        data_len |= ((b & 0x7f) as usize) << shift;
+        if b & 0x80 == 0 {
+            break;
+        }
+    }
+    if data_len != hunk.len() {
+        return Err(invalid_data("wrong length"));
+    }
+    Ok(Some(hunk.freeze()))
+}
+
+impl GrpcNet {
+    pub fn new(net: Net, config: GrpcNetConfig) -> Self {
+        GrpcNet {
+            net,
+            service_name: config.service_name,
+            host: config.host,
+        }
+    }
+}
+
+enum Response {
+    Waiting(ResponseFuture),
+    Body(RecvStream),
+    Eof,
+}
+
+pub struct GrpcStream {
+    send: SendStream<Bytes>,
+    response: Response,
+    /// The body read, not decoded yet.
+    read_buf: BytesMut,
+    data: Bytes,
+    /// Messages not sent yet.
+    write_buf: Bytes,
+    ended: bool,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+}
+
+impl GrpcStream {
+    fn poll_send(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            self.send.reserve_capacity(self.write_buf.len());
+            match futures::ready!(self.send.poll_capacity(cx)) {
+                Some(Ok(capacity)) => {
+                    let chunk = self.write_buf.split_to(capacity.min(self.write_buf.len()));
+                    self.send.send_data(chunk, false).map_err(h2_err)?;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(h2_err(e))),
+                None => return Poll::Ready(Err(ErrorKind::BrokenPipe.into())),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for GrpcStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.data.is_empty() {
+                let n = this.data.len().min(buf.remaining());
+                buf.put_slice(&this.data.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(data) = decode_message(&mut this.read_buf)? {
+                this.data = data;
+                continue;
+            }
+            match &mut this.response {
+                Response::Waiting(response) => {
+                    let response = futures::ready!(Pin::new(response).poll(cx)).map_err(h2_err)?;
+                    if !response.status().is_success() {
+                        return Poll::Ready(Err(io::Error::other(format!(
+                            "gRPC: response status {}",
+                            response.status()
+                        ))));
+                    }
+                    // a Trailers-Only response, failed at once
+                    if response.headers().contains_key("grpc-status") {
+                        check_status(response.headers())?;
+                    }
+                    this.response = Response::Body(response.into_body());
+                }
+                Response::Body(body) => match futures::ready!(body.poll_data(cx)) {
+                    Some(Ok(chunk)) => {
+                        body.flow_control()
+                            .release_capacity(chunk.len())
+                            .map_err(h2_err)?;
+                        this.read_buf.extend_from_slice(&chunk);
+                    }
+                    Some(Err(e)) => return Poll::Ready(Err(h2_err(e))),
+                    None => {
+                        if !this.read_buf.is_empty() {
+                            return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                        }
+                        match futures::ready!(body.poll_trailers(cx)).map_err(h2_err)? {
+                            Some(trailers) => check_status(&trailers)?,
+                            None => return Poll::Ready(Err(invalid_data("no grpc-status"))),
+                        }
+                        this.response = Response::Eof;
+                    }
+                },
+                Response::Eof => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for GrpcStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        futures::ready!(this.poll_send(cx))?;
+        let n = buf.len().min(MAX_MESSAGE_SIZE);
+        this.write_buf = encode_message(&buf[..n]).into();
+        // the message is taken either way, the rest is sent later
+        if let Poll::Ready(Err(e)) = this.poll_send(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_send(cx))?;
+        if !this.ended {
+            this.send.send_data(Bytes::new(), true).map_err(h2_err)?;
+            this.ended = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl ITcpStream for GrpcStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        self.peer_addr.ok_or(NOT_IMPLEMENTED)
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.local_addr.ok_or(NOT_IMPLEMENTED)
+    }
+}
+
+#[async_trait]
+impl INet for GrpcNet {
+    async fn tcp_connect(
+        &self,
+        ctx: &mut rd_interface::Context,
+        addr: Address,
+    ) -> Result<TcpStream> {
+        let host = match &self.host {
+            Some(host) => host.clone(),
+            None => addr.to_string(),
+        };
+        let tcp = self.net.tcp_connect(ctx, addr).await?;
+        let peer_addr = tcp.peer_addr().await.ok();
+        let local_addr = tcp.local_addr().await.ok();
+
+        let (send_request, connection) = client::handshake(tcp).await.map_err(h2_err)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("gRPC connection error: {:?}", e);
+            }
+        });
+        let mut send_request = send_request.ready().await.map_err(h2_err)?;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/{}/Tun", host, self.service_name))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(())
+            .map_err(map_other)?;
+        let (response, send) = send_request.send_request(request, false).map_err(h2_err)?;
+
+        Ok(GrpcStream {
+            send,
+            response: Response::Waiting(response),
+            read_buf: BytesMut::new(),
+            data: Bytes::new(),
+            write_buf: Bytes::new(),
+            ended: false,
+            peer_addr,
+            local_addr,
+        }
+        .into_dyn())
+    }
+
+    async fn tcp_bind(
+        &self,
+        _ctx: &mut rd_interface::Context,
+        _addr: Address,
+    ) -> Result<rd_interface::TcpListener> {
+        Err(NOT_IMPLEMENTED)
+    }
+
+    async fn udp_bind(
+        &self,
+        _ctx: &mut rd_interface::Context,
+        _addr: Address,
+    ) -> Result<UdpSocket> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        assert_eq!(encode_message(b"hi"), b"\x00\x00\x00\x00\x04\x0a\x02hi");
+        let long = vec![7u8; 300];
+        let message = encode_message(&long);
+        // 300 is ac 02 as a varint
+        assert_eq!(&message[..8], b"\x00\x00\x00\x01\x2f\x0a\xac\x02");
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&message);
+        buf.extend_from_slice(&encode_message(b"hi")[..6]);
+        assert_eq!(decode_message(&mut buf).unwrap().unwrap(), long);
+        assert_eq!(decode_message(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"\x02hi");
+        assert_eq!(decode_message(&mut buf).unwrap().unwrap(), &b"hi"[..]);
+        assert!(buf.is_empty());
+
+        // an empty hunk
+        buf.extend_from_slice(b"\x00\x00\x00\x00\x00");
+        assert_eq!(decode_message(&mut buf).unwrap().unwrap(), &b""[..]);
+
+        // refused before it's all received
+        buf.extend_from_slice(b"\x00\xff\xff\xff\xff");
+        assert!(decode_message(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_status() {
+        let mut headers = HeaderMap::new();
+        assert!(check_status(&headers).is_err());
+        headers.insert("grpc-status", "0".parse().unwrap());
+        assert!(check_status(&headers).is_ok());
+        headers.insert("grpc-status", "14".parse().unwrap());
+        headers.insert("grpc-message", "unavailable".parse().unwrap());
+        let err = check_status(&headers).unwrap_err();
+        assert_eq!(err.to_string(), "gRPC: status 14: unavailable");
+    }
+}
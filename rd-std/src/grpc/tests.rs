@@ -0,0 +1,125 @@
+use super::{client::*, GrpcNet};
+use crate::builtin::local::{LocalConfig, LocalNet};
+use crate::tests::get_registry;
+use bytes::{Bytes, BytesMut};
+use hyper::{HeaderMap, Response};
+use rd_interface::{Context, IntoAddress, IntoDyn, Net};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+/// A gRPC tunnel server which sends back the path and authority of the
+/// requests, and echoes each message. The streams end with `status`.
+async fn spawn_grpc_server(
+    status: &'static str,
+) -> (u16, mpsc::UnboundedReceiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut connection = h2::server::handshake(tcp).await.unwrap();
+                let (request, mut respond) = connection.accept().await.unwrap().unwrap();
+                tokio::spawn(async move { while connection.accept().await.is_some() {} });
+
+                let uri = request.uri();
+                tx.send((uri.path().to_string(), uri.authority().unwrap().to_string()))
+                    .unwrap();
+                assert_eq!(request.headers()["content-type"], "application/grpc");
+
+                let response = Response::builder()
+                    .header("content-type", "application/grpc")
+                    .body(())
+                    .unwrap();
+                let mut send = respond.send_response(response, false).unwrap();
+                let mut body = request.into_body();
+                let mut buf = BytesMut::new();
+                while let Some(chunk) = body.data().await {
+                    let chunk = chunk.unwrap();
+                    body.flow_control().release_capacity(chunk.len()).unwrap();
+                    buf.extend_from_slice(&chunk);
+                    while let Some(data) = decode_message(&mut buf).unwrap() {
+                        send.send_data(Bytes::from(encode_message(&data)), false)
+                            .unwrap();
+                    }
+                }
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", status.parse().unwrap());
+                send.send_trailers(trailers).unwrap();
+            });
+        }
+    });
+    (port, rx)
+}
+
+fn grpc_net(config: serde_json::Value) -> Net {
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    GrpcNet::new(local, serde_json::from_value(config).unwrap()).into_dyn()
+}
+
+#[test]
+fn test_grpc_smoke() {
+    let mut registry = get_registry();
+    super::init(&mut registry).unwrap();
+}
+
+#[tokio::test]
+async fn test_grpc_tcp() {
+    let (port, mut requests) = spawn_grpc_server("0").await;
+    let grpc = grpc_net(serde_json::json!({ "service_name": "tunnel", "host": "example.com" }));
+
+    // streams don't mix
+    let mut streams = Vec::new();
+    for i in 0..3u8 {
+        let mut tcp = grpc
+            .tcp_connect(
+                &mut Context::new(),
+                ("127.0.0.1", port).into_address().unwrap(),
+            )
+            .await
+            .unwrap();
+        // more than a message
+        let sent: Vec<u8> = (0..40000u32).map(|j| (j as u8) ^ i).collect();
+        tcp.write_all(&sent).await.unwrap();
+        streams.push((tcp, sent));
+    }
+    for (mut tcp, sent) in streams {
+        let mut received = vec![0u8; sent.len()];
+        tcp.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, sent);
+
+        tcp.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        tcp.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        let (path, authority) = requests.recv().await.unwrap();
+        assert_eq!(path, "/tunnel/Tun");
+        assert_eq!(authority, "example.com");
+    }
+}
+
+#[tokio::test]
+async fn test_grpc_status() {
+    let (port, _requests) = spawn_grpc_server("14").await;
+    let grpc = grpc_net(serde_json::json!({ "service_name": "tunnel" }));
+
+    let mut tcp = grpc
+        .tcp_connect(
+            &mut Context::new(),
+            ("127.0.0.1", port).into_address().unwrap(),
+        )
+        .await
+        .unwrap();
+    tcp.write_all(b"hello").await.unwrap();
+    tcp.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    let err = tcp.read_to_end(&mut received).await.unwrap_err();
+    assert!(err.to_string().contains("status 14"), "{}", err);
+    assert_eq!(received, b"hello");
+}
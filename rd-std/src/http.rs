@@ -1,18 +1,50 @@
+pub use client::HttpProxyNet;
 use rd_interface::{
-    registry::ServerFactory,
+    registry::{NetFactory, NetRef, ServerFactory},
     schemars::{self, JsonSchema},
     Config, Net, Registry, Result,
 };
 use serde_derive::Deserialize;
 pub use server::HttpServer;
 
+mod client;
 mod server;
 
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct ClientConfig {
+    address: String,
+    port: u16,
+    /// Sent with `password` as Basic auth when set.
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+
+    #[serde(default)]
+    net: NetRef,
+}
+
 #[derive(Debug, Deserialize, Config, JsonSchema)]
 pub struct ServerConfig {
     bind: String,
 }
 
+impl NetFactory for HttpProxyNet {
+    const NAME: &'static str = "http";
+    type Config = ClientConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        Ok(HttpProxyNet::new(
+            config.net.try_net()?,
+            config.address,
+            config.port,
+            config.username,
+            config.password,
+        ))
+    }
+}
+
 impl ServerFactory for server::Http {
     const NAME: &'static str = "http";
     type Config = ServerConfig;
@@ -24,6 +56,7 @@ impl ServerFactory for server::Http {
 }
 
 pub fn init(registry: &mut Registry) -> Result<()> {
+    registry.add_net::<HttpProxyNet>();
     registry.add_server::<server::Http>();
     Ok(())
 }
@@ -0,0 +1,289 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rd_interface::{
+    async_trait, impl_async_read_write, Address, INet, ITcpStream, IntoAddress, IntoDyn, Net,
+    Result, TcpStream, UdpSocket, NOT_IMPLEMENTED,
+};
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Responses with a header larger than this are treated as malformed.
+const MAX_HEADER_SIZE: usize = 8192;
+
+pub struct HttpProxyNet {
+    address: String,
+    port: u16,
+    auth: Option<String>,
+    net: Net,
+}
+
+pub struct HttpTcpStream(TcpStream);
+
+impl_async_read_write!(HttpTcpStream, 0);
+
+#[async_trait]
+impl ITcpStream for HttpTcpStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        Err(NOT_IMPLEMENTED)
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
+
+fn invalid_data(msg: impl ToString) -> rd_interface::Error {
+    rd_interface::Error::protocol("http", msg)
+}
+
+/// Reads the response header byte by byte, so nothing after it is consumed.
+async fn read_header(socket: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(512);
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() >= MAX_HEADER_SIZE {
+            return Err(invalid_data("HTTP proxy response header too large"));
+        }
+        buf.push(socket.read_u8().await?);
+    }
+    Ok(buf)
+}
+
+fn check_response(header: &[u8]) -> Result<()> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut resp = httparse::Response::new(&mut headers);
+    match resp.parse(header) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => {
+            return Err(invalid_data("Incomplete HTTP proxy response"))
+        }
+        Err(e) => {
+            return Err(invalid_data(format!(
+                "Malformed HTTP proxy response: {}",
+                e
+            )))
+        }
+    };
+
+    let code = resp.code.unwrap_or_default();
+    let reason = resp.reason.unwrap_or_default();
+    match code {
+        200..=299 => Ok(()),
+        407 => Err(rd_interface::Error::AuthFailed(format!(
+            "HTTP proxy authentication required: {} {}",
+            code, reason
+        ))),
+        _ => Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("HTTP proxy refused CONNECT: {} {}", code, reason),
+        )
+        .into()),
+    }
+}
+
+impl HttpProxyNet {
+    pub fn new(
+        net: Net,
+        address: String,
+        port: u16,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> Self {
+        let auth = username.map(|username| {
+            let credential = format!("{}:{}", username, password.unwrap_or_default());
+            format!("Basic {}", STANDARD.encode(credential))
+        });
+        Self {
+            address,
+            port,
+            auth,
+            net,
+        }
+    }
+    fn server(&self, ctx: &rd_interface::Context) -> Result<Address> {
+        // reuse the address resolved earlier in the chain
+        if let Some(ip) = ctx.resolved(&self.address).and_then(|ips| ips.first()) {
+            return Ok((*ip, self.port).into());
+        }
+        (self.address.as_str(), self.port)
+            .into_address()
+            .map_err(Into::into)
+    }
+    fn request(&self, addr: &Address) -> String {
+        let mut req = format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\n", addr = addr);
+        if let Some(auth) = &self.auth {
+            req += &format!("Proxy-Authorization: {}\r\n", auth);
+        }
+        req += "\r\n";
+        req
+    }
+}
+
+#[async_trait]
+impl INet for HttpProxyNet {
+    async fn tcp_connect(
+        &self,
+        ctx: &mut rd_interface::Context,
+        addr: Address,
+    ) -> Result<TcpStream> {
+        let mut socket = self.net.tcp_connect(ctx, self.server(ctx)?).await?;
+
+        socket.write_all(self.request(&addr).as_bytes()).await?;
+        socket.flush().await?;
+
+        let header = read_header(&mut socket).await?;
+        check_response(&header)?;
+
+        Ok(HttpTcpStream(socket).into_dyn())
+    }
+
+    async fn tcp_bind(
+        &self,
+        _ctx: &mut rd_interface::Context,
+        _addr: Address,
+    ) -> Result<rd_interface::TcpListener> {
+        Err(NOT_IMPLEMENTED)
+    }
+
+    async fn udp_bind(
+        &self,
+        _ctx: &mut rd_interface::Context,
+        _addr: Address,
+    ) -> Result<UdpSocket> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtin::local::{LocalConfig, LocalNet};
+    use crate::tests::{assert_echo, spawn_echo_server};
+    use rd_interface::{Context, IServer};
+    use std::time::Duration;
+    use tokio::{net::TcpListener, time::sleep};
+
+    fn io_kind(e: rd_interface::Error) -> ErrorKind {
+        match e {
+            rd_interface::Error::IO(e) => e.kind(),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    /// Answers one CONNECT with `response` and returns the request it got.
+    async fn spawn_mock_proxy(response: &'static [u8]) -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut req = Vec::new();
+            while !req.ends_with(b"\r\n\r\n") {
+                req.push(socket.read_u8().await.unwrap());
+            }
+            socket.write_all(response).await.unwrap();
+            String::from_utf8(req).unwrap()
+        });
+        (port, handle)
+    }
+
+    fn proxy_net(port: u16, username: Option<&str>) -> HttpProxyNet {
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        HttpProxyNet::new(
+            local,
+            "127.0.0.1".to_string(),
+            port,
+            username.map(ToString::to_string),
+            Some("pass".to_string()),
+        )
+    }
+
+    async fn connect(net: &HttpProxyNet) -> Result<TcpStream> {
+        net.tcp_connect(
+            &mut Context::new(),
+            "example.com:443".into_address().unwrap(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_http_client_success() {
+        let (port, handle) = spawn_mock_proxy(
+            b"HTTP/1.1 200 Connection established\r\nProxy-Agent: mock\r\nX-Extra: 1\r\n\r\nhello",
+        )
+        .await;
+        let net = proxy_net(port, Some("user"));
+
+        let mut tcp = connect(&net).await.unwrap();
+        let mut buf = [0u8; 5];
+        tcp.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let req = handle.await.unwrap();
+        assert!(req.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+        assert!(req.contains("Host: example.com:443\r\n"));
+        // base64 of "user:pass"
+        assert!(req.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_http_client_auth_required() {
+        let (port, handle) = spawn_mock_proxy(
+            b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"x\"\r\n\r\n",
+        )
+        .await;
+        let net = proxy_net(port, None);
+
+        let err = connect(&net).await.err().unwrap();
+        assert!(matches!(err, rd_interface::Error::AuthFailed(_)));
+        assert!(!handle.await.unwrap().contains("Proxy-Authorization"));
+    }
+
+    #[tokio::test]
+    async fn test_http_client_malformed() {
+        let (port, _) = spawn_mock_proxy(b"SSH-2.0-OpenSSH\r\n\r\n").await;
+        let err = connect(&proxy_net(port, None)).await.err().unwrap();
+        assert!(matches!(
+            err,
+            rd_interface::Error::Protocol { proto: "http", .. }
+        ));
+
+        let (port, _) = spawn_mock_proxy(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+        let err = connect(&proxy_net(port, None)).await.err().unwrap();
+        assert_eq!(io_kind(err), ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn test_http_client_resolved() {
+        let (port, handle) = spawn_mock_proxy(b"HTTP/1.1 200 OK\r\n\r\n").await;
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        let net = HttpProxyNet::new(local, "proxy.invalid".to_string(), port, None, None);
+
+        let mut ctx = Context::new();
+        ctx.set_resolved("proxy.invalid", vec!["127.0.0.1".parse().unwrap()]);
+        net.tcp_connect(&mut ctx, "example.com:443".into_address().unwrap())
+            .await
+            .unwrap();
+        assert!(handle.await.unwrap().starts_with("CONNECT example.com:443"));
+    }
+
+    #[tokio::test]
+    async fn test_http_server_client() {
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        spawn_echo_server(&local, "127.0.0.1:26669").await;
+
+        let server = super::super::server::Http::new(
+            local.clone(),
+            local.clone(),
+            "127.0.0.1:16667".to_string(),
+        );
+        tokio::spawn(async move { server.start().await });
+
+        sleep(Duration::from_secs(1)).await;
+
+        let client =
+            HttpProxyNet::new(local, "127.0.0.1".to_string(), 16667, None, None).into_dyn();
+
+        assert_echo(&client, "127.0.0.1:26669").await;
+    }
+}
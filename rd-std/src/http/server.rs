@@ -1,9 +1,19 @@
 use hyper::{
-    client::conn as client_conn, http, server::conn as server_conn, service::service_fn,
-    upgrade::Upgraded, Body, Method, Request, Response,
+    client::conn as client_conn,
+    header::{self, HeaderMap, HeaderName},
+    http,
+    server::conn as server_conn,
+    service::service_fn,
+    upgrade::Upgraded,
+    Body, Method, Request, Response, Uri,
+};
+use rd_interface::{
+    async_trait,
+    util::{BoundAddr, StopSignal},
+    Context, IServer, IntoAddress, Net, Result, TcpStream,
 };
-use rd_interface::{async_trait, Context, IServer, IntoAddress, Net, Result, TcpStream};
 use std::net::SocketAddr;
+use tracing::Instrument;
 
 #[derive(Clone)]
 pub struct HttpServer {
@@ -31,6 +41,8 @@ pub struct Http {
     server: HttpServer,
     listen_net: Net,
     bind: String,
+    stop: StopSignal,
+    local_addr: BoundAddr,
 }
 
 #[async_trait]
@@ -40,17 +52,31 @@ impl IServer for Http {
             .listen_net
             .tcp_bind(&mut Context::new(), self.bind.into_address()?)
             .await?;
+        self.local_addr.set(listener.local_addr().await?);
 
         loop {
-            let (socket, addr) = listener.accept().await?;
+            let (socket, addr) = match self.stop.until(listener.accept()).await {
+                Some(r) => r?,
+                None => return Ok(()),
+            };
             let server = self.server.clone();
-            tokio::spawn(async move {
-                if let Err(e) = server.serve_connection(socket, addr).await {
-                    tracing::error!("Error when serve_connection: {:?}", e);
+            tokio::spawn(
+                async move {
+                    if let Err(e) = server.serve_connection(socket, addr).await {
+                        tracing::error!("Error when serve_connection: {:?}", e);
+                    }
                 }
-            });
+                .instrument(tracing::info_span!("serve_connection", src = %addr)),
+            );
         }
     }
+    async fn stop(&self) -> Result<()> {
+        self.stop.stop();
+        Ok(())
+    }
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.local_addr.get()
+    }
 }
 
 impl Http {
@@ -59,6 +85,8 @@ impl Http {
             server: HttpServer::new(net),
             listen_net,
             bind,
+            stop: StopSignal::new(),
+            local_addr: BoundAddr::new(),
         }
     }
 }
@@ -96,7 +124,8 @@ async fn proxy(net: Net, req: Request<Body>, addr: SocketAddr) -> anyhow::Result
 
             tokio::spawn(connection);
 
-            let resp = request_sender.send_request(req).await?;
+            let mut resp = request_sender.send_request(to_origin_form(req)?).await?;
+            remove_hop_by_hop(resp.headers_mut());
 
             Ok(resp)
         }
@@ -109,11 +138,154 @@ async fn proxy(net: Net, req: Request<Body>, addr: SocketAddr) -> anyhow::Result
     }
 }
 
+/// Headers only meaningful to a single connection, RFC 7230 section 6.1.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    // headers listed in `Connection` are hop-by-hop too
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+/// Turns a proxy request `GET http://host/path` into `GET /path` with a
+/// `Host` header, as the origin expects.
+fn to_origin_form(mut req: Request<Body>) -> anyhow::Result<Request<Body>> {
+    if let Some(authority) = req.uri().authority().cloned() {
+        if !req.headers().contains_key(header::HOST) {
+            req.headers_mut()
+                .insert(header::HOST, authority.as_str().parse()?);
+        }
+    }
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    *req.uri_mut() = path.parse::<Uri>()?;
+    remove_hop_by_hop(req.headers_mut());
+    Ok(req)
+}
+
 fn host_addr(uri: &http::Uri) -> Option<String> {
-    uri.authority().and_then(|auth| Some(auth.to_string()))
+    uri.authority().map(|auth| auth.to_string())
 }
 
 async fn tunnel(mut stream: TcpStream, mut upgraded: Upgraded) -> std::io::Result<()> {
     tokio::io::copy_bidirectional(&mut upgraded, &mut stream).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtin::local::{LocalConfig, LocalNet};
+    use rd_interface::{IntoAddress, IntoDyn};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::mpsc,
+    };
+
+    /// Reads a request or a response header, and `body_len` bytes of body.
+    async fn read_message(
+        socket: &mut (impl AsyncReadExt + Unpin),
+        body_len: usize,
+    ) -> Option<String> {
+        let mut buf = Vec::new();
+        while !buf.ends_with(b"\r\n\r\n") {
+            buf.push(socket.read_u8().await.ok()?);
+        }
+        let mut body = vec![0u8; body_len];
+        socket.read_exact(&mut body).await.ok()?;
+        buf.extend(body);
+        Some(String::from_utf8(buf).unwrap())
+    }
+
+    /// An origin answering `hello` to every request, sending the requests
+    /// it got.
+    async fn spawn_origin() -> (u16, mpsc::UnboundedReceiver<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    while let Some(req) = read_message(&mut socket, 0).await {
+                        tx.send(req).unwrap();
+                        socket
+                            .write_all(
+                                b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nKeep-Alive: timeout=5\r\n\r\nhello",
+                            )
+                            .await
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        (port, rx)
+    }
+
+    #[tokio::test]
+    async fn test_http_server_forward() {
+        let (origin, mut requests) = spawn_origin().await;
+
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        let listener = local
+            .tcp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        let port = listener.local_addr().await.unwrap().port();
+        let server = HttpServer::new(local);
+        tokio::spawn(async move {
+            let (socket, addr) = listener.accept().await.unwrap();
+            server.serve_connection(socket, addr).await
+        });
+
+        let mut client = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        // two requests on one connection
+        for path in ["/a?x=1", "/b"] {
+            let req = format!(
+                "GET http://127.0.0.1:{}{} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nProxy-Connection: keep-alive\r\nConnection: X-Hop\r\nX-Hop: 1\r\nX-End: 1\r\n\r\n",
+                origin, path, origin
+            );
+            client.write_all(req.as_bytes()).await.unwrap();
+
+            let resp = read_message(&mut client, 5).await.unwrap();
+            assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(resp.ends_with("\r\n\r\nhello"));
+            assert!(!resp.to_ascii_lowercase().contains("keep-alive: timeout"));
+
+            let req = requests.recv().await.unwrap();
+            assert!(req.starts_with(&format!("GET {} HTTP/1.1\r\n", path)));
+            let req = req.to_ascii_lowercase();
+            assert!(req.contains(&format!("host: 127.0.0.1:{}\r\n", origin)));
+            assert!(req.contains("x-end: 1\r\n"));
+            assert!(!req.contains("proxy-connection"));
+            assert!(!req.contains("x-hop"));
+        }
+    }
+}
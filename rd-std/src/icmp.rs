@@ -0,0 +1,86 @@
+//! Tunnels TCP connections in the payloads of ICMP echo requests and
+//! replies, for networks where only pings get out. The `icmp_tunnel` net
+//! sends the connections to an `icmp_tunnel` server, which connects to
+//! their destinations.
+//!
+//! Both ends open a raw ICMP socket, so they need root or `CAP_NET_RAW`.
+//! Only IPv4 is supported, and the packets go straight to the socket
+//! instead of through the other nets.
+//!
+//! Every packet carries an HMAC-SHA256 made with the `secret` of both
+//! configs, and the ones without a valid one are ignored. A SYN carries the
+//! time it's sent, and the server opens a session only once, for a SYN at
+//! most 30 seconds off its clock, so a SYN seen on the path can't be sent
+//! again. The tunneled data is authenticated but not encrypted.
+
+pub use client::IcmpTunnelNet;
+pub use server::IcmpTunnelServer;
+
+mod client;
+mod protocol;
+mod server;
+mod session;
+mod socket;
+#[cfg(test)]
+mod tests;
+
+use rd_interface::{
+    registry::{NetFactory, ServerFactory},
+    schemars::{self, JsonSchema},
+    Config, Net, Registry, Result,
+};
+use serde_derive::Deserialize;
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct IcmpTunnelNetConfig {
+    /// IPv4 address of the `icmp_tunnel` server.
+    server: String,
+    /// The `secret` of the server.
+    secret: String,
+}
+
+/// `listen` is not used, the server takes the pings to the host.
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct IcmpTunnelServerConfig {
+    /// Shared with the clients, only their sessions are opened.
+    secret: String,
+}
+
+fn secret(secret: String) -> Result<Vec<u8>> {
+    if secret.is_empty() {
+        return Err(rd_interface::Error::Other(
+            "secret must not be empty".into(),
+        ));
+    }
+    Ok(secret.into_bytes())
+}
+
+impl NetFactory for IcmpTunnelNet {
+    const NAME: &'static str = "icmp_tunnel";
+    type Config = IcmpTunnelNetConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        let server = config
+            .server
+            .parse()
+            .map_err(|e| rd_interface::Error::Other(format!("invalid server: {}", e).into()))?;
+        Ok(IcmpTunnelNet::new(server, secret(config.secret)?))
+    }
+}
+
+impl ServerFactory for IcmpTunnelServer {
+    const NAME: &'static str = "icmp_tunnel";
+    type Config = IcmpTunnelServerConfig;
+    type Server = Self;
+
+    fn new(_listen: Net, net: Net, config: Self::Config) -> Result<Self> {
+        Ok(IcmpTunnelServer::new(net, secret(config.secret)?))
+    }
+}
+
+pub fn init(registry: &mut Registry) -> Result<()> {
+    registry.add_net::<IcmpTunnelNet>();
+    registry.add_server::<IcmpTunnelServer>();
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use super::{
+    protocol::{syn_data, unix_time, Packet, ECHO_REPLY, RST, SERVER, SYN},
+    session::{Session, RTO},
+    socket::{send_loop, IcmpSocket},
+};
+use rd_interface::{
+    async_trait, impl_async_read_write, Address, Context, INet, ITcpStream, IntoDyn, Result,
+    TcpListener, TcpStream, UdpSocket, NOT_IMPLEMENTED,
+};
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::DuplexStream,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{timeout, Instant},
+};
+
+/// How long to wait for the server to open a session.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Sessions = Arc<Mutex<HashMap<u32, mpsc::UnboundedSender<Packet>>>>;
+
+/// The raw socket and the sessions on it, opened on the first connect.
+struct Endpoint {
+    outbox: mpsc::UnboundedSender<(Ipv4Addr, Packet)>,
+    sessions: Sessions,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl Endpoint {
+    fn new(server: Ipv4Addr, secret: Vec<u8>) -> io::Result<Endpoint> {
+        let socket = Arc::new(IcmpSocket::new(secret)?);
+        let (outbox, rx) = mpsc::unbounded_channel();
+        let sessions = Sessions::default();
+        let writer = tokio::spawn(send_loop(socket.clone(), rx));
+        let reader = tokio::spawn(recv_loop(socket, server, sessions.clone()));
+        Ok(Endpoint {
+            outbox,
+            sessions,
+            tasks: [writer, reader],
+        })
+    }
+}
+
+/// Removes a session from its endpoint when dropped, so it's removed however
+/// the session ends.
+struct SessionEntry {
+    id: u32,
+    endpoint: Arc<Endpoint>,
+}
+
+impl Drop for SessionEntry {
+    fn drop(&mut self) {
+        self.endpoint.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn recv_loop(socket: Arc<IcmpSocket>, server: Ipv4Addr, sessions: Sessions) {
+    loop {
+        let (src, packet) = match socket.recv().await {
+            Ok(r) => r,
+            Err(e) => {
+                tracing::error!("Failed to receive ICMP packets: {:?}", e);
+                return;
+            }
+        };
+        // our own requests and the kernel's replies to them
+        if src != server || packet.icmp_type != ECHO_REPLY || !packet.has(SERVER) {
+            continue;
+        }
+        let mut sessions = sessions.lock().unwrap();
+        if let Some(tx) = sessions.get(&packet.session) {
+            let session = packet.session;
+            if tx.send(packet).is_err() {
+                sessions.remove(&session);
+            }
+        }
+    }
+}
+
+/// Tunnels TCP connections in ICMP echo requests and replies to an
+/// `icmp_tunnel` server, for networks letting only pings through. The
+/// server connects to the destinations.
+///
+/// It opens a raw socket, so it needs root or `CAP_NET_RAW`. Only IPv4 is
+/// supported.
+pub struct IcmpTunnelNet {
+    server: Ipv4Addr,
+    secret: Vec<u8>,
+    endpoint: Mutex<Option<Arc<Endpoint>>>,
+}
+
+impl IcmpTunnelNet {
+    /// `secret` is the one of the server.
+    pub fn new(server: Ipv4Addr, secret: Vec<u8>) -> IcmpTunnelNet {
+        IcmpTunnelNet {
+            server,
+            secret,
+            endpoint: Mutex::new(None),
+        }
+    }
+    fn endpoint(&self) -> io::Result<Arc<Endpoint>> {
+        let mut endpoint = self.endpoint.lock().unwrap();
+        if let Some(endpoint) = &*endpoint {
+            return Ok(endpoint.clone());
+        }
+        let new = Arc::new(Endpoint::new(self.server, self.secret.clone())?);
+        *endpoint = Some(new.clone());
+        Ok(new)
+    }
+    #[cfg(test)]
+    pub(super) fn session_count(&self) -> usize {
+        match &*self.endpoint.lock().unwrap() {
+            Some(endpoint) => endpoint.sessions.lock().unwrap().len(),
+            None => 0,
+        }
+    }
+}
+
+pub struct IcmpTcpStream {
+    inner: DuplexStream,
+    _endpoint: Arc<Endpoint>,
+}
+
+impl_async_read_write!(IcmpTcpStream, inner);
+
+#[async_trait]
+impl ITcpStream for IcmpTcpStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        Err(NOT_IMPLEMENTED)
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
+
+#[async_trait]
+impl INet for IcmpTunnelNet {
+    async fn tcp_connect(&self, _ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        let endpoint = self.endpoint()?;
+        let mut id = [0u8; 4];
+        getrandom::getrandom(&mut id).map_err(io::Error::from)?;
+        let id = u32::from_be_bytes(id);
+
+        let (tx, mut inbox) = mpsc::unbounded_channel();
+        endpoint.sessions.lock().unwrap().insert(id, tx);
+        let entry = SessionEntry {
+            id,
+            endpoint: endpoint.clone(),
+        };
+        let mut session = Session::new(id, self.server, false, endpoint.outbox.clone());
+        let target = syn_data(unix_time(), addr.to_string().as_bytes());
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            if Instant::now() >= deadline {
+                return Err(io::Error::from(ErrorKind::TimedOut).into());
+            }
+            session.send(SYN, 0, target.clone());
+            match timeout(RTO, inbox.recv()).await {
+                Ok(Some(packet)) if packet.has(RST) => {
+                    return Err(io::Error::from(ErrorKind::ConnectionRefused).into())
+                }
+                Ok(Some(packet)) if packet.has(SYN) => break,
+                Ok(_) | Err(_) => {}
+            }
+        }
+
+        Ok(IcmpTcpStream {
+            inner: session.spawn_with(inbox, entry),
+            _endpoint: endpoint,
+        }
+        .into_dyn())
+    }
+
+    async fn tcp_bind(&self, _ctx: &mut Context, _addr: Address) -> Result<TcpListener> {
+        Err(NOT_IMPLEMENTED)
+    }
+
+    async fn udp_bind(&self, _ctx: &mut Context, _addr: Address) -> Result<UdpSocket> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
@@ -0,0 +1,250 @@
+//! The packets of a tunnel session, carried in the payload of ICMP echo
+//! messages. The client sends echo requests and the server echo replies.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    convert::TryInto,
+    net::Ipv4Addr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const ECHO_REPLY: u8 = 0;
+pub const ECHO_REQUEST: u8 = 8;
+
+/// Tells the tunnel packets from other pings.
+const MAGIC: &[u8; 4] = b"RDIT";
+const ICMP_HEADER_SIZE: usize = 8;
+/// Bytes of the HMAC-SHA256 kept in a packet.
+const MAC_SIZE: usize = 16;
+/// Where the MAC is, after the fields it covers.
+const MAC_OFFSET: usize = ICMP_HEADER_SIZE + 4 + 1 + 4 + 4 + 4;
+/// ICMP header, magic, flags, session, seq, ack and MAC.
+pub const HEADER_SIZE: usize = MAC_OFFSET + MAC_SIZE;
+/// Max data in a packet, so it fits in a 1500 bytes MTU.
+pub const MSS: usize = 1400;
+/// How far the time in a SYN may be from ours.
+pub const MAX_SYN_AGE: u64 = 30;
+
+/// Opens a session. The data is the time it's sent, see [syn_data], then
+/// the target address.
+pub const SYN: u8 = 0x01;
+/// The sender has no more data. It takes a sequence number after the data.
+pub const FIN: u8 = 0x02;
+/// The session is gone.
+pub const RST: u8 = 0x04;
+/// Sent by the server. The kernel answers echo requests with their own
+/// payload, this tells the server's replies from those.
+pub const SERVER: u8 = 0x80;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub icmp_type: u8,
+    pub icmp_seq: u16,
+    pub flags: u8,
+    pub session: u32,
+    /// Sequence number of the first byte of `data`.
+    pub seq: u32,
+    /// The next sequence number expected from the peer.
+    pub ack: u32,
+    pub data: Vec<u8>,
+}
+
+fn checksum(buf: &[u8]) -> u16 {
+    let mut sum = buf.chunks(2).fold(0u32, |sum, c| {
+        sum + u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32
+    });
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// HMAC-SHA256 of the ICMP type, the fields after the ICMP header and the
+/// data of the packet in `buf`. The ICMP identifier, sequence and checksum
+/// are not covered, they are made of the rest or don't matter.
+fn mac(secret: &[u8], buf: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes any key size");
+    mac.update(&buf[..1]);
+    mac.update(&buf[ICMP_HEADER_SIZE..MAC_OFFSET]);
+    mac.update(&buf[HEADER_SIZE..]);
+    mac
+}
+
+impl Packet {
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+    /// The ICMP message, with the session as the identifier, authenticated
+    /// with `secret`.
+    pub fn encode(&self, secret: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        buf.extend_from_slice(&[self.icmp_type, 0, 0, 0]);
+        buf.extend_from_slice(&(self.session as u16).to_be_bytes());
+        buf.extend_from_slice(&self.icmp_seq.to_be_bytes());
+        buf.extend_from_slice(MAGIC);
+        buf.push(self.flags);
+        buf.extend_from_slice(&self.session.to_be_bytes());
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        buf.extend_from_slice(&self.ack.to_be_bytes());
+        buf.extend_from_slice(&[0; MAC_SIZE]);
+        buf.extend_from_slice(&self.data);
+        let tag = mac(secret, &buf).finalize().into_bytes();
+        buf[MAC_OFFSET..HEADER_SIZE].copy_from_slice(&tag[..MAC_SIZE]);
+        let sum = checksum(&buf);
+        buf[2..4].copy_from_slice(&sum.to_be_bytes());
+        buf
+    }
+    /// Parses an ICMP message. `None` if it's not a tunnel packet, or not
+    /// authenticated with `secret`.
+    pub fn decode(buf: &[u8], secret: &[u8]) -> Option<Packet> {
+        if buf.len() < HEADER_SIZE || &buf[8..12] != MAGIC || checksum(buf) != 0 {
+            return None;
+        }
+        let icmp_type = buf[0];
+        if buf[1] != 0 || (icmp_type != ECHO_REQUEST && icmp_type != ECHO_REPLY) {
+            return None;
+        }
+        mac(secret, buf)
+            .verify_truncated_left(&buf[MAC_OFFSET..HEADER_SIZE])
+            .ok()?;
+        let u32_at = |i: usize| u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        Some(Packet {
+            icmp_type,
+            icmp_seq: u16::from_be_bytes([buf[6], buf[7]]),
+            flags: buf[12],
+            session: u32_at(13),
+            seq: u32_at(17),
+            ack: u32_at(21),
+            data: buf[HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+/// Seconds since the epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The data of a SYN to `target` sent at `time`, so a copy of it can't be
+/// sent again later.
+pub fn syn_data(time: u64, target: &[u8]) -> Vec<u8> {
+    let mut data = time.to_be_bytes().to_vec();
+    data.extend_from_slice(target);
+    data
+}
+
+/// The target in the data of a SYN, `None` if it wasn't sent within
+/// [MAX_SYN_AGE] of `now`.
+pub fn syn_target(now: u64, data: &[u8]) -> Option<&[u8]> {
+    let time = u64::from_be_bytes(data.get(..8)?.try_into().ok()?);
+    if time.max(now) - time.min(now) > MAX_SYN_AGE {
+        return None;
+    }
+    Some(&data[8..])
+}
+
+/// Splits an IPv4 packet, as read from a raw socket, into its source and
+/// payload.
+pub fn parse_ipv4(buf: &[u8]) -> Option<(Ipv4Addr, &[u8])> {
+    if buf.len() < 20 || buf[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = (buf[0] & 0x0f) as usize * 4;
+    if buf.len() < header_len {
+        return None;
+    }
+    let src = Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15]);
+    Some((src, &buf[header_len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet() {
+        let packet = Packet {
+            icmp_type: ECHO_REQUEST,
+            icmp_seq: 7,
+            flags: SYN,
+            session: 0x01020304,
+            seq: 1,
+            ack: 2,
+            // odd length, the checksum pads it
+            data: b"abc".to_vec(),
+        };
+        let buf = packet.encode(b"secret");
+        assert_eq!(buf.len(), HEADER_SIZE + 3);
+        assert_eq!(&buf[4..6], &[3, 4]);
+        assert_eq!(Packet::decode(&buf, b"secret"), Some(packet));
+
+        let mut corrupted = buf.clone();
+        corrupted[HEADER_SIZE] ^= 1;
+        assert_eq!(Packet::decode(&corrupted, b"secret"), None);
+        // a ping
+        let mut ping = vec![ECHO_REQUEST, 0, 0, 0, 0, 1, 0, 1];
+        ping.extend_from_slice(&[0; 32]);
+        let sum = checksum(&ping);
+        ping[2..4].copy_from_slice(&sum.to_be_bytes());
+        assert_eq!(Packet::decode(&ping, b"secret"), None);
+    }
+
+    #[test]
+    fn test_packet_mac() {
+        let packet = Packet {
+            icmp_type: ECHO_REPLY,
+            icmp_seq: 1,
+            flags: SERVER,
+            session: 1,
+            seq: 100,
+            ack: 200,
+            data: b"data".to_vec(),
+        };
+        let buf = packet.encode(b"secret");
+        assert_eq!(Packet::decode(&buf, b"other"), None);
+
+        // changed with a valid checksum
+        let forge = |i: usize| {
+            let mut buf = buf.clone();
+            buf[i] ^= 1;
+            buf[2..4].copy_from_slice(&[0, 0]);
+            let sum = checksum(&buf);
+            buf[2..4].copy_from_slice(&sum.to_be_bytes());
+            buf
+        };
+        // type, flags, session, seq, ack, MAC and data
+        for i in [0, 12, 16, 20, 24, MAC_OFFSET, HEADER_SIZE] {
+            assert_eq!(Packet::decode(&forge(i), b"secret"), None, "{}", i);
+        }
+        // the ICMP sequence is not covered
+        assert!(Packet::decode(&forge(7), b"secret").is_some());
+    }
+
+    #[test]
+    fn test_syn_data() {
+        let data = syn_data(1000, b"example.com:443");
+        assert_eq!(syn_target(1000, &data), Some(&b"example.com:443"[..]));
+        assert!(syn_target(1000 + MAX_SYN_AGE, &data).is_some());
+        assert!(syn_target(1000 - MAX_SYN_AGE, &data).is_some());
+        assert_eq!(syn_target(1001 + MAX_SYN_AGE, &data), None);
+        assert_eq!(syn_target(999 - MAX_SYN_AGE, &data), None);
+        assert_eq!(syn_target(1000, &data[..7]), None);
+    }
+
+    #[test]
+    fn test_parse_ipv4() {
+        let mut buf = vec![
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 1, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        buf.extend_from_slice(b"icmp");
+        assert_eq!(
+            parse_ipv4(&buf),
+            Some((Ipv4Addr::new(10, 0, 0, 1), &b"icmp"[..]))
+        );
+        assert_eq!(parse_ipv4(&buf[..10]), None);
+    }
+}
@@ -0,0 +1,157 @@
+use super::{
+    protocol::{syn_target, unix_time, Packet, ECHO_REQUEST, MAX_SYN_AGE, RST, SERVER, SYN},
+    session::{Outbox, Session},
+    socket::{send_loop, IcmpSocket},
+};
+use rd_interface::{
+    async_trait,
+    util::{connect_tcp, StopSignal},
+    Address, Context, IServer, Net, Result,
+};
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::mpsc, time::Instant};
+
+/// How long a SYN is taken, either side of its time.
+const REPLAY_WINDOW: Duration = Duration::from_secs(2 * MAX_SYN_AGE);
+
+/// Serves the sessions of `icmp_tunnel` nets, connecting to their
+/// destinations with `net`.
+///
+/// It opens a raw socket, so it needs root or `CAP_NET_RAW`. The kernel
+/// keeps answering the pings itself, the clients ignore those replies.
+/// The packets not authenticated with `secret` are ignored, and a SYN is
+/// only taken once, in the minute around the time it carries.
+pub struct IcmpTunnelServer {
+    net: Net,
+    secret: Vec<u8>,
+    stop: StopSignal,
+}
+
+impl IcmpTunnelServer {
+    pub fn new(net: Net, secret: Vec<u8>) -> IcmpTunnelServer {
+        IcmpTunnelServer {
+            net,
+            secret,
+            stop: StopSignal::new(),
+        }
+    }
+}
+
+async fn serve_session(
+    net: Net,
+    mut session: Session,
+    inbox: mpsc::UnboundedReceiver<Packet>,
+    src: Ipv4Addr,
+    target: Vec<u8>,
+) {
+    let target = std::str::from_utf8(&target)
+        .ok()
+        .and_then(|t| t.parse::<Address>().ok());
+    let mut ctx = Context::from_socketaddr(SocketAddr::new(src.into(), 0));
+    let tcp = match target {
+        Some(target) => net.tcp_connect(&mut ctx, target).await,
+        None => Err(rd_interface::Error::Other("invalid target".into())),
+    };
+    match tcp {
+        Ok(tcp) => {
+            session.send(SYN, 0, Vec::new());
+            let stream = session.spawn(inbox);
+            if let Err(e) = connect_tcp(stream, tcp).await {
+                tracing::debug!("ICMP tunnel session from {} ended: {:?}", src, e);
+            }
+        }
+        Err(e) => {
+            tracing::debug!("ICMP tunnel failed to connect: {:?}", e);
+            session.send(RST, 0, Vec::new());
+        }
+    }
+}
+
+/// Tells the client its session is gone.
+fn reset(outbox: &Outbox, src: Ipv4Addr, packet: &Packet) {
+    outbox
+        .send((
+            src,
+            Packet {
+                icmp_type: super::protocol::ECHO_REPLY,
+                icmp_seq: packet.icmp_seq,
+                flags: RST | SERVER,
+                session: packet.session,
+                seq: 0,
+                ack: 0,
+                data: Vec::new(),
+            },
+        ))
+        .ok();
+}
+
+#[async_trait]
+impl IServer for IcmpTunnelServer {
+    async fn start(&self) -> Result<()> {
+        let socket = Arc::new(IcmpSocket::new(self.secret.clone())?);
+        let (outbox, rx) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(send_loop(socket.clone(), rx));
+        let mut sessions: HashMap<(Ipv4Addr, u32), mpsc::UnboundedSender<Packet>> = HashMap::new();
+        // the sessions opened while their SYN could still be replayed
+        let mut opened: HashMap<u32, Instant> = HashMap::new();
+
+        let result = loop {
+            let (src, packet) = match self.stop.until(socket.recv()).await {
+                Some(Ok(r)) => r,
+                Some(Err(e)) => break Err(e.into()),
+                None => break Ok(()),
+            };
+            // our own replies
+            if packet.icmp_type != ECHO_REQUEST || packet.has(SERVER) {
+                continue;
+            }
+            let key = (src, packet.session);
+            let packet = match sessions.get(&key) {
+                Some(tx) => match tx.send(packet) {
+                    Ok(()) => continue,
+                    Err(e) => {
+                        sessions.remove(&key);
+                        e.0
+                    }
+                },
+                None => packet,
+            };
+            if !packet.has(SYN) {
+                if !packet.has(RST) {
+                    reset(&outbox, src, &packet);
+                }
+                continue;
+            }
+            // not answered, as for any other ping
+            let target = match syn_target(unix_time(), &packet.data) {
+                Some(target) => target.to_vec(),
+                None => {
+                    tracing::debug!("ICMP tunnel SYN from {} out of date, dropped", src);
+                    continue;
+                }
+            };
+            opened.retain(|_, at| at.elapsed() < REPLAY_WINDOW);
+            if opened.insert(packet.session, Instant::now()).is_some() {
+                tracing::debug!("ICMP tunnel SYN from {} replayed, dropped", src);
+                continue;
+            }
+
+            sessions.retain(|_, tx| !tx.is_closed());
+            let (tx, inbox) = mpsc::unbounded_channel();
+            sessions.insert(key, tx);
+            let session = Session::new(packet.session, src, true, outbox.clone());
+            tokio::spawn(serve_session(self.net.clone(), session, inbox, src, target));
+        };
+        writer.abort();
+        result
+    }
+    async fn stop(&self) -> Result<()> {
+        self.stop.stop();
+        Ok(())
+    }
+}
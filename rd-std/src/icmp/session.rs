@@ -0,0 +1,244 @@
+//! A reliable byte stream over the tunnel packets. Lost packets are sent
+//! again from the first one not acknowledged, and the receiver only takes
+//! packets in order.
+
+use super::protocol::{Packet, ECHO_REPLY, ECHO_REQUEST, FIN, MSS, RST, SERVER, SYN};
+use std::{collections::VecDeque, net::Ipv4Addr, time::Duration};
+use tokio::{
+    io::{split, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    sync::mpsc,
+    time::{sleep_until, Instant},
+};
+
+/// Bytes sent but not acknowledged, and bytes received but not read, at
+/// most.
+pub const WINDOW: usize = 64 * 1024;
+/// How long to wait for an ack before sending again.
+pub const RTO: Duration = Duration::from_millis(300);
+/// Sessions without packets from the peer for this long are dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a closed session keeps answering, in case its last ack is lost.
+const LINGER: Duration = Duration::from_secs(2);
+
+/// Packets to send, with the address to send them to.
+pub type Outbox = mpsc::UnboundedSender<(Ipv4Addr, Packet)>;
+
+pub struct Session {
+    id: u32,
+    peer: Ipv4Addr,
+    server: bool,
+    outbox: Outbox,
+    icmp_seq: u16,
+
+    /// Bytes not acknowledged yet, starting at `send_base`.
+    send_buf: VecDeque<u8>,
+    send_base: u32,
+    /// Bytes of `send_buf` sent.
+    sent: usize,
+    app_eof: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    rto: Option<Instant>,
+
+    recv_next: u32,
+    /// Bytes received, not read by the app yet.
+    recv_buf: Vec<u8>,
+    peer_fin: bool,
+    last_heard: Instant,
+}
+
+impl Session {
+    pub fn new(id: u32, peer: Ipv4Addr, server: bool, outbox: Outbox) -> Session {
+        Session {
+            id,
+            peer,
+            server,
+            outbox,
+            icmp_seq: 0,
+            send_buf: VecDeque::new(),
+            send_base: 0,
+            sent: 0,
+            app_eof: false,
+            fin_sent: false,
+            fin_acked: false,
+            rto: None,
+            recv_next: 0,
+            recv_buf: Vec::new(),
+            peer_fin: false,
+            last_heard: Instant::now(),
+        }
+    }
+    pub fn send(&mut self, flags: u8, seq: u32, data: Vec<u8>) {
+        let (icmp_type, flags) = if self.server {
+            (ECHO_REPLY, flags | SERVER)
+        } else {
+            (ECHO_REQUEST, flags)
+        };
+        self.icmp_seq = self.icmp_seq.wrapping_add(1);
+        let packet = Packet {
+            icmp_type,
+            icmp_seq: self.icmp_seq,
+            flags,
+            session: self.id,
+            seq,
+            ack: self.recv_next,
+            data,
+        };
+        self.outbox.send((self.peer, packet)).ok();
+    }
+    fn next_seq(&self) -> u32 {
+        self.send_base.wrapping_add(self.sent as u32)
+    }
+    /// Sends the bytes not sent yet, and the FIN once they are all sent.
+    /// Returns false if there was nothing to send.
+    fn flush(&mut self) -> bool {
+        let mut any = false;
+        while self.sent < self.send_buf.len() {
+            let len = (self.send_buf.len() - self.sent).min(MSS);
+            let data = self
+                .send_buf
+                .range(self.sent..self.sent + len)
+                .copied()
+                .collect();
+            let seq = self.next_seq();
+            self.send(0, seq, data);
+            self.sent += len;
+            any = true;
+        }
+        if self.app_eof && !self.fin_sent && !self.fin_acked {
+            let seq = self.next_seq();
+            self.send(FIN, seq, Vec::new());
+            self.fin_sent = true;
+            any = true;
+        }
+        if any && self.rto.is_none() {
+            self.rto = Some(Instant::now() + RTO);
+        }
+        any
+    }
+    /// Returns false if the peer reset the session.
+    fn on_packet(&mut self, packet: Packet) -> bool {
+        let now = Instant::now();
+        self.last_heard = now;
+        if packet.has(RST) {
+            return false;
+        }
+        if packet.has(SYN) {
+            // the reply to it is lost
+            if self.server {
+                self.send(SYN, 0, Vec::new());
+            }
+            return true;
+        }
+
+        let in_flight = self.sent as u32 + self.fin_sent as u32;
+        let acked = packet.ack.wrapping_sub(self.send_base);
+        if acked > 0 && acked <= in_flight {
+            let data_acked = (acked as usize).min(self.sent);
+            self.send_buf.drain(..data_acked);
+            self.send_base = self.send_base.wrapping_add(data_acked as u32);
+            self.sent -= data_acked;
+            if acked as usize > data_acked {
+                self.fin_acked = true;
+                self.fin_sent = false;
+            }
+            let waiting = self.sent > 0 || self.fin_sent;
+            self.rto = if waiting { Some(now + RTO) } else { None };
+        }
+
+        let has_data = !packet.data.is_empty() || packet.has(FIN);
+        if has_data
+            && packet.seq == self.recv_next
+            && !self.peer_fin
+            && self.recv_buf.len() < WINDOW
+        {
+            self.recv_buf.extend_from_slice(&packet.data);
+            self.recv_next = self.recv_next.wrapping_add(packet.data.len() as u32);
+            if packet.has(FIN) {
+                self.peer_fin = true;
+                self.recv_next = self.recv_next.wrapping_add(1);
+            }
+        }
+        if !self.flush() && has_data {
+            let seq = self.next_seq();
+            self.send(0, seq, Vec::new());
+        }
+        true
+    }
+    fn on_timeout(&mut self) {
+        self.sent = 0;
+        self.fin_sent = false;
+        self.rto = None;
+        self.flush();
+    }
+    /// Relays between the peer and the returned stream in a task.
+    /// `inbox` yields the packets of the session from the peer.
+    pub fn spawn(self, inbox: mpsc::UnboundedReceiver<Packet>) -> DuplexStream {
+        self.spawn_with(inbox, ())
+    }
+    /// Like `spawn`, dropping `guard` when the session ends.
+    pub fn spawn_with<G: Send + 'static>(
+        self,
+        inbox: mpsc::UnboundedReceiver<Packet>,
+        guard: G,
+    ) -> DuplexStream {
+        let (app, stream) = tokio::io::duplex(WINDOW);
+        tokio::spawn(async move {
+            self.run(inbox, app).await;
+            drop(guard);
+        });
+        stream
+    }
+    async fn run(mut self, mut inbox: mpsc::UnboundedReceiver<Packet>, app: DuplexStream) {
+        let (mut app_rx, mut app_tx) = split(app);
+        let mut buf = vec![0u8; MSS];
+        let mut app_shutdown = false;
+        let mut done_at = None;
+        loop {
+            if self.peer_fin && self.recv_buf.is_empty() && !app_shutdown {
+                app_tx.shutdown().await.ok();
+                app_shutdown = true;
+            }
+            if done_at.is_none() && app_shutdown && self.fin_acked {
+                done_at = Some(Instant::now());
+            }
+            let deadline = match done_at {
+                Some(done_at) => done_at + LINGER,
+                None => self.last_heard + IDLE_TIMEOUT,
+            };
+            let rto = self.rto.unwrap_or(deadline);
+            let can_read = !self.app_eof && self.send_buf.len() < WINDOW;
+
+            tokio::select! {
+                packet = inbox.recv() => match packet {
+                    Some(packet) => if !self.on_packet(packet) {
+                        return;
+                    },
+                    None => return,
+                },
+                r = app_rx.read(&mut buf), if can_read => {
+                    match r {
+                        Ok(n) if n > 0 => self.send_buf.extend(&buf[..n]),
+                        _ => self.app_eof = true,
+                    }
+                    self.flush();
+                }
+                r = app_tx.write(&self.recv_buf), if !self.recv_buf.is_empty() => match r {
+                    Ok(n) => {
+                        self.recv_buf.drain(..n);
+                    }
+                    // nobody reads it
+                    Err(_) => self.recv_buf.clear(),
+                },
+                _ = sleep_until(rto), if self.rto.is_some() => self.on_timeout(),
+                _ = sleep_until(deadline) => {
+                    if done_at.is_none() {
+                        tracing::debug!("ICMP tunnel session {} timed out", self.id);
+                        self.send(RST, 0, Vec::new());
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
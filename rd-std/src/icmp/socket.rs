@@ -0,0 +1,66 @@
+use super::protocol::{parse_ipv4, Packet};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::{
+    io::{self, Read},
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{io::unix::AsyncFd, sync::mpsc};
+
+/// A raw ICMPv4 socket, receiving every ICMP packet to the host. Opening it
+/// needs root or `CAP_NET_RAW`. The packets are authenticated with `secret`.
+pub struct IcmpSocket {
+    fd: AsyncFd<Socket>,
+    secret: Vec<u8>,
+}
+
+impl IcmpSocket {
+    pub fn new(secret: Vec<u8>) -> io::Result<IcmpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
+        socket.set_nonblocking(true)?;
+        Ok(IcmpSocket {
+            fd: AsyncFd::new(socket)?,
+            secret,
+        })
+    }
+    /// Receives the next tunnel packet authenticated with the secret, with
+    /// its source.
+    pub async fn recv(&self) -> io::Result<(Ipv4Addr, Packet)> {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let n = match guard.try_io(|fd| (&mut fd.get_ref()).read(&mut buf)) {
+                Ok(n) => n?,
+                Err(_would_block) => continue,
+            };
+            if let Some((src, icmp)) = parse_ipv4(&buf[..n]) {
+                if let Some(packet) = Packet::decode(icmp, &self.secret) {
+                    return Ok((src, packet));
+                }
+            }
+        }
+    }
+    pub async fn send(&self, dst: Ipv4Addr, packet: &Packet) -> io::Result<()> {
+        let buf = packet.encode(&self.secret);
+        let addr = SockAddr::from(SocketAddr::new(dst.into(), 0));
+        loop {
+            let mut guard = self.fd.writable().await?;
+            match guard.try_io(|fd| fd.get_ref().send_to(&buf, &addr)) {
+                Ok(r) => return r.map(drop),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Sends the packets from `outbox` until every sender is gone.
+pub async fn send_loop(
+    socket: Arc<IcmpSocket>,
+    mut outbox: mpsc::UnboundedReceiver<(Ipv4Addr, Packet)>,
+) {
+    while let Some((dst, packet)) = outbox.recv().await {
+        if let Err(e) = socket.send(dst, &packet).await {
+            tracing::debug!("Failed to send ICMP packet to {}: {:?}", dst, e);
+        }
+    }
+}
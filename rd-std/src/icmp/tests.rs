@@ -0,0 +1,121 @@
+use super::{
+    protocol::Packet,
+    session::{Outbox, Session},
+    socket::IcmpSocket,
+    IcmpTunnelNet, IcmpTunnelServer,
+};
+use crate::builtin::local::{LocalConfig, LocalNet};
+use crate::tests::{assert_echo, get_registry, spawn_echo_server};
+use rd_interface::{Context, INet, IServer, IntoAddress, IntoDyn, Net};
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+    time::sleep,
+};
+
+/// Passes the packets of `outbox` to `inbox`, dropping the `drop`th ones.
+fn lossy_link(drop: &'static [usize]) -> (Outbox, mpsc::UnboundedReceiver<Packet>) {
+    let (outbox, mut rx) = mpsc::unbounded_channel::<(Ipv4Addr, Packet)>();
+    let (tx, inbox) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut i = 0;
+        while let Some((_, packet)) = rx.recv().await {
+            if !drop.contains(&i) {
+                tx.send(packet).ok();
+            }
+            i += 1;
+        }
+    });
+    (outbox, inbox)
+}
+
+#[test]
+fn test_icmp_smoke() {
+    let mut registry = get_registry();
+    super::init(&mut registry).unwrap();
+}
+
+#[tokio::test]
+async fn test_session_lossy() {
+    let (client_outbox, server_inbox) = lossy_link(&[2, 9, 10, 30]);
+    let (server_outbox, client_inbox) = lossy_link(&[1, 5, 25]);
+    let client = Session::new(1, Ipv4Addr::LOCALHOST, false, client_outbox);
+    let server = Session::new(1, Ipv4Addr::LOCALHOST, true, server_outbox);
+    let mut client = client.spawn(client_inbox);
+    let mut server = server.spawn(server_inbox);
+
+    let sent: Vec<u8> = (0..30000u32).map(|i| (i % 251) as u8).collect();
+    let echo = tokio::spawn(async move {
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        received.reverse();
+        server.write_all(&received).await.unwrap();
+        server.shutdown().await.unwrap();
+    });
+
+    client.write_all(&sent).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    received.reverse();
+    assert_eq!(received, sent);
+    echo.await.unwrap();
+}
+
+/// Needs root or `CAP_NET_RAW`, skipped otherwise.
+#[tokio::test]
+async fn test_icmp_tunnel_loopback() {
+    if IcmpSocket::new(Vec::new()).is_err() {
+        println!("no raw socket, skipped");
+        return;
+    }
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    let server = Arc::new(IcmpTunnelServer::new(local.clone(), b"secret".to_vec()));
+    let serving = tokio::spawn({
+        let server = server.clone();
+        async move { server.start().await }
+    });
+    spawn_echo_server(&local, "127.0.0.1:26683").await;
+    sleep(Duration::from_millis(10)).await;
+
+    let net = IcmpTunnelNet::new(Ipv4Addr::LOCALHOST, b"secret".to_vec());
+    // refused by the server, the session is gone right away
+    let result = net
+        .tcp_connect(&mut Context::new(), "127.0.0.1:1".into_address().unwrap())
+        .await;
+    assert!(result.is_err());
+    assert_eq!(net.session_count(), 0);
+
+    let mut tcp = net
+        .tcp_connect(
+            &mut Context::new(),
+            "127.0.0.1:26683".into_address().unwrap(),
+        )
+        .await
+        .unwrap();
+    let sent: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let (mut rx, mut tx) = tokio::io::split(&mut tcp);
+    let (_, received) = tokio::join!(async { tx.write_all(&sent).await.unwrap() }, async {
+        let mut received = vec![0u8; sent.len()];
+        rx.read_exact(&mut received).await.unwrap();
+        received
+    });
+    assert_eq!(received, sent);
+    drop(tcp);
+
+    let net = Arc::new(net);
+    let dyn_net: Net = net.clone();
+    assert_echo(&dyn_net, "127.0.0.1:26683").await;
+    // the closed sessions are gone once they stop lingering
+    for _ in 0..50 {
+        if net.session_count() == 0 {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(net.session_count(), 0);
+
+    server.stop().await.unwrap();
+    serving.await.unwrap().unwrap();
+}
@@ -1,28 +1,142 @@
 use rd_interface::{Registry, Result};
 
 pub mod builtin;
+pub mod dns;
+#[cfg(any(test, feature = "fault"))]
+pub mod fault;
+pub mod grpc;
 pub mod http;
+#[cfg(all(unix, any(test, feature = "icmp")))]
+pub mod icmp;
+#[cfg(any(test, feature = "memory"))]
+pub mod memory;
 pub mod mixed;
+pub mod mux;
 pub mod redir;
 pub mod rule;
+pub mod sniff;
 pub mod socks5;
+pub mod tls;
+pub mod trojan;
+pub mod vmess;
+pub mod ws;
 
 pub fn init(registry: &mut Registry) -> Result<()> {
     builtin::init(registry)?;
+    dns::init(registry)?;
+    #[cfg(feature = "fault")]
+    fault::init(registry)?;
+    grpc::init(registry)?;
     http::init(registry)?;
+    #[cfg(all(unix, feature = "icmp"))]
+    icmp::init(registry)?;
     mixed::init(registry)?;
+    mux::init(registry)?;
     redir::init(registry)?;
     rule::init(registry)?;
     socks5::init(registry)?;
+    trojan::init(registry)?;
+    vmess::init(registry)?;
+    ws::init(registry)?;
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::builtin;
-    use rd_interface::{Context, IntoAddress, Net, Registry};
+/// Helpers for the tests of the nets, here and in the crates using them.
+#[cfg(any(test, feature = "test_util"))]
+pub mod tests {
+    use crate::builtin::{
+        self,
+        local::{LocalConfig, LocalNet},
+    };
+    use rd_interface::{
+        async_trait, Address, Context, INet, IUdpSocket, IntoAddress, IntoDyn, Net, Registry,
+        Result, TcpListener, TcpStream, UdpSocket, NOT_IMPLEMENTED,
+    };
+    use std::{
+        net::{IpAddr, SocketAddr},
+        sync::{Arc, Mutex},
+    };
     use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 
+    /// Records the addresses connected to and sent to through it. The
+    /// connects fail, UDP sockets are bound locally. Domains are resolved to
+    /// `ips`, or not at all if it's empty.
+    #[derive(Default)]
+    pub struct RecordNet {
+        pub ips: Vec<IpAddr>,
+        pub connected: Mutex<Vec<Address>>,
+        /// The source address in the context of the last connect.
+        pub source: Mutex<Option<SocketAddr>>,
+        pub sent: Arc<Mutex<Vec<Address>>>,
+    }
+
+    impl RecordNet {
+        pub fn resolving(ips: &[&str]) -> RecordNet {
+            RecordNet {
+                ips: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+                ..Default::default()
+            }
+        }
+        /// Takes the addresses connected to so far.
+        pub fn take_connected(&self) -> Vec<String> {
+            let connected = std::mem::take(&mut *self.connected.lock().unwrap());
+            connected.iter().map(ToString::to_string).collect()
+        }
+        /// Takes the addresses sent to so far.
+        pub fn take_sent(&self) -> Vec<String> {
+            let sent = std::mem::take(&mut *self.sent.lock().unwrap());
+            sent.iter().map(ToString::to_string).collect()
+        }
+    }
+
+    #[async_trait]
+    impl INet for RecordNet {
+        async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+            self.connected.lock().unwrap().push(addr);
+            *self.source.lock().unwrap() = ctx.source_addr();
+            Err(NOT_IMPLEMENTED)
+        }
+        async fn tcp_bind(&self, _ctx: &mut Context, _addr: Address) -> Result<TcpListener> {
+            Err(NOT_IMPLEMENTED)
+        }
+        async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+            let local = LocalNet::new(LocalConfig::default());
+            let udp = local.udp_bind(ctx, addr).await?;
+            Ok(RecordUdp(udp, self.sent.clone()).into_dyn())
+        }
+        async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+            match addr {
+                Address::Domain(_, port) if !self.ips.is_empty() => {
+                    Ok(self.ips.iter().map(|ip| (*ip, *port).into()).collect())
+                }
+                Address::SocketAddr(addr) => Ok(vec![*addr]),
+                _ => Err(NOT_IMPLEMENTED),
+            }
+        }
+    }
+
+    struct RecordUdp(UdpSocket, Arc<Mutex<Vec<Address>>>);
+
+    #[async_trait]
+    impl IUdpSocket for RecordUdp {
+        async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+            self.0.recv_from(buf).await
+        }
+        async fn send_to(&self, buf: &[u8], addr: Address) -> Result<usize> {
+            self.1.lock().unwrap().push(addr.clone());
+            self.0.send_to(buf, addr).await
+        }
+        async fn local_addr(&self) -> Result<SocketAddr> {
+            self.0.local_addr().await
+        }
+        async fn connect(&self, addr: SocketAddr) -> Result<()> {
+            self.0.connect(addr).await
+        }
+        async fn peer_addr(&self) -> Result<SocketAddr> {
+            self.0.peer_addr().await
+        }
+    }
+
     pub fn get_registry() -> Registry {
         let mut registry = Registry::new();
         builtin::init(&mut registry).unwrap();
@@ -46,12 +160,12 @@ mod tests {
     }
 
     pub async fn assert_echo(net: &Net, addr: impl IntoAddress) {
-        const BUF: &'static [u8] = b"asdfasdfasdfasj12312313123";
+        const BUF: &[u8] = b"asdfasdfasdfasj12312313123";
         let mut tcp = net
             .tcp_connect(&mut Context::new(), addr.into_address().unwrap())
             .await
             .unwrap();
-        tcp.write_all(&BUF).await.unwrap();
+        tcp.write_all(BUF).await.unwrap();
 
         let mut buf = [0u8; BUF.len()];
         tcp.read_exact(&mut buf).await.unwrap();
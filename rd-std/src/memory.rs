@@ -0,0 +1,303 @@
+//! A net connecting to itself in memory, so nets can be tested without OS
+//! sockets.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use rd_interface::{
+    async_trait, impl_async_read_write, Address, Context, INet, ITcpListener, ITcpStream,
+    IUdpSocket, IntoDyn, Result, TcpListener, TcpStream, UdpSocket,
+};
+use tokio::{
+    io::{duplex, DuplexStream},
+    sync::mpsc,
+};
+
+/// Buffer size of each direction of a stream.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+#[derive(Default)]
+struct State {
+    tcp: Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<MemoryTcpStream>>>,
+    udp: Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<Datagram>>>,
+    next_port: AtomicU16,
+}
+
+impl State {
+    /// Gives port 0 a port nobody is bound to.
+    fn local_addr<T>(&self, addr: SocketAddr, bound: &HashMap<SocketAddr, T>) -> SocketAddr {
+        if addr.port() != 0 {
+            return addr;
+        }
+        loop {
+            let port = FIRST_EPHEMERAL_PORT
+                .wrapping_add(self.next_port.fetch_add(1, Ordering::Relaxed))
+                .max(FIRST_EPHEMERAL_PORT);
+            let addr = SocketAddr::new(addr.ip(), port);
+            if !bound.contains_key(&addr) {
+                return addr;
+            }
+        }
+    }
+}
+
+/// Listeners and sockets are only reachable at the exact address they are
+/// bound to, so binding `0.0.0.0` doesn't accept connections to `127.0.0.1`.
+/// Domains are not resolved.
+///
+/// Clones share the listeners and sockets.
+#[derive(Clone, Default)]
+pub struct MemoryNet(Arc<State>);
+
+impl MemoryNet {
+    pub fn new() -> MemoryNet {
+        MemoryNet::default()
+    }
+}
+
+pub struct MemoryTcpStream {
+    inner: DuplexStream,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl_async_read_write!(MemoryTcpStream, inner);
+
+#[async_trait]
+impl ITcpStream for MemoryTcpStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+pub struct MemoryTcpListener {
+    state: Arc<State>,
+    addr: SocketAddr,
+    rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<MemoryTcpStream>>,
+}
+
+#[async_trait]
+impl ITcpListener for MemoryTcpListener {
+    async fn accept(&self) -> Result<(TcpStream, SocketAddr)> {
+        let stream = self
+            .rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let peer_addr = stream.peer_addr;
+        Ok((stream.into_dyn(), peer_addr))
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryTcpListener {
+    fn drop(&mut self) {
+        self.state.tcp.lock().unwrap().remove(&self.addr);
+    }
+}
+
+pub struct MemoryUdpSocket {
+    state: Arc<State>,
+    addr: SocketAddr,
+    rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+#[async_trait]
+impl IUdpSocket for MemoryUdpSocket {
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let (data, from) = self
+            .rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        // the rest is discarded, like a real socket
+        let size = data.len().min(buf.len());
+        buf[..size].copy_from_slice(&data[..size]);
+        Ok((size, from))
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: Address) -> Result<usize> {
+        let addr = addr.to_socket_addr()?;
+        // nobody bound to `addr`, it's lost
+        if let Some(tx) = self.state.udp.lock().unwrap().get(&addr) {
+            let _ = tx.send((buf.to_vec(), self.addr));
+        }
+        Ok(buf.len())
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryUdpSocket {
+    fn drop(&mut self) {
+        self.state.udp.lock().unwrap().remove(&self.addr);
+    }
+}
+
+#[async_trait]
+impl INet for MemoryNet {
+    async fn tcp_connect(&self, _ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        let peer_addr = addr.to_socket_addr()?;
+        let tcp = self.0.tcp.lock().unwrap();
+        let tx = tcp
+            .get(&peer_addr)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+
+        let ip = match peer_addr.ip() {
+            IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            ip => ip,
+        };
+        let local_addr = self.0.local_addr(SocketAddr::new(ip, 0), &tcp);
+        let (client, server) = duplex(STREAM_BUFFER_SIZE);
+        tx.send(MemoryTcpStream {
+            inner: server,
+            local_addr: peer_addr,
+            peer_addr: local_addr,
+        })
+        .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+
+        Ok(MemoryTcpStream {
+            inner: client,
+            local_addr,
+            peer_addr,
+        }
+        .into_dyn())
+    }
+
+    async fn tcp_bind(&self, _ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        let addr = addr.to_socket_addr()?;
+        let mut tcp = self.0.tcp.lock().unwrap();
+        if tcp.contains_key(&addr) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse).into());
+        }
+        let addr = self.0.local_addr(addr, &tcp);
+        let (tx, rx) = mpsc::unbounded_channel();
+        tcp.insert(addr, tx);
+
+        Ok(MemoryTcpListener {
+            state: self.0.clone(),
+            addr,
+            rx: tokio::sync::Mutex::new(rx),
+        }
+        .into_dyn())
+    }
+
+    async fn udp_bind(&self, _ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        let addr = addr.to_socket_addr()?;
+        let mut udp = self.0.udp.lock().unwrap();
+        if udp.contains_key(&addr) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse).into());
+        }
+        let addr = self.0.local_addr(addr, &udp);
+        let (tx, rx) = mpsc::unbounded_channel();
+        udp.insert(addr, tx);
+
+        Ok(MemoryUdpSocket {
+            state: self.0.clone(),
+            addr,
+            rx: tokio::sync::Mutex::new(rx),
+        }
+        .into_dyn())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{assert_echo, spawn_echo_server};
+    use rd_interface::{IntoAddress, Net};
+
+    #[tokio::test]
+    async fn test_memory_tcp() {
+        let memory = MemoryNet::new();
+        let net: Net = memory.clone().into_dyn();
+        spawn_echo_server(&net, "127.0.0.1:80").await;
+        assert_echo(&net, "127.0.0.1:80").await;
+
+        // a clone reaches the same listeners
+        assert_echo(&memory.into_dyn(), "127.0.0.1:80").await;
+
+        let err = net
+            .tcp_connect(&mut Context::new(), "127.0.0.1:81".into_address().unwrap())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            io::Error::from(err).kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_tcp_bind() {
+        let net = MemoryNet::new();
+        let listener = net
+            .tcp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().await.unwrap();
+        assert_ne!(addr.port(), 0);
+
+        let tcp = net
+            .tcp_connect(&mut Context::new(), addr.into_address().unwrap())
+            .await
+            .unwrap();
+        let (accepted, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, tcp.local_addr().await.unwrap());
+        assert_eq!(accepted.local_addr().await.unwrap(), addr);
+
+        assert!(net
+            .tcp_bind(&mut Context::new(), addr.into_address().unwrap())
+            .await
+            .is_err());
+        // dropping the listener frees the address
+        drop(listener);
+        net.tcp_bind(&mut Context::new(), addr.into_address().unwrap())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_udp() {
+        let net = MemoryNet::new();
+        let a = net
+            .udp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        let b = net
+            .udp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        let a_addr = a.local_addr().await.unwrap();
+        let b_addr = b.local_addr().await.unwrap();
+
+        a.send_to(b"hello", b_addr.into()).await.unwrap();
+        let mut buf = [0u8; 16];
+        let (size, from) = b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"hello");
+        assert_eq!(from, a_addr);
+    }
+}
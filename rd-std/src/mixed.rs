@@ -1,30 +1,48 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 
 use rd_interface::{
     async_trait,
     registry::ServerFactory,
     schemars::{self, JsonSchema},
-    util::PeekableTcpStream,
+    util::{BoundAddr, PeekableTcpStream, StopSignal},
     Config, Context, IServer, IntoAddress, IntoDyn, Net, Registry, Result, TcpStream,
 };
 use serde_derive::Deserialize;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tracing::Instrument;
 
-use crate::{http::HttpServer, socks5::Socks5Server};
+use crate::{
+    http::HttpServer,
+    socks5::Socks5Server,
+    tls::{self, TlsServerConfig},
+};
+
+/// Connections not done with the TLS handshake by then are closed.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Clone)]
 struct HttpSocks5Server {
     http_server: HttpServer,
     socks5_server: Socks5Server,
+    tls: Option<TlsAcceptor>,
 }
 
 impl HttpSocks5Server {
-    fn new(listen_net: Net, net: Net) -> Self {
+    fn new(listen_net: Net, net: Net, tls: Option<TlsAcceptor>) -> Self {
         Self {
             http_server: HttpServer::new(net.clone()),
-            socks5_server: Socks5Server::new(listen_net.clone(), net.clone()),
+            socks5_server: Socks5Server::new(listen_net.clone(), net.clone(), Default::default()),
+            tls,
         }
     }
     pub async fn serve_connection(self, socket: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
+        let socket = match &self.tls {
+            Some(acceptor) => timeout(TLS_HANDSHAKE_TIMEOUT, tls::accept(acceptor, socket))
+                .await
+                .map_err(|_| anyhow::anyhow!("TLS handshake timed out"))??,
+            None => socket,
+        };
         let buf = &mut [0u8; 1];
         let mut socket = PeekableTcpStream::new(socket);
         socket.peek_exact(buf).await?;
@@ -40,6 +58,8 @@ impl HttpSocks5Server {
 pub struct HttpSocks5 {
     listen_net: Net,
     bind: String,
+    stop: StopSignal,
+    local_addr: BoundAddr,
 
     server: HttpSocks5Server,
 }
@@ -51,26 +71,42 @@ impl IServer for HttpSocks5 {
             .listen_net
             .tcp_bind(&mut Context::new(), self.bind.into_address()?)
             .await?;
+        self.local_addr.set(listener.local_addr().await?);
 
         loop {
-            let (socket, addr) = listener.accept().await?;
+            let (socket, addr) = match self.stop.until(listener.accept()).await {
+                Some(r) => r?,
+                None => return Ok(()),
+            };
 
             let server = self.server.clone();
-            let _ = tokio::spawn(async move {
-                if let Err(e) = server.serve_connection(socket, addr).await {
-                    tracing::error!("Error when serve_connection: {:?}", e)
+            tokio::spawn(
+                async move {
+                    if let Err(e) = server.serve_connection(socket, addr).await {
+                        tracing::error!("Error when serve_connection: {:?}", e)
+                    }
                 }
-            });
+                .instrument(tracing::info_span!("serve_connection", src = %addr)),
+            );
         }
     }
+    async fn stop(&self) -> Result<()> {
+        self.stop.stop();
+        Ok(())
+    }
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.local_addr.get()
+    }
 }
 
 impl HttpSocks5 {
-    fn new(listen_net: Net, net: Net, bind: String) -> Self {
+    fn new(listen_net: Net, net: Net, bind: String, tls: Option<TlsAcceptor>) -> Self {
         HttpSocks5 {
-            server: HttpSocks5Server::new(listen_net.clone(), net),
+            server: HttpSocks5Server::new(listen_net.clone(), net, tls),
             listen_net,
             bind,
+            stop: StopSignal::new(),
+            local_addr: BoundAddr::new(),
         }
     }
 }
@@ -78,6 +114,9 @@ impl HttpSocks5 {
 #[derive(Debug, Deserialize, Config, JsonSchema)]
 pub struct ServerConfig {
     bind: String,
+    /// Accepts TLS connections only, and serves HTTP or SOCKS5 inside.
+    #[serde(default)]
+    tls: Option<TlsServerConfig>,
 }
 
 impl ServerFactory for HttpSocks5 {
@@ -85,8 +124,9 @@ impl ServerFactory for HttpSocks5 {
     type Config = ServerConfig;
     type Server = Self;
 
-    fn new(listen: Net, net: Net, Self::Config { bind }: Self::Config) -> Result<Self> {
-        Ok(HttpSocks5::new(listen, net, bind))
+    fn new(listen: Net, net: Net, Self::Config { bind, tls }: Self::Config) -> Result<Self> {
+        let tls = tls.map(|tls| tls.acceptor()).transpose()?;
+        Ok(HttpSocks5::new(listen, net, bind, tls))
     }
 }
 
@@ -94,3 +134,211 @@ pub fn init(registry: &mut Registry) -> Result<()> {
     registry.add_server::<HttpSocks5>();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtin::local::{LocalConfig, LocalNet};
+    use crate::tests::spawn_echo_server;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use std::{
+        convert::TryFrom,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{
+        rustls::{
+            crypto::ring,
+            pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+            ClientConfig, RootCertStore,
+        },
+        TlsConnector,
+    };
+
+    /// A temporary file, removed on drop.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn path(&self) -> String {
+            self.0.to_string_lossy().to_string()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn write_temp(name: &str, content: &str) -> TempFile {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rd-mixed-{}-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst),
+            name
+        ));
+        std::fs::write(&path, content).unwrap();
+        TempFile(path)
+    }
+
+    struct Certs {
+        server: rcgen::CertifiedKey,
+        ca: rcgen::Certificate,
+        client: rcgen::Certificate,
+        client_key: KeyPair,
+    }
+
+    fn certs() -> Certs {
+        let server = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let client = CertificateParams::new(vec!["client".to_string()])
+            .unwrap()
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+
+        Certs {
+            server,
+            ca,
+            client,
+            client_key,
+        }
+    }
+
+    /// Serves TLS http+socks5 on a random port.
+    async fn spawn_server(local: &Net, tls: TlsServerConfig) -> u16 {
+        let server =
+            HttpSocks5Server::new(local.clone(), local.clone(), Some(tls.acceptor().unwrap()));
+        let listener = local
+            .tcp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        let port = listener.local_addr().await.unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (socket, addr) = listener.accept().await.unwrap();
+                let server = server.clone();
+                tokio::spawn(async move { server.serve_connection(socket, addr).await });
+            }
+        });
+        port
+    }
+
+    /// Connects to `target` by SOCKS5 over TLS and echoes a message.
+    async fn socks5_over_tls(
+        port: u16,
+        certs: &Certs,
+        with_client_cert: bool,
+        target: u16,
+    ) -> std::io::Result<()> {
+        let mut roots = RootCertStore::empty();
+        roots.add(certs.server.cert.der().clone()).unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = if with_client_cert {
+            let key =
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certs.client_key.serialize_der()));
+            builder
+                .with_client_auth_cert(vec![certs.client.der().clone()], key)
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        let tcp = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut tls = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await?;
+
+        tls.write_all(&[5, 1, 0]).await?;
+        let mut auth = [0u8; 2];
+        tls.read_exact(&mut auth).await?;
+        assert_eq!(auth, [5, 0]);
+
+        let [hi, lo] = target.to_be_bytes();
+        tls.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, hi, lo]).await?;
+        let mut reply = [0u8; 10];
+        tls.read_exact(&mut reply).await?;
+        assert_eq!(reply[..2], [5, 0]);
+
+        tls.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        tls.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls_socks5() {
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        spawn_echo_server(&local, "127.0.0.1:26677").await;
+        let certs = certs();
+
+        let cert = write_temp("cert.pem", &certs.server.cert.pem());
+        let key = write_temp("key.pem", &certs.server.key_pair.serialize_pem());
+        let ca = write_temp("ca.pem", &certs.ca.pem());
+        let tls = TlsServerConfig {
+            cert: cert.path(),
+            key: key.path(),
+            client_ca: None,
+        };
+        let port = spawn_server(&local, tls.clone()).await;
+        socks5_over_tls(port, &certs, false, 26677).await.unwrap();
+
+        // plaintext SOCKS5 is refused
+        let mut tcp = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        tcp.write_all(&[5, 1, 0]).await.unwrap();
+        let mut auth = [0u8; 2];
+        let r = tcp.read_exact(&mut auth).await;
+        // closed, or a TLS alert
+        assert!(r.is_err() || auth != [5, 0]);
+
+        let tls = TlsServerConfig {
+            client_ca: Some(ca.path()),
+            ..tls
+        };
+        let port = spawn_server(&local, tls).await;
+        assert!(socks5_over_tls(port, &certs, false, 26677).await.is_err());
+        socks5_over_tls(port, &certs, true, 26677).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_local_addr() {
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        let server = Arc::new(HttpSocks5::new(
+            local.clone(),
+            local,
+            "127.0.0.1:0".to_string(),
+            None,
+        ));
+        assert!(server.local_addr().await.is_err());
+
+        {
+            let server = server.clone();
+            tokio::spawn(async move { server.start().await });
+        }
+        let addr = loop {
+            match server.local_addr().await {
+                Ok(addr) => break addr,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        assert_ne!(addr.port(), 0);
+        assert!(tokio::net::TcpStream::connect(addr).await.is_ok());
+
+        server.stop().await.unwrap();
+    }
+}
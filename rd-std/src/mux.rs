@@ -0,0 +1,463 @@
+//! Connections as streams of a few yamux sessions, instead of a connection
+//! each.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::Duration,
+};
+
+use futures::future::poll_fn;
+use rd_interface::{
+    async_trait, impl_async_read_write,
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Address, Config, Context, INet, ITcpStream, IntoDyn, Net, Registry, Result, TcpListener,
+    TcpStream, UdpSocket, NOT_IMPLEMENTED,
+};
+use serde_derive::Deserialize;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{sleep, Sleep},
+};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use yamux::{Connection, Mode};
+
+type OpenReply = oneshot::Sender<yamux::Result<yamux::Stream>>;
+
+enum Command {
+    Open(OpenReply),
+    /// The last stream is closed.
+    Idle,
+}
+
+/// A stream counted in its session. Dropping it gives the place back.
+struct Slot {
+    streams: Arc<AtomicUsize>,
+    tx: mpsc::UnboundedSender<Command>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if self.streams.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _ = self.tx.send(Command::Idle);
+        }
+    }
+}
+
+struct Session {
+    streams: Arc<AtomicUsize>,
+    tx: mpsc::UnboundedSender<Command>,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+}
+
+impl Session {
+    fn new(
+        tcp: TcpStream,
+        peer_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+        idle_timeout: Duration,
+    ) -> Session {
+        let streams = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(drive(tcp, rx, streams.clone(), idle_timeout));
+        Session {
+            streams,
+            tx,
+            peer_addr,
+            local_addr,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Takes a place for a stream, if there are less than `max`.
+    fn reserve(&self, max: usize) -> Option<Slot> {
+        self.streams
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if n < max {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(Slot {
+            streams: self.streams.clone(),
+            tx: self.tx.clone(),
+        })
+    }
+
+    async fn open(&self, slot: Slot) -> io::Result<MuxStream> {
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "The session is closed");
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(Command::Open(reply)).map_err(|_| closed())?;
+        let stream = rx.await.map_err(|_| closed())?.map_err(io::Error::other)?;
+        Ok(MuxStream {
+            inner: stream.compat(),
+            peer_addr: self.peer_addr,
+            local_addr: self.local_addr,
+            _slot: slot,
+        })
+    }
+}
+
+/// Runs the session until it fails, it's idle for `idle_timeout`, or the
+/// net is dropped.
+async fn drive(
+    tcp: TcpStream,
+    mut rx: mpsc::UnboundedReceiver<Command>,
+    streams: Arc<AtomicUsize>,
+    idle_timeout: Duration,
+) {
+    let mut connection = Connection::new(tcp.compat(), yamux::Config::default(), Mode::Client);
+    let mut opening: Vec<OpenReply> = Vec::new();
+    let mut idle: Option<Pin<Box<Sleep>>> = None;
+
+    poll_fn(|cx| {
+        loop {
+            match rx.poll_recv(cx) {
+                Poll::Ready(Some(Command::Open(reply))) => {
+                    idle = None;
+                    opening.push(reply);
+                }
+                Poll::Ready(Some(Command::Idle)) => {
+                    if streams.load(Ordering::SeqCst) == 0 {
+                        idle = Some(Box::pin(sleep(idle_timeout)));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            }
+        }
+        while !opening.is_empty() {
+            match connection.poll_new_outbound(cx) {
+                Poll::Ready(result) => {
+                    let _ = opening.remove(0).send(result);
+                }
+                Poll::Pending => break,
+            }
+        }
+        if let Some(sleep) = &mut idle {
+            if sleep.as_mut().poll(cx).is_ready() {
+                if streams.load(Ordering::SeqCst) == 0 {
+                    return Poll::Ready(());
+                }
+                idle = None;
+            }
+        }
+        // the server doesn't open streams, but this moves the session on
+        loop {
+            match connection.poll_next_inbound(cx) {
+                Poll::Ready(Some(Ok(_))) => continue,
+                Poll::Ready(Some(Err(e))) => {
+                    tracing::debug!("Multiplex session error: {:?}", e);
+                    return Poll::Ready(());
+                }
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    })
+    .await;
+
+    rx.close();
+    let _ = poll_fn(|cx| connection.poll_close(cx)).await;
+}
+
+pub struct MuxStream {
+    inner: Compat<yamux::Stream>,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    _slot: Slot,
+}
+
+impl_async_read_write!(MuxStream, inner);
+
+#[async_trait]
+impl ITcpStream for MuxStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        self.peer_addr.ok_or(NOT_IMPLEMENTED)
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.local_addr.ok_or(NOT_IMPLEMENTED)
+    }
+}
+
+/// Connects to an address once, and opens a stream of the yamux session
+/// over it for each connection. A session takes up to
+/// `max_streams_per_session` streams, another one is opened past it. It goes
+/// under nets like `trojan` or `vmess`, the server must take yamux.
+pub struct MuxNet {
+    net: Net,
+    max_streams: usize,
+    idle_timeout: Duration,
+    sessions: Mutex<HashMap<Address, Vec<Arc<Session>>>>,
+}
+
+impl MuxNet {
+    pub fn new(net: Net, config: MuxNetConfig) -> Result<Self> {
+        if config.max_streams_per_session == 0 {
+            return Err(rd_interface::Error::Other(
+                "max_streams_per_session must be at least 1".into(),
+            ));
+        }
+        Ok(MuxNet {
+            net,
+            max_streams: config.max_streams_per_session,
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes a place in a live session to `addr`.
+    fn reserve(&self, addr: &Address) -> Option<(Arc<Session>, Slot)> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let sessions = sessions.get_mut(addr)?;
+        sessions.retain(|s| !s.is_closed());
+        sessions
+            .iter()
+            .find_map(|s| Some((s.clone(), s.reserve(self.max_streams)?)))
+    }
+}
+
+#[async_trait]
+impl INet for MuxNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        if let Some((session, slot)) = self.reserve(&addr) {
+            match session.open(slot).await {
+                Ok(stream) => return Ok(stream.into_dyn()),
+                // it's closed just now, a new one is opened
+                Err(e) => tracing::debug!("Multiplex session closed: {:?}", e),
+            }
+        }
+
+        let tcp = self.net.tcp_connect(ctx, addr.clone()).await?;
+        let peer_addr = tcp.peer_addr().await.ok();
+        let local_addr = tcp.local_addr().await.ok();
+        let session = Arc::new(Session::new(tcp, peer_addr, local_addr, self.idle_timeout));
+        let slot = session
+            .reserve(self.max_streams)
+            .expect("a new session has room");
+        self.sessions
+            .lock()
+            .unwrap()
+            .entry(addr)
+            .or_default()
+            .push(session.clone());
+        Ok(session.open(slot).await?.into_dyn())
+    }
+
+    async fn tcp_bind(&self, _ctx: &mut Context, _addr: Address) -> Result<TcpListener> {
+        Err(NOT_IMPLEMENTED)
+    }
+
+    async fn udp_bind(&self, _ctx: &mut Context, _addr: Address) -> Result<UdpSocket> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
+
+fn default_max_streams_per_session() -> usize {
+    64
+}
+
+fn default_idle_timeout() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct MuxNetConfig {
+    /// Streams a session takes at once, 64 by default.
+    #[serde(default = "default_max_streams_per_session")]
+    max_streams_per_session: usize,
+    /// Seconds a session without streams is kept, 60 by default.
+    #[serde(default = "default_idle_timeout")]
+    idle_timeout: u64,
+
+    #[serde(default)]
+    net: NetRef,
+}
+
+impl NetFactory for MuxNet {
+    const NAME: &'static str = "multiplex";
+    type Config = MuxNetConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        MuxNet::new(config.net.try_net()?, config)
+    }
+}
+
+pub fn init(registry: &mut Registry) -> Result<()> {
+    registry.add_net::<MuxNet>();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtin::local::{LocalConfig, LocalNet};
+    use crate::tests::get_registry;
+    use futures::{future::select, io::AsyncWriteExt as _, pin_mut, AsyncReadExt as _};
+    use rd_interface::IntoAddress;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net,
+        sync::Notify,
+    };
+
+    /// A yamux server echoing each stream. It counts the connections, and
+    /// drops them all when `kill` is notified.
+    async fn spawn_mux_server() -> (u16, Arc<AtomicUsize>, Arc<Notify>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let kill = Arc::new(Notify::new());
+        let (count, killed) = (connections.clone(), kill.clone());
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+                let killed = killed.clone();
+                tokio::spawn(async move {
+                    let mut connection =
+                        Connection::new(tcp.compat(), yamux::Config::default(), Mode::Server);
+                    let serve = async {
+                        while let Some(Ok(stream)) =
+                            poll_fn(|cx| connection.poll_next_inbound(cx)).await
+                        {
+                            tokio::spawn(async move {
+                                let (reader, mut writer) = stream.split();
+                                let _ = futures::io::copy(reader, &mut writer).await;
+                                let _ = writer.close().await;
+                            });
+                        }
+                    };
+                    let killed = killed.notified();
+                    pin_mut!(serve, killed);
+                    select(serve, killed).await;
+                });
+            }
+        });
+        (port, connections, kill)
+    }
+
+    fn mux_net(config: serde_json::Value) -> Net {
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        MuxNet::new(local, serde_json::from_value(config).unwrap())
+            .unwrap()
+            .into_dyn()
+    }
+
+    async fn connect(net: &Net, port: u16) -> TcpStream {
+        net.tcp_connect(
+            &mut Context::new(),
+            ("127.0.0.1", port).into_address().unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn echo(tcp: &mut TcpStream, sent: &[u8]) {
+        tcp.write_all(sent).await.unwrap();
+        let mut received = vec![0u8; sent.len()];
+        tcp.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn test_mux_smoke() {
+        let mut registry = get_registry();
+        super::init(&mut registry).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mux_streams() {
+        let (port, connections, _) = spawn_mux_server().await;
+        let mux = mux_net(serde_json::json!({}));
+
+        let mut streams = Vec::new();
+        for i in 0..10u8 {
+            let mut tcp = connect(&mux, port).await;
+            let sent: Vec<u8> = (0..100000u32).map(|j| (j as u8) ^ i).collect();
+            tcp.write_all(&sent).await.unwrap();
+            streams.push((tcp, sent));
+        }
+        // streams don't mix
+        for (mut tcp, sent) in streams {
+            let mut received = vec![0u8; sent.len()];
+            tcp.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, sent);
+
+            tcp.shutdown().await.unwrap();
+            let mut rest = Vec::new();
+            tcp.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mux_max_streams() {
+        let (port, connections, _) = spawn_mux_server().await;
+        let mux = mux_net(serde_json::json!({ "max_streams_per_session": 2 }));
+
+        let mut streams = Vec::new();
+        for i in 0..5u8 {
+            let mut tcp = connect(&mux, port).await;
+            echo(&mut tcp, &[i; 10]).await;
+            streams.push(tcp);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+        // places are given back
+        streams.clear();
+        let mut tcp = connect(&mux, port).await;
+        echo(&mut tcp, b"again").await;
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_mux_session_dies() {
+        let (port, connections, kill) = spawn_mux_server().await;
+        let mux = mux_net(serde_json::json!({}));
+
+        let mut tcp = connect(&mux, port).await;
+        echo(&mut tcp, b"hello").await;
+        kill.notify_waiters();
+        let mut rest = Vec::new();
+        let _ = tcp.read_to_end(&mut rest).await;
+        assert!(rest.is_empty());
+
+        let mut tcp = connect(&mux, port).await;
+        echo(&mut tcp, b"hello").await;
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mux_idle_timeout() {
+        let (port, connections, _) = spawn_mux_server().await;
+        let mux = mux_net(serde_json::json!({ "idle_timeout": 0 }));
+
+        let mut tcp = connect(&mux, port).await;
+        echo(&mut tcp, b"hello").await;
+        drop(tcp);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut tcp = connect(&mux, port).await;
+        echo(&mut tcp, b"hello").await;
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+}
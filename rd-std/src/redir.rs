@@ -14,20 +14,26 @@ mod linux {
         async_trait,
         registry::ServerFactory,
         schemars::{self, JsonSchema},
-        util::connect_tcp,
+        util::{connect_tcp, StopSignal},
         Context, IServer, IntoAddress, IntoDyn, Net, Result,
     };
     use serde_derive::Deserialize;
     use tokio::net::{TcpListener, TcpStream};
+    use tracing::Instrument;
 
     #[derive(Debug, Deserialize, JsonSchema)]
     pub struct RedirServerConfig {
         bind: String,
+        /// Sniffs the host the client connects to for the `host` rule. Only
+        /// for protocols where the client speaks first, like TLS and HTTP.
+        #[serde(default)]
+        sniff: bool,
     }
 
     pub struct RedirServer {
         cfg: RedirServerConfig,
         net: Net,
+        stop: StopSignal,
     }
 
     #[async_trait]
@@ -36,32 +42,57 @@ mod linux {
             let listener = TcpListener::bind(&self.cfg.bind).await?;
             self.serve_listener(listener).await
         }
+
+        async fn stop(&self) -> Result<()> {
+            self.stop.stop();
+            Ok(())
+        }
     }
 
     impl RedirServer {
         pub fn new(cfg: RedirServerConfig, net: Net) -> Self {
-            RedirServer { cfg, net }
+            RedirServer {
+                cfg,
+                net,
+                stop: StopSignal::new(),
+            }
         }
 
         pub async fn serve_listener(&self, listener: TcpListener) -> Result<()> {
             loop {
-                let (socket, addr) = listener.accept().await?;
+                let (socket, addr) = match self.stop.until(listener.accept()).await {
+                    Some(r) => r?,
+                    None => return Ok(()),
+                };
                 let net = self.net.clone();
-                let _ = tokio::spawn(async move {
-                    if let Err(e) = Self::serve_connection(net, socket, addr).await {
-                        tracing::error!("Error when serve_connection: {:?}", e);
+                let sniff = self.cfg.sniff;
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = Self::serve_connection(net, socket, addr, sniff).await {
+                            tracing::error!("Error when serve_connection: {:?}", e);
+                        }
                     }
-                });
+                    .instrument(tracing::info_span!("serve_connection", src = %addr)),
+                );
             }
         }
 
-        async fn serve_connection(net: Net, socket: TcpStream, addr: SocketAddr) -> Result<()> {
+        async fn serve_connection(
+            net: Net,
+            socket: TcpStream,
+            addr: SocketAddr,
+            sniff: bool,
+        ) -> Result<()> {
             let target = socket.origin_addr()?;
-
-            let target_tcp = net
-                .tcp_connect(&mut Context::from_socketaddr(addr), target.into_address()?)
-                .await?;
+            let mut ctx = Context::from_socketaddr(addr);
             let socket = CompatTcp(socket).into_dyn();
+            let socket = if sniff {
+                crate::sniff::sniff(socket, &mut ctx).await?
+            } else {
+                socket
+            };
+
+            let target_tcp = net.tcp_connect(&mut ctx, target.into_address()?).await?;
 
             connect_tcp(socket, target_tcp).await?;
 
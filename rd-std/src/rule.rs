@@ -1,8 +1,15 @@
 mod any;
+mod composite;
 pub mod config;
 mod domain;
+mod geo_prefer;
+mod geoip;
+mod host;
 mod ip_cidr;
 mod matcher;
+mod port;
+mod prefix;
+mod rewrite;
 mod rule_net;
 mod udp;
 
@@ -18,7 +25,29 @@ impl NetFactory for rule_net::RuleNet {
     }
 }
 
+impl NetFactory for rewrite::RewriteNet {
+    const NAME: &'static str = "rewrite";
+    type Config = config::RewriteConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        rewrite::RewriteNet::new(config)
+    }
+}
+
+impl NetFactory for geo_prefer::GeoPreferNet {
+    const NAME: &'static str = "geo_prefer";
+    type Config = config::GeoPreferConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        geo_prefer::GeoPreferNet::new(config)
+    }
+}
+
 pub fn init(registry: &mut Registry) -> Result<()> {
     registry.add_net::<rule_net::RuleNet>();
+    registry.add_net::<rewrite::RewriteNet>();
+    registry.add_net::<geo_prefer::GeoPreferNet>();
     Ok(())
 }
@@ -0,0 +1,141 @@
+use super::config::{CompositeMatcher, CompositeOp};
+use super::matcher::{Matcher, MaybeAsync};
+use futures::FutureExt;
+use rd_interface::{registry::ResolveNetRef, Address};
+
+impl ResolveNetRef for CompositeMatcher {}
+
+/// Evaluates `results` in order and stops at the first one equal to `stop_on`.
+///
+/// Sync results are checked as they come. Once an async result shows up,
+/// the remaining results are awaited one by one in the returned future.
+fn short_circuit(
+    mut results: impl Iterator<Item = MaybeAsync<bool>>,
+    stop_on: bool,
+) -> MaybeAsync<bool> {
+    while let Some(result) = results.next() {
+        match result {
+            MaybeAsync::Sync { value } => {
+                if value == Some(stop_on) {
+                    return stop_on.into();
+                }
+            }
+            future @ MaybeAsync::Async { .. } => {
+                let pending = std::iter::once(future).chain(results).collect::<Vec<_>>();
+                return MaybeAsync::Async {
+                    future: async move {
+                        for result in pending {
+                            if result.await == stop_on {
+                                return stop_on;
+                            }
+                        }
+                        !stop_on
+                    }
+                    .boxed(),
+                };
+            }
+        }
+    }
+    (!stop_on).into()
+}
+
+impl Matcher for CompositeMatcher {
+    fn match_rule(&self, ctx: &rd_interface::Context, addr: &Address) -> MaybeAsync<bool> {
+        match &self.op {
+            CompositeOp::All(matchers) => {
+                short_circuit(matchers.iter().map(|m| m.match_rule(ctx, addr)), false)
+            }
+            CompositeOp::Any(matchers) => {
+                short_circuit(matchers.iter().map(|m| m.match_rule(ctx, addr)), true)
+            }
+            CompositeOp::Not(matcher) => match matcher.match_rule(ctx, addr) {
+                MaybeAsync::Sync { value } => {
+                    (!value.expect("Don't poll twice on MaybeAsync")).into()
+                }
+                MaybeAsync::Async { future } => MaybeAsync::Async {
+                    future: future.map(|v| !v).boxed(),
+                },
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rd_interface::{Context, IntoAddress};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    fn matcher(config: &str) -> CompositeMatcher {
+        serde_json::from_str(config).unwrap()
+    }
+
+    async fn is_match(matcher: &CompositeMatcher, addr: &str) -> bool {
+        matcher
+            .match_rule(&Context::new(), &addr.into_address().unwrap())
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_composite_two_level() {
+        let m = matcher(
+            r#"{
+                "any": [
+                    {
+                        "type": "composite",
+                        "all": [
+                            { "type": "domain", "method": "suffix", "domain": "example.com" },
+                            { "type": "port", "port": "443" }
+                        ]
+                    },
+                    {
+                        "type": "composite",
+                        "not": { "type": "port", "port": "1-1024" }
+                    }
+                ]
+            }"#,
+        );
+
+        assert!(is_match(&m, "www.example.com:443").await);
+        assert!(!is_match(&m, "www.example.com:80").await);
+        assert!(!is_match(&m, "example.org:443").await);
+        assert!(is_match(&m, "example.org:8080").await);
+        assert!(is_match(&m, "1.2.3.4:8080").await);
+    }
+
+    fn delayed(value: bool, polled: &Arc<AtomicUsize>) -> MaybeAsync<bool> {
+        let polled = polled.clone();
+        MaybeAsync::Async {
+            future: async move {
+                tokio::task::yield_now().await;
+                polled.fetch_add(1, Ordering::SeqCst);
+                value
+            }
+            .boxed(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_short_circuit_async() {
+        let polled = Arc::new(AtomicUsize::new(0));
+
+        let results = vec![true.into(), delayed(false, &polled), delayed(true, &polled)];
+        assert!(!short_circuit(results.into_iter(), false).await);
+        assert_eq!(polled.load(Ordering::SeqCst), 1);
+
+        let results = vec![
+            false.into(),
+            delayed(false, &polled),
+            delayed(true, &polled),
+        ];
+        assert!(short_circuit(results.into_iter(), true).await);
+        assert_eq!(polled.load(Ordering::SeqCst), 3);
+
+        let results = vec![false.into(), true.into(), delayed(true, &polled)];
+        assert!(!short_circuit(results.into_iter(), false).await);
+        assert_eq!(polled.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,6 +1,6 @@
 use std::{fmt, str::FromStr};
 
-use super::matcher;
+use super::{domain::DomainSet, matcher};
 use rd_interface::{
     registry::{NetRef, ResolveNetRef},
     schemars::{
@@ -8,8 +8,9 @@ use rd_interface::{
         schema::{InstanceType, SchemaObject},
         JsonSchema,
     },
-    Config,
+    Arc, Config,
 };
+use serde::{Deserializer, Serializer};
 use serde_derive::{Deserialize, Serialize};
 use serde_with::rust::display_fromstr;
 use smoltcp::wire;
@@ -20,12 +21,41 @@ pub enum DomainMatcherMethod {
     Keyword,
     Suffix,
     Match,
+    Regex,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Config, JsonSchema)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(try_from = "DomainMatcherConfig")]
 pub struct DomainMatcher {
     pub method: DomainMatcherMethod,
-    pub domain: String,
+    /// One domain or a list of them, matched if any of them matches.
+    pub domain: OneOrMany<String>,
+    /// Compiled from `domain` for `method`.
+    #[serde(skip)]
+    pub set: DomainSet,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DomainMatcherConfig {
+    pub method: DomainMatcherMethod,
+    pub domain: OneOrMany<String>,
+}
+
+/// A config value that can be either a single item or a list of items.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    pub fn as_slice(&self) -> &[T] {
+        match self {
+            OneOrMany::One(v) => std::slice::from_ref(v),
+            OneOrMany::Many(v) => v,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -42,14 +72,12 @@ impl FromStr for IpCidr {
 
     /// Parse a string representation of an IP CIDR.
     fn from_str(s: &str) -> rd_interface::Result<IpCidr> {
-        match wire::Ipv4Cidr::from_str(s) {
-            Ok(cidr) => return Ok(IpCidr(wire::IpCidr::Ipv4(cidr))),
-            Err(_) => (),
+        if let Ok(cidr) = wire::Ipv4Cidr::from_str(s) {
+            return Ok(IpCidr(wire::IpCidr::Ipv4(cidr)));
         }
 
-        match wire::Ipv6Cidr::from_str(s) {
-            Ok(cidr) => return Ok(IpCidr(wire::IpCidr::Ipv6(cidr))),
-            Err(_) => (),
+        if let Ok(cidr) = wire::Ipv6Cidr::from_str(s) {
+            return Ok(IpCidr(wire::IpCidr::Ipv6(cidr)));
         }
 
         Err(rd_interface::Error::Other(
@@ -58,13 +86,22 @@ impl FromStr for IpCidr {
     }
 }
 
+impl serde::Serialize for IpCidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        display_fromstr::serialize(self, serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for IpCidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        display_fromstr::deserialize(deserializer)
+    }
+}
+
+/// Matches the destination IP against one or more CIDRs, e.g. `"10.0.0.0/8"`.
 #[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct IPMatcher {
-    #[serde(
-        serialize_with = "display_fromstr::serialize",
-        deserialize_with = "display_fromstr::deserialize"
-    )]
-    pub ipcidr: IpCidr,
+    pub ipcidr: OneOrMany<IpCidr>,
 }
 
 impl JsonSchema for IpCidr {
@@ -82,14 +119,140 @@ impl JsonSchema for IpCidr {
     }
 }
 
+/// A MaxMind GeoIP database, opened once when the config is loaded.
+#[derive(Clone)]
+pub struct GeoIpDb {
+    pub path: String,
+    pub reader: Arc<maxminddb::Reader<Vec<u8>>>,
+}
+
+impl fmt::Debug for GeoIpDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("GeoIpDb").field(&self.path).finish()
+    }
+}
+
+impl serde::Serialize for GeoIpDb {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.path)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for GeoIpDb {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let path = String::deserialize(deserializer)?;
+        let reader = maxminddb::Reader::open_readfile(&path).map_err(|e| {
+            serde::de::Error::custom(format!("Failed to open geoip db {}: {}", path, e))
+        })?;
+        Ok(GeoIpDb {
+            path,
+            reader: Arc::new(reader),
+        })
+    }
+}
+
+impl JsonSchema for GeoIpDb {
+    fn schema_name() -> String {
+        "GeoIpDb".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        String::json_schema(gen)
+    }
+}
+
+/// Matches the country of the destination IP, looked up in a `.mmdb` file.
+/// Domains are resolved first.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct GeoIpMatcher {
+    pub db: GeoIpDb,
+    /// ISO country codes, e.g. `"CN"`.
+    pub country: OneOrMany<String>,
+}
+
+/// An inclusive port range, written as `"80"` or `"1000-2000"`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl JsonSchema for PortRange {
+    fn schema_name() -> String {
+        "PortRange".to_string()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        String::json_schema(gen)
+    }
+}
+
+/// Matches the destination port against one or more ports or ranges.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PortMatcher {
+    pub port: OneOrMany<PortRange>,
+}
+
+/// Matches the host sniffed from the connection, see [`crate::sniff`].
+/// Useful when the client connects by IP.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct HostMatcher {
+    #[serde(flatten)]
+    pub domain: DomainMatcher,
+}
+
+/// Matches the first bytes of the connection peeked by [`crate::sniff`],
+/// given in `hex` or `base64`, e.g. `{ "type": "prefix", "hex": "cafebabe" }`.
+/// At most [`crate::sniff::MAX_PEEKED_SIZE`] bytes are peeked.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(try_from = "PrefixMatcherConfig")]
+pub struct PrefixMatcher {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base64: Option<String>,
+    /// Decoded from `hex` or `base64`.
+    #[serde(skip)]
+    pub prefix: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PrefixMatcherConfig {
+    #[serde(default)]
+    pub hex: Option<String>,
+    #[serde(default)]
+    pub base64: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Config, JsonSchema)]
 pub struct AnyMatcher {}
 
+/// Combines other matchers, e.g. `{ "type": "composite", "all": [...] }`.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct CompositeMatcher {
+    #[serde(flatten)]
+    pub op: CompositeOp,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CompositeOp {
+    All(Vec<Matcher>),
+    Any(Vec<Matcher>),
+    Not(Box<Matcher>),
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum Matcher {
     Domain(DomainMatcher),
     IpCidr(IPMatcher),
+    GeoIp(GeoIpMatcher),
+    Port(PortMatcher),
+    Host(HostMatcher),
+    Prefix(PrefixMatcher),
+    Composite(CompositeMatcher),
     Any(AnyMatcher),
 }
 
@@ -103,10 +266,56 @@ pub struct RuleItem {
 #[derive(Debug, Serialize, Deserialize, Clone, Config, JsonSchema)]
 pub struct RuleConfig {
     pub rule: Vec<RuleItem>,
+    /// The target when no rule matches. Without it, such connections are
+    /// refused.
+    #[serde(default)]
+    pub default: Option<NetRef>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Config, JsonSchema)]
+pub struct RewriteItem {
+    /// The new destination. `"host:port"` replaces the whole address, a
+    /// bare `"host"` keeps the port.
+    pub to: String,
+    #[serde(flatten)]
+    pub matcher: Matcher,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Config, JsonSchema)]
+pub struct RewriteConfig {
+    #[serde(default)]
+    pub net: NetRef,
+    /// Only the first matched item is applied.
+    pub rewrite: Vec<RewriteItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Config, JsonSchema)]
+pub struct GeoPreferConfig {
+    #[serde(default)]
+    pub net: NetRef,
+    /// The database and the countries preferred.
+    #[serde(flatten)]
+    pub prefer: GeoIpMatcher,
 }
 
 impl ResolveNetRef for Matcher {}
 
+impl Matcher {
+    /// The `type` of the matcher in config.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Matcher::Domain(_) => "domain",
+            Matcher::IpCidr(_) => "ipcidr",
+            Matcher::GeoIp(_) => "geoip",
+            Matcher::Port(_) => "port",
+            Matcher::Host(_) => "host",
+            Matcher::Prefix(_) => "prefix",
+            Matcher::Composite(_) => "composite",
+            Matcher::Any(_) => "any",
+        }
+    }
+}
+
 impl matcher::Matcher for Matcher {
     fn match_rule(
         &self,
@@ -116,6 +325,11 @@ impl matcher::Matcher for Matcher {
         match self {
             Matcher::Domain(i) => i.match_rule(ctx, addr),
             Matcher::IpCidr(i) => i.match_rule(ctx, addr),
+            Matcher::GeoIp(i) => i.match_rule(ctx, addr),
+            Matcher::Port(i) => i.match_rule(ctx, addr),
+            Matcher::Host(i) => i.match_rule(ctx, addr),
+            Matcher::Prefix(i) => i.match_rule(ctx, addr),
+            Matcher::Composite(i) => i.match_rule(ctx, addr),
             Matcher::Any(i) => i.match_rule(ctx, addr),
         }
     }
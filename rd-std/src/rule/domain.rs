@@ -1,9 +1,16 @@
-use std::convert::TryFrom;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    iter::FromIterator,
+};
 
-use super::config::{DomainMatcher, DomainMatcherMethod as Method};
+use super::config::{DomainMatcher, DomainMatcherConfig, DomainMatcherMethod as Method};
 use super::matcher::{Matcher, MaybeAsync};
 use anyhow::Result;
-use rd_interface::Address;
+use rd_interface::{registry::ResolveNetRef, Address};
+use regex::RegexSet;
+
+impl ResolveNetRef for DomainMatcher {}
 
 impl TryFrom<String> for Method {
     type Error = anyhow::Error;
@@ -13,18 +20,106 @@ impl TryFrom<String> for Method {
             "keyword" => Method::Keyword,
             "suffix" => Method::Suffix,
             "match" => Method::Match,
+            "regex" => Method::Regex,
             _ => return Err(anyhow::anyhow!("Unsupported method: {}", value)),
         })
     }
 }
 
-impl DomainMatcher {
+impl TryFrom<DomainMatcherConfig> for DomainMatcher {
+    type Error = anyhow::Error;
+
+    fn try_from(
+        DomainMatcherConfig { method, domain }: DomainMatcherConfig,
+    ) -> Result<Self, Self::Error> {
+        let domains = domain.as_slice().iter();
+        let set = match method {
+            Method::Keyword => DomainSet::Keyword(domains.cloned().collect()),
+            Method::Suffix => DomainSet::Suffix(domains.collect()),
+            Method::Match => DomainSet::Match(domains.cloned().collect()),
+            Method::Regex => DomainSet::Regex(RegexSet::new(domains)?),
+        };
+        Ok(DomainMatcher {
+            method,
+            domain,
+            set,
+        })
+    }
+}
+
+/// The domains of a [DomainMatcher], compiled so that matching doesn't get
+/// slower with the size of the list.
+#[derive(Debug, Clone)]
+pub enum DomainSet {
+    Keyword(Vec<String>),
+    Suffix(SuffixTrie),
+    Match(HashSet<String>),
+    Regex(RegexSet),
+}
+
+impl Default for DomainSet {
+    fn default() -> Self {
+        DomainSet::Keyword(Vec::new())
+    }
+}
+
+impl DomainSet {
     fn test(&self, domain: &str) -> bool {
-        match self.method {
-            Method::Keyword => domain.contains(&self.domain),
-            Method::Match => domain == &self.domain,
-            Method::Suffix => domain.ends_with(&self.domain),
+        match self {
+            DomainSet::Keyword(keywords) => keywords.iter().any(|k| domain.contains(k.as_str())),
+            DomainSet::Suffix(trie) => trie.test(domain),
+            DomainSet::Match(set) => set.contains(domain),
+            DomainSet::Regex(set) => set.is_match(domain),
+        }
+    }
+}
+
+/// A trie of the suffixes, walked from the last byte of the domain.
+#[derive(Debug, Clone, Default)]
+pub struct SuffixTrie {
+    children: HashMap<u8, SuffixTrie>,
+    end: bool,
+}
+
+impl SuffixTrie {
+    fn insert(&mut self, suffix: &str) {
+        let node = suffix
+            .bytes()
+            .rev()
+            .fold(self, |node, b| node.children.entry(b).or_default());
+        node.end = true;
+    }
+    fn test(&self, domain: &str) -> bool {
+        let mut node = self;
+        if node.end {
+            return true;
+        }
+        for b in domain.bytes().rev() {
+            node = match node.children.get(&b) {
+                Some(n) => n,
+                None => return false,
+            };
+            if node.end {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl<'a> FromIterator<&'a String> for SuffixTrie {
+    fn from_iter<I: IntoIterator<Item = &'a String>>(iter: I) -> Self {
+        let mut trie = SuffixTrie::default();
+        for suffix in iter {
+            trie.insert(suffix);
         }
+        trie
+    }
+}
+
+impl DomainMatcher {
+    pub(super) fn test(&self, domain: &str) -> bool {
+        self.set.test(domain)
     }
 }
 
@@ -38,3 +133,75 @@ impl Matcher for DomainMatcher {
         .into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rd_interface::{Context, IntoAddress};
+
+    fn matcher(method: &str, domain: &str) -> DomainMatcher {
+        let config = serde_json::json!({ "method": method, "domain": domain });
+        serde_json::from_value(config).unwrap()
+    }
+
+    async fn is_match(matcher: &DomainMatcher, addr: &str) -> bool {
+        matcher
+            .match_rule(&Context::new(), &addr.into_address().unwrap())
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_regex_unanchored() {
+        let m = matcher("regex", r"goo+gle");
+
+        assert!(is_match(&m, "www.google.com:443").await);
+        assert!(is_match(&m, "gooogle.cn:443").await);
+        assert!(!is_match(&m, "gogle.com:443").await);
+    }
+
+    #[tokio::test]
+    async fn test_regex_anchored() {
+        let m = matcher("regex", r"^(.+\.)?example\.com$");
+
+        assert!(is_match(&m, "example.com:443").await);
+        assert!(is_match(&m, "www.example.com:443").await);
+        assert!(!is_match(&m, "example.com.evil:443").await);
+        assert!(!is_match(&m, "notexample.com:443").await);
+    }
+
+    #[test]
+    fn test_regex_invalid() {
+        let config = serde_json::json!({ "method": "regex", "domain": "(unclosed" });
+        assert!(serde_json::from_value::<DomainMatcher>(config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_suffix_set() {
+        let config = serde_json::json!({
+            "method": "suffix",
+            "domain": ["google.com", ".twimg.com", "t.co"],
+        });
+        let m: DomainMatcher = serde_json::from_value(config).unwrap();
+
+        assert!(is_match(&m, "google.com:443").await);
+        assert!(is_match(&m, "a.b.c.google.com:443").await);
+        assert!(is_match(&m, "pbs.twimg.com:443").await);
+        assert!(is_match(&m, "t.co:443").await);
+        assert!(!is_match(&m, "twimg.com:443").await);
+        assert!(!is_match(&m, "google.com.hk:443").await);
+        assert!(!is_match(&m, "example.com:443").await);
+    }
+
+    #[tokio::test]
+    async fn test_match_set() {
+        let config = serde_json::json!({
+            "method": "match",
+            "domain": ["example.com", "example.org"],
+        });
+        let m: DomainMatcher = serde_json::from_value(config).unwrap();
+
+        assert!(is_match(&m, "example.com:443").await);
+        assert!(is_match(&m, "example.org:80").await);
+        assert!(!is_match(&m, "www.example.com:443").await);
+    }
+}
@@ -0,0 +1,125 @@
+use super::config::{self, GeoIpMatcher};
+use rd_interface::{
+    async_trait, Address, Context, Error, INet, Net, Result, TcpListener, TcpStream, UdpSocket,
+};
+use std::net::SocketAddr;
+
+/// Resolves domains with `net` and connects to the addresses in the
+/// preferred countries first, then to the rest in the order resolved.
+pub struct GeoPreferNet {
+    net: Net,
+    prefer: GeoIpMatcher,
+}
+
+impl GeoPreferNet {
+    pub fn new(config: config::GeoPreferConfig) -> Result<GeoPreferNet> {
+        Ok(GeoPreferNet {
+            net: config.net.try_net()?,
+            prefer: config.prefer,
+        })
+    }
+    /// Moves the preferred addresses to the front, keeping the order
+    /// otherwise.
+    fn sort(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (mut preferred, rest): (Vec<_>, Vec<_>) =
+            addrs.into_iter().partition(|a| self.prefer.test(a.ip()));
+        preferred.extend(rest);
+        preferred
+    }
+}
+
+#[async_trait]
+impl INet for GeoPreferNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        if !matches!(addr, Address::Domain(..)) {
+            return self.net.tcp_connect(ctx, addr).await;
+        }
+        let addrs = match self.lookup_host(&addr).await {
+            Ok(addrs) => addrs,
+            // `net` resolves it itself
+            Err(Error::NotImplemented) => return self.net.tcp_connect(ctx, addr).await,
+            Err(e) => return Err(e),
+        };
+        let addrs: Vec<Address> = addrs.into_iter().map(Into::into).collect();
+        self.net.tcp_connect_any(ctx, &addrs).await
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        self.net.tcp_bind(ctx, addr).await
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        self.net.udp_bind(ctx, addr).await
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        let addrs = self.net.lookup_host(addr).await?;
+        Ok(self.sort(addrs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rule::geoip::tests::write_db, tests::RecordNet};
+    use rd_interface::{
+        registry::{NetMap, ResolveNetRef},
+        IntoAddress, IntoDyn,
+    };
+    use std::sync::Arc;
+
+    /// Resolves every domain to addresses in several countries.
+    fn mixed_net() -> RecordNet {
+        RecordNet::resolving(&["192.0.2.1", "81.2.69.142", "198.51.100.1"])
+    }
+
+    #[tokio::test]
+    async fn test_geo_prefer() {
+        let path = write_db("rd-std-geo-prefer.mmdb", [81, 2, 69, 0], 24, "GB");
+        let inner = Arc::new(mixed_net());
+        let mut nets = NetMap::new();
+        nets.insert("mixed".to_string(), inner.clone());
+
+        let mut config: config::GeoPreferConfig = serde_json::from_value(serde_json::json!({
+            "net": "mixed",
+            "db": path,
+            "country": "GB",
+        }))
+        .unwrap();
+        config.resolve(&nets).unwrap();
+        let net = GeoPreferNet::new(config).unwrap();
+
+        let addr = "example.com:443".into_address().unwrap();
+        let addrs = net.lookup_host(&addr).await.unwrap();
+        assert_eq!(addrs[0], SocketAddr::from(([81, 2, 69, 142], 443)));
+
+        assert!(net.tcp_connect(&mut Context::new(), addr).await.is_err());
+        assert_eq!(
+            inner.take_connected(),
+            ["81.2.69.142:443", "192.0.2.1:443", "198.51.100.1:443"]
+        );
+
+        // addresses go as is
+        let addr = "192.0.2.1:80".into_address().unwrap();
+        assert!(net.tcp_connect(&mut Context::new(), addr).await.is_err());
+        assert_eq!(inner.take_connected(), ["192.0.2.1:80"]);
+    }
+
+    #[test]
+    fn test_geo_prefer_keeps_order() {
+        let path = write_db("rd-std-geo-prefer-order.mmdb", [81, 2, 69, 0], 24, "GB");
+        let config = serde_json::json!({ "db": path, "country": "GB" });
+        let net = GeoPreferNet {
+            net: mixed_net().into_dyn(),
+            prefer: serde_json::from_value(config).unwrap(),
+        };
+        let addrs = vec![
+            SocketAddr::from(([192, 0, 2, 2], 1)),
+            SocketAddr::from(([81, 2, 69, 1], 1)),
+            SocketAddr::from(([192, 0, 2, 1], 1)),
+            SocketAddr::from(([81, 2, 69, 2], 1)),
+        ];
+        let sorted = net.sort(addrs.clone());
+        assert_eq!(sorted, [addrs[1], addrs[3], addrs[0], addrs[2]]);
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use super::config::GeoIpMatcher;
+use super::matcher::{Matcher, MaybeAsync};
+use futures::FutureExt;
+use maxminddb::geoip2;
+use rd_interface::{registry::ResolveNetRef, Address};
+
+impl ResolveNetRef for GeoIpMatcher {}
+
+async fn lookup_host(domain: String, port: u16) -> io::Result<SocketAddr> {
+    tokio::net::lookup_host((domain.as_ref(), port))
+        .await?
+        .next()
+        .ok_or_else(|| io::ErrorKind::AddrNotAvailable.into())
+}
+
+impl GeoIpMatcher {
+    /// Whether `address` is in one of the countries.
+    pub(super) fn test(&self, address: IpAddr) -> bool {
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            v4 => v4,
+        };
+        let reader = &self.db.reader;
+        // an IPv4-only database can't answer for v6 addresses.
+        if address.is_ipv6() && reader.metadata.ip_version != 6 {
+            return false;
+        }
+        let iso_code = match reader.lookup::<geoip2::Country>(address) {
+            Ok(geoip2::Country {
+                country: Some(country),
+                ..
+            }) => country.iso_code,
+            _ => None,
+        };
+        match iso_code {
+            Some(code) => self
+                .country
+                .as_slice()
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(code)),
+            None => false,
+        }
+    }
+}
+
+impl Matcher for GeoIpMatcher {
+    fn match_rule(&self, _ctx: &rd_interface::Context, addr: &Address) -> MaybeAsync<bool> {
+        match addr {
+            Address::SocketAddr(addr) => self.test(addr.ip()).into(),
+            Address::Domain(_, _) => {
+                let matcher = self.clone();
+                let addr = addr.clone();
+                MaybeAsync::Async {
+                    future: async move {
+                        match addr.resolve(lookup_host).await {
+                            Ok(addr) => matcher.test(addr.ip()),
+                            Err(_) => false,
+                        }
+                    }
+                    .boxed(),
+                }
+            }
+            Address::Unix(_) => false.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use rd_interface::{Context, IntoAddress};
+    use std::path::PathBuf;
+
+    fn control(ty: u8, size: usize) -> u8 {
+        (ty << 5) | size as u8
+    }
+
+    fn string(buf: &mut Vec<u8>, s: &str) {
+        buf.push(control(2, s.len()));
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    fn uint(buf: &mut Vec<u8>, ty: u8, v: &[u8]) {
+        buf.push(control(ty, v.len()));
+        buf.extend_from_slice(v);
+    }
+
+    /// Writes a minimal IPv4 database mapping a single network to `iso_code`.
+    pub(in crate::rule) fn write_db(
+        name: &str,
+        network: [u8; 4],
+        prefix_len: usize,
+        iso_code: &str,
+    ) -> PathBuf {
+        let node_count = prefix_len as u32;
+        let mut buf = Vec::new();
+
+        // search tree, 24 bit records. The last node points to the only data record.
+        for i in 0..prefix_len {
+            let bit = (network[i / 8] >> (7 - i % 8)) & 1;
+            let next = if i + 1 < prefix_len {
+                i as u32 + 1
+            } else {
+                node_count + 16
+            };
+            let (left, right) = if bit == 0 {
+                (next, node_count)
+            } else {
+                (node_count, next)
+            };
+            buf.extend_from_slice(&left.to_be_bytes()[1..]);
+            buf.extend_from_slice(&right.to_be_bytes()[1..]);
+        }
+        buf.extend_from_slice(&[0; 16]);
+
+        // data section: { "country": { "iso_code": iso_code } }
+        buf.push(control(7, 1));
+        string(&mut buf, "country");
+        buf.push(control(7, 1));
+        string(&mut buf, "iso_code");
+        string(&mut buf, iso_code);
+
+        buf.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+        buf.push(control(7, 9));
+        string(&mut buf, "binary_format_major_version");
+        uint(&mut buf, 5, &2u16.to_be_bytes());
+        string(&mut buf, "binary_format_minor_version");
+        uint(&mut buf, 5, &0u16.to_be_bytes());
+        string(&mut buf, "build_epoch");
+        // uint64 is an extended type
+        buf.extend_from_slice(&[control(0, 8), 9 - 7]);
+        buf.extend_from_slice(&0u64.to_be_bytes());
+        string(&mut buf, "database_type");
+        string(&mut buf, "Test-Country");
+        string(&mut buf, "description");
+        buf.push(control(7, 0));
+        string(&mut buf, "ip_version");
+        uint(&mut buf, 5, &4u16.to_be_bytes());
+        string(&mut buf, "languages");
+        // empty array, also an extended type
+        buf.extend_from_slice(&[control(0, 0), 11 - 7]);
+        string(&mut buf, "node_count");
+        uint(&mut buf, 6, &node_count.to_be_bytes());
+        string(&mut buf, "record_size");
+        uint(&mut buf, 5, &24u16.to_be_bytes());
+
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, buf).unwrap();
+        path
+    }
+
+    fn matcher(path: &PathBuf, country: &str) -> GeoIpMatcher {
+        let config = serde_json::json!({ "db": path, "country": country });
+        serde_json::from_value(config).unwrap()
+    }
+
+    async fn is_match(matcher: &GeoIpMatcher, addr: Address) -> bool {
+        matcher.match_rule(&Context::new(), &addr).await
+    }
+
+    #[tokio::test]
+    async fn test_geoip_known_ip() {
+        let path = write_db("rd-std-geoip-known.mmdb", [81, 2, 69, 0], 24, "GB");
+        let gb = matcher(&path, "gb");
+        let cn = matcher(&path, "CN");
+
+        let addr = "81.2.69.142:443".into_address().unwrap();
+        assert!(is_match(&gb, addr.clone()).await);
+        assert!(!is_match(&cn, addr).await);
+
+        let mapped = "[::ffff:81.2.69.142]:443".into_address().unwrap();
+        assert!(is_match(&gb, mapped).await);
+
+        // domains go through the async resolve path
+        let domain = Address::Domain("81.2.69.142".to_string(), 443);
+        assert!(is_match(&gb, domain).await);
+    }
+
+    #[tokio::test]
+    async fn test_geoip_unmatched() {
+        let path = write_db("rd-std-geoip-unmatched.mmdb", [81, 2, 69, 0], 24, "GB");
+        let gb = matcher(&path, "GB");
+
+        let private = "192.168.1.1:443".into_address().unwrap();
+        assert!(!is_match(&gb, private).await);
+
+        let v6 = "[2001:db8::1]:443".into_address().unwrap();
+        assert!(!is_match(&gb, v6).await);
+    }
+
+    #[test]
+    fn test_geoip_missing_db() {
+        let config = serde_json::json!({ "db": "/nonexistent.mmdb", "country": "GB" });
+        assert!(serde_json::from_value::<GeoIpMatcher>(config).is_err());
+    }
+}
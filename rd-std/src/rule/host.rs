@@ -0,0 +1,45 @@
+use super::config::HostMatcher;
+use super::matcher::{Matcher, MaybeAsync};
+use rd_interface::{context::common_field::SniffedHost, registry::ResolveNetRef, Address};
+
+impl ResolveNetRef for HostMatcher {}
+
+impl Matcher for HostMatcher {
+    fn match_rule(&self, ctx: &rd_interface::Context, _addr: &Address) -> MaybeAsync<bool> {
+        match ctx.get_common::<SniffedHost>() {
+            Ok(SniffedHost { host }) => self.domain.test(&host),
+            // nothing is sniffed, pass it.
+            Err(_) => false,
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rd_interface::{Context, IntoAddress};
+
+    #[tokio::test]
+    async fn test_host_matcher() {
+        let config = serde_json::json!({ "method": "suffix", "domain": "example.com" });
+        let matcher: HostMatcher = serde_json::from_value(config).unwrap();
+        // the destination is an IP, only the sniffed host is matched
+        let addr = "1.2.3.4:443".into_address().unwrap();
+
+        let mut ctx = Context::new();
+        assert!(!matcher.match_rule(&ctx, &addr).await);
+
+        ctx.insert_common(SniffedHost {
+            host: "www.example.com".to_string(),
+        })
+        .unwrap();
+        assert!(matcher.match_rule(&ctx, &addr).await);
+
+        ctx.insert_common(SniffedHost {
+            host: "example.org".to_string(),
+        })
+        .unwrap();
+        assert!(!matcher.match_rule(&ctx, &addr).await);
+    }
+}
@@ -1,16 +1,53 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
-use super::config::IPMatcher;
+use super::config::{IPMatcher, IpCidr};
 use super::matcher::{Matcher, MaybeAsync};
 use rd_interface::{registry::ResolveNetRef, Address};
-use smoltcp::wire::IpAddress;
+use smoltcp::wire;
 
 impl ResolveNetRef for IPMatcher {}
+impl ResolveNetRef for IpCidr {}
+
+fn prefix_mask(bits: u32, prefix_len: u8) -> u128 {
+    // shifting by the full width is not allowed
+    match bits - prefix_len as u32 {
+        s if s >= 128 => 0,
+        s => (u128::MAX << s) & (u128::MAX >> (128 - bits)),
+    }
+}
+
+impl IpCidr {
+    pub(crate) fn contains(&self, address: IpAddr) -> bool {
+        match (&self.0, address) {
+            (wire::IpCidr::Ipv4(cidr), IpAddr::V4(ip)) => {
+                let mask = prefix_mask(32, cidr.prefix_len());
+                let network = u32::from_be_bytes(cidr.address().0) as u128;
+                (network & mask) == (u32::from(ip) as u128 & mask)
+            }
+            (wire::IpCidr::Ipv6(cidr), IpAddr::V6(ip)) => {
+                let mask = prefix_mask(128, cidr.prefix_len());
+                let network = u128::from_be_bytes(cidr.address().0);
+                (network & mask) == (u128::from(ip) & mask)
+            }
+            _ => false,
+        }
+    }
+}
 
 impl IPMatcher {
-    fn test(&self, address: impl Into<IpAddress>) -> bool {
-        let address: IpAddress = address.into();
-        return self.ipcidr.0.contains_addr(&address);
+    fn contains(&self, address: IpAddr) -> bool {
+        self.ipcidr
+            .as_slice()
+            .iter()
+            .any(|cidr| cidr.contains(address))
+    }
+    fn test(&self, address: IpAddr) -> bool {
+        // a v4-mapped v6 address also matches the v4 ranges.
+        let mapped = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4),
+            IpAddr::V4(_) => None,
+        };
+        self.contains(address) || mapped.map(|v4| self.contains(v4)).unwrap_or(false)
     }
 }
 
@@ -23,7 +60,48 @@ impl Matcher for IPMatcher {
                 Ok(addr) => self.test(addr.ip()),
                 Err(_) => false,
             },
+            Address::Unix(_) => false,
         }
         .into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rd_interface::{Context, IntoAddress};
+
+    fn matcher(config: &str) -> IPMatcher {
+        serde_json::from_str(config).unwrap()
+    }
+
+    async fn is_match(matcher: &IPMatcher, addr: &str) -> bool {
+        matcher
+            .match_rule(&Context::new(), &addr.into_address().unwrap())
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_ip_cidr_list() {
+        let m = matcher(r#"{ "ipcidr": ["10.0.0.0/8", "fe80::/10"] }"#);
+
+        assert!(is_match(&m, "10.0.0.0:80").await);
+        assert!(is_match(&m, "10.255.255.255:80").await);
+        assert!(!is_match(&m, "11.0.0.0:80").await);
+        assert!(!is_match(&m, "9.255.255.255:80").await);
+
+        assert!(is_match(&m, "[fe80::1]:80").await);
+        assert!(is_match(&m, "[febf:ffff::1]:80").await);
+        assert!(!is_match(&m, "[fec0::1]:80").await);
+
+        assert!(!is_match(&m, "example.com:80").await);
+    }
+
+    #[tokio::test]
+    async fn test_ip_cidr_v4_mapped() {
+        let m = matcher(r#"{ "ipcidr": "192.168.0.0/16" }"#);
+
+        assert!(is_match(&m, "[::ffff:192.168.1.1]:80").await);
+        assert!(!is_match(&m, "[::ffff:192.169.0.1]:80").await);
+    }
+}
@@ -2,13 +2,8 @@ use futures::{future::BoxFuture, Future, FutureExt};
 use std::{pin, task};
 
 pub(super) enum MaybeAsync<T> {
-    Sync {
-        value: Option<T>,
-    },
-    #[allow(dead_code)]
-    Async {
-        future: BoxFuture<'static, T>,
-    },
+    Sync { value: Option<T> },
+    Async { future: BoxFuture<'static, T> },
 }
 
 impl<T> From<T> for MaybeAsync<T> {
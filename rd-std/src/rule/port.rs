@@ -0,0 +1,109 @@
+use std::convert::TryFrom;
+
+use super::config::{PortMatcher, PortRange};
+use super::matcher::{Matcher, MaybeAsync};
+use anyhow::Result;
+use rd_interface::{registry::ResolveNetRef, Address};
+
+impl ResolveNetRef for PortMatcher {}
+
+impl TryFrom<String> for PortRange {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .map_err(|_| anyhow::anyhow!("Invalid port range: {}", value))
+        };
+        let (start, end) = match value.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => {
+                let port = parse(&value)?;
+                (port, port)
+            }
+        };
+        if start > end {
+            return Err(anyhow::anyhow!(
+                "Invalid port range: {}, start is greater than end",
+                value
+            ));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        if range.start == range.end {
+            range.start.to_string()
+        } else {
+            format!("{}-{}", range.start, range.end)
+        }
+    }
+}
+
+impl PortMatcher {
+    fn test(&self, port: u16) -> bool {
+        self.port
+            .as_slice()
+            .iter()
+            .any(|range| range.start <= port && port <= range.end)
+    }
+}
+
+impl Matcher for PortMatcher {
+    fn match_rule(&self, _ctx: &rd_interface::Context, addr: &Address) -> MaybeAsync<bool> {
+        match addr.port() {
+            Some(port) => self.test(port),
+            None => false,
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rd_interface::{Context, IntoAddress};
+
+    fn matcher(config: &str) -> PortMatcher {
+        serde_json::from_str(config).unwrap()
+    }
+
+    async fn is_match(matcher: &PortMatcher, addr: &str) -> bool {
+        matcher
+            .match_rule(&Context::new(), &addr.into_address().unwrap())
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_single_port() {
+        let m = matcher(r#"{ "port": "443" }"#);
+
+        assert!(is_match(&m, "1.2.3.4:443").await);
+        assert!(is_match(&m, "example.com:443").await);
+        assert!(!is_match(&m, "1.2.3.4:80").await);
+        assert!(!is_match(&m, "example.com:4430").await);
+    }
+
+    #[tokio::test]
+    async fn test_port_range() {
+        let m = matcher(r#"{ "port": ["25", "1000-2000"] }"#);
+
+        assert!(is_match(&m, "1.2.3.4:25").await);
+        assert!(is_match(&m, "1.2.3.4:1000").await);
+        assert!(is_match(&m, "example.com:1500").await);
+        assert!(is_match(&m, "[::1]:2000").await);
+        assert!(!is_match(&m, "1.2.3.4:999").await);
+        assert!(!is_match(&m, "example.com:2001").await);
+    }
+
+    #[test]
+    fn test_invalid_range() {
+        assert!(PortRange::try_from("2000-1000".to_string()).is_err());
+        assert!(PortRange::try_from("80-".to_string()).is_err());
+        assert!(PortRange::try_from("65536".to_string()).is_err());
+        assert!(serde_json::from_str::<PortMatcher>(r#"{ "port": "2000-1000" }"#).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use super::config::{PrefixMatcher, PrefixMatcherConfig};
+use super::matcher::{Matcher, MaybeAsync};
+use crate::sniff::PeekedBytes;
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rd_interface::{registry::ResolveNetRef, Address};
+use std::convert::TryFrom;
+
+impl ResolveNetRef for PrefixMatcher {}
+
+fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    let digits = hex
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| anyhow!("Invalid hex prefix: {:?}", hex))?;
+    if digits.len() % 2 != 0 {
+        bail!("Odd number of hex digits in prefix: {:?}", hex);
+    }
+    Ok(digits.chunks(2).map(|d| d[0] << 4 | d[1]).collect())
+}
+
+impl TryFrom<PrefixMatcherConfig> for PrefixMatcher {
+    type Error = anyhow::Error;
+
+    fn try_from(PrefixMatcherConfig { hex, base64 }: PrefixMatcherConfig) -> anyhow::Result<Self> {
+        let prefix = match (&hex, &base64) {
+            (Some(hex), None) => decode_hex(hex)?,
+            (None, Some(base64)) => STANDARD
+                .decode(base64)
+                .map_err(|e| anyhow!("Invalid base64 prefix {:?}: {}", base64, e))?,
+            _ => bail!("Exactly one of hex and base64 is required"),
+        };
+        if prefix.is_empty() {
+            bail!("The prefix is empty");
+        }
+        Ok(PrefixMatcher {
+            hex,
+            base64,
+            prefix,
+        })
+    }
+}
+
+impl Matcher for PrefixMatcher {
+    fn match_rule(&self, ctx: &rd_interface::Context, _addr: &Address) -> MaybeAsync<bool> {
+        match ctx.extensions().get::<PeekedBytes>() {
+            Some(PeekedBytes(bytes)) => bytes.starts_with(&self.prefix),
+            // nothing is peeked, pass it.
+            None => false,
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtin::local::{LocalConfig, LocalNet};
+    use crate::sniff::sniff;
+    use rd_interface::{Context, IntoAddress, IntoDyn};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn matcher(config: serde_json::Value) -> anyhow::Result<PrefixMatcher> {
+        Ok(serde_json::from_value(config)?)
+    }
+
+    #[test]
+    fn test_prefix_config() {
+        let hex = matcher(serde_json::json!({ "hex": "CA fe ba be" })).unwrap();
+        assert_eq!(hex.prefix, b"\xca\xfe\xba\xbe");
+        let base64 = matcher(serde_json::json!({ "base64": "yv66vg==" })).unwrap();
+        assert_eq!(base64.prefix, b"\xca\xfe\xba\xbe");
+
+        assert!(matcher(serde_json::json!({ "hex": "cafeb" })).is_err());
+        assert!(matcher(serde_json::json!({ "hex": "zz" })).is_err());
+        assert!(matcher(serde_json::json!({ "hex": "" })).is_err());
+        assert!(matcher(serde_json::json!({})).is_err());
+        assert!(matcher(serde_json::json!({ "hex": "ca", "base64": "yg==" })).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prefix_matcher() {
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        let listener = local
+            .tcp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().await.unwrap();
+
+        // the client sends the magic number and waits for a reply
+        let client = tokio::spawn(async move {
+            let mut tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            tcp.write_all(b"\xca\xfe\xba\xbehello").await.unwrap();
+            tcp.read_exact(&mut [0u8; 2]).await.unwrap();
+            tcp.write_all(b" world").await.unwrap();
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut ctx = Context::new();
+        let mut socket = sniff(socket, &mut ctx).await.unwrap();
+
+        let cafe = matcher(serde_json::json!({ "hex": "cafebabe" })).unwrap();
+        let other = matcher(serde_json::json!({ "hex": "cafed00d" })).unwrap();
+        let dst = "1.2.3.4:443".into_address().unwrap();
+        assert!(cafe.match_rule(&ctx, &dst).await);
+        assert!(!other.match_rule(&ctx, &dst).await);
+        assert!(!cafe.match_rule(&Context::new(), &dst).await);
+
+        // the peeked bytes are replayed
+        socket.write_all(b"ok").await.unwrap();
+        client.await.unwrap();
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"\xca\xfe\xba\xbehello world");
+    }
+}
@@ -0,0 +1,149 @@
+use super::config::{self, Matcher};
+use super::matcher::Matcher as _;
+use rd_interface::{
+    async_trait, Address, AddressError, Context, INet, IntoAddress, Net, Result, TcpListener,
+    TcpStream, UdpSocket,
+};
+use std::{io, net::SocketAddr};
+
+enum Target {
+    Address(Address),
+    /// Keeps the port of the original address.
+    Host(String),
+}
+
+impl Target {
+    fn parse(to: &str) -> Result<Target> {
+        match to.parse() {
+            Ok(addr) => Ok(Target::Address(addr)),
+            Err(AddressError::MissingPort) => Ok(Target::Host(to.to_string())),
+            Err(e) => Err(io::Error::from(e).into()),
+        }
+    }
+    fn apply(&self, addr: &Address) -> Result<Address> {
+        match self {
+            Target::Address(to) => Ok(to.clone()),
+            Target::Host(host) => {
+                let port = addr.port().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::AddrNotAvailable, "no port to keep")
+                })?;
+                Ok((host.as_str(), port).into_address()?)
+            }
+        }
+    }
+}
+
+struct RewriteItem {
+    matcher: Matcher,
+    target: Target,
+}
+
+/// Rewrites the destination with the first matched item before passing it
+/// to `net`.
+pub struct RewriteNet {
+    net: Net,
+    rewrite: Vec<RewriteItem>,
+}
+
+impl RewriteNet {
+    pub fn new(config: config::RewriteConfig) -> Result<RewriteNet> {
+        let rewrite = config
+            .rewrite
+            .into_iter()
+            .map(|config::RewriteItem { to, matcher }| {
+                Ok(RewriteItem {
+                    matcher,
+                    target: Target::parse(&to)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(RewriteNet {
+            net: config.net.try_net()?,
+            rewrite,
+        })
+    }
+    async fn rewrite(&self, ctx: &Context, addr: Address) -> Result<Address> {
+        for item in &self.rewrite {
+            if item.matcher.match_rule(ctx, &addr).await {
+                let to = item.target.apply(&addr)?;
+                tracing::trace!("rewrite {} -> {}", addr, to);
+                return Ok(to);
+            }
+        }
+        Ok(addr)
+    }
+}
+
+#[async_trait]
+impl INet for RewriteNet {
+    async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+        let addr = self.rewrite(ctx, addr).await?;
+        self.net.tcp_connect(ctx, addr).await
+    }
+
+    async fn tcp_bind(&self, ctx: &mut Context, addr: Address) -> Result<TcpListener> {
+        self.net.tcp_bind(ctx, addr).await
+    }
+
+    async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
+        let addr = self.rewrite(ctx, addr).await?;
+        self.net.udp_bind(ctx, addr).await
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        let addr = self.rewrite(&Context::new(), addr.clone()).await?;
+        self.net.lookup_host(&addr).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::RecordNet;
+    use rd_interface::registry::NetMap;
+    use std::sync::Arc;
+
+    async fn connect(net: &RewriteNet, addr: &str) {
+        net.tcp_connect(&mut Context::new(), addr.into_address().unwrap())
+            .await
+            .ok();
+    }
+
+    #[tokio::test]
+    async fn test_rewrite() {
+        use rd_interface::registry::ResolveNetRef;
+
+        let inner = Arc::new(RecordNet::default());
+        let mut nets = NetMap::new();
+        nets.insert("record".to_string(), inner.clone());
+
+        let mut config: config::RewriteConfig = serde_json::from_value(serde_json::json!({
+            "net": "record",
+            "rewrite": [
+                { "type": "domain", "method": "match", "domain": "example.com", "to": "127.0.0.1:8080" },
+                { "type": "domain", "method": "suffix", "domain": ".example.org", "to": "127.0.0.2" },
+                { "type": "port", "port": "80", "to": "10.0.0.1:3128" },
+            ],
+        }))
+        .unwrap();
+        config.resolve(&nets).unwrap();
+        let net = RewriteNet::new(config).unwrap();
+        let last = || inner.take_connected().pop().unwrap();
+
+        connect(&net, "example.com:443").await;
+        assert_eq!(last(), "127.0.0.1:8080");
+        // the first match wins
+        connect(&net, "www.example.org:80").await;
+        assert_eq!(last(), "127.0.0.2:80");
+        connect(&net, "1.2.3.4:80").await;
+        assert_eq!(last(), "10.0.0.1:3128");
+        connect(&net, "example.net:443").await;
+        assert_eq!(last(), "example.net:443");
+    }
+
+    #[test]
+    fn test_rewrite_invalid_target() {
+        assert!(Target::parse("127.0.0.1:http").is_err());
+        assert!(matches!(Target::parse("[::1]"), Ok(Target::Host(_))));
+    }
+}
@@ -1,14 +1,21 @@
 use super::config;
 use super::matcher::Matcher;
 use super::udp::UdpRuleSocket;
-use std::io;
+use std::{io, net::SocketAddr};
 
 use rd_interface::{
-    async_trait, context::common_field::SourceAddress, Address, Arc, Context, INet, IntoDyn, Net,
-    Result, TcpListener, TcpStream, UdpSocket, NOT_IMPLEMENTED,
+    async_trait,
+    context::{
+        common_field::{RuleTarget, SourceAddress},
+        CommonField,
+    },
+    Address, Arc, Context, INet, IntoDyn, Net, Result, TcpListener, TcpStream, UdpSocket,
+    NOT_IMPLEMENTED,
 };
 
 pub struct RuleItem {
+    /// The index and the type of the rule, e.g. `#0 domain`.
+    pub rule_name: String,
     pub target_name: String,
     pub target: Net,
     matcher: config::Matcher,
@@ -17,6 +24,22 @@ pub struct RuleItem {
 #[derive(Clone)]
 pub struct Rule {
     rule: Arc<Vec<RuleItem>>,
+    default: Option<Arc<RuleItem>>,
+}
+
+/// The rule nets a connection went through, to find loops.
+#[derive(Clone, Default)]
+struct VisitedRules(Vec<usize>);
+
+tokio::task_local! {
+    /// The rule nets a `lookup_host` went through, which has no context.
+    static LOOKUP_VISITED: VisitedRules;
+}
+
+fn loop_detected() -> rd_interface::Error {
+    rd_interface::Error::Other(
+        "Loop detected: the connection enters the same rule net twice".into(),
+    )
 }
 
 impl Rule {
@@ -24,17 +47,54 @@ impl Rule {
         let rule = config
             .rule
             .into_iter()
-            .map(|config::RuleItem { target, matcher }| {
+            .enumerate()
+            .map(|(i, config::RuleItem { target, matcher })| {
                 Ok(RuleItem {
+                    rule_name: format!("#{} {}", i, matcher.type_name()),
                     matcher,
-                    target: target.net(),
+                    target: target.try_net()?,
                     target_name: target.name().to_string(),
                 })
             })
             .collect::<Result<Vec<_>>>()?;
         let rule = Arc::new(rule);
+        let default = match config.default {
+            Some(target) => Some(Arc::new(RuleItem {
+                rule_name: "default".to_string(),
+                matcher: config::Matcher::Any(config::AnyMatcher {}),
+                target: target.try_net()?,
+                target_name: target.name().to_string(),
+            })),
+            None => None,
+        };
 
-        Ok(Rule { rule })
+        Ok(Rule { rule, default })
+    }
+    fn id(&self) -> usize {
+        Arc::as_ptr(&self.rule) as usize
+    }
+    /// Records that the connection of `ctx` goes through this rule net.
+    /// Fails if it's already inside it, since the connection would never
+    /// end.
+    pub fn enter(&self, ctx: &mut Context) -> Result<()> {
+        let id = self.id();
+        let ext = ctx.extensions_mut();
+        if ext.get::<VisitedRules>().is_none() {
+            ext.insert(VisitedRules::default());
+        }
+        let visited = ext.get_mut::<VisitedRules>().unwrap();
+        if visited.0.contains(&id) {
+            return Err(loop_detected());
+        }
+        visited.0.push(id);
+        Ok(())
+    }
+    /// Undoes [`enter()`](Rule::enter()), so `ctx` can be reused.
+    pub fn leave(&self, ctx: &mut Context) {
+        let id = self.id();
+        if let Some(visited) = ctx.extensions_mut().get_mut::<VisitedRules>() {
+            visited.0.retain(|i| *i != id);
+        }
     }
     pub async fn get_rule(&self, ctx: &Context, target: &Address) -> Result<&RuleItem> {
         let src = ctx
@@ -43,7 +103,7 @@ impl Rule {
             .unwrap_or_default();
 
         for rule in self.rule.iter() {
-            if rule.matcher.match_rule(ctx, &target).await {
+            if rule.matcher.match_rule(ctx, target).await {
                 tracing::trace!(
                     "[{}] {} -> {} matched rule: {:?}",
                     &rule.target_name,
@@ -51,17 +111,37 @@ impl Rule {
                     &target,
                     &rule.matcher
                 );
-                return Ok(&rule);
+                return Ok(rule);
             }
         }
 
+        if let Some(default) = &self.default {
+            tracing::trace!(
+                "[{}] {} -> {} matched default",
+                &default.target_name,
+                &src,
+                &target
+            );
+            return Ok(default);
+        }
+
         tracing::info!("{} -> {} not matched, reject", src, target);
-        Err(rd_interface::Error::IO(
-            io::ErrorKind::ConnectionRefused.into(),
-        ))
+        Err(rd_interface::Error::IO(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("no rule matches {} and no default is set", target),
+        )))
     }
     pub async fn get_rule_append(&self, ctx: &mut Context, target: &Address) -> Result<&RuleItem> {
+        // don't leak the rule of an outer rule net
+        ctx.set_matched_rule(None);
+        ctx.remove_value(RuleTarget::KEY).ok();
+
         let rule = self.get_rule(ctx, target).await?;
+        ctx.set_matched_rule(Some(rule.rule_name.clone()));
+        ctx.insert_common(RuleTarget {
+            net: rule.target_name.clone(),
+        })
+        .map_err(|e| rd_interface::Error::Other(e.into()))?;
         Ok(rule)
     }
 }
@@ -86,13 +166,12 @@ impl INet for RuleNet {
             .map(|s| s.addr.to_string())
             .unwrap_or_default();
 
-        let r = self
-            .rule
-            .get_rule_append(ctx, &addr)
-            .await?
-            .target
-            .tcp_connect(ctx, addr.clone())
-            .await;
+        self.rule.enter(ctx)?;
+        let r = match self.rule.get_rule_append(ctx, &addr).await {
+            Ok(rule) => rule.target.tcp_connect(ctx, addr.clone()).await,
+            Err(e) => Err(e),
+        };
+        self.rule.leave(ctx);
 
         if let Err(e) = &r {
             tracing::error!("{} -> {} Failed to connect: {:?}", &src, &addr, e);
@@ -106,6 +185,180 @@ impl INet for RuleNet {
     }
 
     async fn udp_bind(&self, ctx: &mut Context, addr: Address) -> Result<UdpSocket> {
-        Ok(UdpRuleSocket::new(self.rule.clone(), ctx.clone(), addr).into_dyn())
+        self.rule.enter(ctx)?;
+        let udp = UdpRuleSocket::new(self.rule.clone(), ctx.clone(), addr).into_dyn();
+        self.rule.leave(ctx);
+        Ok(udp)
+    }
+
+    /// Resolved by the net a connect to `addr` would go to.
+    async fn lookup_host(&self, addr: &Address) -> Result<Vec<SocketAddr>> {
+        let mut visited = LOOKUP_VISITED.try_with(Clone::clone).unwrap_or_default();
+        if visited.0.contains(&self.rule.id()) {
+            return Err(loop_detected());
+        }
+        visited.0.push(self.rule.id());
+        LOOKUP_VISITED
+            .scope(visited, async {
+                let rule = self.rule.get_rule(&Context::new(), addr).await?;
+                rule.target.lookup_host(addr).await
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rd_interface::{registry::NetMap, registry::ResolveNetRef, IntoAddress, NotImplementedNet};
+    use std::sync::Mutex;
+
+    /// Forwards to a net set after it's created, to make a cycle.
+    struct LateNet(Mutex<Option<Net>>);
+
+    #[async_trait]
+    impl INet for LateNet {
+        async fn tcp_connect(&self, ctx: &mut Context, addr: Address) -> Result<TcpStream> {
+            let net = self.0.lock().unwrap().clone().unwrap();
+            net.tcp_connect(ctx, addr).await
+        }
+
+        async fn tcp_bind(&self, _ctx: &mut Context, _addr: Address) -> Result<TcpListener> {
+            Err(NOT_IMPLEMENTED)
+        }
+
+        async fn udp_bind(&self, _ctx: &mut Context, _addr: Address) -> Result<UdpSocket> {
+            Err(NOT_IMPLEMENTED)
+        }
+
+        async fn lookup_host(&self, addr: &Address) -> Result<Vec<std::net::SocketAddr>> {
+            let net = self.0.lock().unwrap().clone().unwrap();
+            net.lookup_host(addr).await
+        }
+    }
+
+    fn rule_net(target: Net) -> Net {
+        let mut nets = NetMap::new();
+        nets.insert("target".to_string(), target);
+        let mut config: config::RuleConfig = serde_json::from_value(serde_json::json!({
+            "rule": [{ "type": "any", "target": "target" }]
+        }))
+        .unwrap();
+        config.resolve(&nets).unwrap();
+        RuleNet::new(config).unwrap().into_dyn()
+    }
+
+    #[tokio::test]
+    async fn test_rule_loop() {
+        // a -> b -> a
+        let late = Arc::new(LateNet(Mutex::new(None)));
+        let b = rule_net(late.clone());
+        let a = rule_net(b);
+        *late.0.lock().unwrap() = Some(a.clone());
+
+        let r = a
+            .tcp_connect(&mut Context::new(), "1.2.3.4:80".into_address().unwrap())
+            .await;
+        match r {
+            Err(rd_interface::Error::Other(e)) => assert!(e.to_string().contains("Loop detected")),
+            _ => panic!("expected a loop error"),
+        }
+        let r = a
+            .lookup_host(&"example.com:80".into_address().unwrap())
+            .await;
+        match r {
+            Err(rd_interface::Error::Other(e)) => assert!(e.to_string().contains("Loop detected")),
+            _ => panic!("expected a loop error"),
+        }
+
+        // going through a rule net one after another isn't a loop
+        let mut ctx = Context::new();
+        let noop = rule_net(NotImplementedNet.into_dyn());
+        for _ in 0..2 {
+            let r = noop
+                .tcp_connect(&mut ctx, "1.2.3.4:80".into_address().unwrap())
+                .await;
+            assert!(matches!(r, Err(rd_interface::Error::NotImplemented)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matched_rule() {
+        let mut nets = NetMap::new();
+        nets.insert("noop".to_string(), NotImplementedNet.into_dyn());
+        let mut config: config::RuleConfig = serde_json::from_value(serde_json::json!({
+            "rule": [
+                { "type": "domain", "method": "suffix", "domain": "example.com", "target": "noop" },
+                { "type": "port", "port": "80", "target": "noop" },
+            ]
+        }))
+        .unwrap();
+        config.resolve(&nets).unwrap();
+        let net = RuleNet::new(config).unwrap();
+
+        async fn connect(net: &RuleNet, ctx: &mut Context, addr: &str) -> Option<String> {
+            net.tcp_connect(ctx, addr.into_address().unwrap())
+                .await
+                .ok();
+            ctx.matched_rule().map(ToString::to_string)
+        }
+
+        let mut ctx = Context::new();
+        assert_eq!(
+            connect(&net, &mut ctx, "example.com:443").await.as_deref(),
+            Some("#0 domain")
+        );
+        assert_eq!(
+            connect(&net, &mut ctx, "1.2.3.4:80").await.as_deref(),
+            Some("#1 port")
+        );
+        // nothing matched, the last rule is cleared
+        assert_eq!(connect(&net, &mut ctx, "1.2.3.4:22").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_default() {
+        let mut nets = NetMap::new();
+        nets.insert("proxy".to_string(), NotImplementedNet.into_dyn());
+        nets.insert("direct".to_string(), NotImplementedNet.into_dyn());
+        let config = serde_json::json!({
+            "rule": [
+                { "type": "domain", "method": "suffix", "domain": "example.com", "target": "proxy" },
+            ],
+            "default": "direct",
+        });
+        let mut config: config::RuleConfig = serde_json::from_value(config).unwrap();
+        config.resolve(&nets).unwrap();
+        let rule = Rule::new(config).unwrap();
+
+        let ctx = Context::new();
+        let target = |addr: &str| {
+            let addr = addr.into_address().unwrap();
+            let rule = &rule;
+            let ctx = &ctx;
+            async move { rule.get_rule(ctx, &addr).await.unwrap().target_name.clone() }
+        };
+        assert_eq!(target("www.example.com:443").await, "proxy");
+        assert_eq!(target("1.2.3.4:22").await, "direct");
+
+        // no default
+        let mut config: config::RuleConfig = serde_json::from_value(serde_json::json!({
+            "rule": [{ "type": "port", "port": "80", "target": "proxy" }],
+        }))
+        .unwrap();
+        config.resolve(&nets).unwrap();
+        let rule = Rule::new(config).unwrap();
+        let err = match rule
+            .get_rule(&ctx, &"1.2.3.4:22".into_address().unwrap())
+            .await
+        {
+            Err(rd_interface::Error::IO(e)) => e,
+            _ => panic!("expected an error"),
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            err.to_string(),
+            "no rule matches 1.2.3.4:22 and no default is set"
+        );
     }
 }
@@ -81,7 +81,7 @@ impl UdpRuleSocket {
             bind_addr,
         }
     }
-    async fn get_net_name<'a>(&'a self, ctx: &Context, addr: &Address) -> Result<String> {
+    async fn get_net_name(&self, ctx: &Context, addr: &Address) -> Result<String> {
         let mut c = self.cache.lock().await;
         if let Some(v) = c.get(addr) {
             Ok(v.to_string())
@@ -0,0 +1,372 @@
+//! Reads the destination host from the first bytes of a connection, without
+//! consuming them.
+
+use std::time::Duration;
+
+use rd_interface::{
+    context::common_field::SniffedHost, util::PeekableTcpStream, Context, IntoDyn, Result,
+    TcpStream,
+};
+use tokio::time::timeout;
+
+/// Handshake record, RFC 8446 section 5.1.
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+/// Max length of a TLS record.
+const MAX_RECORD_SIZE: usize = 16384;
+/// HTTP requests with a header larger than this aren't sniffed.
+const MAX_HEADER_SIZE: usize = 8192;
+/// Streams not sniffed by then are passed on as they are.
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(5);
+/// Peeked bytes kept in the context, for matching the first bytes.
+pub const MAX_PEEKED_SIZE: usize = 256;
+
+/// The first bytes of the connection, read while sniffing it without
+/// consuming them. Kept in the [extensions](Context::extensions) of the
+/// context.
+#[derive(Debug, Clone)]
+pub struct PeekedBytes(pub Vec<u8>);
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+    /// Takes a vector with a `len_size` bytes long length prefix.
+    fn vec(&mut self, len_size: usize) -> Option<Reader<'a>> {
+        let len = match len_size {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => self.u24()?,
+        };
+        self.take(len).map(Reader)
+    }
+}
+
+/// Returns the SNI of a TLS record holding a ClientHello.
+pub fn parse_sni(record: &[u8]) -> Option<String> {
+    let mut r = Reader(record);
+    if r.u8()? != CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+    r.u16()?;
+    let mut r = r.vec(2)?;
+    if r.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let mut hello = r.vec(3)?;
+    // legacy_version, random
+    hello.take(2 + 32)?;
+    // legacy_session_id, cipher_suites, legacy_compression_methods
+    hello.vec(1)?;
+    hello.vec(2)?;
+    hello.vec(1)?;
+
+    let mut extensions = hello.vec(2)?;
+    while !extensions.0.is_empty() {
+        let ty = extensions.u16()?;
+        let mut data = extensions.vec(2)?;
+        if ty != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = data.vec(2)?;
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec(2)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name.0)
+                    .ok()
+                    .map(|s| s.to_ascii_lowercase());
+            }
+        }
+    }
+    None
+}
+
+/// Peeks the first TLS record of `socket` and returns its SNI, if it's a
+/// ClientHello.
+pub async fn peek_sni(socket: &mut PeekableTcpStream) -> Result<Option<String>> {
+    let mut header = [0u8; 5];
+    socket.peek_exact(&mut header).await?;
+    if header[0] != CONTENT_TYPE_HANDSHAKE {
+        return Ok(None);
+    }
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if len > MAX_RECORD_SIZE {
+        return Ok(None);
+    }
+    let mut record = vec![0u8; header.len() + len];
+    socket.peek_exact(&mut record).await?;
+    Ok(parse_sni(&record))
+}
+
+/// Returns the host of an HTTP request header, from `Host` or the absolute
+/// URI. `None` if the header is incomplete or it's not HTTP.
+pub fn parse_http_host(header: &[u8]) -> Option<String> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    if !req.parse(header).ok()?.is_complete() {
+        return None;
+    }
+    let host = match req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("host"))
+    {
+        Some(h) => std::str::from_utf8(h.value).ok()?,
+        None => {
+            let path = req.path?;
+            let authority = path.split_once("://")?.1;
+            authority.split('/').next()?
+        }
+    };
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some(host.to_ascii_lowercase())
+}
+
+/// Peeks the HTTP request header of `socket`, reading until it's complete,
+/// and returns its host. Stops as soon as the bytes can't be HTTP.
+pub async fn peek_http_host(socket: &mut PeekableTcpStream) -> Result<Option<String>> {
+    loop {
+        let peeked = socket.peeked();
+        if let Some(end) = find_header_end(peeked) {
+            return Ok(parse_http_host(&peeked[..end]));
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        if httparse::Request::new(&mut headers).parse(peeked).is_err() {
+            return Ok(None);
+        }
+        let len = peeked.len();
+        if len >= MAX_HEADER_SIZE {
+            return Ok(None);
+        }
+        if socket.peek_more(MAX_HEADER_SIZE - len).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
+}
+
+/// Sniffs the host `socket` connects to, and stores it in `ctx` as
+/// [`SniffedHost`]. Up to [`MAX_PEEKED_SIZE`] of the bytes peeked are
+/// stored in its extensions as [`PeekedBytes`]. The returned stream
+/// replays the peeked bytes.
+///
+/// It waits for the client to speak first, so don't use it for protocols
+/// where the server does. Streams still not sniffed after
+/// [`SNIFF_TIMEOUT`] are returned without a host.
+pub async fn sniff(socket: TcpStream, ctx: &mut Context) -> Result<TcpStream> {
+    let mut socket = PeekableTcpStream::new(socket);
+    match timeout(SNIFF_TIMEOUT, sniff_host(&mut socket)).await {
+        Ok(host) => {
+            if let Some(host) = host? {
+                ctx.insert_common(SniffedHost { host })?;
+            }
+        }
+        Err(_) => tracing::debug!("sniffing timed out"),
+    }
+    let peeked = socket.peeked();
+    ctx.extensions_mut().insert(PeekedBytes(
+        peeked[..peeked.len().min(MAX_PEEKED_SIZE)].to_vec(),
+    ));
+    Ok(socket.into_dyn())
+}
+
+async fn sniff_host(socket: &mut PeekableTcpStream) -> Result<Option<String>> {
+    let mut first = [0u8; 1];
+    socket.peek_exact(&mut first).await?;
+    match first[0] {
+        CONTENT_TYPE_HANDSHAKE => peek_sni(socket).await,
+        // HTTP methods are upper case
+        b if b.is_ascii_uppercase() => peek_http_host(socket).await,
+        // what the client sent already, without waiting for more
+        _ => {
+            socket.peek_more(MAX_HEADER_SIZE).await?;
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtin::local::{LocalConfig, LocalNet};
+    use rd_interface::IntoAddress;
+    use std::{convert::TryFrom, sync::Arc};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{
+        crypto::ring, pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore,
+    };
+
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        let mut conn = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut hello = Vec::new();
+        conn.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    #[test]
+    fn test_parse_sni() {
+        let hello = client_hello("Example.COM");
+        assert_eq!(parse_sni(&hello), Some("example.com".to_string()));
+
+        assert_eq!(parse_sni(&hello[..hello.len() - 1]), None);
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n\r\n"), None);
+        // no SNI for IP addresses
+        assert_eq!(parse_sni(&client_hello("127.0.0.1")), None);
+    }
+
+    #[test]
+    fn test_parse_http_host() {
+        let host = |h: &[u8]| parse_http_host(h);
+        assert_eq!(
+            host(b"GET / HTTP/1.1\r\nHost: Example.com:8080\r\n\r\n"),
+            Some("example.com".to_string())
+        );
+        assert_eq!(
+            host(b"GET / HTTP/1.1\r\nhost: [::1]:80\r\n\r\n"),
+            Some("::1".to_string())
+        );
+        assert_eq!(
+            host(b"GET http://example.org/a HTTP/1.0\r\n\r\n"),
+            Some("example.org".to_string())
+        );
+        assert_eq!(host(b"GET / HTTP/1.1\r\nHost: example.com\r\n"), None);
+        assert_eq!(host(b"GET / HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(host(b"SSH-2.0-OpenSSH\r\n\r\n"), None);
+    }
+
+    /// Sends `parts` with a pause between them, and sniffs the accepted
+    /// stream. Returns the sniffed host and all the bytes read afterwards.
+    async fn sniff_parts(parts: Vec<Vec<u8>>) -> (Option<String>, Vec<u8>) {
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        let listener = local
+            .tcp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().await.unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            for part in parts {
+                tcp.write_all(&part).await.unwrap();
+                tcp.flush().await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut ctx = Context::new();
+        let mut socket = sniff(socket, &mut ctx).await.unwrap();
+
+        client.await.unwrap();
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.unwrap();
+        (
+            ctx.get_common::<SniffedHost>().ok().map(|h| h.host),
+            received,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_sniff_tls() {
+        let mut sent = client_hello("example.com");
+        sent.extend_from_slice(b"after the hello");
+        // the record comes in two parts
+        let parts = vec![sent[..3].to_vec(), sent[3..].to_vec()];
+
+        let (host, received) = sniff_parts(parts).await;
+        assert_eq!(host.as_deref(), Some("example.com"));
+        assert_eq!(received, sent);
+    }
+
+    #[tokio::test]
+    async fn test_sniff_http() {
+        let req = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\nbody".to_vec();
+        let (host, received) = sniff_parts(vec![req.clone()]).await;
+        assert_eq!(host.as_deref(), Some("example.com"));
+        assert_eq!(received, req);
+
+        // the header line is split across two reads
+        let parts = vec![req[..24].to_vec(), req[24..].to_vec()];
+        let (host, received) = sniff_parts(parts).await;
+        assert_eq!(host.as_deref(), Some("example.com"));
+        assert_eq!(received, req);
+    }
+
+    #[tokio::test]
+    async fn test_sniff_client_banner() {
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        let listener = local
+            .tcp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().await.unwrap();
+
+        // like SSH, the client sends a line and waits for the server
+        let client = tokio::spawn(async move {
+            let mut tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            tcp.write_all(b"SSH-2.0-x\r\n").await.unwrap();
+            let mut buf = [0u8; 11];
+            tcp.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"SSH-2.0-y\r\n");
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut ctx = Context::new();
+        let mut socket = tokio::time::timeout(SNIFF_TIMEOUT / 5, sniff(socket, &mut ctx))
+            .await
+            .expect("sniffing waits for the client")
+            .unwrap();
+        assert!(ctx.get_common::<SniffedHost>().is_err());
+
+        let mut buf = [0u8; 11];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"SSH-2.0-x\r\n");
+        socket.write_all(b"SSH-2.0-y\r\n").await.unwrap();
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sniff_http_too_large() {
+        let mut req = b"GET / HTTP/1.1\r\nX-Pad: ".to_vec();
+        req.extend(vec![b'a'; MAX_HEADER_SIZE]);
+        req.extend_from_slice(b"\r\nHost: example.com\r\n\r\n");
+        let (host, received) = sniff_parts(vec![req.clone()]).await;
+        assert_eq!(host, None);
+        assert_eq!(received, req);
+    }
+}
@@ -1,23 +1,42 @@
 pub use client::Socks5Client;
 pub use server::Socks5Server;
+pub use socks4::Socks4Client;
 
 mod client;
-mod common;
+pub(crate) mod common;
 mod server;
+mod socks4;
 #[cfg(test)]
 mod tests;
 
 use rd_interface::{
     registry::{NetFactory, NetRef, ServerFactory},
     schemars::{self, JsonSchema},
-    Config, Net, Registry, Result,
+    Config, Error, Net, Registry, Result,
 };
 use serde_derive::Deserialize;
+use std::{collections::HashMap, time::Duration};
 
 #[derive(Debug, Deserialize, Config, JsonSchema)]
 pub struct ClientConfig {
     address: String,
     port: u16,
+    /// Sent with `password` when the server asks for it.
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+
+    #[serde(default)]
+    net: NetRef,
+}
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct Socks4ClientConfig {
+    address: String,
+    port: u16,
+    #[serde(default)]
+    user_id: String,
 
     #[serde(default)]
     net: NetRef,
@@ -26,6 +45,25 @@ pub struct ClientConfig {
 #[derive(Debug, Deserialize, Config, JsonSchema)]
 pub struct ServerConfig {
     bind: String,
+    /// Username to password. Clients must authenticate when it's not empty.
+    #[serde(default)]
+    users: HashMap<String, String>,
+    /// Seconds a UDP association is kept without packets, 300 by default.
+    #[serde(default = "default_udp_timeout")]
+    udp_timeout: u64,
+}
+
+fn default_udp_timeout() -> u64 {
+    server::DEFAULT_UDP_TIMEOUT.as_secs()
+}
+
+impl ServerConfig {
+    fn udp_timeout(&self) -> Result<Duration> {
+        match self.udp_timeout {
+            0 => Err(Error::Other("udp_timeout must be positive".into())),
+            secs => Ok(Duration::from_secs(secs)),
+        }
+    }
 }
 
 impl NetFactory for Socks5Client {
@@ -34,10 +72,29 @@ impl NetFactory for Socks5Client {
     type Net = Self;
 
     fn new(config: Self::Config) -> Result<Self> {
-        Ok(Socks5Client::new(
-            config.net.net(),
+        let ClientConfig {
+            address,
+            port,
+            username,
+            password,
+            net,
+        } = config;
+        let auth = username.map(|username| (username, password.unwrap_or_default()));
+        Ok(Socks5Client::new(net.try_net()?, address, port, auth))
+    }
+}
+
+impl NetFactory for Socks4Client {
+    const NAME: &'static str = "socks4";
+    type Config = Socks4ClientConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        Ok(Socks4Client::new(
+            config.net.try_net()?,
             config.address,
             config.port,
+            config.user_id,
         ))
     }
 }
@@ -47,13 +104,21 @@ impl ServerFactory for server::Socks5 {
     type Config = ServerConfig;
     type Server = Self;
 
-    fn new(listen: Net, net: Net, Self::Config { bind }: Self::Config) -> Result<Self> {
-        Ok(server::Socks5::new(listen, net, bind))
+    fn new(listen: Net, net: Net, config: Self::Config) -> Result<Self> {
+        let udp_timeout = config.udp_timeout()?;
+        Ok(server::Socks5::new(
+            listen,
+            net,
+            config.bind,
+            config.users,
+            udp_timeout,
+        ))
     }
 }
 
 pub fn init(registry: &mut Registry) -> Result<()> {
     registry.add_net::<Socks5Client>();
+    registry.add_net::<Socks4Client>();
     registry.add_server::<server::Socks5>();
     Ok(())
 }
@@ -1,20 +1,21 @@
 use socks5_protocol::{
-    AuthMethod, AuthRequest, AuthResponse, CommandRequest, CommandResponse, Version,
+    AuthMethod, AuthRequest, AuthResponse, Command, CommandRequest, CommandResponse, Version,
 };
 
 use crate::socks5::common::map_err;
 
-use super::common::{pack_udp, parse_udp, ra2sa};
+use super::common::{pack_udp, parse_udp, ra2sa, PASSWORD_AUTH_VERSION};
 use rd_interface::{
-    async_trait, impl_async_read_write, INet, ITcpStream, IUdpSocket, IntoAddress, IntoDyn, Net,
-    Result, TcpStream, UdpSocket, NOT_IMPLEMENTED,
+    async_trait, impl_async_read_write, INet, ITcpListener, ITcpStream, IUdpSocket, IntoAddress,
+    IntoDyn, Net, Result, TcpStream, UdpSocket, NOT_IMPLEMENTED,
 };
-use std::net::SocketAddr;
-use tokio::io::{split, AsyncWriteExt, BufWriter};
+use std::{io, net::SocketAddr, sync::Mutex};
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt, BufWriter};
 
 pub struct Socks5Client {
     address: String,
     port: u16,
+    auth: Option<(String, String)>,
     net: Net,
 }
 
@@ -22,7 +23,7 @@ pub struct Socks5TcpStream(TcpStream);
 
 impl_async_read_write!(Socks5TcpStream, 0);
 
-pub struct Socks5UdpSocket(UdpSocket, TcpStream, SocketAddr);
+pub struct Socks5UdpSocket(UdpSocket, #[allow(dead_code)] TcpStream, SocketAddr);
 
 #[async_trait]
 impl IUdpSocket for Socks5UdpSocket {
@@ -46,7 +47,7 @@ impl IUdpSocket for Socks5UdpSocket {
     }
 
     async fn send_to(&self, buf: &[u8], addr: rd_interface::Address) -> Result<usize> {
-        let bytes = pack_udp(ra2sa(addr), buf).await?;
+        let bytes = pack_udp(ra2sa(addr)?, buf).await?;
 
         self.0.send_to(&bytes, self.2.into()).await
     }
@@ -56,6 +57,34 @@ impl IUdpSocket for Socks5UdpSocket {
     }
 }
 
+/// Waits for the connection to the address bound by a BIND command, which
+/// comes with the second reply. Only one connection is accepted.
+pub struct Socks5TcpListener {
+    socket: Mutex<Option<TcpStream>>,
+    bound: SocketAddr,
+}
+
+#[async_trait]
+impl ITcpListener for Socks5TcpListener {
+    async fn accept(&self) -> Result<(TcpStream, SocketAddr)> {
+        let mut socket = self
+            .socket
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| io::Error::other("SOCKS5 BIND accepts only one connection"))?;
+
+        let resp = CommandResponse::read(&mut socket).await.map_err(map_err)?;
+        let addr = resp.address.to_socket_addr().map_err(map_err)?;
+
+        Ok((Socks5TcpStream(socket).into_dyn(), addr))
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.bound)
+    }
+}
+
 #[async_trait]
 impl ITcpStream for Socks5TcpStream {
     async fn peer_addr(&self) -> Result<SocketAddr> {
@@ -74,9 +103,9 @@ impl INet for Socks5Client {
         ctx: &mut rd_interface::Context,
         addr: rd_interface::Address,
     ) -> Result<UdpSocket> {
-        let mut socket = self.net.tcp_connect(ctx, self.server()?).await?;
+        let mut socket = self.net.tcp_connect(ctx, self.server(ctx)?).await?;
 
-        let req = CommandRequest::udp_associate(ra2sa(addr.clone().into_address()?));
+        let req = CommandRequest::udp_associate(ra2sa(addr.clone().into_address()?)?);
         let resp = self.send_command(&mut socket, req).await?;
         let client = self.net.udp_bind(ctx, addr.clone()).await?;
 
@@ -89,28 +118,57 @@ impl INet for Socks5Client {
         ctx: &mut rd_interface::Context,
         addr: rd_interface::Address,
     ) -> Result<TcpStream> {
-        let mut socket = self.net.tcp_connect(ctx, self.server()?).await?;
+        let mut socket = self.net.tcp_connect(ctx, self.server(ctx)?).await?;
 
-        let req = CommandRequest::connect(ra2sa(addr.into_address()?));
+        let req = CommandRequest::connect(ra2sa(addr.into_address()?)?);
         let _resp = self.send_command(&mut socket, req).await?;
 
         Ok(Socks5TcpStream(socket).into_dyn())
     }
 
+    /// `addr` is where the connection is expected from, as in active mode FTP.
     async fn tcp_bind(
         &self,
-        _ctx: &mut rd_interface::Context,
-        _addr: rd_interface::Address,
+        ctx: &mut rd_interface::Context,
+        addr: rd_interface::Address,
     ) -> Result<rd_interface::TcpListener> {
-        Err(rd_interface::Error::NotImplemented)
+        let mut socket = self.net.tcp_connect(ctx, self.server(ctx)?).await?;
+
+        let req = CommandRequest {
+            command: Command::Bind,
+            address: ra2sa(addr.into_address()?)?,
+        };
+        let resp = self.send_command(&mut socket, req).await?;
+        let mut bound = resp.address.to_socket_addr().map_err(map_err)?;
+        // an unspecified address stands for the server's
+        if bound.ip().is_unspecified() {
+            if let Ok(server) = socket.peer_addr().await {
+                bound.set_ip(server.ip());
+            }
+        }
+
+        Ok(Socks5TcpListener {
+            socket: Mutex::new(Some(socket)),
+            bound,
+        }
+        .into_dyn())
     }
 }
 
 impl Socks5Client {
-    pub fn new(net: Net, address: String, port: u16) -> Self {
-        Self { address, port, net }
+    pub fn new(net: Net, address: String, port: u16, auth: Option<(String, String)>) -> Self {
+        Self {
+            address,
+            port,
+            auth,
+            net,
+        }
     }
-    fn server(&self) -> Result<rd_interface::Address> {
+    fn server(&self, ctx: &rd_interface::Context) -> Result<rd_interface::Address> {
+        // reuse the address resolved earlier in the chain
+        if let Some(ip) = ctx.resolved(&self.address).and_then(|ips| ips.first()) {
+            return Ok((*ip, self.port).into());
+        }
         (self.address.as_str(), self.port)
             .into_address()
             .map_err(Into::into)
@@ -124,15 +182,45 @@ impl Socks5Client {
         let mut tx = BufWriter::with_capacity(512, tx);
 
         let version = Version::V5;
-        let auth_req = AuthRequest::new(vec![AuthMethod::Noauth]);
+        let mut methods = vec![AuthMethod::Noauth];
+        if self.auth.is_some() {
+            methods.push(AuthMethod::UsernamePassword);
+        }
+        let auth_req = AuthRequest::new(methods);
         version.write(&mut tx).await.map_err(map_err)?;
         auth_req.write(&mut tx).await.map_err(map_err)?;
         tx.flush().await?;
 
         Version::read(&mut rx).await.map_err(map_err)?;
         let resp = AuthResponse::read(&mut rx).await.map_err(map_err)?;
-        if resp.method() != AuthMethod::Noauth {
-            return Err(rd_interface::Error::Other("Auth failed".to_string().into()));
+        match (resp.method(), &self.auth) {
+            (AuthMethod::Noauth, _) => {}
+            (AuthMethod::UsernamePassword, Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(rd_interface::Error::Other(
+                        "Username or password too long".into(),
+                    ));
+                }
+                let mut req = vec![PASSWORD_AUTH_VERSION, username.len() as u8];
+                req.extend_from_slice(username.as_bytes());
+                req.push(password.len() as u8);
+                req.extend_from_slice(password.as_bytes());
+                tx.write_all(&req).await?;
+                tx.flush().await?;
+
+                let mut status = [0u8; 2];
+                rx.read_exact(&mut status).await?;
+                if status[1] != 0x00 {
+                    return Err(rd_interface::Error::AuthFailed(
+                        "socks5 username/password rejected".to_string(),
+                    ));
+                }
+            }
+            _ => {
+                return Err(rd_interface::Error::AuthFailed(
+                    "socks5 server has no acceptable auth method".to_string(),
+                ))
+            }
         }
 
         command_req.write(&mut tx).await.map_err(map_err)?;
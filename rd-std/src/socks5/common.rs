@@ -1,21 +1,70 @@
-use socks5_protocol::{Address, Error};
+use socks5_protocol::{Address, CommandReply, Error};
 use std::io::{self, ErrorKind, Result};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncReadExt;
+
+/// Version of the RFC 1929 username/password sub-negotiation.
+pub const PASSWORD_AUTH_VERSION: u8 = 0x01;
+
+fn reply_error(reply: CommandReply) -> io::Error {
+    let (kind, msg) = match reply {
+        CommandReply::ConnectionNotAllowedByRuleset => (
+            ErrorKind::PermissionDenied,
+            "connection not allowed by ruleset",
+        ),
+        CommandReply::NetworkUnreachable => (ErrorKind::NetworkUnreachable, "network unreachable"),
+        CommandReply::HostUnreachable => (ErrorKind::HostUnreachable, "host unreachable"),
+        CommandReply::ConnectionRefused => (ErrorKind::ConnectionRefused, "connection refused"),
+        CommandReply::TtlExpired => (ErrorKind::TimedOut, "TTL expired"),
+        CommandReply::CommandNotSupported => (ErrorKind::Unsupported, "command not supported"),
+        CommandReply::AddressTypeNotSupported => {
+            (ErrorKind::Unsupported, "address type not supported")
+        }
+        _ => (ErrorKind::Other, "general SOCKS server failure"),
+    };
+    io::Error::new(kind, format!("SOCKS5 {} ({})", msg, reply.to_u8()))
+}
 
 pub fn map_err(e: Error) -> rd_interface::Error {
     match e {
         Error::Io(io) => rd_interface::Error::IO(io),
-        e => rd_interface::Error::Other(e.into()),
+        Error::CommandReply(reply) => rd_interface::Error::IO(reply_error(reply)),
+        e => rd_interface::Error::protocol("socks5", e),
     }
 }
 
+/// RSV, FRAG, and the shortest address, a one byte domain with its length
+/// and port.
+const MIN_UDP_HEADER_SIZE: usize = 3 + 1 + 1 + 1 + 2;
+
+fn truncated_udp(len: usize) -> io::Error {
+    io::Error::new(
+        ErrorKind::UnexpectedEof,
+        format!("truncated UDP packet of {} bytes", len),
+    )
+}
+
+/// Splits a UDP packet into its address and payload.
 pub async fn parse_udp(buf: &[u8]) -> Result<(Address, &[u8])> {
+    if buf.len() < MIN_UDP_HEADER_SIZE {
+        return Err(truncated_udp(buf.len()));
+    }
     let mut cursor = std::io::Cursor::new(buf);
     let mut header = [0u8; 3];
     cursor.read_exact(&mut header).await?;
     let addr = match header[0..3] {
-        // TODO: support fragment sequence or at least give another error
-        [0x00, 0x00, 0x00] => Address::read(&mut cursor).await.map_err(map_err)?,
+        [0x00, 0x00, 0x00] => match Address::read(&mut cursor).await {
+            Ok(addr) => addr,
+            Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(truncated_udp(buf.len()))
+            }
+            Err(e) => return Err(map_err(e).into()),
+        },
+        [0x00, 0x00, frag] => {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("fragmented UDP packet is not supported, FRAG {}", frag),
+            ))
+        }
         _ => {
             return Err(io::Error::new(
                 ErrorKind::InvalidData,
@@ -23,8 +72,7 @@ pub async fn parse_udp(buf: &[u8]) -> Result<(Address, &[u8])> {
                     "server response wrong RSV {} RSV {} FRAG {}",
                     header[0], header[1], header[2]
                 ),
-            )
-            .into())
+            ))
         }
     };
 
@@ -34,15 +82,19 @@ pub async fn parse_udp(buf: &[u8]) -> Result<(Address, &[u8])> {
 }
 
 pub async fn pack_udp(addr: Address, buf: &[u8]) -> Result<Vec<u8>> {
-    let addr: Address = addr.into();
-    let mut cursor = std::io::Cursor::new(Vec::new());
-    cursor.write_all(&[0x00, 0x00, 0x00]).await?;
-    addr.write(&mut cursor).await.map_err(map_err)?;
-    cursor.write_all(buf).await?;
+    let mut bytes = Vec::new();
+    pack_udp_into(addr, buf, &mut bytes).await?;
+    Ok(bytes)
+}
 
-    let bytes = cursor.into_inner();
+/// Same as [pack_udp], but clears and reuses `out` to save an allocation.
+pub async fn pack_udp_into(addr: Address, buf: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    out.clear();
+    out.extend_from_slice(&[0x00, 0x00, 0x00]);
+    addr.write(&mut *out).await.map_err(map_err)?;
+    out.extend_from_slice(buf);
 
-    Ok(bytes)
+    Ok(())
 }
 
 pub fn sa2ra(addr: socks5_protocol::Address) -> rd_interface::Address {
@@ -51,9 +103,15 @@ pub fn sa2ra(addr: socks5_protocol::Address) -> rd_interface::Address {
         socks5_protocol::Address::SocketAddr(s) => rd_interface::Address::SocketAddr(s),
     }
 }
-pub fn ra2sa(addr: rd_interface::Address) -> socks5_protocol::Address {
-    match addr {
+pub fn ra2sa(addr: rd_interface::Address) -> Result<socks5_protocol::Address> {
+    Ok(match addr {
         rd_interface::Address::Domain(d, p) => socks5_protocol::Address::Domain(d, p),
         rd_interface::Address::SocketAddr(s) => socks5_protocol::Address::SocketAddr(s),
-    }
+        rd_interface::Address::Unix(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "Unix socket addresses can't be sent to a proxy",
+            ))
+        }
+    })
 }
@@ -1,33 +1,62 @@
-use super::common::{pack_udp, parse_udp, sa2ra};
+use super::common::{pack_udp_into, parse_udp, sa2ra, PASSWORD_AUTH_VERSION};
+use super::ServerConfig;
+use futures::{
+    future::{select, Either},
+    pin_mut,
+};
 use rd_interface::{
     async_trait,
-    util::{connect_tcp, connect_udp},
-    Context, IServer, IUdpChannel, IntoAddress, IntoDyn, Net, Result, TcpStream, UdpSocket,
+    constant::UDP_BUFFER_SIZE,
+    util::{connect_tcp, connect_udp, BoundAddr, StopSignal},
+    Context, Error, IServer, IUdpChannel, IntoAddress, IntoDyn, Net, Result, TcpStream, UdpSocket,
+    Value,
 };
+use serde::Deserialize;
 use socks5_protocol::{
     Address, AuthMethod, AuthRequest, AuthResponse, Command, CommandReply, CommandRequest,
     CommandResponse, Version,
 };
 use std::{
+    collections::HashMap,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
     sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter},
+    sync::Mutex,
+    time::{sleep_until, Instant},
 };
-use tokio::io::{split, AsyncWriteExt, BufWriter};
+use tracing::Instrument;
 
+#[derive(Clone)]
 struct Config {
     net: Net,
     listen_net: Net,
+    /// Username to password. Auth is required when it's not empty.
+    users: HashMap<String, String>,
+    udp_timeout: Duration,
 }
 
+/// How long a UDP association is kept without packets by default.
+pub const DEFAULT_UDP_TIMEOUT: Duration = Duration::from_secs(300);
+
 #[derive(Clone)]
 pub struct Socks5Server {
-    cfg: Arc<Config>,
+    /// Replaced on reload, the connections being served keep the old one.
+    cfg: Arc<RwLock<Arc<Config>>>,
 }
 
 impl Socks5Server {
     pub async fn serve_connection(self, socket: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
         let default_addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
-        let Config { net, listen_net } = &*self.cfg;
+        let cfg = self.config();
+        let Config {
+            net,
+            listen_net,
+            users,
+            udp_timeout,
+        } = &*cfg;
         let local_ip = socket.local_addr().await?.ip();
         let (mut rx, tx) = split(socket);
         let mut tx = BufWriter::with_capacity(512, tx);
@@ -35,14 +64,38 @@ impl Socks5Server {
         let version = Version::read(&mut rx).await?;
         let auth_req = AuthRequest::read(&mut rx).await?;
 
-        let method = auth_req.select_from(&[AuthMethod::Noauth]);
+        let required = if users.is_empty() {
+            AuthMethod::Noauth
+        } else {
+            AuthMethod::UsernamePassword
+        };
+        let method = if auth_req.0.contains(&required) {
+            required
+        } else {
+            AuthMethod::NoAcceptableMethod
+        };
         let auth_resp = AuthResponse::new(method);
-        // TODO: do auth here
 
         version.write(&mut tx).await?;
         auth_resp.write(&mut tx).await?;
         tx.flush().await?;
 
+        match method {
+            AuthMethod::Noauth => {}
+            AuthMethod::UsernamePassword => {
+                let (username, password) = read_password_auth(&mut rx).await?;
+                let ok = users.get(&username) == Some(&password);
+                tx.write_all(&[PASSWORD_AUTH_VERSION, if ok { 0x00 } else { 0x01 }])
+                    .await?;
+                tx.flush().await?;
+                if !ok {
+                    tracing::debug!("socks5 auth failed for user {:?}", username);
+                    return Ok(());
+                }
+            }
+            _ => return Ok(()),
+        }
+
         let cmd_req = CommandRequest::read(&mut rx).await?;
 
         match cmd_req.command {
@@ -86,10 +139,7 @@ impl Socks5Server {
                         return Ok(());
                     }
                 };
-                let out = match net
-                    .udp_bind(&mut Context::from_socketaddr(addr), dst.into())
-                    .await
-                {
+                let out = match net.udp_bind(&mut Context::from_socketaddr(addr), dst).await {
                     Ok(socket) => socket,
                     Err(e) => {
                         CommandResponse::error(e).write(&mut tx).await?;
@@ -119,10 +169,36 @@ impl Socks5Server {
                 CommandResponse::success(addr).write(&mut tx).await?;
                 tx.flush().await?;
 
-                let socket = rx.unsplit(tx.into_inner());
+                let mut socket = rx.unsplit(tx.into_inner());
 
-                let udp_channel = Socks5UdpSocket(udp, socket, RwLock::new(None));
-                connect_udp(udp_channel.into_dyn(), out).await?;
+                // The relay lives as long as the control connection, unless
+                // it's idle for too long.
+                let udp_channel = Socks5UdpSocket::new(udp);
+                let last_active = udp_channel.last_active.clone();
+                let udp_timeout = *udp_timeout;
+                let relay = connect_udp(udp_channel.into_dyn(), out);
+                let idle = async move {
+                    loop {
+                        let deadline = *last_active.lock().await + udp_timeout;
+                        if Instant::now() >= deadline {
+                            tracing::debug!("UDP association idle, closing");
+                            break;
+                        }
+                        sleep_until(deadline).await;
+                    }
+                };
+                let closed = async move {
+                    let mut buf = [0u8; 64];
+                    while let Ok(n) = socket.read(&mut buf).await {
+                        if n == 0 {
+                            break;
+                        }
+                    }
+                };
+                pin_mut!(relay, closed, idle);
+                if let Either::Left((r, _)) = select(relay, select(closed, idle)).await {
+                    r?;
+                }
             }
             _ => {
                 return Ok(());
@@ -131,14 +207,81 @@ impl Socks5Server {
 
         Ok(())
     }
-    pub fn new(listen_net: Net, net: Net) -> Self {
+    pub fn new(listen_net: Net, net: Net, users: HashMap<String, String>) -> Self {
         Self {
-            cfg: Arc::new(Config { net, listen_net }),
+            cfg: Arc::new(RwLock::new(Arc::new(Config {
+                net,
+                listen_net,
+                users,
+                udp_timeout: DEFAULT_UDP_TIMEOUT,
+            }))),
         }
     }
+    /// Closes UDP associations without packets either way for `udp_timeout`.
+    pub fn with_udp_timeout(self, udp_timeout: Duration) -> Self {
+        let mut cfg = Config::clone(&self.config());
+        cfg.udp_timeout = udp_timeout;
+        *self.cfg.write().unwrap() = Arc::new(cfg);
+        self
+    }
+    /// Serves the connections accepted from now on with `net`, `users` and
+    /// `udp_timeout`.
+    pub fn reload(&self, net: Net, users: HashMap<String, String>, udp_timeout: Duration) {
+        let listen_net = self.config().listen_net.clone();
+        *self.cfg.write().unwrap() = Arc::new(Config {
+            net,
+            listen_net,
+            users,
+            udp_timeout,
+        });
+    }
+    fn config(&self) -> Arc<Config> {
+        self.cfg.read().unwrap().clone()
+    }
 }
 
-pub struct Socks5UdpSocket(UdpSocket, TcpStream, RwLock<Option<SocketAddr>>);
+/// Reads the RFC 1929 username/password request.
+async fn read_password_auth(
+    rx: &mut (impl AsyncRead + Unpin),
+) -> std::io::Result<(String, String)> {
+    async fn read_field(rx: &mut (impl AsyncRead + Unpin)) -> std::io::Result<String> {
+        let len = rx.read_u8().await?;
+        let mut buf = vec![0u8; len as usize];
+        rx.read_exact(&mut buf).await?;
+        String::from_utf8(buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    let version = rx.read_u8().await?;
+    if version != PASSWORD_AUTH_VERSION {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unsupported auth version {}", version),
+        ));
+    }
+    let username = read_field(rx).await?;
+    let password = read_field(rx).await?;
+    Ok((username, password))
+}
+
+pub struct Socks5UdpSocket {
+    udp: UdpSocket,
+    client: RwLock<Option<SocketAddr>>,
+    /// Reused for every packet sent to the client.
+    send_buf: Mutex<Vec<u8>>,
+    /// When the last packet went either way.
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl Socks5UdpSocket {
+    fn new(udp: UdpSocket) -> Self {
+        Socks5UdpSocket {
+            udp,
+            client: RwLock::new(None),
+            send_buf: Mutex::new(Vec::with_capacity(259 + UDP_BUFFER_SIZE)),
+            last_active: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
 
 #[async_trait]
 impl IUdpChannel for Socks5UdpSocket {
@@ -146,12 +289,23 @@ impl IUdpChannel for Socks5UdpSocket {
         // 259 is max size of address, atype 1 + domain len 1 + domain 255 + port 2
         let bytes_size = 259 + buf.len();
         let mut bytes = vec![0u8; bytes_size];
-        let (recv_len, from_addr) = self.0.recv_from(&mut bytes).await?;
-        let saved_addr = { *self.2.read().unwrap() };
-        if let None = saved_addr {
-            *self.2.write().unwrap() = Some(from_addr);
-        }
+        let recv_len = loop {
+            let (recv_len, from_addr) = self.udp.recv_from(&mut bytes).await?;
+            let saved_addr = { *self.client.read().unwrap() };
+            if saved_addr.is_none() {
+                *self.client.write().unwrap() = Some(from_addr);
+            }
+
+            // Fragments must be dropped since reassembly isn't supported.
+            match bytes[..recv_len].get(2) {
+                Some(frag) if *frag != 0 => {
+                    tracing::debug!("Drop fragmented UDP packet, FRAG {}", frag);
+                }
+                _ => break recv_len,
+            }
+        };
         bytes.truncate(recv_len);
+        *self.last_active.lock().await = Instant::now();
 
         let (addr, payload) = parse_udp(&bytes).await?;
         let to_copy = payload.len().min(buf.len());
@@ -163,11 +317,13 @@ impl IUdpChannel for Socks5UdpSocket {
     async fn send_recv_from(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
         let saddr: Address = addr.into();
 
-        let bytes = pack_udp(saddr, buf).await?;
+        let mut bytes = self.send_buf.lock().await;
+        pack_udp_into(saddr, buf, &mut bytes).await?;
 
-        let addr = { *self.2.read().unwrap() };
+        let addr = { *self.client.read().unwrap() };
+        *self.last_active.lock().await = Instant::now();
         Ok(if let Some(addr) = addr {
-            self.0.send_to(&bytes, addr.into()).await?
+            self.udp.send_to(&bytes, addr.into()).await?
         } else {
             0
         })
@@ -178,6 +334,8 @@ pub struct Socks5 {
     server: Socks5Server,
     listen_net: Net,
     bind: String,
+    stop: StopSignal,
+    local_addr: BoundAddr,
 }
 
 #[async_trait]
@@ -187,25 +345,56 @@ impl IServer for Socks5 {
             .listen_net
             .tcp_bind(&mut Context::new(), self.bind.into_address()?)
             .await?;
+        self.local_addr.set(listener.local_addr().await?);
 
         loop {
-            let (socket, addr) = listener.accept().await?;
+            let (socket, addr) = match self.stop.until(listener.accept()).await {
+                Some(r) => r?,
+                None => return Ok(()),
+            };
             let server = self.server.clone();
-            let _ = tokio::spawn(async move {
-                if let Err(e) = server.serve_connection(socket, addr).await {
-                    tracing::error!("Error when serve_connection: {:?}", e)
+            tokio::spawn(
+                async move {
+                    if let Err(e) = server.serve_connection(socket, addr).await {
+                        tracing::error!("Error when serve_connection: {:?}", e)
+                    }
                 }
-            });
+                .instrument(tracing::info_span!("serve_connection", src = %addr)),
+            );
         }
     }
+    async fn stop(&self) -> Result<()> {
+        self.stop.stop();
+        Ok(())
+    }
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.local_addr.get()
+    }
+    async fn reload(&self, net: Net, config: Value) -> Result<()> {
+        let config = ServerConfig::deserialize(config)?;
+        if config.bind != self.bind {
+            return Err(Error::Other("the bind address changed".into()));
+        }
+        let udp_timeout = config.udp_timeout()?;
+        self.server.reload(net, config.users, udp_timeout);
+        Ok(())
+    }
 }
 
 impl Socks5 {
-    pub fn new(listen_net: Net, net: Net, bind: String) -> Self {
+    pub fn new(
+        listen_net: Net,
+        net: Net,
+        bind: String,
+        users: HashMap<String, String>,
+        udp_timeout: Duration,
+    ) -> Self {
         Socks5 {
-            server: Socks5Server::new(listen_net.clone(), net),
+            server: Socks5Server::new(listen_net.clone(), net, users).with_udp_timeout(udp_timeout),
             listen_net,
             bind,
+            stop: StopSignal::new(),
+            local_addr: BoundAddr::new(),
         }
     }
 }
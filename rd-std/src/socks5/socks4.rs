@@ -0,0 +1,144 @@
+use rd_interface::{
+    async_trait, impl_async_read_write, Address, INet, ITcpStream, IntoAddress, IntoDyn, Net,
+    Result, TcpStream, UdpSocket, NOT_IMPLEMENTED,
+};
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const VERSION: u8 = 0x04;
+const CMD_CONNECT: u8 = 0x01;
+const REPLY_GRANTED: u8 = 90;
+
+/// A SOCKS4 client, using the 4a extension for domain targets.
+pub struct Socks4Client {
+    address: String,
+    port: u16,
+    user_id: String,
+    net: Net,
+}
+
+pub struct Socks4TcpStream(TcpStream);
+
+impl_async_read_write!(Socks4TcpStream, 0);
+
+#[async_trait]
+impl ITcpStream for Socks4TcpStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        Err(NOT_IMPLEMENTED)
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
+
+fn reply_error(code: u8) -> rd_interface::Error {
+    let (kind, msg) = match code {
+        91 => (ErrorKind::ConnectionRefused, "request rejected or failed"),
+        92 => (ErrorKind::PermissionDenied, "identd is unreachable"),
+        93 => (ErrorKind::PermissionDenied, "identd user id mismatch"),
+        _ => (ErrorKind::InvalidData, "unknown reply code"),
+    };
+    io::Error::new(kind, format!("SOCKS4 {} ({})", msg, code)).into()
+}
+
+impl Socks4Client {
+    pub fn new(net: Net, address: String, port: u16, user_id: String) -> Self {
+        Self {
+            address,
+            port,
+            user_id,
+            net,
+        }
+    }
+    fn server(&self) -> Result<Address> {
+        (self.address.as_str(), self.port)
+            .into_address()
+            .map_err(Into::into)
+    }
+    fn request(&self, addr: &Address) -> Result<Vec<u8>> {
+        let mut req = vec![VERSION, CMD_CONNECT];
+        match addr {
+            Address::SocketAddr(SocketAddr::V4(addr)) => {
+                req.extend_from_slice(&addr.port().to_be_bytes());
+                req.extend_from_slice(&addr.ip().octets());
+                req.extend_from_slice(self.user_id.as_bytes());
+                req.push(0);
+            }
+            Address::SocketAddr(SocketAddr::V6(_)) => {
+                return Err(io::Error::new(
+                    ErrorKind::AddrNotAvailable,
+                    "SOCKS4 doesn't support IPv6 addresses",
+                )
+                .into())
+            }
+            Address::Domain(domain, port) => {
+                // SOCKS4a: an invalid IP 0.0.0.x tells the server to read the domain.
+                req.extend_from_slice(&port.to_be_bytes());
+                req.extend_from_slice(&[0, 0, 0, 1]);
+                req.extend_from_slice(self.user_id.as_bytes());
+                req.push(0);
+                req.extend_from_slice(domain.as_bytes());
+                req.push(0);
+            }
+            Address::Unix(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::AddrNotAvailable,
+                    "SOCKS4 doesn't support Unix socket addresses",
+                )
+                .into())
+            }
+        };
+        Ok(req)
+    }
+}
+
+#[async_trait]
+impl INet for Socks4Client {
+    async fn tcp_connect(
+        &self,
+        ctx: &mut rd_interface::Context,
+        addr: Address,
+    ) -> Result<TcpStream> {
+        // IP literals in a domain are sent as plain SOCKS4.
+        let addr = match addr {
+            Address::Domain(domain, port) => match domain.parse::<IpAddr>() {
+                Ok(ip) => Address::SocketAddr(SocketAddr::new(ip, port)),
+                Err(_) => Address::Domain(domain, port),
+            },
+            addr => addr,
+        };
+        let req = self.request(&addr)?;
+
+        let mut socket = self.net.tcp_connect(ctx, self.server()?).await?;
+        socket.write_all(&req).await?;
+        socket.flush().await?;
+
+        let mut reply = [0u8; 8];
+        socket.read_exact(&mut reply).await?;
+        if reply[1] != REPLY_GRANTED {
+            return Err(reply_error(reply[1]));
+        }
+
+        Ok(Socks4TcpStream(socket).into_dyn())
+    }
+
+    async fn tcp_bind(
+        &self,
+        _ctx: &mut rd_interface::Context,
+        _addr: Address,
+    ) -> Result<rd_interface::TcpListener> {
+        Err(NOT_IMPLEMENTED)
+    }
+
+    async fn udp_bind(
+        &self,
+        _ctx: &mut rd_interface::Context,
+        _addr: Address,
+    ) -> Result<UdpSocket> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
@@ -1,7 +1,7 @@
 use super::*;
 use crate::builtin::local::{LocalConfig, LocalNet};
 use crate::tests::{assert_echo, get_registry, spawn_echo_server};
-use rd_interface::{IServer, IntoDyn};
+use rd_interface::{INet, IServer, IntoDyn, Net};
 use std::time::Duration;
 use tokio::time::sleep;
 
@@ -16,12 +16,635 @@ async fn test_socks5_server_client() {
     let local = LocalNet::new(LocalConfig::default()).into_dyn();
     spawn_echo_server(&local, "127.0.0.1:26666").await;
 
-    let server = server::Socks5::new(local.clone(), local.clone(), "127.0.0.1:16666".to_string());
+    let server = server::Socks5::new(
+        local.clone(),
+        local.clone(),
+        "127.0.0.1:16666".to_string(),
+        Default::default(),
+        server::DEFAULT_UDP_TIMEOUT,
+    );
     tokio::spawn(async move { server.start().await });
 
     sleep(Duration::from_secs(1)).await;
 
-    let client = client::Socks5Client::new(local, "127.0.0.1".to_string(), 16666).into_dyn();
+    let client = client::Socks5Client::new(local, "127.0.0.1".to_string(), 16666, None).into_dyn();
 
     assert_echo(&client, "127.0.0.1:26666").await;
 }
+
+/// Accepts one SOCKS4 request, replies with `code` and echoes afterwards.
+/// Returns the user id and the requested target.
+async fn spawn_socks4_server(code: u8) -> (u16, tokio::task::JoinHandle<(String, String)>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn read_cstr(socket: &mut tokio::net::TcpStream) -> String {
+        let mut s = Vec::new();
+        loop {
+            match socket.read_u8().await.unwrap() {
+                0 => break,
+                b => s.push(b),
+            }
+        }
+        String::from_utf8(s).unwrap()
+    }
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut head = [0u8; 8];
+        socket.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[..2], [4, 1]);
+        let port = u16::from_be_bytes([head[2], head[3]]);
+        let user_id = read_cstr(&mut socket).await;
+        let target = match head[4..8] {
+            [0, 0, 0, x] if x != 0 => format!("{}:{}", read_cstr(&mut socket).await, port),
+            [a, b, c, d] => format!("{}.{}.{}.{}:{}", a, b, c, d, port),
+            _ => unreachable!(),
+        };
+
+        socket
+            .write_all(&[0, code, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        if code == 90 {
+            let (mut rx, mut tx) = socket.split();
+            tokio::io::copy(&mut rx, &mut tx).await.ok();
+        }
+        (user_id, target)
+    });
+    (port, handle)
+}
+
+#[tokio::test]
+async fn test_socks4_client() {
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+
+    let (port, handle) = spawn_socks4_server(90).await;
+    let client = Socks4Client::new(
+        local.clone(),
+        "127.0.0.1".to_string(),
+        port,
+        "rd".to_string(),
+    )
+    .into_dyn();
+    assert_echo(&client, "1.2.3.4:80").await;
+    assert_eq!(
+        handle.await.unwrap(),
+        ("rd".to_string(), "1.2.3.4:80".to_string())
+    );
+
+    let (port, handle) = spawn_socks4_server(90).await;
+    let client = Socks4Client::new(local, "127.0.0.1".to_string(), port, String::new()).into_dyn();
+    assert_echo(&client, "example.com:443").await;
+    assert_eq!(
+        handle.await.unwrap(),
+        (String::new(), "example.com:443".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_socks4_client_rejected() {
+    use rd_interface::{Context, IntoAddress};
+
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    let (port, _) = spawn_socks4_server(91).await;
+    let client = Socks4Client::new(local, "127.0.0.1".to_string(), port, String::new());
+
+    let result = client
+        .tcp_connect(&mut Context::new(), "1.2.3.4:80".into_address().unwrap())
+        .await;
+    match result {
+        Err(rd_interface::Error::IO(e)) => {
+            assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused)
+        }
+        _ => panic!("expected ConnectionRefused"),
+    }
+}
+
+#[tokio::test]
+async fn test_socks5_udp_associate() {
+    use rd_interface::{Context, IntoAddress};
+    use tokio::time::timeout;
+
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+
+    let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        loop {
+            let (size, addr) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..size], addr).await.unwrap();
+        }
+    });
+
+    let listener = local
+        .tcp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+        .await
+        .unwrap();
+    let port = listener.local_addr().await.unwrap().port();
+    let server = server::Socks5Server::new(local.clone(), local.clone(), Default::default());
+    let handle = tokio::spawn(async move {
+        let (socket, addr) = listener.accept().await.unwrap();
+        server.serve_connection(socket, addr).await
+    });
+
+    let client = client::Socks5Client::new(local, "127.0.0.1".to_string(), port, None);
+    let udp = client
+        .udp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+        .await
+        .unwrap();
+
+    udp.send_to(b"hello", echo_addr.into()).await.unwrap();
+    let mut buf = [0u8; 1024];
+    let (size, addr) = timeout(Duration::from_secs(5), udp.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..size], b"hello");
+    assert_eq!(addr, echo_addr);
+
+    // closing the control connection ends the relay.
+    drop(udp);
+    timeout(Duration::from_secs(5), handle)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_socks5_udp_timeout() {
+    use rd_interface::{Context, IntoAddress};
+    use tokio::time::timeout;
+
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+
+    let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        loop {
+            let (size, addr) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..size], addr).await.unwrap();
+        }
+    });
+
+    let listener = local
+        .tcp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+        .await
+        .unwrap();
+    let port = listener.local_addr().await.unwrap().port();
+    let server = server::Socks5Server::new(local.clone(), local.clone(), Default::default())
+        .with_udp_timeout(Duration::from_millis(300));
+    let mut handle = tokio::spawn(async move {
+        let (socket, addr) = listener.accept().await.unwrap();
+        server.serve_connection(socket, addr).await
+    });
+
+    let client = client::Socks5Client::new(local, "127.0.0.1".to_string(), port, None);
+    let udp = client
+        .udp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+        .await
+        .unwrap();
+
+    // packets keep the association
+    let mut buf = [0u8; 1024];
+    for _ in 0..4 {
+        udp.send_to(b"hello", echo_addr.into()).await.unwrap();
+        let (size, _) = timeout(Duration::from_secs(5), udp.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..size], b"hello");
+        sleep(Duration::from_millis(150)).await;
+    }
+    assert!(timeout(Duration::from_millis(10), &mut handle)
+        .await
+        .is_err());
+
+    // and it's closed without them, though the control connection is open
+    timeout(Duration::from_secs(5), handle)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    drop(udp);
+}
+
+#[tokio::test]
+async fn test_parse_udp_fragment() {
+    let packet = [0, 0, 1, 1, 127, 0, 0, 1, 0, 80, b'x'];
+    let err = common::parse_udp(&packet).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+
+    let packet = [0, 0, 0, 1, 127, 0, 0, 1, 0, 80, b'x'];
+    let (addr, payload) = common::parse_udp(&packet).await.unwrap();
+    assert_eq!(addr.to_string(), "127.0.0.1:80");
+    assert_eq!(payload, b"x");
+
+    // a bad RSV is told apart from fragments
+    let packet = [1, 0, 1, 1, 127, 0, 0, 1, 0, 80, b'x'];
+    let err = common::parse_udp(&packet).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn test_parse_udp_truncated() {
+    let packets: [&[u8]; 5] = [
+        &[],
+        &[0, 0, 0],
+        &[0, 0, 0, 1, 127, 0, 0, 1, 0],
+        // IPv6 with part of the address
+        &[0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        // a domain longer than the packet
+        &[0, 0, 0, 3, 20, b'e', b'x', b'a', b'm', b'p', b'l', b'e'],
+    ];
+    for packet in packets {
+        let err = common::parse_udp(packet).await.unwrap_err();
+        assert_eq!(
+            err.kind(),
+            std::io::ErrorKind::UnexpectedEof,
+            "{:?}",
+            packet
+        );
+    }
+
+    // an empty payload is fine
+    let packet = [0, 0, 0, 1, 127, 0, 0, 1, 0, 80];
+    let (_, payload) = common::parse_udp(&packet).await.unwrap();
+    assert!(payload.is_empty());
+
+    // shorter than any IPv4 packet
+    let packet = [0, 0, 0, 3, 1, b'a', 0, 80];
+    let (addr, payload) = common::parse_udp(&packet).await.unwrap();
+    assert_eq!(addr.to_string(), "a:80");
+    assert!(payload.is_empty());
+}
+
+async fn spawn_socks5_server(local: &Net, users: &[(&str, &str)]) -> u16 {
+    use rd_interface::{Context, IntoAddress};
+
+    let users = users
+        .iter()
+        .map(|(u, p)| (u.to_string(), p.to_string()))
+        .collect();
+    let server = server::Socks5Server::new(local.clone(), local.clone(), users);
+    let listener = local
+        .tcp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+        .await
+        .unwrap();
+    let port = listener.local_addr().await.unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (socket, addr) = listener.accept().await.unwrap();
+            let server = server.clone();
+            tokio::spawn(async move { server.serve_connection(socket, addr).await });
+        }
+    });
+    port
+}
+
+async fn assert_auth_failed(client: &client::Socks5Client) {
+    use rd_interface::{Context, IntoAddress};
+
+    let result = client
+        .tcp_connect(
+            &mut Context::new(),
+            "127.0.0.1:26670".into_address().unwrap(),
+        )
+        .await;
+    match result {
+        Err(rd_interface::Error::AuthFailed(_)) => {}
+        Err(e) => panic!("expected AuthFailed, got {:?}", e),
+        Ok(_) => panic!("expected AuthFailed"),
+    }
+}
+
+#[tokio::test]
+async fn test_socks5_auth() {
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    spawn_echo_server(&local, "127.0.0.1:26670").await;
+    let port = spawn_socks5_server(&local, &[("user", "pass")]).await;
+
+    let auth = |u: &str, p: &str| Some((u.to_string(), p.to_string()));
+
+    let client = client::Socks5Client::new(
+        local.clone(),
+        "127.0.0.1".to_string(),
+        port,
+        auth("user", "pass"),
+    )
+    .into_dyn();
+    assert_echo(&client, "127.0.0.1:26670").await;
+
+    let client = client::Socks5Client::new(
+        local.clone(),
+        "127.0.0.1".to_string(),
+        port,
+        auth("user", "bad"),
+    );
+    assert_auth_failed(&client).await;
+
+    let client = client::Socks5Client::new(local.clone(), "127.0.0.1".to_string(), port, None);
+    assert_auth_failed(&client).await;
+
+    // a client with credentials can still use a server without auth.
+    let port = spawn_socks5_server(&local, &[]).await;
+    let client =
+        client::Socks5Client::new(local, "127.0.0.1".to_string(), port, auth("user", "pass"))
+            .into_dyn();
+    assert_echo(&client, "127.0.0.1:26670").await;
+}
+
+#[tokio::test]
+async fn test_socks5_client_protocol_error() {
+    use rd_interface::{Context, IntoAddress};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // answers the greeting as a SOCKS4 server would
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        socket.read_exact(&mut [0u8; 3]).await.unwrap();
+        socket.write_all(&[4, 0]).await.unwrap();
+    });
+
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    let client = client::Socks5Client::new(local, "127.0.0.1".to_string(), port, None);
+    let result = client
+        .tcp_connect(&mut Context::new(), "1.2.3.4:80".into_address().unwrap())
+        .await;
+    match result {
+        Err(rd_interface::Error::Protocol { proto, .. }) => assert_eq!(proto, "socks5"),
+        Err(e) => panic!("expected Protocol, got {:?}", e),
+        Ok(_) => panic!("expected Protocol"),
+    }
+}
+
+#[tokio::test]
+async fn test_socks5_client_resolved() {
+    use rd_interface::{Context, IntoAddress};
+
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    spawn_echo_server(&local, "127.0.0.1:26671").await;
+    let port = spawn_socks5_server(&local, &[]).await;
+    let client = client::Socks5Client::new(local, "proxy.invalid".to_string(), port, None);
+    let target = || "127.0.0.1:26671".into_address().unwrap();
+
+    assert!(client
+        .tcp_connect(&mut Context::new(), target())
+        .await
+        .is_err());
+
+    let mut ctx = Context::new();
+    ctx.set_resolved("proxy.invalid", vec!["127.0.0.1".parse().unwrap()]);
+    assert!(client.tcp_connect(&mut ctx, target()).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_socks5_server_stop() {
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    let server = std::sync::Arc::new(server::Socks5::new(
+        local.clone(),
+        local,
+        "127.0.0.1:26676".to_string(),
+        Default::default(),
+        server::DEFAULT_UDP_TIMEOUT,
+    ));
+    let handle = {
+        let server = server.clone();
+        tokio::spawn(async move { server.start().await })
+    };
+
+    sleep(Duration::from_millis(200)).await;
+    assert!(tokio::net::TcpStream::connect("127.0.0.1:26676")
+        .await
+        .is_ok());
+
+    assert_eq!(server.local_addr().await.unwrap().port(), 26676);
+    server.stop().await.unwrap();
+    handle.await.unwrap().unwrap();
+
+    // the port is released
+    tokio::net::TcpListener::bind("127.0.0.1:26676")
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_pack_udp_into() {
+    use common::pack_udp_into;
+    use socks5_protocol::Address;
+
+    let cases: Vec<(Address, &[u8], Vec<u8>)> = vec![
+        (
+            Address::SocketAddr("1.2.3.4:53".parse().unwrap()),
+            b"hello",
+            b"\x00\x00\x00\x01\x01\x02\x03\x04\x00\x35hello".to_vec(),
+        ),
+        (
+            Address::SocketAddr("[2001:db8::1]:443".parse().unwrap()),
+            b"",
+            b"\x00\x00\x00\x04\x20\x01\x0d\xb8\x00\x00\x00\x00\
+              \x00\x00\x00\x00\x00\x00\x00\x01\x01\xbb"
+                .to_vec(),
+        ),
+        (
+            Address::Domain("example.com".to_string(), 80),
+            b"\xab\xcd",
+            b"\x00\x00\x00\x03\x0bexample.com\x00\x50\xab\xcd".to_vec(),
+        ),
+        (
+            Address::SocketAddr("1.2.3.4:53".parse().unwrap()),
+            b"",
+            b"\x00\x00\x00\x01\x01\x02\x03\x04\x00\x35".to_vec(),
+        ),
+    ];
+
+    // one scratch buffer for every packet, like the relay loop
+    let mut out = Vec::new();
+    for (addr, payload, expected) in cases {
+        pack_udp_into(addr, payload, &mut out).await.unwrap();
+        assert_eq!(out, expected);
+    }
+}
+
+/// Takes a BIND request for `expected` and replies `first`. Then replies
+/// `second` from 10.0.0.2:2000 and echoes when it's 0.
+async fn spawn_socks5_bind_server(expected: &'static str, first: u8, second: u8) -> u16 {
+    use socks5_protocol::{AuthRequest, Command, CommandRequest, Version};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        Version::read(&mut socket).await.unwrap();
+        AuthRequest::read(&mut socket).await.unwrap();
+        socket.write_all(&[5, 0]).await.unwrap();
+
+        let req = CommandRequest::read(&mut socket).await.unwrap();
+        assert!(matches!(req.command, Command::Bind));
+        assert_eq!(
+            req.address.to_socket_addr().unwrap(),
+            expected.parse().unwrap()
+        );
+        // bound to 0.0.0.0:4000
+        socket
+            .write_all(&[5, first, 0, 1, 0, 0, 0, 0, 0x0f, 0xa0])
+            .await
+            .unwrap();
+        if first != 0 {
+            return;
+        }
+
+        sleep(Duration::from_millis(100)).await;
+        socket
+            .write_all(&[5, second, 0, 1, 10, 0, 0, 2, 0x07, 0xd0])
+            .await
+            .unwrap();
+        if second == 0 {
+            let (mut rx, mut tx) = socket.split();
+            tokio::io::copy(&mut rx, &mut tx).await.ok();
+        } else {
+            socket.read_to_end(&mut Vec::new()).await.ok();
+        }
+    });
+    port
+}
+
+fn io_kind<T>(result: rd_interface::Result<T>) -> std::io::ErrorKind {
+    match result {
+        Err(rd_interface::Error::IO(e)) => e.kind(),
+        Err(e) => panic!("expected IO, got {:?}", e),
+        Ok(_) => panic!("expected an error"),
+    }
+}
+
+#[tokio::test]
+async fn test_socks5_bind() {
+    use rd_interface::{Context, IntoAddress};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let port = spawn_socks5_bind_server("10.0.0.2:0", 0, 0).await;
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    let client = client::Socks5Client::new(local, "127.0.0.1".to_string(), port, None);
+
+    let listener = client
+        .tcp_bind(&mut Context::new(), "10.0.0.2:0".into_address().unwrap())
+        .await
+        .unwrap();
+    // the server's address in place of 0.0.0.0
+    assert_eq!(
+        listener.local_addr().await.unwrap(),
+        "127.0.0.1:4000".parse().unwrap()
+    );
+
+    let (mut tcp, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer, "10.0.0.2:2000".parse().unwrap());
+    tcp.write_all(b"PORT").await.unwrap();
+    let mut buf = [0u8; 4];
+    tcp.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"PORT");
+
+    assert!(listener.accept().await.is_err());
+}
+
+#[tokio::test]
+async fn test_socks5_bind_rejected() {
+    use rd_interface::{Context, IntoAddress};
+
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    let addr = "10.0.0.2:0".into_address().unwrap();
+
+    // the first reply
+    let port = spawn_socks5_bind_server("10.0.0.2:0", 7, 0).await;
+    let client = client::Socks5Client::new(local.clone(), "127.0.0.1".to_string(), port, None);
+    let result = client.tcp_bind(&mut Context::new(), addr.clone()).await;
+    assert_eq!(io_kind(result), std::io::ErrorKind::Unsupported);
+
+    // the second reply
+    let port = spawn_socks5_bind_server("10.0.0.2:0", 0, 5).await;
+    let client = client::Socks5Client::new(local, "127.0.0.1".to_string(), port, None);
+    let listener = client.tcp_bind(&mut Context::new(), addr).await.unwrap();
+    assert_eq!(
+        io_kind(listener.accept().await),
+        std::io::ErrorKind::ConnectionRefused
+    );
+}
+
+#[tokio::test]
+async fn test_socks5_reload() {
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    spawn_echo_server(&local, "127.0.0.1:26680").await;
+
+    let users = std::iter::once(("a".to_string(), "1".to_string())).collect();
+    let server = std::sync::Arc::new(server::Socks5::new(
+        local.clone(),
+        local.clone(),
+        "127.0.0.1:0".to_string(),
+        users,
+        server::DEFAULT_UDP_TIMEOUT,
+    ));
+    let handle = {
+        let server = server.clone();
+        tokio::spawn(async move { server.start().await })
+    };
+    let addr = loop {
+        if let Ok(addr) = server.local_addr().await {
+            break addr;
+        }
+        sleep(Duration::from_millis(10)).await;
+    };
+
+    let client = |u: &str, p: &str| {
+        client::Socks5Client::new(
+            local.clone(),
+            "127.0.0.1".to_string(),
+            addr.port(),
+            Some((u.to_string(), p.to_string())),
+        )
+    };
+    assert_echo(&client("a", "1").into_dyn(), "127.0.0.1:26680").await;
+
+    server
+        .reload(
+            local.clone(),
+            serde_json::json!({ "bind": "127.0.0.1:0", "users": { "b": "2" } }),
+        )
+        .await
+        .unwrap();
+    assert_auth_failed(&client("a", "1")).await;
+    assert_echo(&client("b", "2").into_dyn(), "127.0.0.1:26680").await;
+
+    // still the same listener
+    assert_eq!(server.local_addr().await.unwrap(), addr);
+    assert!(!handle.is_finished());
+
+    let result = server
+        .reload(local.clone(), serde_json::json!({ "bind": "127.0.0.1:1" }))
+        .await;
+    assert!(result.is_err());
+    let result = server
+        .reload(
+            local.clone(),
+            serde_json::json!({ "bind": "127.0.0.1:0", "udp_timeout": 0 }),
+        )
+        .await;
+    assert!(result.is_err());
+    server.stop().await.unwrap();
+    handle.await.unwrap().unwrap();
+}
+
+#[test]
+fn test_socks5_zero_udp_timeout() {
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    let config: ServerConfig =
+        serde_json::from_value(serde_json::json!({ "bind": "127.0.0.1:0", "udp_timeout": 0 }))
+            .unwrap();
+    let result = <server::Socks5 as ServerFactory>::new(local.clone(), local, config);
+    assert!(result.is_err());
+}
@@ -0,0 +1,88 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use rd_interface::{
+    async_trait,
+    error::map_other,
+    impl_async_read_write,
+    schemars::{self, JsonSchema},
+    Config, ITcpStream, IntoDyn, Result, TcpStream,
+};
+use serde_derive::Deserialize;
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+/// Terminates TLS on the accepted connections of a server.
+#[derive(Debug, Clone, Deserialize, Config, JsonSchema)]
+pub struct TlsServerConfig {
+    /// Path of the PEM certificate chain.
+    pub cert: String,
+    /// Path of the PEM private key.
+    pub key: String,
+    /// Path of the PEM CA certificates. If set, clients must present a
+    /// certificate signed by one of them.
+    #[serde(default)]
+    pub client_ca: Option<String>,
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path)?;
+    CertificateDer::pem_slice_iter(&pem)
+        .collect::<std::result::Result<_, _>>()
+        .map_err(map_other)
+}
+
+impl TlsServerConfig {
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(map_other)?;
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(path)? {
+                    roots.add(cert).map_err(map_other)?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .build()
+                    .map_err(map_other)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let key = PrivateKeyDer::from_pem_slice(&std::fs::read(&self.key)?).map_err(map_other)?;
+        let config = builder
+            .with_single_cert(read_certs(&self.cert)?, key)
+            .map_err(map_other)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Does the TLS handshake on an accepted stream.
+pub async fn accept(acceptor: &TlsAcceptor, socket: TcpStream) -> Result<TcpStream> {
+    let tls = acceptor.accept(socket).await?;
+    Ok(TlsServerStream(tls).into_dyn())
+}
+
+pub struct TlsServerStream(TlsStream<TcpStream>);
+
+impl_async_read_write!(TlsServerStream, 0);
+
+#[async_trait]
+impl ITcpStream for TlsServerStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        self.0.get_ref().0.peer_addr().await
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.0.get_ref().0.local_addr().await
+    }
+}
@@ -0,0 +1,43 @@
+pub use client::TrojanNet;
+
+mod client;
+#[cfg(test)]
+mod tests;
+
+use rd_interface::{
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Config, Registry, Result,
+};
+use serde_derive::Deserialize;
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct TrojanNetConfig {
+    server: String,
+    port: u16,
+    password: String,
+    /// TLS server name, defaults to `server`.
+    #[serde(default)]
+    sni: Option<String>,
+    /// Accept any certificate from the server.
+    #[serde(default)]
+    skip_cert_verify: bool,
+
+    #[serde(default)]
+    net: NetRef,
+}
+
+impl NetFactory for TrojanNet {
+    const NAME: &'static str = "trojan";
+    type Config = TrojanNetConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        TrojanNet::new(config.net.try_net()?, config)
+    }
+}
+
+pub fn init(registry: &mut Registry) -> Result<()> {
+    registry.add_net::<TrojanNet>();
+    Ok(())
+}
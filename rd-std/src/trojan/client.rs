@@ -0,0 +1,264 @@
+use super::TrojanNetConfig;
+use crate::socks5::common::{map_err, ra2sa, sa2ra};
+use rd_interface::{
+    async_trait, impl_async_read_write, Address, INet, ITcpStream, IUdpSocket, IntoAddress,
+    IntoDyn, Net, Result, TcpStream, UdpSocket, NOT_IMPLEMENTED,
+};
+use sha2::{Digest, Sha224};
+use std::{convert::TryFrom, net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::Mutex,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, CryptoProvider},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    },
+    TlsConnector,
+};
+
+const CRLF: &[u8] = b"\r\n";
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+pub struct TrojanNet {
+    net: Net,
+    server: String,
+    port: u16,
+    password: String,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+}
+
+/// Hex encoded SHA-224 of the password, as sent in the request header.
+fn hash_password(password: &str) -> String {
+    Sha224::digest(password.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn tls_connector(skip_cert_verify: bool) -> Result<TlsConnector> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(rd_interface::error::map_other)?;
+    let config = if skip_cert_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier(provider)))
+            .with_no_client_auth()
+    } else {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+impl TrojanNet {
+    pub fn new(net: Net, config: TrojanNetConfig) -> Result<Self> {
+        let sni = config.sni.clone().unwrap_or_else(|| config.server.clone());
+        let server_name = ServerName::try_from(sni).map_err(rd_interface::error::map_other)?;
+
+        Ok(TrojanNet {
+            net,
+            server: config.server,
+            port: config.port,
+            password: hash_password(&config.password),
+            server_name,
+            connector: tls_connector(config.skip_cert_verify)?,
+        })
+    }
+    fn server(&self) -> Result<Address> {
+        (self.server.as_str(), self.port)
+            .into_address()
+            .map_err(Into::into)
+    }
+    /// Connects to the server and sends the request header.
+    async fn connect(
+        &self,
+        ctx: &mut rd_interface::Context,
+        cmd: u8,
+        addr: Address,
+    ) -> Result<TlsStream<TcpStream>> {
+        let tcp = self.net.tcp_connect(ctx, self.server()?).await?;
+        let mut tls = self
+            .connector
+            .connect(self.server_name.clone(), tcp)
+            .await?;
+
+        let mut header = Vec::with_capacity(56 + 2 + 1 + 259 + 2);
+        header.extend_from_slice(self.password.as_bytes());
+        header.extend_from_slice(CRLF);
+        header.push(cmd);
+        ra2sa(addr)?.write(&mut header).await.map_err(map_err)?;
+        header.extend_from_slice(CRLF);
+
+        tls.write_all(&header).await?;
+        tls.flush().await?;
+
+        Ok(tls)
+    }
+}
+
+pub struct TrojanTcpStream(TlsStream<TcpStream>);
+
+impl_async_read_write!(TrojanTcpStream, 0);
+
+#[async_trait]
+impl ITcpStream for TrojanTcpStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        self.0.get_ref().0.peer_addr().await
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.0.get_ref().0.local_addr().await
+    }
+}
+
+pub struct TrojanUdpSocket {
+    rx: Mutex<ReadHalf<TlsStream<TcpStream>>>,
+    tx: Mutex<WriteHalf<TlsStream<TcpStream>>>,
+}
+
+#[async_trait]
+impl IUdpSocket for TrojanUdpSocket {
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let mut rx = self.rx.lock().await;
+
+        let addr = socks5_protocol::Address::read(&mut *rx)
+            .await
+            .map_err(map_err)?;
+        let mut len_crlf = [0u8; 4];
+        rx.read_exact(&mut len_crlf).await?;
+        let len = u16::from_be_bytes([len_crlf[0], len_crlf[1]]) as usize;
+
+        let mut payload = vec![0u8; len];
+        rx.read_exact(&mut payload).await?;
+        let to_copy = len.min(buf.len());
+        buf[..to_copy].copy_from_slice(&payload[..to_copy]);
+
+        Ok((to_copy, sa2ra(addr).to_socket_addr()?))
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: Address) -> Result<usize> {
+        if buf.len() > u16::MAX as usize {
+            return Err(rd_interface::Error::Other("UDP packet too large".into()));
+        }
+
+        let mut packet = Vec::with_capacity(259 + 4 + buf.len());
+        ra2sa(addr)?.write(&mut packet).await.map_err(map_err)?;
+        packet.extend_from_slice(&(buf.len() as u16).to_be_bytes());
+        packet.extend_from_slice(CRLF);
+        packet.extend_from_slice(buf);
+
+        let mut tx = self.tx.lock().await;
+        tx.write_all(&packet).await?;
+        tx.flush().await?;
+
+        Ok(buf.len())
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
+
+#[async_trait]
+impl INet for TrojanNet {
+    async fn tcp_connect(
+        &self,
+        ctx: &mut rd_interface::Context,
+        addr: Address,
+    ) -> Result<TcpStream> {
+        let tls = self.connect(ctx, CMD_CONNECT, addr).await?;
+        Ok(TrojanTcpStream(tls).into_dyn())
+    }
+
+    async fn tcp_bind(
+        &self,
+        _ctx: &mut rd_interface::Context,
+        _addr: Address,
+    ) -> Result<rd_interface::TcpListener> {
+        Err(NOT_IMPLEMENTED)
+    }
+
+    async fn udp_bind(&self, ctx: &mut rd_interface::Context, addr: Address) -> Result<UdpSocket> {
+        let tls = self.connect(ctx, CMD_UDP_ASSOCIATE, addr).await?;
+        let (rx, tx) = split(tls);
+        Ok(TrojanUdpSocket {
+            rx: Mutex::new(rx),
+            tx: Mutex::new(tx),
+        }
+        .into_dyn())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_password() {
+        assert_eq!(
+            hash_password("abc"),
+            "23097d223405d8228642a477bda255b32aadbce4bda0b3f7e36c9da7"
+        );
+        assert_eq!(hash_password("").len(), 56);
+    }
+}
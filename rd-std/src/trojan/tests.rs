@@ -0,0 +1,137 @@
+use super::*;
+use crate::builtin::local::{LocalConfig, LocalNet};
+use crate::tests::{assert_echo, get_registry, spawn_echo_server};
+use rd_interface::{Context, IntoAddress, IntoDyn, Net};
+use socks5_protocol::Address;
+use std::sync::Arc;
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+// SHA-224 of "abc"
+const PASSWORD_HASH: &[u8] = b"23097d223405d8228642a477bda255b32aadbce4bda0b3f7e36c9da7";
+
+async fn read_crlf(stream: &mut (impl AsyncRead + Unpin)) {
+    let mut crlf = [0u8; 2];
+    stream.read_exact(&mut crlf).await.unwrap();
+    assert_eq!(&crlf, b"\r\n");
+}
+
+/// A Trojan server which relays TCP to the target and echoes UDP packets.
+async fn spawn_trojan_server() -> u16 {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.cert.der().clone()], key)
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let mut tls = acceptor.accept(tcp).await.unwrap();
+
+                let mut hash = [0u8; 56];
+                tls.read_exact(&mut hash).await.unwrap();
+                assert_eq!(&hash[..], PASSWORD_HASH);
+                read_crlf(&mut tls).await;
+                let cmd = tls.read_u8().await.unwrap();
+                let target = Address::read(&mut tls).await.unwrap();
+                read_crlf(&mut tls).await;
+
+                match cmd {
+                    0x01 => {
+                        let mut remote = TcpStream::connect(target.to_string()).await.unwrap();
+                        copy_bidirectional(&mut tls, &mut remote).await.ok();
+                    }
+                    0x03 => loop {
+                        let addr = match Address::read(&mut tls).await {
+                            Ok(addr) => addr,
+                            Err(_) => break,
+                        };
+                        let len = tls.read_u16().await.unwrap();
+                        read_crlf(&mut tls).await;
+                        let mut payload = vec![0u8; len as usize];
+                        tls.read_exact(&mut payload).await.unwrap();
+
+                        let mut packet = Vec::new();
+                        addr.write(&mut packet).await.unwrap();
+                        packet.extend_from_slice(&len.to_be_bytes());
+                        packet.extend_from_slice(b"\r\n");
+                        packet.extend_from_slice(&payload);
+                        tls.write_all(&packet).await.unwrap();
+                        tls.flush().await.unwrap();
+                    },
+                    cmd => panic!("unexpected command {}", cmd),
+                }
+            });
+        }
+    });
+    port
+}
+
+fn trojan_net(local: Net, port: u16) -> Net {
+    let config = TrojanNetConfig {
+        server: "127.0.0.1".to_string(),
+        port,
+        password: "abc".to_string(),
+        sni: Some("localhost".to_string()),
+        skip_cert_verify: true,
+        net: Default::default(),
+    };
+    TrojanNet::new(local, config).unwrap().into_dyn()
+}
+
+#[test]
+fn test_trojan_smoke() {
+    let mut registry = get_registry();
+    super::init(&mut registry).unwrap();
+}
+
+#[tokio::test]
+async fn test_trojan_tcp() {
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    spawn_echo_server(&local, "127.0.0.1:26668").await;
+    let port = spawn_trojan_server().await;
+
+    let trojan = trojan_net(local, port);
+    assert_echo(&trojan, "127.0.0.1:26668").await;
+}
+
+#[tokio::test]
+async fn test_trojan_udp() {
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    let port = spawn_trojan_server().await;
+
+    let trojan = trojan_net(local, port);
+    let udp = trojan
+        .udp_bind(&mut Context::new(), "0.0.0.0:0".into_address().unwrap())
+        .await
+        .unwrap();
+
+    let target = "1.2.3.4:53".into_address().unwrap();
+    for payload in [&b"hello"[..], &b"trojan udp"[..]] {
+        udp.send_to(payload, target.clone()).await.unwrap();
+
+        let mut buf = [0u8; 1024];
+        let (size, addr) = udp.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], payload);
+        assert_eq!(addr, "1.2.3.4:53".parse().unwrap());
+    }
+}
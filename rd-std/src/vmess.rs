@@ -0,0 +1,67 @@
+pub use client::VmessNet;
+
+mod client;
+mod protocol;
+#[cfg(test)]
+mod tests;
+
+use rd_interface::{
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Config, Registry, Result,
+};
+use serde_derive::Deserialize;
+
+/// How the data is sealed.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Config, JsonSchema)]
+pub enum Security {
+    #[default]
+    #[serde(rename = "aes-128-gcm")]
+    Aes128Gcm,
+    #[serde(rename = "chacha20-poly1305")]
+    Chacha20Poly1305,
+    #[serde(rename = "none")]
+    None,
+}
+
+impl Security {
+    /// Id of the security in the request header.
+    fn id(self) -> u8 {
+        match self {
+            Security::Aes128Gcm => 3,
+            Security::Chacha20Poly1305 => 4,
+            Security::None => 5,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct VmessNetConfig {
+    server: String,
+    port: u16,
+    uuid: String,
+    /// Kept for the configs of V2Ray. The AEAD header is always sent, which
+    /// servers take whatever the alter id is.
+    #[serde(default)]
+    alter_id: u16,
+    #[serde(default)]
+    security: Security,
+
+    #[serde(default)]
+    net: NetRef,
+}
+
+impl NetFactory for VmessNet {
+    const NAME: &'static str = "vmess";
+    type Config = VmessNetConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        VmessNet::new(config.net.try_net()?, config)
+    }
+}
+
+pub fn init(registry: &mut Registry) -> Result<()> {
+    registry.add_net::<VmessNet>();
+    Ok(())
+}
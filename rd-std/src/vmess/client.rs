@@ -0,0 +1,335 @@
+use super::{
+    protocol::{
+        aes_gcm_open, auth_id, cmd_key, kdf12, kdf16, response_key, seal_header, ChunkCipher,
+        RequestHeader, CMD_TCP, KDF_RESPONSE_KEY, KDF_RESPONSE_LENGTH_KEY,
+        KDF_RESPONSE_LENGTH_NONCE, KDF_RESPONSE_NONCE, SEALED_LENGTH_SIZE, TAG_SIZE,
+    },
+    Security, VmessNetConfig,
+};
+use rd_interface::{
+    async_trait, error::map_other, Address, INet, ITcpStream, IntoAddress, IntoDyn, Net, Result,
+    TcpStream, UdpSocket, NOT_IMPLEMENTED,
+};
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    task::{self, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use uuid::Uuid;
+
+/// Max size of the data in a chunk.
+const MAX_CHUNK_SIZE: usize = 8192;
+
+pub struct VmessNet {
+    net: Net,
+    server: String,
+    port: u16,
+    cmd_key: [u8; 16],
+    security: Security,
+}
+
+fn random<const N: usize>() -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    getrandom::getrandom(&mut buf)?;
+    Ok(buf)
+}
+
+impl VmessNet {
+    pub fn new(net: Net, config: VmessNetConfig) -> Result<Self> {
+        let uuid = Uuid::parse_str(&config.uuid).map_err(map_other)?;
+        if config.alter_id > 0 {
+            tracing::debug!("alter_id is ignored, the AEAD header is sent");
+        }
+
+        Ok(VmessNet {
+            net,
+            server: config.server,
+            port: config.port,
+            cmd_key: cmd_key(uuid.as_bytes()),
+            security: config.security,
+        })
+    }
+    fn server(&self) -> Result<Address> {
+        (self.server.as_str(), self.port)
+            .into_address()
+            .map_err(Into::into)
+    }
+    /// Seals a request header to `addr`, with new body keys.
+    fn request(&self, addr: Address) -> io::Result<(RequestHeader, Vec<u8>)> {
+        let [body_iv, body_key] = [random()?, random()?];
+        let [response_auth, padding_len] = random()?;
+        let header = RequestHeader {
+            body_iv,
+            body_key,
+            response_auth,
+            security: self.security,
+            command: CMD_TCP,
+            addr,
+        };
+        let padding: [u8; 16] = random()?;
+        let encoded = header.encode(&padding[..(padding_len % 16) as usize])?;
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?
+            .as_secs();
+        let auth_id = auth_id(&self.cmd_key, time, random()?);
+        let sealed = seal_header(&self.cmd_key, &auth_id, &random()?, &encoded);
+        Ok((header, sealed))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReadState {
+    ResponseLength,
+    ResponseHeader,
+    ChunkLength,
+    Chunk,
+    Eof,
+}
+
+pub struct VmessTcpStream {
+    inner: TcpStream,
+    response_auth: u8,
+    response_key: [u8; 16],
+    response_iv: [u8; 16],
+    reader: ChunkCipher,
+    writer: ChunkCipher,
+
+    state: ReadState,
+    /// What the state reads, `filled` bytes of it are read.
+    read_buf: Vec<u8>,
+    filled: usize,
+    data: Vec<u8>,
+    data_pos: usize,
+
+    /// Sealed chunks not written yet.
+    write_buf: Vec<u8>,
+    written: usize,
+    ending: bool,
+}
+
+impl VmessTcpStream {
+    fn new(inner: TcpStream, header: &RequestHeader) -> VmessTcpStream {
+        let (key, iv) = (
+            response_key(&header.body_key),
+            response_key(&header.body_iv),
+        );
+        VmessTcpStream {
+            inner,
+            response_auth: header.response_auth,
+            response_key: key,
+            response_iv: iv,
+            reader: ChunkCipher::new(header.security, &key, &iv),
+            writer: ChunkCipher::new(header.security, &header.body_key, &header.body_iv),
+            state: ReadState::ResponseLength,
+            read_buf: vec![0; SEALED_LENGTH_SIZE],
+            filled: 0,
+            data: Vec::new(),
+            data_pos: 0,
+            write_buf: Vec::new(),
+            written: 0,
+            ending: false,
+        }
+    }
+
+    fn expect(&mut self, state: ReadState, len: usize) {
+        self.state = state;
+        self.read_buf = vec![0; len];
+        self.filled = 0;
+    }
+
+    /// Reads until `read_buf` is full. Returns false if the stream ends
+    /// before anything is read.
+    fn poll_fill(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<bool>> {
+        while self.filled < self.read_buf.len() {
+            let mut buf = ReadBuf::new(&mut self.read_buf[self.filled..]);
+            futures::ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+            let n = buf.filled().len();
+            if n == 0 {
+                if self.filled == 0 {
+                    return Poll::Ready(Ok(false));
+                }
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+            self.filled += n;
+        }
+        Poll::Ready(Ok(true))
+    }
+
+    /// Takes what's read, and moves on to the next state.
+    fn advance(&mut self) -> io::Result<()> {
+        let read = std::mem::take(&mut self.read_buf);
+        match self.state {
+            ReadState::ResponseLength => {
+                let length = aes_gcm_open(
+                    &kdf16(&self.response_key, &[KDF_RESPONSE_LENGTH_KEY]),
+                    &kdf12(&self.response_iv, &[KDF_RESPONSE_LENGTH_NONCE]),
+                    &read,
+                    &[],
+                )?;
+                let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+                self.expect(ReadState::ResponseHeader, length + TAG_SIZE);
+            }
+            ReadState::ResponseHeader => {
+                let header = aes_gcm_open(
+                    &kdf16(&self.response_key, &[KDF_RESPONSE_KEY]),
+                    &kdf12(&self.response_iv, &[KDF_RESPONSE_NONCE]),
+                    &read,
+                    &[],
+                )?;
+                // the commands after it are not used
+                if header.first() != Some(&self.response_auth) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "VMess: wrong response auth",
+                    ));
+                }
+                self.expect(ReadState::ChunkLength, 2);
+            }
+            ReadState::ChunkLength => {
+                let length = u16::from_be_bytes([read[0], read[1]]) as usize;
+                if length == self.reader.overhead() {
+                    self.state = ReadState::Eof;
+                } else {
+                    self.expect(ReadState::Chunk, length);
+                }
+            }
+            ReadState::Chunk => {
+                self.data = self.reader.open(&read)?;
+                self.data_pos = 0;
+                self.expect(ReadState::ChunkLength, 2);
+            }
+            ReadState::Eof => {}
+        }
+        Ok(())
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.write_buf.len() {
+            let n = futures::ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.written..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.write_buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for VmessTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.data_pos < this.data.len() {
+                let n = (this.data.len() - this.data_pos).min(buf.remaining());
+                buf.put_slice(&this.data[this.data_pos..this.data_pos + n]);
+                this.data_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.state == ReadState::Eof {
+                return Poll::Ready(Ok(()));
+            }
+            if !futures::ready!(this.poll_fill(cx))? {
+                // closed between chunks, take it as the end
+                if this.state == ReadState::ChunkLength {
+                    this.state = ReadState::Eof;
+                    continue;
+                }
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+            this.advance()?;
+        }
+    }
+}
+
+impl AsyncWrite for VmessTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // an empty chunk would end the stream
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        futures::ready!(this.poll_write_buf(cx))?;
+        let n = buf.len().min(MAX_CHUNK_SIZE);
+        this.write_buf = this.writer.seal(&buf[..n]);
+        // the chunk is taken either way, the rest is written later
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.ending {
+            futures::ready!(this.poll_write_buf(cx))?;
+            this.write_buf = this.writer.seal(&[]);
+            this.ending = true;
+        }
+        futures::ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl ITcpStream for VmessTcpStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr().await
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr().await
+    }
+}
+
+#[async_trait]
+impl INet for VmessNet {
+    async fn tcp_connect(
+        &self,
+        ctx: &mut rd_interface::Context,
+        addr: Address,
+    ) -> Result<TcpStream> {
+        let (header, sealed) = self.request(addr)?;
+        let mut tcp = self.net.tcp_connect(ctx, self.server()?).await?;
+        tcp.write_all(&sealed).await?;
+        Ok(VmessTcpStream::new(tcp, &header).into_dyn())
+    }
+
+    async fn tcp_bind(
+        &self,
+        _ctx: &mut rd_interface::Context,
+        _addr: Address,
+    ) -> Result<rd_interface::TcpListener> {
+        Err(NOT_IMPLEMENTED)
+    }
+
+    async fn udp_bind(
+        &self,
+        _ctx: &mut rd_interface::Context,
+        _addr: Address,
+    ) -> Result<UdpSocket> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
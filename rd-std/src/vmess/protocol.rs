@@ -0,0 +1,371 @@
+//! The VMess request header and chunks, with the AEAD header V2Ray sends
+//! since 4.28.
+
+use super::Security;
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt},
+    Aes128,
+};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128Gcm,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+use md5::Md5;
+use rd_interface::Address;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+};
+
+pub const VERSION: u8 = 1;
+/// Data goes in length prefixed chunks.
+pub const OPTION_CHUNK_STREAM: u8 = 0x01;
+pub const CMD_TCP: u8 = 0x01;
+pub const ATYP_IPV4: u8 = 0x01;
+pub const ATYP_DOMAIN: u8 = 0x02;
+pub const ATYP_IPV6: u8 = 0x03;
+pub const TAG_SIZE: usize = 16;
+/// Size of the sealed length of the request and response headers.
+pub const SEALED_LENGTH_SIZE: usize = 2 + TAG_SIZE;
+
+const CMD_KEY_SALT: &[u8] = b"c48619fe-8f02-49e0-b9e9-edf763e17e21";
+const KDF_SALT: &[u8] = b"VMess AEAD KDF";
+pub const KDF_AUTH_ID_KEY: &[u8] = b"AES Auth ID Encryption";
+pub const KDF_HEADER_LENGTH_KEY: &[u8] = b"VMess Header AEAD Key_Length";
+pub const KDF_HEADER_LENGTH_NONCE: &[u8] = b"VMess Header AEAD Nonce_Length";
+pub const KDF_HEADER_KEY: &[u8] = b"VMess Header AEAD Key";
+pub const KDF_HEADER_NONCE: &[u8] = b"VMess Header AEAD Nonce";
+pub const KDF_RESPONSE_LENGTH_KEY: &[u8] = b"AEAD Resp Header Len Key";
+pub const KDF_RESPONSE_LENGTH_NONCE: &[u8] = b"AEAD Resp Header Len IV";
+pub const KDF_RESPONSE_KEY: &[u8] = b"AEAD Resp Header Key";
+pub const KDF_RESPONSE_NONCE: &[u8] = b"AEAD Resp Header IV";
+
+const HMAC_BLOCK_SIZE: usize = 64;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("VMess: {}", msg))
+}
+
+/// The key the header is sealed with, derived from the user id.
+pub fn cmd_key(uuid: &[u8; 16]) -> [u8; 16] {
+    Md5::new()
+        .chain_update(uuid)
+        .chain_update(CMD_KEY_SALT)
+        .finalize()
+        .into()
+}
+
+/// SHA-256, inside an HMAC for each of `keys`, the last one outermost.
+fn kdf_hash(keys: &[&[u8]], data: &[u8]) -> [u8; 32] {
+    let (key, inner) = match keys.split_last() {
+        Some(v) => v,
+        None => return Sha256::digest(data).into(),
+    };
+    // all the keys are shorter than a block
+    let mut ipad = [0x36u8; HMAC_BLOCK_SIZE];
+    let mut opad = [0x5cu8; HMAC_BLOCK_SIZE];
+    for (i, b) in key.iter().enumerate() {
+        ipad[i] ^= b;
+        opad[i] ^= b;
+    }
+    let inner_hash = kdf_hash(inner, &[&ipad[..], data].concat());
+    kdf_hash(inner, &[&opad[..], &inner_hash[..]].concat())
+}
+
+pub fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
+    let mut keys = vec![KDF_SALT];
+    keys.extend_from_slice(path);
+    kdf_hash(&keys, key)
+}
+
+pub fn kdf16(key: &[u8], path: &[&[u8]]) -> [u8; 16] {
+    let mut key16 = [0u8; 16];
+    key16.copy_from_slice(&kdf(key, path)[..16]);
+    key16
+}
+
+pub fn kdf12(key: &[u8], path: &[&[u8]]) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&kdf(key, path)[..12]);
+    nonce
+}
+
+pub fn aes_gcm_seal(key: &[u8; 16], nonce: &[u8; 12], msg: &[u8], aad: &[u8]) -> Vec<u8> {
+    Aes128Gcm::new(key.into())
+        .encrypt(nonce.into(), Payload { msg, aad })
+        .expect("sealing never fails")
+}
+
+pub fn aes_gcm_open(
+    key: &[u8; 16],
+    nonce: &[u8; 12],
+    msg: &[u8],
+    aad: &[u8],
+) -> io::Result<Vec<u8>> {
+    Aes128Gcm::new(key.into())
+        .decrypt(nonce.into(), Payload { msg, aad })
+        .map_err(|_| invalid_data("bad authentication tag"))
+}
+
+/// FNV-1a 32, the checksum at the end of the request header.
+pub fn fnv1a32(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5u32, |hash, b| {
+        (hash ^ *b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Tells the server who the user is and when the request is sent.
+pub fn auth_id(cmd_key: &[u8; 16], time: u64, random: [u8; 4]) -> [u8; 16] {
+    let mut id = [0u8; 16];
+    id[..8].copy_from_slice(&time.to_be_bytes());
+    id[8..12].copy_from_slice(&random);
+    let crc = crc32fast::hash(&id[..12]);
+    id[12..].copy_from_slice(&crc.to_be_bytes());
+
+    let key = kdf16(cmd_key, &[KDF_AUTH_ID_KEY]);
+    Aes128::new(&key.into()).encrypt_block(GenericArray::from_mut_slice(&mut id));
+    id
+}
+
+/// Seals `header` as the auth id, its length, the nonce, and itself.
+pub fn seal_header(
+    cmd_key: &[u8; 16],
+    auth_id: &[u8; 16],
+    nonce: &[u8; 8],
+    header: &[u8],
+) -> Vec<u8> {
+    let path = |label| [label, &auth_id[..], &nonce[..]];
+    let length = aes_gcm_seal(
+        &kdf16(cmd_key, &path(KDF_HEADER_LENGTH_KEY)),
+        &kdf12(cmd_key, &path(KDF_HEADER_LENGTH_NONCE)),
+        &(header.len() as u16).to_be_bytes(),
+        auth_id,
+    );
+    let header = aes_gcm_seal(
+        &kdf16(cmd_key, &path(KDF_HEADER_KEY)),
+        &kdf12(cmd_key, &path(KDF_HEADER_NONCE)),
+        header,
+        auth_id,
+    );
+    [&auth_id[..], &length, &nonce[..], &header].concat()
+}
+
+pub struct RequestHeader {
+    pub body_iv: [u8; 16],
+    pub body_key: [u8; 16],
+    /// Sent back first in the response header.
+    pub response_auth: u8,
+    pub security: Security,
+    pub command: u8,
+    pub addr: Address,
+}
+
+impl RequestHeader {
+    pub fn encode(&self, padding: &[u8]) -> io::Result<Vec<u8>> {
+        let mut buf = vec![VERSION];
+        buf.extend_from_slice(&self.body_iv);
+        buf.extend_from_slice(&self.body_key);
+        buf.push(self.response_auth);
+        buf.push(OPTION_CHUNK_STREAM);
+        buf.push((padding.len() as u8) << 4 | self.security.id());
+        // reserved
+        buf.push(0);
+        buf.push(self.command);
+        match &self.addr {
+            Address::SocketAddr(SocketAddr::V4(addr)) => {
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+                buf.push(ATYP_IPV4);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            Address::SocketAddr(SocketAddr::V6(addr)) => {
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+                buf.push(ATYP_IPV6);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            Address::Domain(domain, port) => {
+                if domain.len() > u8::MAX as usize {
+                    return Err(io::Error::new(ErrorKind::InvalidInput, "Domain too long"));
+                }
+                buf.extend_from_slice(&port.to_be_bytes());
+                buf.push(ATYP_DOMAIN);
+                buf.push(domain.len() as u8);
+                buf.extend_from_slice(domain.as_bytes());
+            }
+            Address::Unix(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "VMess can't connect to Unix sockets",
+                ))
+            }
+        }
+        buf.extend_from_slice(padding);
+        let checksum = fnv1a32(&buf);
+        buf.extend_from_slice(&checksum.to_be_bytes());
+        Ok(buf)
+    }
+}
+
+/// The response body key or IV, from the request one.
+pub fn response_key(key: &[u8; 16]) -> [u8; 16] {
+    let mut response = [0u8; 16];
+    response.copy_from_slice(&Sha256::digest(key)[..16]);
+    response
+}
+
+enum Cipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+    None,
+}
+
+/// Seals and opens the chunks of one direction. A chunk is its length and
+/// the sealed data, an empty one ends the stream.
+pub struct ChunkCipher {
+    cipher: Cipher,
+    iv: [u8; 16],
+    count: u16,
+}
+
+impl ChunkCipher {
+    pub fn new(security: Security, key: &[u8; 16], iv: &[u8; 16]) -> ChunkCipher {
+        let cipher = match security {
+            Security::Aes128Gcm => Cipher::Aes128Gcm(Box::new(Aes128Gcm::new(key.into()))),
+            Security::Chacha20Poly1305 => {
+                let first: [u8; 16] = Md5::digest(key).into();
+                let second = Md5::digest(first);
+                let key = [&first[..], &second[..]].concat();
+                Cipher::ChaCha20Poly1305(Box::new(
+                    ChaCha20Poly1305::new_from_slice(&key).expect("the key is 32 bytes"),
+                ))
+            }
+            Security::None => Cipher::None,
+        };
+        ChunkCipher {
+            cipher,
+            iv: *iv,
+            count: 0,
+        }
+    }
+
+    /// Added to the data by sealing.
+    pub fn overhead(&self) -> usize {
+        match self.cipher {
+            Cipher::None => 0,
+            _ => TAG_SIZE,
+        }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&self.iv[..12]);
+        nonce[..2].copy_from_slice(&self.count.to_be_bytes());
+        self.count = self.count.wrapping_add(1);
+        nonce
+    }
+
+    /// Returns the chunk holding `data`.
+    pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        let sealed = match &self.cipher {
+            Cipher::Aes128Gcm(c) => c.encrypt(&nonce.into(), data),
+            Cipher::ChaCha20Poly1305(c) => c.encrypt(&nonce.into(), data),
+            Cipher::None => Ok(data.to_vec()),
+        }
+        .expect("sealing never fails");
+        [&(sealed.len() as u16).to_be_bytes()[..], &sealed].concat()
+    }
+
+    /// Opens the data of a chunk, without its length.
+    pub fn open(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        match &self.cipher {
+            Cipher::Aes128Gcm(c) => c.decrypt(&nonce.into(), data),
+            Cipher::ChaCha20Poly1305(c) => c.decrypt(&nonce.into(), data),
+            Cipher::None => Ok(data.to_vec()),
+        }
+        .map_err(|_| invalid_data("bad authentication tag"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Computed with Python's hashlib, hmac and cryptography.
+    const UUID: [u8; 16] = [
+        0xb8, 0x31, 0x38, 0x1d, 0x63, 0x24, 0x4d, 0x53, 0xad, 0x4f, 0x8c, 0xda, 0x48, 0xb3, 0x08,
+        0x11,
+    ];
+
+    const CMD_KEY_HEX: &str = "b50d916ac0cec067981af8e5f38a758f";
+    const KDF_AUTH_ID_HEX: &str =
+        "1415ba74ca8b3d041a8f583fb4116315c589ae7b6e81765b601aa166c62871f7";
+    const KDF_HEADER_HEX: &str = "1110e46db2482d93ed3e913b0f3851248200a2cfb5e3fb333e06507ee85c0cbc";
+    const AUTH_ID_HEX: &str = "17f33db036b2a7d0c03b4200662291ac";
+    const SEALED_HEADER_HEX: &str = concat!(
+        "17f33db036b2a7d0c03b4200662291ac",
+        "6a7be1b7ba880ceec8c5cffc3ac070ec85de",
+        "6e6f6e6365313233",
+        "be91ecca07de32e6b97c56633076b66fbfe9d2de385c"
+    );
+
+    #[test]
+    fn test_kdf() {
+        let cmd_key = cmd_key(&UUID);
+        assert_eq!(hex(&cmd_key), CMD_KEY_HEX);
+        assert_eq!(hex(&kdf(&cmd_key, &[KDF_AUTH_ID_KEY])), KDF_AUTH_ID_HEX);
+        assert_eq!(
+            hex(&kdf(
+                &cmd_key,
+                &[KDF_HEADER_KEY, b"0123456789abcdef", b"nonce123"]
+            )),
+            KDF_HEADER_HEX
+        );
+    }
+
+    #[test]
+    fn test_seal_header() {
+        let cmd_key = cmd_key(&UUID);
+        let auth_id = auth_id(&cmd_key, 1_600_000_000, [1, 2, 3, 4]);
+        assert_eq!(hex(&auth_id), AUTH_ID_HEX);
+        let sealed = seal_header(&cmd_key, &auth_id, b"nonce123", b"header");
+        assert_eq!(hex(&sealed), SEALED_HEADER_HEX);
+    }
+
+    #[test]
+    fn test_fnv1a32() {
+        assert_eq!(fnv1a32(b""), 0x811c9dc5);
+        assert_eq!(fnv1a32(b"a"), 0xe40c292c);
+        assert_eq!(fnv1a32(b"foobar"), 0xbf9cf968);
+    }
+
+    #[test]
+    fn test_chunk_cipher() {
+        for security in [
+            Security::Aes128Gcm,
+            Security::Chacha20Poly1305,
+            Security::None,
+        ] {
+            let mut sealer = ChunkCipher::new(security, &[1; 16], &[2; 16]);
+            let mut opener = ChunkCipher::new(security, &[1; 16], &[2; 16]);
+            for data in [&b"hello"[..], b"", b"world"] {
+                let chunk = sealer.seal(data);
+                let len = u16::from_be_bytes([chunk[0], chunk[1]]) as usize;
+                assert_eq!(len, data.len() + sealer.overhead());
+                assert_eq!(opener.open(&chunk[2..]).unwrap(), data);
+            }
+        }
+
+        // the nonces move on with each chunk
+        let mut sealer = ChunkCipher::new(Security::Aes128Gcm, &[1; 16], &[2; 16]);
+        let mut opener = ChunkCipher::new(Security::Aes128Gcm, &[1; 16], &[2; 16]);
+        sealer.seal(b"skipped");
+        assert!(opener.open(&sealer.seal(b"data")[2..]).is_err());
+    }
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
@@ -0,0 +1,227 @@
+use super::{protocol::*, *};
+use crate::builtin::local::{LocalConfig, LocalNet};
+use crate::tests::get_registry;
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, KeyInit},
+    Aes128,
+};
+use rd_interface::{Context, IntoAddress, IntoDyn, Net};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    sync::mpsc,
+};
+use uuid::Uuid;
+
+const UUID: &str = "b831381d-6324-4d53-ad4f-8cda48b30811";
+
+/// The request header, as the server opens it.
+#[derive(Debug)]
+struct Request {
+    body_iv: [u8; 16],
+    body_key: [u8; 16],
+    response_auth: u8,
+    option: u8,
+    security: u8,
+    command: u8,
+    port: u16,
+    addr_type: u8,
+    addr: Vec<u8>,
+}
+
+async fn read_vec(stream: &mut (impl AsyncRead + Unpin), len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await.unwrap();
+    buf
+}
+
+async fn open_request(stream: &mut (impl AsyncRead + Unpin), cmd_key: &[u8; 16]) -> Request {
+    let mut auth_id = [0u8; 16];
+    stream.read_exact(&mut auth_id).await.unwrap();
+    let mut id = auth_id;
+    let key = kdf16(cmd_key, &[KDF_AUTH_ID_KEY]);
+    Aes128::new(&key.into()).decrypt_block(GenericArray::from_mut_slice(&mut id));
+    assert_eq!(crc32fast::hash(&id[..12]).to_be_bytes(), id[12..]);
+    let mut time = [0u8; 8];
+    time.copy_from_slice(&id[..8]);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    assert!(now.abs_diff(u64::from_be_bytes(time)) < 120);
+
+    let sealed_length = read_vec(stream, SEALED_LENGTH_SIZE).await;
+    let nonce = read_vec(stream, 8).await;
+    let path = |label| [label, &auth_id[..], &nonce[..]];
+    let length = aes_gcm_open(
+        &kdf16(cmd_key, &path(KDF_HEADER_LENGTH_KEY)),
+        &kdf12(cmd_key, &path(KDF_HEADER_LENGTH_NONCE)),
+        &sealed_length,
+        &auth_id,
+    )
+    .unwrap();
+    let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+    let header = aes_gcm_open(
+        &kdf16(cmd_key, &path(KDF_HEADER_KEY)),
+        &kdf12(cmd_key, &path(KDF_HEADER_NONCE)),
+        &read_vec(stream, length + TAG_SIZE).await,
+        &auth_id,
+    )
+    .unwrap();
+
+    let (header, checksum) = header.split_at(header.len() - 4);
+    assert_eq!(fnv1a32(header).to_be_bytes(), checksum);
+    assert_eq!(header[0], VERSION);
+    let mut body_iv = [0u8; 16];
+    let mut body_key = [0u8; 16];
+    body_iv.copy_from_slice(&header[1..17]);
+    body_key.copy_from_slice(&header[17..33]);
+    let padding = (header[35] >> 4) as usize;
+    let addr_type = header[40];
+    let addr = &header[41..header.len() - padding];
+    let addr = match addr_type {
+        ATYP_DOMAIN => {
+            assert_eq!(addr[0] as usize, addr.len() - 1);
+            &addr[1..]
+        }
+        _ => addr,
+    };
+    Request {
+        body_iv,
+        body_key,
+        response_auth: header[33],
+        option: header[34],
+        security: header[35] & 0x0f,
+        command: header[37],
+        port: u16::from_be_bytes([header[38], header[39]]),
+        addr_type,
+        addr: addr.to_vec(),
+    }
+}
+
+/// A VMess server which sends back the request headers it gets, and echoes
+/// the data.
+async fn spawn_vmess_server() -> (u16, mpsc::UnboundedReceiver<Request>) {
+    let cmd_key = cmd_key(Uuid::parse_str(UUID).unwrap().as_bytes());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut tcp, _) = listener.accept().await.unwrap();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let request = open_request(&mut tcp, &cmd_key).await;
+                let security = match request.security {
+                    3 => Security::Aes128Gcm,
+                    4 => Security::Chacha20Poly1305,
+                    5 => Security::None,
+                    s => panic!("unexpected security {}", s),
+                };
+                let key = response_key(&request.body_key);
+                let iv = response_key(&request.body_iv);
+                let mut reader = ChunkCipher::new(security, &request.body_key, &request.body_iv);
+                let mut writer = ChunkCipher::new(security, &key, &iv);
+
+                // the response auth, option, and no command
+                let header = [request.response_auth, 0, 0, 0];
+                let length = aes_gcm_seal(
+                    &kdf16(&key, &[KDF_RESPONSE_LENGTH_KEY]),
+                    &kdf12(&iv, &[KDF_RESPONSE_LENGTH_NONCE]),
+                    &(header.len() as u16).to_be_bytes(),
+                    &[],
+                );
+                let header = aes_gcm_seal(
+                    &kdf16(&key, &[KDF_RESPONSE_KEY]),
+                    &kdf12(&iv, &[KDF_RESPONSE_NONCE]),
+                    &header,
+                    &[],
+                );
+                tcp.write_all(&[length, header].concat()).await.unwrap();
+                tx.send(request).unwrap();
+
+                // the empty chunk ending the request ends the response too
+                loop {
+                    let length = tcp.read_u16().await.unwrap() as usize;
+                    let data = reader.open(&read_vec(&mut tcp, length).await).unwrap();
+                    tcp.write_all(&writer.seal(&data)).await.unwrap();
+                    if data.is_empty() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (port, rx)
+}
+
+fn vmess_net(port: u16, security: &str) -> Net {
+    let config = serde_json::from_value(serde_json::json!({
+        "server": "127.0.0.1",
+        "port": port,
+        "uuid": UUID,
+        "security": security,
+    }))
+    .unwrap();
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    VmessNet::new(local, config).unwrap().into_dyn()
+}
+
+#[test]
+fn test_vmess_smoke() {
+    let mut registry = get_registry();
+    super::init(&mut registry).unwrap();
+}
+
+#[tokio::test]
+async fn test_vmess_tcp() {
+    let (port, mut requests) = spawn_vmess_server().await;
+    // more than a chunk
+    let sent: Vec<u8> = (0..20000u32).map(|i| i as u8).collect();
+
+    for (security, id) in [("aes-128-gcm", 3), ("chacha20-poly1305", 4), ("none", 5)] {
+        let vmess = vmess_net(port, security);
+        let mut tcp = vmess
+            .tcp_connect(
+                &mut Context::new(),
+                "example.com:443".into_address().unwrap(),
+            )
+            .await
+            .unwrap();
+        tcp.write_all(&sent).await.unwrap();
+        tcp.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        tcp.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, sent);
+
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.option, OPTION_CHUNK_STREAM);
+        assert_eq!(request.security, id);
+        assert_eq!(request.command, CMD_TCP);
+        assert_eq!(request.port, 443);
+        assert_eq!(request.addr_type, ATYP_DOMAIN);
+        assert_eq!(request.addr, b"example.com");
+    }
+}
+
+#[tokio::test]
+async fn test_vmess_ip_addr() {
+    let (port, mut requests) = spawn_vmess_server().await;
+    let vmess = vmess_net(port, "aes-128-gcm");
+    for (addr, addr_type, ip) in [
+        ("1.2.3.4:80", ATYP_IPV4, vec![1, 2, 3, 4]),
+        ("[::1]:80", ATYP_IPV6, [vec![0; 15], vec![1]].concat()),
+    ] {
+        let mut tcp = vmess
+            .tcp_connect(&mut Context::new(), addr.into_address().unwrap())
+            .await
+            .unwrap();
+        tcp.shutdown().await.unwrap();
+
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.port, 80);
+        assert_eq!(request.addr_type, addr_type);
+        assert_eq!(request.addr, ip);
+    }
+}
@@ -0,0 +1,47 @@
+pub use client::WsNet;
+
+mod client;
+mod stream;
+#[cfg(test)]
+mod tests;
+
+use rd_interface::{
+    registry::{NetFactory, NetRef},
+    schemars::{self, JsonSchema},
+    Config, Registry, Result,
+};
+use serde_derive::Deserialize;
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+/// Connects over WebSocket, each connection being one WebSocket. It goes
+/// under nets like `trojan` or `vmess`, to reach servers behind a CDN.
+#[derive(Debug, Deserialize, Config, JsonSchema)]
+pub struct WsNetConfig {
+    /// Path of the upgrade request, `/` by default.
+    #[serde(default = "default_path")]
+    path: String,
+    /// `Host` of the upgrade request, the address connected to by default.
+    #[serde(default)]
+    host: Option<String>,
+
+    #[serde(default)]
+    net: NetRef,
+}
+
+impl NetFactory for WsNet {
+    const NAME: &'static str = "websocket";
+    type Config = WsNetConfig;
+    type Net = Self;
+
+    fn new(config: Self::Config) -> Result<Self> {
+        Ok(WsNet::new(config.net.try_net()?, config))
+    }
+}
+
+pub fn init(registry: &mut Registry) -> Result<()> {
+    registry.add_net::<WsNet>();
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use super::{stream::WsStream, WsNetConfig};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rd_interface::{
+    async_trait, error::map_other, Address, Error, INet, IntoDyn, Net, Result, TcpStream,
+    UdpSocket, NOT_IMPLEMENTED,
+};
+use sha1::{Digest, Sha1};
+use std::io::{self, ErrorKind};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Appended to the key by the server, RFC 6455 section 1.3.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+pub struct WsNet {
+    net: Net,
+    path: String,
+    host: Option<String>,
+}
+
+/// The `Sec-WebSocket-Accept` the server answers `key` with.
+pub fn accept_key(key: &str) -> String {
+    STANDARD.encode(Sha1::digest(format!("{}{}", key, WEBSOCKET_GUID)))
+}
+
+fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
+}
+
+impl WsNet {
+    pub fn new(net: Net, config: WsNetConfig) -> Self {
+        WsNet {
+            net,
+            path: config.path,
+            host: config.host,
+        }
+    }
+
+    /// Upgrades `tcp` to a WebSocket.
+    async fn handshake(&self, mut tcp: TcpStream, host: &str) -> Result<WsStream> {
+        let mut nonce = [0u8; 16];
+        getrandom::getrandom(&mut nonce).map_err(io::Error::from)?;
+        let key = STANDARD.encode(nonce);
+        let request = format!(
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            self.path, host, key
+        );
+        tcp.write_all(request.as_bytes()).await?;
+
+        let mut buf = Vec::new();
+        let end = loop {
+            if let Some(end) = find_header_end(&buf) {
+                break end;
+            }
+            if buf.len() > MAX_RESPONSE_SIZE {
+                return Err(Error::Other("WebSocket upgrade response too large".into()));
+            }
+            let mut chunk = [0u8; 1024];
+            let n = tcp.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        };
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+        response.parse(&buf[..end]).map_err(map_other)?;
+        if response.code != Some(101) {
+            return Err(Error::Other(
+                format!(
+                    "WebSocket upgrade failed: {} {}",
+                    response.code.unwrap_or_default(),
+                    response.reason.unwrap_or_default()
+                )
+                .into(),
+            ));
+        }
+        let accept = response
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("sec-websocket-accept"))
+            .map(|h| h.value);
+        if accept != Some(accept_key(&key).as_bytes()) {
+            return Err(Error::Other(
+                "WebSocket upgrade failed: wrong accept".into(),
+            ));
+        }
+
+        // frames may come right after the response
+        Ok(WsStream::new(tcp, buf.split_off(end)))
+    }
+}
+
+#[async_trait]
+impl INet for WsNet {
+    async fn tcp_connect(
+        &self,
+        ctx: &mut rd_interface::Context,
+        addr: Address,
+    ) -> Result<TcpStream> {
+        let host = match &self.host {
+            Some(host) => host.clone(),
+            None => addr.to_string(),
+        };
+        let tcp = self.net.tcp_connect(ctx, addr).await?;
+        Ok(self.handshake(tcp, &host).await?.into_dyn())
+    }
+
+    async fn tcp_bind(
+        &self,
+        _ctx: &mut rd_interface::Context,
+        _addr: Address,
+    ) -> Result<rd_interface::TcpListener> {
+        Err(NOT_IMPLEMENTED)
+    }
+
+    async fn udp_bind(
+        &self,
+        _ctx: &mut rd_interface::Context,
+        _addr: Address,
+    ) -> Result<UdpSocket> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}
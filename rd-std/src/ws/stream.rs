@@ -0,0 +1,390 @@
+//! Binary WebSocket frames as a byte stream, RFC 6455 section 5.
+
+use rd_interface::{async_trait, ITcpStream, Result, TcpStream};
+use std::{
+    convert::TryInto,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    task::{self, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xa;
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+/// Normal closure.
+const CLOSE_NORMAL: u16 = 1000;
+/// Max size of the payload of a control frame, RFC 6455 section 5.5.
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+/// Max size of the payload of a frame sent.
+const MAX_FRAME_SIZE: usize = 16 * 1024;
+const READ_SIZE: usize = 8192;
+
+/// Builds a frame, masked if `mask` is given, as a client must do.
+pub fn encode_frame(fin: bool, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(14 + payload.len());
+    frame.push(if fin { FIN | opcode } else { opcode });
+    let mask_bit = if mask.is_some() { MASKED } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => frame.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    frame
+}
+
+pub struct FrameHeader {
+    pub opcode: u8,
+    pub mask: Option<[u8; 4]>,
+    pub payload_len: u64,
+    /// Size of the header itself.
+    pub len: usize,
+}
+
+/// Parses the header at the start of `buf`, `None` if it's incomplete.
+pub fn parse_frame_header(buf: &[u8]) -> Option<FrameHeader> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let (payload_len, mut len) = match buf[1] & !MASKED {
+        126 => (
+            u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as u64,
+            4,
+        ),
+        127 => (u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?), 10),
+        payload_len => (payload_len as u64, 2),
+    };
+    let mask = if buf[1] & MASKED != 0 {
+        let mask = buf.get(len..len + 4)?.try_into().ok()?;
+        len += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    Some(FrameHeader {
+        opcode: buf[0] & 0x0f,
+        mask,
+        payload_len,
+        len,
+    })
+}
+
+fn random_mask() -> io::Result<[u8; 4]> {
+    let mut mask = [0u8; 4];
+    getrandom::getrandom(&mut mask)?;
+    Ok(mask)
+}
+
+enum ReadState {
+    Header,
+    /// Reading the payload of a data frame.
+    Payload {
+        remaining: u64,
+        mask: Option<[u8; 4]>,
+        mask_pos: usize,
+    },
+    Eof,
+}
+
+/// Data frames are read whatever their opcode is, and fragments are just
+/// more bytes. Pings are answered, and a close is answered and ends the
+/// stream.
+pub struct WsStream {
+    inner: TcpStream,
+    /// Read from `inner`, not taken yet.
+    read_buf: Vec<u8>,
+    state: ReadState,
+
+    /// Frames not written yet.
+    write_buf: Vec<u8>,
+    written: usize,
+    closing: bool,
+}
+
+impl WsStream {
+    /// `read` is what's read after the upgrade response.
+    pub fn new(inner: TcpStream, read: Vec<u8>) -> WsStream {
+        WsStream {
+            inner,
+            read_buf: read,
+            state: ReadState::Header,
+            write_buf: Vec::new(),
+            written: 0,
+            closing: false,
+        }
+    }
+
+    /// Reads more into `read_buf`. Returns false if the stream ends.
+    fn poll_read_more(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<bool>> {
+        let mut buf = [0u8; READ_SIZE];
+        let mut buf = ReadBuf::new(&mut buf);
+        futures::ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+        self.read_buf.extend_from_slice(buf.filled());
+        Poll::Ready(Ok(!buf.filled().is_empty()))
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.write_buf.len() {
+            let n = futures::ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.written..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.write_buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Queues a control frame, and tries to write it right away.
+    fn send_control(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        opcode: u8,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let frame = encode_frame(true, opcode, payload, Some(random_mask()?));
+        self.write_buf.extend_from_slice(&frame);
+        match self.poll_write_buf(cx) {
+            Poll::Ready(Err(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads a frame header, handling the control frames. Returns false if
+    /// the stream ends.
+    fn poll_header(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            let header = match parse_frame_header(&self.read_buf) {
+                Some(header) => header,
+                None => {
+                    if !futures::ready!(self.poll_read_more(cx))? {
+                        if self.read_buf.is_empty() {
+                            return Poll::Ready(Ok(false));
+                        }
+                        return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                    }
+                    continue;
+                }
+            };
+            match header.opcode {
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                    self.read_buf.drain(..header.len);
+                    self.state = ReadState::Payload {
+                        remaining: header.payload_len,
+                        mask: header.mask,
+                        mask_pos: 0,
+                    };
+                    return Poll::Ready(Ok(true));
+                }
+                OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                    if header.payload_len > MAX_CONTROL_PAYLOAD {
+                        return Poll::Ready(Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            "WebSocket: control frame too long",
+                        )));
+                    }
+                    // control frames are short, and come whole
+                    let end = header.len + header.payload_len as usize;
+                    while self.read_buf.len() < end {
+                        if !futures::ready!(self.poll_read_more(cx))? {
+                            return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                        }
+                    }
+                    let mut payload: Vec<u8> =
+                        self.read_buf.drain(..end).skip(header.len).collect();
+                    if let Some(mask) = header.mask {
+                        payload
+                            .iter_mut()
+                            .enumerate()
+                            .for_each(|(i, b)| *b ^= mask[i % 4]);
+                    }
+                    match header.opcode {
+                        OPCODE_PING if !self.closing => {
+                            self.send_control(cx, OPCODE_PONG, &payload)?
+                        }
+                        OPCODE_CLOSE => {
+                            if !self.closing {
+                                self.closing = true;
+                                self.send_control(cx, OPCODE_CLOSE, &payload)?;
+                            }
+                            return Poll::Ready(Ok(false));
+                        }
+                        _ => {}
+                    }
+                }
+                opcode => {
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("WebSocket: unknown opcode {}", opcode),
+                    )))
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // a pong may be left to write, and nothing else may write it
+        if !this.write_buf.is_empty() {
+            if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        loop {
+            match &mut this.state {
+                ReadState::Eof => return Poll::Ready(Ok(())),
+                ReadState::Header => {
+                    if !futures::ready!(this.poll_header(cx))? {
+                        this.state = ReadState::Eof;
+                    }
+                }
+                ReadState::Payload { remaining: 0, .. } => this.state = ReadState::Header,
+                ReadState::Payload { .. } if this.read_buf.is_empty() => {
+                    if !futures::ready!(this.poll_read_more(cx))? {
+                        return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                    }
+                }
+                ReadState::Payload {
+                    remaining,
+                    mask,
+                    mask_pos,
+                } => {
+                    let n = (this.read_buf.len() as u64)
+                        .min(*remaining)
+                        .min(buf.remaining() as u64) as usize;
+                    let data = this.read_buf.drain(..n);
+                    match mask {
+                        Some(mask) => {
+                            let unmasked: Vec<u8> = data
+                                .enumerate()
+                                .map(|(i, b)| b ^ mask[(*mask_pos + i) % 4])
+                                .collect();
+                            buf.put_slice(&unmasked);
+                        }
+                        None => buf.put_slice(data.as_slice()),
+                    }
+                    *mask_pos += n;
+                    *remaining -= n as u64;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        futures::ready!(this.poll_write_buf(cx))?;
+        let n = buf.len().min(MAX_FRAME_SIZE);
+        this.write_buf = encode_frame(true, OPCODE_BINARY, &buf[..n], Some(random_mask()?));
+        // the frame is taken either way, the rest is written later
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closing {
+            futures::ready!(this.poll_write_buf(cx))?;
+            let frame = encode_frame(
+                true,
+                OPCODE_CLOSE,
+                &CLOSE_NORMAL.to_be_bytes(),
+                Some(random_mask()?),
+            );
+            this.write_buf = frame;
+            this.closing = true;
+        }
+        futures::ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl ITcpStream for WsStream {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr().await
+    }
+
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        self.inner.local_addr().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame() {
+        // RFC 6455 section 5.7
+        assert_eq!(
+            encode_frame(true, OPCODE_TEXT, b"Hello", None),
+            b"\x81\x05\x48\x65\x6c\x6c\x6f"
+        );
+        let masked = encode_frame(true, OPCODE_TEXT, b"Hello", Some([0x37, 0xfa, 0x21, 0x3d]));
+        assert_eq!(masked, b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58");
+        assert_eq!(
+            encode_frame(false, OPCODE_TEXT, b"Hel", None),
+            b"\x01\x03\x48\x65\x6c"
+        );
+
+        let header = parse_frame_header(&masked).unwrap();
+        assert_eq!(header.opcode, OPCODE_TEXT);
+        assert_eq!(header.mask, Some([0x37, 0xfa, 0x21, 0x3d]));
+        assert_eq!(header.payload_len, 5);
+        assert_eq!(header.len, 6);
+        assert!(parse_frame_header(&masked[..5]).is_none());
+
+        let frame = encode_frame(true, OPCODE_BINARY, &[0; 256], None);
+        assert_eq!(&frame[..4], b"\x82\x7e\x01\x00");
+        assert_eq!(parse_frame_header(&frame).unwrap().payload_len, 256);
+        let frame = encode_frame(true, OPCODE_BINARY, &[0; 65536], None);
+        assert_eq!(&frame[..10], b"\x82\x7f\x00\x00\x00\x00\x00\x01\x00\x00");
+        assert_eq!(parse_frame_header(&frame).unwrap().payload_len, 65536);
+    }
+}
@@ -0,0 +1,226 @@
+use super::{client::accept_key, stream::*, *};
+use crate::builtin::local::{LocalConfig, LocalNet};
+use crate::tests::get_registry;
+use rd_interface::{
+    async_trait, impl_async_read_write, Context, ITcpStream, IntoAddress, IntoDyn, Net, Result,
+    NOT_IMPLEMENTED,
+};
+use std::{io::ErrorKind, net::SocketAddr, time::Duration};
+use tokio::{
+    io::{duplex, AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::TcpListener,
+    sync::mpsc,
+    time::timeout,
+};
+
+/// What the server sees of a connection.
+#[derive(Debug, Default)]
+struct Seen {
+    path: String,
+    host: String,
+    pong: Vec<u8>,
+}
+
+async fn read_frame(stream: &mut (impl AsyncRead + Unpin)) -> (u8, Vec<u8>) {
+    let mut header = vec![0u8; 2];
+    stream.read_exact(&mut header).await.unwrap();
+    assert!(header[1] & 0x80 != 0, "frames from the client are masked");
+    let extra = match header[1] & 0x7f {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    } + 4;
+    header.resize(2 + extra, 0);
+    stream.read_exact(&mut header[2..]).await.unwrap();
+    let header = parse_frame_header(&header).unwrap();
+
+    let mut payload = vec![0u8; header.payload_len as usize];
+    stream.read_exact(&mut payload).await.unwrap();
+    let mask = header.mask.unwrap();
+    payload
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b ^= mask[i % 4]);
+    (header.opcode, payload)
+}
+
+/// A WebSocket server which pings first, and echoes the data in two
+/// fragments per frame.
+async fn spawn_ws_server() -> (u16, mpsc::UnboundedReceiver<Seen>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut tcp, _) = listener.accept().await.unwrap();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(tcp.read_u8().await.unwrap());
+                }
+                let mut headers = [httparse::EMPTY_HEADER; 16];
+                let mut req = httparse::Request::new(&mut headers);
+                req.parse(&request).unwrap();
+                let header = |name: &str| {
+                    let h = req.headers.iter().find(|h| h.name == name).unwrap();
+                    String::from_utf8(h.value.to_vec()).unwrap()
+                };
+                assert_eq!(header("Upgrade"), "websocket");
+                let mut seen = Seen {
+                    path: req.path.unwrap().to_string(),
+                    host: header("Host"),
+                    ..Default::default()
+                };
+
+                let mut response = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\n\
+                     Upgrade: websocket\r\n\
+                     Connection: Upgrade\r\n\
+                     Sec-WebSocket-Accept: {}\r\n\r\n",
+                    accept_key(&header("Sec-WebSocket-Key"))
+                )
+                .into_bytes();
+                response.extend(encode_frame(true, OPCODE_PING, b"ping!", None));
+                tcp.write_all(&response).await.unwrap();
+
+                loop {
+                    match read_frame(&mut tcp).await {
+                        (OPCODE_PONG, payload) => seen.pong = payload,
+                        (OPCODE_BINARY, payload) => {
+                            let (a, b) = payload.split_at(payload.len() / 2);
+                            let mut echo = encode_frame(false, OPCODE_BINARY, a, None);
+                            echo.extend(encode_frame(true, OPCODE_CONTINUATION, b, None));
+                            tcp.write_all(&echo).await.unwrap();
+                        }
+                        (OPCODE_CLOSE, payload) => {
+                            let close = encode_frame(true, OPCODE_CLOSE, &payload, None);
+                            tcp.write_all(&close).await.unwrap();
+                            break;
+                        }
+                        (opcode, _) => panic!("unexpected opcode {}", opcode),
+                    }
+                }
+                tx.send(seen).unwrap();
+            });
+        }
+    });
+    (port, rx)
+}
+
+fn ws_net(config: serde_json::Value) -> Net {
+    let local = LocalNet::new(LocalConfig::default()).into_dyn();
+    WsNet::new(local, serde_json::from_value(config).unwrap()).into_dyn()
+}
+
+#[test]
+fn test_ws_smoke() {
+    let mut registry = get_registry();
+    super::init(&mut registry).unwrap();
+}
+
+#[tokio::test]
+async fn test_ws_tcp() {
+    let (port, mut seen) = spawn_ws_server().await;
+    let ws = ws_net(serde_json::json!({ "path": "/ws", "host": "cdn.example.com" }));
+
+    let mut tcp = ws
+        .tcp_connect(
+            &mut Context::new(),
+            ("127.0.0.1", port).into_address().unwrap(),
+        )
+        .await
+        .unwrap();
+    // more than a frame
+    let sent: Vec<u8> = (0..40000u32).map(|i| i as u8).collect();
+    tcp.write_all(&sent).await.unwrap();
+    let mut received = vec![0u8; sent.len()];
+    tcp.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, sent);
+
+    // the close is answered, and ends the stream
+    tcp.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    tcp.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+
+    let seen = seen.recv().await.unwrap();
+    assert_eq!(seen.path, "/ws");
+    assert_eq!(seen.host, "cdn.example.com");
+    assert_eq!(seen.pong, b"ping!");
+}
+
+#[tokio::test]
+async fn test_ws_default_host() {
+    let (port, mut seen) = spawn_ws_server().await;
+    let ws = ws_net(serde_json::json!({}));
+
+    let mut tcp = ws
+        .tcp_connect(
+            &mut Context::new(),
+            ("127.0.0.1", port).into_address().unwrap(),
+        )
+        .await
+        .unwrap();
+    tcp.shutdown().await.unwrap();
+    tcp.read_to_end(&mut Vec::new()).await.unwrap();
+
+    let seen = seen.recv().await.unwrap();
+    assert_eq!(seen.path, "/");
+    assert_eq!(seen.host, format!("127.0.0.1:{}", port));
+}
+
+struct DuplexTcp(DuplexStream);
+impl_async_read_write!(DuplexTcp, 0);
+
+#[async_trait]
+impl ITcpStream for DuplexTcp {
+    async fn peer_addr(&self) -> Result<SocketAddr> {
+        Err(NOT_IMPLEMENTED)
+    }
+    async fn local_addr(&self) -> Result<SocketAddr> {
+        Err(NOT_IMPLEMENTED)
+    }
+}
+
+/// A `WsStream` over a pipe holding `size` bytes, and the server end.
+fn ws_pipe(size: usize) -> (WsStream, DuplexStream) {
+    let (client, server) = duplex(size);
+    (
+        WsStream::new(DuplexTcp(client).into_dyn(), Vec::new()),
+        server,
+    )
+}
+
+#[tokio::test]
+async fn test_ws_control_frame_too_long() {
+    let (mut ws, mut server) = ws_pipe(1024);
+    // a ping claiming 126 bytes of payload
+    server.write_all(&[0x89, 126, 0, 126]).await.unwrap();
+
+    let err = ws.read(&mut [0u8; 16]).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn test_ws_pong_flushed_on_read() {
+    // too small for the pong to be written at once
+    let (mut ws, mut server) = ws_pipe(8);
+    let server = tokio::spawn(async move {
+        let ping = encode_frame(true, OPCODE_PING, b"0123456789", None);
+        server.write_all(&ping).await.unwrap();
+        let pong = read_frame(&mut server).await;
+        let data = encode_frame(true, OPCODE_BINARY, b"data", None);
+        server.write_all(&data).await.unwrap();
+        pong
+    });
+
+    // only reading, the pong is written by the reads
+    let mut data = [0u8; 4];
+    timeout(Duration::from_secs(5), ws.read_exact(&mut data))
+        .await
+        .expect("the pong was not written")
+        .unwrap();
+    assert_eq!(&data, b"data");
+    assert_eq!(server.await.unwrap(), (OPCODE_PONG, b"0123456789".to_vec()));
+}
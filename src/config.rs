@@ -1,12 +1,13 @@
 pub mod default;
 
-use std::collections::HashMap;
+use std::{collections::HashMap, convert::Infallible};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use rd_interface::Value;
 use serde_derive::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
 
-use crate::Registry;
+use crate::{util::topological_sort, Registry};
 
 pub type ConfigNet = HashMap<String, Net>;
 pub type ConfigServer = HashMap<String, Server>;
@@ -37,16 +38,78 @@ pub struct Config {
     pub net: ConfigNet,
     #[serde(default)]
     pub server: ConfigServer,
+    /// The net used when a net field is omitted in a net config. Defaults to
+    /// `"local"`.
+    #[serde(default)]
+    pub default_net: Option<String>,
+    /// Caps the TCP connections open through the servers at once. When a
+    /// reload changes the cap, the connections already open are no longer
+    /// counted, so more than `max` may be open until they close.
+    #[serde(default)]
+    pub connection_limit: Option<ConnectionLimit>,
+}
+
+/// What to do with a connection over the limit.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Exceed {
+    /// Fail it with `ConnectionRefused`.
+    #[default]
+    Reject,
+    /// Wait until another connection closes.
+    Delay,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct ConnectionLimit {
+    /// At least 1, and at most `Semaphore::MAX_PERMITS`.
+    pub max: usize,
+    /// What to do with the connections over `max`, `reject` by default.
+    #[serde(default)]
+    pub on_exceed: Exceed,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Net {
-    #[serde(rename = "type")]
+    /// May be omitted when `extends` is set, the type of the base is used.
+    #[serde(rename = "type", default)]
     pub net_type: String,
     #[serde(flatten)]
     pub opt: Value,
 }
 
+impl ConnectionLimit {
+    pub fn validate(&self) -> Result<()> {
+        if self.max == 0 || self.max > Semaphore::MAX_PERMITS {
+            return Err(anyhow!(
+                "connection_limit.max must be between 1 and {}, got {}",
+                Semaphore::MAX_PERMITS,
+                self.max
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Net {
+    /// The name of the net this one extends.
+    pub fn extends(&self) -> Option<&str> {
+        self.opt.get("extends").and_then(Value::as_str)
+    }
+
+    /// Merges `self` on top of `base`, and drops the `extends` key.
+    fn extend(&mut self, base: &Net) {
+        if let Some(opt) = self.opt.as_object_mut() {
+            opt.remove("extends");
+        }
+        if self.net_type.is_empty() {
+            self.net_type = base.net_type.clone();
+        }
+        let patch = std::mem::replace(&mut self.opt, base.opt.clone());
+        merge_patch(&mut self.opt, patch);
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Server {
     #[serde(rename = "type")]
@@ -63,5 +126,232 @@ impl Config {
     pub fn merge(&mut self, other: Config) {
         self.net.extend(other.net);
         self.server.extend(other.server);
+        if other.default_net.is_some() {
+            self.default_net = other.default_net;
+        }
+        if other.connection_limit.is_some() {
+            self.connection_limit = other.connection_limit;
+        }
+    }
+
+    /// Expands `${NAME}` in the string options of nets and servers to the
+    /// environment variable `NAME`. It's an error if it's not set.
+    pub fn expand_env(&mut self) -> Result<()> {
+        let var = |name: &str| std::env::var(name).ok();
+        for (name, net) in self.net.iter_mut() {
+            expand_env(&mut net.opt, &format!("net.{}", name), &var)?;
+        }
+        for (name, server) in self.server.iter_mut() {
+            expand_env(&mut server.opt, &format!("server.{}", name), &var)?;
+        }
+        Ok(())
+    }
+
+    /// Resolves the `extends` key of every net: the config of the base net
+    /// is merged with the fields of the net, as a JSON merge patch. Bases
+    /// are resolved before the nets extending them.
+    pub fn resolve_extends(&mut self) -> Result<()> {
+        let extends: HashMap<String, Option<String>> = self
+            .net
+            .iter()
+            .map(|(name, net)| (name.clone(), net.extends().map(ToString::to_string)))
+            .collect();
+        let sorted = match topological_sort(extends, |base| {
+            Ok::<_, Infallible>(base.iter().cloned().collect())
+        }) {
+            Ok(sorted) => sorted,
+            Err(e) => match e {},
+        };
+        let sorted = sorted.map_err(|mut cycle| {
+            cycle.sort();
+            anyhow!("Cycle detected in extends: {}", cycle.join(", "))
+        })?;
+
+        for (name, base) in sorted {
+            let base = match base {
+                Some(base) => base,
+                None => continue,
+            };
+            let base = self
+                .net
+                .get(&base)
+                .ok_or_else(|| anyhow!("Net {:?} extends an unknown net {:?}", name, base))?
+                .clone();
+            if let Some(net) = self.net.get_mut(&name) {
+                net.extend(&base);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replaces every `${NAME}` in the strings of `value` with the environment
+/// variable `NAME`, using `var` to look it up. `path` tells where `value` is
+/// in errors.
+fn expand_env(value: &mut Value, path: &str, var: &impl Fn(&str) -> Option<String>) -> Result<()> {
+    match value {
+        Value::String(s) if s.contains("${") => {
+            *s = expand_str(s, var).map_err(|e| anyhow!("{}: {}", path, e))?;
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                expand_env(item, &format!("{}.{}", path, i), var)?;
+            }
+        }
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                expand_env(v, &format!("{}.{}", path, k), var)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_str(s: &str, var: &impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow!("Unclosed `${{` in {:?}", s))?;
+        let name = &after[..end];
+        let value = var(name).ok_or_else(|| anyhow!("Environment variable {} is not set", name))?;
+        out.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Applies `patch` to `target` as in RFC 7396: objects are merged
+/// recursively, `null` removes a field and anything else replaces it.
+fn merge_patch(target: &mut Value, patch: Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        patch => {
+            *target = patch;
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    if let Value::Object(target) = target {
+        for (k, v) in patch {
+            if v.is_null() {
+                target.remove(&k);
+            } else {
+                merge_patch(target.entry(k).or_insert(Value::Null), v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(net: Value) -> Config {
+        serde_json::from_value(json!({ "net": net })).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_extends() {
+        let mut config = config(json!({
+            "child": {
+                "extends": "base",
+                "server": "b.example.com",
+                "sni": null,
+            },
+            "grandchild": {
+                "extends": "child",
+                "port": 8443,
+            },
+            "base": {
+                "type": "trojan",
+                "server": "a.example.com",
+                "port": 443,
+                "password": "p",
+                "sni": "example.com",
+            },
+        }));
+        config.resolve_extends().unwrap();
+
+        let child = &config.net["child"];
+        assert_eq!(child.net_type, "trojan");
+        assert_eq!(
+            child.opt,
+            json!({ "server": "b.example.com", "port": 443, "password": "p" })
+        );
+        let grandchild = &config.net["grandchild"];
+        assert_eq!(grandchild.net_type, "trojan");
+        assert_eq!(
+            grandchild.opt,
+            json!({ "server": "b.example.com", "port": 8443, "password": "p" })
+        );
+        // the base is left as is
+        assert_eq!(config.net["base"].opt["server"], "a.example.com");
+    }
+
+    #[test]
+    fn test_expand_env() {
+        std::env::set_var("RD_TEST_PROXY_PW", "secret");
+        let mut proxy = config(json!({
+            "proxy": {
+                "type": "trojan",
+                "server": "example.com",
+                "port": 443,
+                "password": "${RD_TEST_PROXY_PW}",
+                "sni": "a-${RD_TEST_PROXY_PW}-b",
+            },
+        }));
+        proxy.expand_env().unwrap();
+        let opt = &proxy.net["proxy"].opt;
+        assert_eq!(opt["password"], "secret");
+        assert_eq!(opt["sni"], "a-secret-b");
+        assert_eq!(opt["server"], "example.com");
+
+        let mut missing = config(json!({
+            "proxy": { "type": "trojan", "password": "${RD_TEST_MISSING}" },
+        }));
+        assert_eq!(
+            missing.expand_env().unwrap_err().to_string(),
+            "net.proxy.password: Environment variable RD_TEST_MISSING is not set"
+        );
+
+        let var = |name: &str| Some(name.to_lowercase());
+        assert_eq!(expand_str("${A}${B} $C {D}", &var).unwrap(), "ab $C {D}");
+        assert!(expand_str("${A", &var).is_err());
+    }
+
+    #[test]
+    fn test_connection_limit_validate() {
+        let limit = |max| ConnectionLimit {
+            max,
+            on_exceed: Exceed::Reject,
+        };
+        assert!(limit(1).validate().is_ok());
+        assert!(limit(Semaphore::MAX_PERMITS).validate().is_ok());
+        assert!(limit(0).validate().is_err());
+        assert!(limit(Semaphore::MAX_PERMITS + 1).validate().is_err());
+    }
+
+    #[test]
+    fn test_resolve_extends_error() {
+        let mut unknown = config(json!({ "a": { "extends": "b" } }));
+        assert!(unknown.resolve_extends().is_err());
+
+        let mut cycle = config(json!({
+            "a": { "extends": "b" },
+            "b": { "extends": "a" },
+        }));
+        assert_eq!(
+            cycle.resolve_extends().unwrap_err().to_string(),
+            "Cycle detected in extends: a, b"
+        );
     }
 }
@@ -1,30 +1,40 @@
+mod access_log;
 mod event;
 mod server_net;
+mod snapshot;
+mod stats;
 mod wrap_net;
 
 use crate::{
     config,
-    rabbit_digger::{RabbitDigger, RabbitDiggerBuilder},
+    rabbit_digger::{RabbitDigger, RabbitDiggerBuilder, ServerTask},
     Registry,
 };
 
-pub use self::event::{BatchEvent, Event};
+pub use self::access_log::AccessLog;
+pub use self::event::{BatchEvent, ConnectionInfo, Event, EventType, TcpInfo};
+pub use self::snapshot::{ConnectionSnapshot, StateSnapshot};
+pub use self::stats::NetStats;
 use anyhow::{anyhow, Context, Result};
-use futures::{
-    channel::oneshot,
-    future::{ready, try_select, Either},
-    pin_mut, stream, FutureExt, Stream, StreamExt, TryStreamExt,
-};
+use futures::{channel::oneshot, future::ready, stream, Stream, StreamExt, TryStreamExt};
 use rd_interface::{schemars::schema::RootSchema, IntoDyn, Net};
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use stats::ConnectionStats;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 use tokio::{sync::broadcast, time::timeout};
 use tokio::{
     sync::mpsc,
     sync::{RwLock, RwLockReadGuard},
     task::spawn,
-    time::sleep,
 };
+use uuid::Uuid;
 
 pub struct OnceConfigStopper {
     tx: oneshot::Sender<()>,
@@ -57,18 +67,15 @@ pub struct Inner {
     state: State,
 }
 
-#[derive(Debug)]
-pub struct TaskInfo {
-    pub name: String,
-}
-
 #[derive(Debug)]
 pub struct Running {
     config: config::Config,
     registry: RegistrySchema,
+    servers: HashMap<String, ServerTask>,
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum State {
     Idle,
     Running(Running),
@@ -97,43 +104,116 @@ impl State {
 pub struct Controller {
     inner: Arc<RwLock<Inner>>,
     event_sender: mpsc::UnboundedSender<Event>,
+    stats: Arc<Mutex<ConnectionStats>>,
+    access_log: Arc<Mutex<Option<AccessLog>>>,
+    dropped_events: Arc<AtomicU64>,
+    killers: server_net::Killers,
+    limiter: server_net::ConnectionLimiter,
+    /// Held while servers are out of `inner` to be stopped or reloaded.
+    reloading: Arc<tokio::sync::Mutex<()>>,
 }
 
-async fn process(mut rx: mpsc::UnboundedReceiver<Event>, sender: broadcast::Sender<BatchEvent>) {
-    loop {
-        let e = match rx.recv().now_or_never() {
-            Some(Some(e)) => e,
-            Some(None) => break,
-            None => {
-                sleep(Duration::from_millis(100)).await;
-                continue;
-            }
-        };
+/// Max events sent to subscribers at once.
+const MAX_BATCH_SIZE: usize = 1024;
+/// Batches of events kept for the subscribers by default.
+pub const DEFAULT_EVENT_CAPACITY: usize = 256;
 
+/// Sends the events to the subscribers in batches. `sender` keeps the last
+/// `capacity` batches, a subscriber further behind than that misses the
+/// oldest ones. The events missed by a subscriber are counted in `dropped`.
+async fn process(
+    mut rx: mpsc::UnboundedReceiver<Event>,
+    sender: broadcast::Sender<BatchEvent>,
+    capacity: usize,
+    stats: Arc<Mutex<ConnectionStats>>,
+    access_log: Arc<Mutex<Option<AccessLog>>>,
+    dropped: Arc<AtomicU64>,
+) {
+    // sizes of the batches the channel may still hold
+    let mut sent = VecDeque::with_capacity(capacity);
+    while let Some(e) = rx.recv().await {
+        // send what's queued together, so a burst is one batch
         let mut events = BatchEvent::with_capacity(16);
         events.push(Arc::new(e));
-        while let Some(Some(e)) = rx.recv().now_or_never() {
-            events.push(Arc::new(e));
+        while events.len() < MAX_BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(e) => events.push(Arc::new(e)),
+                Err(_) => break,
+            }
+        }
+
+        {
+            let mut stats = stats.lock().unwrap();
+            for e in &events {
+                stats.update(e);
+            }
+        }
+        if let Some(access_log) = &mut *access_log.lock().unwrap() {
+            for e in &events {
+                access_log.update(e);
+            }
         }
 
+        // a full channel overwrites its oldest batch, which a subscriber
+        // hasn't received yet
+        if sender.len() == capacity {
+            if let Some(size) = sent.front() {
+                dropped.fetch_add(*size as u64, Ordering::Relaxed);
+            }
+        }
+        let size = events.len();
         // Failed only when no receiver
-        sender.send(events).ok();
+        if sender.send(events).is_ok() {
+            if sent.len() == capacity {
+                sent.pop_front();
+            }
+            sent.push_back(size);
+        }
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Controller {
     pub fn new() -> Controller {
-        let (sender, _) = broadcast::channel(16);
+        Controller::with_event_capacity(DEFAULT_EVENT_CAPACITY)
+    }
+
+    /// A controller keeping `capacity` batches of events for its
+    /// subscribers, rounded up to a power of two.
+    pub fn with_event_capacity(capacity: usize) -> Controller {
+        let capacity = capacity.max(1).next_power_of_two();
+        let (sender, _) = broadcast::channel(capacity);
         let inner = Arc::new(RwLock::new(Inner {
             sender: sender.clone(),
             state: State::Idle,
             builder: RabbitDiggerBuilder::new(),
         }));
         let (event_sender, event_receiver) = mpsc::unbounded_channel();
-        spawn(process(event_receiver, sender));
+        let stats = Arc::new(Mutex::new(ConnectionStats::default()));
+        let access_log = Arc::new(Mutex::new(None));
+        let dropped_events = Arc::new(AtomicU64::new(0));
+        spawn(process(
+            event_receiver,
+            sender,
+            capacity,
+            stats.clone(),
+            access_log.clone(),
+            dropped_events.clone(),
+        ));
         Controller {
             inner,
             event_sender,
+            stats,
+            access_log,
+            dropped_events,
+            killers: Default::default(),
+            limiter: Default::default(),
+            reloading: Default::default(),
         }
     }
 
@@ -168,7 +248,7 @@ impl Controller {
 
         let mut config = match timeout(Duration::from_secs(1), config_stream.try_next()).await {
             Ok(Ok(Some(cfg))) => cfg,
-            Ok(Err(e)) => return Err(e.context(format!("Failed to get first config."))),
+            Ok(Err(e)) => return Err(e.context("Failed to get first config.")),
             Err(_) | Ok(Ok(None)) => {
                 return Err(anyhow!("The config_stream is empty, can not start."))
             }
@@ -183,31 +263,18 @@ impl Controller {
                 servers,
                 ..
             } = self.inner.read().await.builder.build(self, config)?;
+            self.limiter.set(rd_config.connection_limit);
 
             self.change_state(State::Running(Running {
                 config: rd_config,
                 registry: get_registry_schema(&registry)?,
+                servers: RabbitDigger::spawn(servers),
             }))
             .await?;
 
-            let run_fut = RabbitDigger::run(servers);
-            pin_mut!(run_fut);
-            let new_config = match try_select(run_fut, config_stream.try_next()).await {
-                Ok(Either::Left((_, cfg_fut))) => {
-                    tracing::info!("Exited normally, waiting for next config...");
-                    cfg_fut.await
-                }
-                Ok(Either::Right((cfg, _))) => Ok(cfg),
-                Err(Either::Left((e, cfg_fut))) => {
-                    tracing::error!(
-                        "Rabbit digger went to error: {:?}, waiting for next config...",
-                        e
-                    );
-                    cfg_fut.await
-                }
-                Err(Either::Right((e, _))) => Err(e),
-            };
+            let new_config = config_stream.try_next().await;
 
+            self.stop_servers().await;
             self.change_state(State::Idle).await?;
 
             config = match new_config? {
@@ -219,6 +286,114 @@ impl Controller {
         Ok(())
     }
 
+    async fn stop_servers(&self) {
+        let _reloading = self.reloading.lock().await;
+        let servers = match &mut self.inner.write().await.state {
+            State::Running(r) => std::mem::take(&mut r.servers),
+            State::Idle => return,
+        };
+        for (_, task) in servers {
+            task.stop().await;
+        }
+    }
+
+    /// Swaps in a new config while running. Only the servers whose config, or
+    /// the config of the nets they use, changed are restarted. The others
+    /// keep running with their current nets.
+    pub async fn reload_config(&self, new: config::Config) -> Result<()> {
+        let _reloading = self.reloading.lock().await;
+        // The changed servers are taken out, not to hold the lock while
+        // they stop or reload.
+        let (config, registry, changed, mut old) = {
+            let mut inner = self.inner.write().await;
+            if inner.state.running().is_none() {
+                return Err(anyhow!("Can not reload config when not running."));
+            }
+            let RabbitDigger {
+                config,
+                registry,
+                servers,
+                ..
+            } = inner.builder.build(self, new)?;
+            let registry = get_registry_schema(&registry)?;
+            let running = match &mut inner.state {
+                State::Running(r) => r,
+                State::Idle => unreachable!(),
+            };
+
+            let mut old = std::mem::take(&mut running.servers);
+            let mut changed = Vec::new();
+            for server in servers {
+                let name = server.name().to_string();
+                match old.remove(&name) {
+                    Some(task) if task.hash() == server.hash() => {
+                        running.servers.insert(name, task);
+                    }
+                    task => changed.push((server, task)),
+                }
+            }
+            (config, registry, changed, old)
+        };
+
+        let mut servers = HashMap::new();
+        let mut to_start = Vec::new();
+        let mut reloaded = Vec::new();
+        for (server, task) in changed {
+            let name = server.name().to_string();
+            match task {
+                Some(mut task) => match task.reload(&server).await {
+                    Ok(()) => {
+                        reloaded.push(name.clone());
+                        servers.insert(name, task);
+                    }
+                    Err(e) => {
+                        tracing::debug!("Server {} can not reload in place: {:?}", name, e);
+                        old.insert(name, task);
+                        to_start.push(server);
+                    }
+                },
+                None => to_start.push(server),
+            }
+        }
+
+        // Stop first, the new servers may bind the same address.
+        for (_, task) in old {
+            task.stop().await;
+        }
+        let mut restarted = Vec::with_capacity(to_start.len());
+        for server in to_start {
+            restarted.push(server.name().to_string());
+            servers.insert(server.name().to_string(), server.spawn());
+        }
+        restarted.sort();
+        reloaded.sort();
+        tracing::info!(
+            "Config reloaded, restarted servers: {:?}, reloaded in place: {:?}",
+            restarted,
+            reloaded
+        );
+
+        // still running, `stop_servers` waits for `reloading`
+        let mut inner = self.inner.write().await;
+        let running = match &mut inner.state {
+            State::Running(r) => r,
+            State::Idle => unreachable!(),
+        };
+        running.servers.extend(servers);
+        self.limiter.set(config.connection_limit);
+        running.config = config;
+        running.registry = registry;
+
+        self.event_sender
+            .send(Event::new(
+                Uuid::new_v4(),
+                EventType::ConfigChanged(restarted),
+            ))
+            .ok();
+
+        Ok(())
+    }
+
     pub fn get_net(&self, net_name: String, net: Net) -> Net {
         wrap_net::ControllerNet {
             net_name,
@@ -232,6 +407,8 @@ impl Controller {
         server_net::ControllerServerNet {
             net,
             sender: self.event_sender.clone(),
+            killers: self.killers.clone(),
+            limiter: self.limiter.clone(),
         }
         .into_dyn()
     }
@@ -245,9 +422,66 @@ impl Controller {
     pub async fn lock<'a>(&'a self) -> RwLockReadGuard<'a, Inner> {
         self.inner.read().await
     }
+    /// Receives the events in batches. A subscriber more batches behind than
+    /// the capacity of the controller misses the oldest ones, and gets
+    /// `RecvError::Lagged` with how many it missed. They are counted in
+    /// [`dropped_events`](Controller::dropped_events).
     pub async fn get_subscriber(&self) -> broadcast::Receiver<BatchEvent> {
         self.inner.read().await.sender.subscribe()
     }
+    /// Like `get_subscriber`, but only yields the events of one connection.
+    pub async fn get_subscriber_for(&self, uuid: Uuid) -> impl Stream<Item = BatchEvent> {
+        event::filter_by_uuid(self.get_subscriber().await, uuid)
+    }
+    /// Events overwritten before every subscriber received them, since the
+    /// controller was created.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+    /// The open connections, oldest first.
+    pub fn list_connections(&self) -> Vec<ConnectionInfo> {
+        self.stats.lock().unwrap().list()
+    }
+    /// Makes the reads and writes of the TCP connection `uuid` fail, so the
+    /// server relaying it closes it. Its `CloseConnection` event is sent
+    /// once it's dropped. Returns false if there is no such connection.
+    pub fn kill_connection(&self, uuid: Uuid) -> bool {
+        self.killers.kill(&uuid)
+    }
+    /// Bytes `(inbound, outbound)` of each open connection so far.
+    pub fn connection_stats(&self) -> HashMap<Uuid, (u64, u64)> {
+        self.stats.lock().unwrap().snapshot()
+    }
+    /// Logs the connections closed from now on to `access_log`, or stops
+    /// logging them if it's `None`.
+    pub fn set_access_log(&self, access_log: Option<AccessLog>) {
+        *self.access_log.lock().unwrap() = access_log;
+    }
+    /// The config, servers, open connections and stats at once. They are
+    /// read under the same locks, so they agree with each other.
+    pub async fn snapshot(&self) -> StateSnapshot {
+        let inner = self.inner.read().await;
+        let mut servers = BTreeMap::new();
+        if let Some(running) = inner.state.running() {
+            for (name, task) in &running.servers {
+                servers.insert(name.clone(), task.local_addr().await.ok());
+            }
+        }
+        let stats = self.stats.lock().unwrap();
+        StateSnapshot {
+            state: inner.state(),
+            config: inner.config().cloned(),
+            servers,
+            connections: stats.list_with_bytes(),
+            nets: stats.nets(),
+            dropped_events: self.dropped_events(),
+        }
+    }
+    /// Returns the connection and byte totals of each net, in the Prometheus
+    /// text format, for a `/metrics` handler.
+    pub fn metrics_text(&self) -> String {
+        self.stats.lock().unwrap().metrics_text()
+    }
 }
 
 impl Inner {
@@ -281,3 +515,342 @@ fn get_registry_schema(registry: &Registry) -> Result<RegistrySchema> {
 
     Ok(r)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{net::TcpStream, time::sleep};
+
+    #[tokio::test]
+    async fn test_process_batch() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (sender, mut subscriber) = broadcast::channel(16);
+        let stats = Arc::new(Mutex::new(ConnectionStats::default()));
+        spawn(process(
+            rx,
+            sender,
+            16,
+            stats,
+            Default::default(),
+            Default::default(),
+        ));
+
+        // a lone event isn't held back
+        let start = std::time::Instant::now();
+        tx.send(Event::new(Uuid::new_v4(), EventType::CloseConnection))
+            .unwrap();
+        assert_eq!(subscriber.recv().await.unwrap().len(), 1);
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        let count = MAX_BATCH_SIZE + 10;
+        for _ in 0..count {
+            tx.send(Event::new(Uuid::new_v4(), EventType::Outbound(1)))
+                .unwrap();
+        }
+        let mut batches = Vec::new();
+        let mut received = 0;
+        while received < count {
+            let batch = subscriber.recv().await.unwrap();
+            received += batch.len();
+            batches.push(batch.len());
+        }
+        assert_eq!(received, count);
+        assert!(batches.iter().all(|len| *len <= MAX_BATCH_SIZE));
+        assert!(batches.len() <= 3, "{:?}", batches);
+    }
+
+    #[tokio::test]
+    async fn test_event_lag() {
+        use tokio::sync::broadcast::error::RecvError;
+
+        let ctl = Controller::with_event_capacity(2);
+        let mut slow = ctl.get_subscriber().await;
+        let mut fast = ctl.get_subscriber().await;
+        for i in 0..5 {
+            ctl.event_sender
+                .send(Event::new(Uuid::new_v4(), EventType::Outbound(i)))
+                .unwrap();
+            // a batch each
+            assert_eq!(fast.recv().await.unwrap().len(), 1);
+        }
+
+        // the slow one missed all but the last two
+        assert_eq!(ctl.dropped_events(), 3);
+        assert!(matches!(slow.recv().await, Err(RecvError::Lagged(3))));
+        let events = slow.recv().await.unwrap();
+        assert!(matches!(events[0].event_type, EventType::Outbound(3)));
+    }
+
+    fn socks5_config(servers: &[(&str, u16)]) -> config::Config {
+        let server = servers
+            .iter()
+            .map(|(name, port)| {
+                let server = serde_json::json!({
+                    "type": "socks5",
+                    "bind": format!("127.0.0.1:{}", port),
+                });
+                (name.to_string(), serde_json::from_value(server).unwrap())
+            })
+            .collect();
+        config::Config {
+            server,
+            ..Default::default()
+        }
+    }
+
+    async fn wait_listening(port: u16, listening: bool) {
+        for _ in 0..50 {
+            if TcpStream::connect(("127.0.0.1", port)).await.is_ok() == listening {
+                return;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        panic!("port {} listening should be {}", port, listening);
+    }
+
+    #[tokio::test]
+    async fn test_reload_config() {
+        let ctl = Controller::new();
+        assert!(ctl.reload_config(Default::default()).await.is_err());
+
+        let stopper = ctl
+            .start(socks5_config(&[("a", 26673), ("b", 26674)]))
+            .await;
+        wait_listening(26673, true).await;
+        wait_listening(26674, true).await;
+
+        let mut subscriber = ctl.get_subscriber().await;
+        ctl.reload_config(socks5_config(&[("a", 26673), ("b", 26675)]))
+            .await
+            .unwrap();
+
+        let restarted = loop {
+            let events = subscriber.recv().await.unwrap();
+            if let Some(restarted) = events.iter().find_map(|e| match &e.event_type {
+                EventType::ConfigChanged(restarted) => Some(restarted.clone()),
+                _ => None,
+            }) {
+                break restarted;
+            }
+        };
+        assert_eq!(restarted, vec!["b".to_string()]);
+
+        wait_listening(26674, false).await;
+        wait_listening(26675, true).await;
+        wait_listening(26673, true).await;
+
+        let listen = ctl.lock().await.config().unwrap().server["b"].opt["bind"].clone();
+        assert_eq!(listen, "127.0.0.1:26675");
+
+        // reloading the same config restarts nothing
+        ctl.reload_config(socks5_config(&[("a", 26673), ("b", 26675)]))
+            .await
+            .unwrap();
+        ctl.reload_config(socks5_config(&[("a", 26673)]))
+            .await
+            .unwrap();
+        wait_listening(26675, false).await;
+
+        stopper.stop().await.ok();
+        wait_listening(26673, false).await;
+    }
+
+    #[tokio::test]
+    async fn test_reload_in_place() {
+        let ctl = Controller::new();
+        let stopper = ctl.start(socks5_config(&[("a", 26681)])).await;
+        wait_listening(26681, true).await;
+
+        let mut subscriber = ctl.get_subscriber().await;
+        let mut config = socks5_config(&[("a", 26681)]);
+        config.server.get_mut("a").unwrap().opt["users"] = serde_json::json!({ "u": "p" });
+        ctl.reload_config(config).await.unwrap();
+
+        let restarted = loop {
+            let events = subscriber.recv().await.unwrap();
+            if let Some(restarted) = events.iter().find_map(|e| match &e.event_type {
+                EventType::ConfigChanged(restarted) => Some(restarted.clone()),
+                _ => None,
+            }) {
+                break restarted;
+            }
+        };
+        assert!(restarted.is_empty());
+        wait_listening(26681, true).await;
+
+        // the reloaded hash is kept, reloading again does nothing
+        let hash = ctl.lock().await.state.running().unwrap().servers["a"].hash();
+        let mut config = socks5_config(&[("a", 26681)]);
+        config.server.get_mut("a").unwrap().opt["users"] = serde_json::json!({ "u": "p" });
+        ctl.reload_config(config).await.unwrap();
+        assert_eq!(
+            ctl.lock().await.state.running().unwrap().servers["a"].hash(),
+            hash
+        );
+
+        stopper.stop().await.ok();
+        wait_listening(26681, false).await;
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        use rd_interface::IntoAddress;
+
+        let ctl = Controller::new();
+        let buf = access_log::tests::SharedBuf::default();
+        ctl.set_access_log(Some(AccessLog::new(buf.clone())));
+        let mut subscriber = ctl.get_subscriber().await;
+
+        let uuid = Uuid::new_v4();
+        let addr = "127.0.0.1:80".into_address().unwrap();
+        for event_type in [
+            EventType::NewTcp(addr.into()),
+            EventType::Outbound(5),
+            EventType::Inbound(7),
+            EventType::CloseConnection,
+        ] {
+            ctl.event_sender.send(Event::new(uuid, event_type)).unwrap();
+        }
+        let mut received = 0;
+        while received < 4 {
+            received += subscriber.recv().await.unwrap().len();
+        }
+
+        let lines = buf.wait_lines(1).await;
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["uuid"], uuid.to_string());
+        assert_eq!(lines[0]["destination"], "127.0.0.1:80");
+        assert_eq!(lines[0]["inbound"], 7);
+        assert_eq!(lines[0]["outbound"], 5);
+    }
+
+    #[tokio::test]
+    async fn test_list_connections() {
+        use rd_interface::IntoAddress;
+
+        let ctl = Controller::new();
+        let wait_for = |len: usize| {
+            let ctl = ctl.clone();
+            async move {
+                for _ in 0..50 {
+                    let list = ctl.list_connections();
+                    if list.len() == len {
+                        return list;
+                    }
+                    sleep(Duration::from_millis(10)).await;
+                }
+                panic!("expected {} connections", len);
+            }
+        };
+
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let addr = "127.0.0.1:80".into_address().unwrap();
+        ctl.event_sender
+            .send(Event::new(a, EventType::NewTcp(addr.into())))
+            .unwrap();
+        ctl.event_sender
+            .send(Event::new(
+                b,
+                EventType::NewUdp("0.0.0.0:0".into_address().unwrap()),
+            ))
+            .unwrap();
+        let list = wait_for(2).await;
+        let find = |uuid| list.iter().find(|c| c.uuid == uuid).unwrap();
+        assert_eq!(find(a).protocol, "tcp");
+        assert_eq!(find(a).destination.to_string(), "127.0.0.1:80");
+        assert_eq!(find(b).protocol, "udp");
+
+        ctl.event_sender
+            .send(Event::new(a, EventType::CloseConnection))
+            .unwrap();
+        let list = wait_for(1).await;
+        assert_eq!(list[0].uuid, b);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        use rd_interface::IntoAddress;
+
+        let ctl = Controller::new();
+        let snapshot = ctl.snapshot().await;
+        assert_eq!(snapshot.state, "Idle");
+        assert!(snapshot.config.is_none());
+
+        let stopper = ctl.start(socks5_config(&[("a", 26682)])).await;
+        wait_listening(26682, true).await;
+        let uuid = Uuid::new_v4();
+        let addr = "127.0.0.1:80".into_address().unwrap();
+        ctl.event_sender
+            .send(Event::new(uuid, EventType::NewTcp(addr.into())))
+            .unwrap();
+        ctl.event_sender
+            .send(Event::new(uuid, EventType::Outbound(5)))
+            .unwrap();
+
+        let mut snapshot = ctl.snapshot().await;
+        for _ in 0..50 {
+            if snapshot.connections.iter().any(|c| c.outbound == 5) {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+            snapshot = ctl.snapshot().await;
+        }
+        assert_eq!(snapshot.state, "Running");
+        assert_eq!(
+            snapshot.servers["a"],
+            Some("127.0.0.1:26682".parse().unwrap())
+        );
+        assert_eq!(snapshot.connections.len(), 1);
+        assert_eq!(snapshot.connections[0].info.uuid, uuid);
+        assert_eq!(snapshot.connections[0].outbound, 5);
+        assert_eq!(snapshot.nets["unknown"].active, 1);
+        assert_eq!(snapshot.nets["unknown"].outbound, 5);
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["config"]["server"]["a"]["type"], "socks5");
+        assert_eq!(json["connections"][0]["destination"], "127.0.0.1:80");
+        assert_eq!(json["connections"][0]["outbound"], 5);
+
+        stopper.stop().await.ok();
+        wait_listening(26682, false).await;
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_for() {
+        let ctl = Controller::new();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut events = Box::pin(ctl.get_subscriber_for(a).await);
+        let send = |uuid, size| {
+            ctl.event_sender
+                .send(Event::new(uuid, EventType::Outbound(size)))
+                .unwrap()
+        };
+
+        send(a, 1);
+        send(b, 2);
+        send(a, 3);
+        let batch = timeout(Duration::from_secs(1), events.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let sizes: Vec<_> = batch
+            .iter()
+            .map(|e| match e.event_type {
+                EventType::Outbound(s) if e.uuid == a => s,
+                _ => panic!("unexpected event {:?}", e),
+            })
+            .collect();
+        assert_eq!(sizes, [1, 3]);
+
+        // a batch of `b` alone is skipped
+        send(b, 4);
+        sleep(Duration::from_millis(300)).await;
+        send(a, 5);
+        let batch = timeout(Duration::from_secs(1), events.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.len(), 1);
+        assert!(matches!(batch[0].event_type, EventType::Outbound(5)));
+    }
+}
@@ -0,0 +1,316 @@
+//! An access log of the connections, a JSON line each once it's closed.
+
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::event::{Event, EventType};
+use rd_interface::Address;
+use serde_derive::Serialize;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// A line of the log.
+#[derive(Debug, Serialize)]
+struct Record {
+    uuid: Uuid,
+    /// When the connection was opened, in milliseconds since the epoch.
+    time: u64,
+    /// `tcp` or `udp`.
+    protocol: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<SocketAddr>,
+    /// For UDP, where the first datagram was sent, or the bound address if
+    /// none was.
+    destination: Address,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    net: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule: Option<String>,
+    inbound: u64,
+    outbound: u64,
+    /// How long the connection was open, in milliseconds.
+    duration: u64,
+}
+
+struct Open {
+    record: Record,
+    opened: SystemTime,
+    /// Whether a datagram was sent yet, for UDP.
+    sent: bool,
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Writes the lines to `sink` until the sender is dropped, so the events
+/// aren't held up by a slow disk.
+fn write_lines(mut sink: impl Write, mut rx: mpsc::UnboundedReceiver<Vec<u8>>) {
+    while let Some(line) = rx.blocking_recv() {
+        let mut result = sink.write_all(&line);
+        // flush once for what's queued together
+        while result.is_ok() {
+            match rx.try_recv() {
+                Ok(line) => result = sink.write_all(&line),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = result.and_then(|_| sink.flush()) {
+            tracing::warn!("Failed to write the access log: {:?}", e);
+        }
+    }
+}
+
+/// Writes a record of each connection to `sink` when it's closed, from the
+/// events of the controller. The writes are done on a thread of its own,
+/// which exits when the log is dropped.
+pub struct AccessLog {
+    lines: mpsc::UnboundedSender<Vec<u8>>,
+    open: HashMap<Uuid, Open>,
+}
+
+impl AccessLog {
+    pub fn new(sink: impl Write + Send + 'static) -> AccessLog {
+        let (lines, rx) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_lines(sink, rx))
+            .expect("failed to spawn the access log thread");
+        AccessLog {
+            lines,
+            open: HashMap::new(),
+        }
+    }
+
+    /// Appends to the file at `path`, or writes to stdout if it's `-`.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<AccessLog> {
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            return Ok(AccessLog::new(io::stdout()));
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog::new(file))
+    }
+
+    pub fn update(&mut self, event: &Event) {
+        let (protocol, destination, source, net, rule) = match &event.event_type {
+            EventType::NewTcp(info) => (
+                "tcp",
+                info.addr.clone(),
+                info.source,
+                info.net.clone(),
+                info.rule.clone(),
+            ),
+            EventType::NewUdp(addr) => ("udp", addr.clone(), None, None, None),
+            EventType::Inbound(size) | EventType::UdpInbound(_, size) => {
+                if let Some(open) = self.open.get_mut(&event.uuid) {
+                    open.record.inbound += *size as u64;
+                }
+                return;
+            }
+            EventType::Outbound(size) => {
+                if let Some(open) = self.open.get_mut(&event.uuid) {
+                    open.record.outbound += *size as u64;
+                }
+                return;
+            }
+            EventType::UdpOutbound(addr, size) => {
+                if let Some(open) = self.open.get_mut(&event.uuid) {
+                    if !open.sent {
+                        open.record.destination = addr.clone();
+                        open.sent = true;
+                    }
+                    open.record.outbound += *size as u64;
+                }
+                return;
+            }
+            EventType::CloseConnection => {
+                if let Some(open) = self.open.remove(&event.uuid) {
+                    self.write(open, event.time);
+                }
+                return;
+            }
+            EventType::ConfigChanged(_) => return,
+        };
+        self.open.insert(
+            event.uuid,
+            Open {
+                record: Record {
+                    uuid: event.uuid,
+                    time: millis(event.time),
+                    protocol,
+                    source,
+                    destination,
+                    net,
+                    rule,
+                    inbound: 0,
+                    outbound: 0,
+                    duration: 0,
+                },
+                opened: event.time,
+                sent: false,
+            },
+        );
+    }
+
+    fn write(&mut self, open: Open, closed: SystemTime) {
+        let mut record = open.record;
+        record.duration = closed
+            .duration_since(open.opened)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let mut line = serde_json::to_vec(&record).expect("record is serializable");
+        line.push(b'\n');
+        // the writer only stops with the sender
+        self.lines.send(line).ok();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::controller::TcpInfo;
+    use rd_interface::IntoAddress;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    /// A sink the test can read back.
+    #[derive(Clone, Default)]
+    pub struct SharedBuf(pub Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        pub fn lines(&self) -> Vec<serde_json::Value> {
+            let buf = self.0.lock().unwrap();
+            String::from_utf8(buf.clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+
+        /// Waits for the writer to write `len` lines.
+        pub async fn wait_lines(&self, len: usize) -> Vec<serde_json::Value> {
+            for _ in 0..50 {
+                let lines = self.lines();
+                if lines.len() >= len {
+                    return lines;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("expected {} lines", len);
+        }
+    }
+
+    fn at(event: Event, millis: u64) -> Event {
+        Event {
+            time: UNIX_EPOCH + Duration::from_millis(millis),
+            ..event
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let buf = SharedBuf::default();
+        let mut log = AccessLog::new(buf.clone());
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let addr = "example.com:443".into_address().unwrap();
+        let mut info: TcpInfo = addr.clone().into();
+        info.source = Some("127.0.0.1:50000".parse().unwrap());
+        info.net = Some("proxy".to_string());
+        info.rule = Some("domain".to_string());
+        let events = vec![
+            at(Event::new(a, EventType::NewTcp(info)), 1000),
+            at(
+                Event::new(b, EventType::NewUdp("0.0.0.0:0".into_address().unwrap())),
+                1100,
+            ),
+            at(Event::new(a, EventType::Outbound(100)), 1200),
+            at(Event::new(a, EventType::Inbound(1000)), 1300),
+            at(
+                Event::new(
+                    b,
+                    EventType::UdpOutbound("8.8.8.8:53".into_address().unwrap(), 30),
+                ),
+                1400,
+            ),
+            at(
+                Event::new(
+                    b,
+                    EventType::UdpOutbound("1.1.1.1:53".into_address().unwrap(), 30),
+                ),
+                1400,
+            ),
+            at(Event::new(a, EventType::Inbound(24)), 1500),
+            at(Event::new(a, EventType::CloseConnection), 3500),
+        ];
+        for e in &events {
+            log.update(e);
+        }
+
+        // b is still open
+        let lines = buf.wait_lines(1).await;
+        assert_eq!(lines.len(), 1);
+        assert_eq!(
+            lines[0],
+            serde_json::json!({
+                "uuid": a.to_string(),
+                "time": 1000,
+                "protocol": "tcp",
+                "source": "127.0.0.1:50000",
+                "destination": "example.com:443",
+                "net": "proxy",
+                "rule": "domain",
+                "inbound": 1024,
+                "outbound": 100,
+                "duration": 2500,
+            })
+        );
+
+        log.update(&at(Event::new(b, EventType::CloseConnection), 1600));
+        let lines = buf.wait_lines(2).await;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[1],
+            serde_json::json!({
+                "uuid": b.to_string(),
+                "time": 1100,
+                "protocol": "udp",
+                "destination": "8.8.8.8:53",
+                "inbound": 0,
+                "outbound": 60,
+                "duration": 500,
+            })
+        );
+
+        // closed twice, or never opened, the next line is c's
+        let c = Uuid::new_v4();
+        log.update(&Event::new(a, EventType::CloseConnection));
+        log.update(&Event::new(c, EventType::NewTcp(addr.clone().into())));
+        log.update(&Event::new(c, EventType::CloseConnection));
+        let lines = buf.wait_lines(3).await;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2]["uuid"], c.to_string());
+    }
+}
@@ -1,26 +1,111 @@
-use std::time::SystemTime;
+use std::{net::SocketAddr, time::SystemTime};
 
+use futures::{stream, Stream};
 use rd_interface::{Address, Arc};
-use serde::ser::Serializer;
+use serde::ser::{Serialize, Serializer};
 use serde_derive::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
 use uuid::Uuid;
 
+/// A new TCP connection and how it's routed.
+#[derive(Debug, Serialize)]
+pub struct TcpInfo {
+    /// Serialized as the payload of `NewTcp`, the other fields go next to
+    /// `event_type`.
+    #[serde(skip_serializing)]
+    pub addr: Address,
+    /// Where the connection came from, if the server told.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<SocketAddr>,
+    /// The rule that routed the connection, if it went through a rule net.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<String>,
+    /// The net chosen by `rule`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net: Option<String>,
+}
+
+impl From<Address> for TcpInfo {
+    fn from(addr: Address) -> Self {
+        TcpInfo {
+            addr,
+            source: None,
+            rule: None,
+            net: None,
+        }
+    }
+}
+
+/// An open connection, as listed by the controller.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub uuid: Uuid,
+    /// `tcp` or `udp`.
+    pub protocol: &'static str,
+    /// For UDP, the bound address.
+    pub destination: Address,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<SocketAddr>,
+    /// The net chosen by the rule net, if it went through one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net: Option<String>,
+    #[serde(serialize_with = "serialize_system_time")]
+    pub start: SystemTime,
+}
+
 #[derive(Debug, Serialize)]
 pub enum EventType {
-    NewTcp(Address),
+    NewTcp(#[serde(serialize_with = "serialize_tcp_addr")] TcpInfo),
     CloseConnection,
     Outbound(usize),
     Inbound(usize),
+    NewUdp(Address),
+    /// A datagram of `usize` bytes sent to `Address`.
+    UdpOutbound(Address, usize),
+    /// A datagram of `usize` bytes received from `Address`.
+    UdpInbound(Address, usize),
+    /// The config was reloaded, restarting the named servers.
+    ConfigChanged(Vec<String>),
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug)]
 pub struct Event {
     pub uuid: Uuid,
     pub event_type: EventType,
-    #[serde(serialize_with = "serialize_system_time")]
     pub time: SystemTime,
 }
 
+/// How an [`Event`] is serialized. The fields of a `NewTcp` besides its
+/// address are flattened into the event, so the payload stays a string.
+#[derive(Serialize)]
+struct EventRepr<'a> {
+    uuid: &'a Uuid,
+    event_type: &'a EventType,
+    #[serde(serialize_with = "serialize_system_time")]
+    time: SystemTime,
+    #[serde(flatten)]
+    tcp: Option<&'a TcpInfo>,
+}
+
+impl Serialize for Event {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        EventRepr {
+            uuid: &self.uuid,
+            event_type: &self.event_type,
+            time: self.time,
+            tcp: match &self.event_type {
+                EventType::NewTcp(info) => Some(info),
+                _ => None,
+            },
+        }
+        .serialize(serializer)
+    }
+}
+
+fn serialize_tcp_addr<S: Serializer>(info: &TcpInfo, serializer: S) -> Result<S::Ok, S::Error> {
+    info.addr.serialize(serializer)
+}
+
 fn serialize_system_time<S>(system_time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -40,3 +125,26 @@ impl Event {
         }
     }
 }
+
+/// Yields the events of `uuid` only. Batches without them are skipped.
+pub fn filter_by_uuid(
+    rx: broadcast::Receiver<BatchEvent>,
+    uuid: Uuid,
+) -> impl Stream<Item = BatchEvent> {
+    stream::unfold(rx, move |mut rx| async move {
+        loop {
+            let events = match rx.recv().await {
+                Ok(events) => events,
+                Err(RecvError::Lagged(n)) => {
+                    tracing::warn!("Event subscriber lagged, {} batches skipped", n);
+                    continue;
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            let events: BatchEvent = events.into_iter().filter(|e| e.uuid == uuid).collect();
+            if !events.is_empty() {
+                return Some((events, rx));
+            }
+        }
+    })
+}
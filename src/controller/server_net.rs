@@ -1,21 +1,173 @@
 use std::{
+    collections::HashMap,
+    future::Future,
     io,
-    net::SocketAddr,
+    net::{Ipv4Addr, SocketAddr},
     pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
-use super::event::{Event, EventType};
+use super::event::{Event, EventType, TcpInfo};
+use crate::config::{ConnectionLimit, Exceed};
+use bytes::BytesMut;
+use futures::task::AtomicWaker;
 use rd_interface::{
-    async_trait, context::common_field, Address, AsyncRead, AsyncWrite, INet, IntoDyn, Net,
-    ReadBuf, TcpListener, UdpSocket,
+    async_trait, context::common_field, Address, AsyncRead, AsyncWrite, INet, IntoDyn, Net, ReadBuf,
 };
-use tokio::sync::mpsc;
+use tokio::{
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    time::{sleep_until, Sleep},
+};
+use tracing::Instrument;
 use uuid::Uuid;
 
 pub struct ControllerServerNet {
     pub net: Net,
     pub sender: mpsc::UnboundedSender<Event>,
+    pub killers: Killers,
+    pub limiter: ConnectionLimiter,
+}
+
+/// Makes a stream fail once killed, waking its pending reads and writes.
+#[derive(Default)]
+pub struct KillHandle {
+    killed: AtomicBool,
+    read: AtomicWaker,
+    write: AtomicWaker,
+}
+
+impl KillHandle {
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::SeqCst);
+        self.read.wake();
+        self.write.wake();
+    }
+    fn check(&self, waker: &AtomicWaker, cx: &mut Context<'_>) -> io::Result<()> {
+        waker.register(cx.waker());
+        if self.killed.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "killed by the controller",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The kill handles of the open streams, by uuid.
+#[derive(Clone, Default)]
+pub struct Killers(Arc<Mutex<HashMap<Uuid, Arc<KillHandle>>>>);
+
+impl Killers {
+    /// Kills the stream of `uuid`. Returns false if there is none.
+    pub fn kill(&self, uuid: &Uuid) -> bool {
+        match self.0.lock().unwrap().remove(uuid) {
+            Some(handle) => {
+                handle.kill();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A cap and the permits left under it.
+struct Limit {
+    limit: ConnectionLimit,
+    semaphore: Arc<Semaphore>,
+    /// The permits to forget once released, when the cap went below the
+    /// connections open.
+    owed: Arc<Mutex<usize>>,
+}
+
+impl Limit {
+    fn new(limit: ConnectionLimit) -> Limit {
+        Limit {
+            limit,
+            semaphore: Arc::new(Semaphore::new(limit.max)),
+            owed: Default::default(),
+        }
+    }
+    fn resize(&mut self, max: usize) {
+        let old = self.limit.max;
+        let mut owed = self.owed.lock().unwrap();
+        if max > old {
+            let paid = (*owed).min(max - old);
+            *owed -= paid;
+            self.semaphore.add_permits(max - old - paid);
+        } else {
+            let forgotten = self.semaphore.forget_permits(old - max);
+            *owed += old - max - forgotten;
+        }
+    }
+}
+
+/// The place of an open connection under the cap.
+pub struct Permit {
+    permit: Option<OwnedSemaphorePermit>,
+    owed: Arc<Mutex<usize>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut owed = self.owed.lock().unwrap();
+        if *owed > 0 {
+            *owed -= 1;
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// The cap on the open TCP connections, shared by the server nets of a
+/// controller. Each stream holds a permit until it's closed.
+#[derive(Clone, Default)]
+pub struct ConnectionLimiter(Arc<Mutex<Option<Limit>>>);
+
+impl ConnectionLimiter {
+    /// Sets the cap, or removes it if it's `None`. A changed cap still
+    /// counts the connections already open, the ones over it are left
+    /// open. `limit` must be validated.
+    pub fn set(&self, limit: Option<ConnectionLimit>) {
+        let mut current = self.0.lock().unwrap();
+        match (current.as_mut(), limit) {
+            (Some(current), Some(limit)) => {
+                current.resize(limit.max);
+                current.limit = limit;
+            }
+            (_, limit) => *current = limit.map(Limit::new),
+        }
+    }
+    /// Takes a permit for a new connection. `None` if there is no cap.
+    pub async fn acquire(&self) -> io::Result<Option<Permit>> {
+        let (limit, semaphore, owed) = match &*self.0.lock().unwrap() {
+            Some(l) => (l.limit, l.semaphore.clone(), l.owed.clone()),
+            None => return Ok(None),
+        };
+        let permit = match limit.on_exceed {
+            Exceed::Reject => semaphore.try_acquire_owned().ok(),
+            Exceed::Delay => semaphore.acquire_owned().await.ok(),
+        };
+        match permit {
+            Some(permit) => Ok(Some(Permit {
+                permit: Some(permit),
+                owed,
+            })),
+            None => {
+                tracing::debug!("{} connections open, rejected", limit.max);
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("too many connections, at most {}", limit.max),
+                ))
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -25,35 +177,244 @@ impl INet for ControllerServerNet {
         ctx: &mut rd_interface::Context,
         addr: Address,
     ) -> rd_interface::Result<rd_interface::TcpStream> {
-        let tcp = self.net.tcp_connect(ctx, addr.clone()).await?;
-        let src = ctx
-            .get_common::<common_field::SourceAddress>()
-            .map(|s| s.addr.to_string())
-            .unwrap_or_default();
+        // the destinations of the servers are chosen by their clients
+        if let Address::Unix(_) = addr {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "servers don't connect to Unix sockets",
+            )
+            .into());
+        }
+        let permit = self.limiter.acquire().await?;
+        let uuid = Uuid::new_v4();
+        let span = tracing::info_span!("tcp", uuid = %uuid, addr = %addr);
+        let tcp = self
+            .net
+            .tcp_connect(ctx, addr.clone())
+            .instrument(span.clone())
+            .await?;
+        let source = ctx.source_addr();
+        let src = source.map(|addr| addr.to_string()).unwrap_or_default();
 
-        tracing::info!("{:?} {} -> {}", &ctx.net_list(), &src, &addr,);
+        span.in_scope(|| tracing::info!("{:?} {} -> {}", &ctx.net_list(), &src, &addr));
 
-        let tcp = TcpStream::new(tcp, self.sender.clone());
-        tcp.send(EventType::NewTcp(addr));
+        let info = TcpInfo {
+            addr,
+            source,
+            rule: ctx.matched_rule().map(ToString::to_string),
+            net: ctx
+                .get_common::<common_field::RuleTarget>()
+                .ok()
+                .map(|t| t.net),
+        };
+
+        let mut tcp = TcpStream::new(tcp, self.sender.clone(), uuid);
+        tcp.register(&self.killers);
+        tcp.permit = permit;
+        tcp.send(EventType::NewTcp(info));
         Ok(tcp.into_dyn())
     }
 
-    // TODO: wrap TcpListener
     async fn tcp_bind(
         &self,
         ctx: &mut rd_interface::Context,
         addr: Address,
-    ) -> rd_interface::Result<TcpListener> {
-        self.net.tcp_bind(ctx, addr).await
+    ) -> rd_interface::Result<rd_interface::TcpListener> {
+        let listener = self.net.tcp_bind(ctx, addr).await?;
+        Ok(TcpListener {
+            inner: listener,
+            sender: self.sender.clone(),
+            killers: self.killers.clone(),
+            limiter: self.limiter.clone(),
+        }
+        .into_dyn())
     }
 
-    // TODO: wrap UdpSocket
     async fn udp_bind(
         &self,
         ctx: &mut rd_interface::Context,
         addr: Address,
-    ) -> rd_interface::Result<UdpSocket> {
-        self.net.udp_bind(ctx, addr).await
+    ) -> rd_interface::Result<rd_interface::UdpSocket> {
+        let uuid = Uuid::new_v4();
+        let udp = self
+            .net
+            .udp_bind(ctx, addr.clone())
+            .instrument(tracing::info_span!("udp", uuid = %uuid, addr = %addr))
+            .await?;
+        let udp = UdpSocket::new(udp, self.sender.clone(), uuid);
+        udp.send(EventType::NewUdp(addr));
+        Ok(udp.into_dyn())
+    }
+
+    async fn lookup_host(&self, addr: &Address) -> rd_interface::Result<Vec<SocketAddr>> {
+        self.net.lookup_host(addr).await
+    }
+}
+
+pub struct UdpSocket {
+    inner: rd_interface::UdpSocket,
+    sender: mpsc::UnboundedSender<Event>,
+    uuid: Uuid,
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.send(EventType::CloseConnection);
+    }
+}
+
+impl UdpSocket {
+    pub fn send(&self, event_type: EventType) {
+        if self.sender.send(Event::new(self.uuid, event_type)).is_err() {
+            tracing::warn!("Failed to send event");
+        }
+    }
+    pub fn new(
+        inner: rd_interface::UdpSocket,
+        sender: mpsc::UnboundedSender<Event>,
+        uuid: Uuid,
+    ) -> UdpSocket {
+        UdpSocket {
+            inner,
+            sender,
+            uuid,
+        }
+    }
+}
+
+#[async_trait]
+impl rd_interface::IUdpSocket for UdpSocket {
+    async fn recv_from(&self, buf: &mut [u8]) -> rd_interface::Result<(usize, SocketAddr)> {
+        let (size, addr) = self.inner.recv_from(buf).await?;
+        self.send(EventType::UdpInbound(addr.into(), size));
+        Ok((size, addr))
+    }
+
+    async fn recv_from_buf(&self, buf: &mut BytesMut) -> rd_interface::Result<(usize, SocketAddr)> {
+        let (size, addr) = self.inner.recv_from_buf(buf).await?;
+        self.send(EventType::UdpInbound(addr.into(), size));
+        Ok((size, addr))
+    }
+
+    async fn recv_batch(
+        &self,
+        buf: &mut BytesMut,
+        max: usize,
+    ) -> rd_interface::Result<Vec<(usize, SocketAddr)>> {
+        let datagrams = self.inner.recv_batch(buf, max).await?;
+        for (size, addr) in &datagrams {
+            self.send(EventType::UdpInbound((*addr).into(), *size));
+        }
+        Ok(datagrams)
+    }
+
+    async fn send_to(&self, buf: &[u8], addr: Address) -> rd_interface::Result<usize> {
+        let size = self.inner.send_to(buf, addr.clone()).await?;
+        self.send(EventType::UdpOutbound(addr, size));
+        Ok(size)
+    }
+
+    async fn local_addr(&self) -> rd_interface::Result<SocketAddr> {
+        self.inner.local_addr().await
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> rd_interface::Result<()> {
+        self.inner.connect(addr).await
+    }
+
+    async fn peer_addr(&self) -> rd_interface::Result<SocketAddr> {
+        self.inner.peer_addr().await
+    }
+}
+
+pub struct TcpListener {
+    inner: rd_interface::TcpListener,
+    sender: mpsc::UnboundedSender<Event>,
+    killers: Killers,
+    limiter: ConnectionLimiter,
+}
+
+#[async_trait]
+impl rd_interface::ITcpListener for TcpListener {
+    async fn accept(&self) -> rd_interface::Result<(rd_interface::TcpStream, SocketAddr)> {
+        loop {
+            let (tcp, addr) = self.inner.accept().await?;
+            // the rejected ones are closed right away
+            let permit = match self.limiter.acquire().await {
+                Ok(permit) => permit,
+                Err(_) => continue,
+            };
+            // the connection is to the listener, from the peer
+            let local = match tcp.local_addr().await {
+                Ok(local) => local,
+                Err(_) => self
+                    .inner
+                    .local_addr()
+                    .await
+                    .unwrap_or_else(|_| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)),
+            };
+            let mut tcp = TcpStream::new(tcp, self.sender.clone(), Uuid::new_v4());
+            tcp.register(&self.killers);
+            tcp.permit = permit;
+            tcp.send(EventType::NewTcp(TcpInfo {
+                addr: local.into(),
+                source: Some(addr),
+                rule: None,
+                net: None,
+            }));
+            return Ok((tcp.into_dyn(), addr));
+        }
+    }
+
+    async fn local_addr(&self) -> rd_interface::Result<SocketAddr> {
+        self.inner.local_addr().await
+    }
+}
+
+/// Traffic events are sent at most once per interval, unless this many
+/// bytes are pending.
+const TRAFFIC_EVENT_BYTES: usize = 64 * 1024;
+const TRAFFIC_EVENT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Batches the byte counts of a stream into few events.
+#[derive(Default)]
+struct Traffic {
+    pending: usize,
+    last_sent: Option<Instant>,
+}
+
+impl Traffic {
+    /// Adds `size` bytes, returning the bytes to report if it's time to.
+    fn add(&mut self, size: usize) -> Option<usize> {
+        self.pending += size;
+        let due = match self.last_sent {
+            Some(t) => t.elapsed() >= TRAFFIC_EVENT_INTERVAL,
+            None => true,
+        };
+        if due || self.pending >= TRAFFIC_EVENT_BYTES {
+            self.last_sent = Some(Instant::now());
+            self.take()
+        } else {
+            None
+        }
+    }
+    fn take(&mut self) -> Option<usize> {
+        match std::mem::take(&mut self.pending) {
+            0 => None,
+            s => Some(s),
+        }
+    }
+    /// When the pending bytes are due, if there are any.
+    fn deadline(&self) -> Option<Instant> {
+        match (self.pending, self.last_sent) {
+            (0, _) => None,
+            (_, Some(t)) => Some(t + TRAFFIC_EVENT_INTERVAL),
+            (_, None) => Some(Instant::now()),
+        }
+    }
+    fn flush(&mut self) -> Option<usize> {
+        self.last_sent = Some(Instant::now());
+        self.take()
     }
 }
 
@@ -61,10 +422,28 @@ pub struct TcpStream {
     inner: rd_interface::TcpStream,
     sender: mpsc::UnboundedSender<Event>,
     uuid: Uuid,
+    inbound: Traffic,
+    outbound: Traffic,
+    /// Sends the pending traffic when it's due, even if no more bytes come.
+    traffic_timer: Option<Pin<Box<Sleep>>>,
+    kill: Arc<KillHandle>,
+    /// Where `kill` is registered.
+    killers: Option<Killers>,
+    /// Released once the stream is closed.
+    permit: Option<Permit>,
 }
 
 impl Drop for TcpStream {
     fn drop(&mut self) {
+        if let Some(killers) = &self.killers {
+            killers.0.lock().unwrap().remove(&self.uuid);
+        }
+        if let Some(s) = self.inbound.take() {
+            self.send(EventType::Inbound(s));
+        }
+        if let Some(s) = self.outbound.take() {
+            self.send(EventType::Outbound(s));
+        }
         self.send(EventType::CloseConnection);
     }
 }
@@ -75,12 +454,53 @@ impl TcpStream {
             tracing::warn!("Failed to send event");
         }
     }
-    pub fn new(inner: rd_interface::TcpStream, sender: mpsc::UnboundedSender<Event>) -> TcpStream {
-        let uuid = Uuid::new_v4();
+    pub fn new(
+        inner: rd_interface::TcpStream,
+        sender: mpsc::UnboundedSender<Event>,
+        uuid: Uuid,
+    ) -> TcpStream {
         TcpStream {
             inner,
             sender,
             uuid,
+            inbound: Traffic::default(),
+            outbound: Traffic::default(),
+            traffic_timer: None,
+            kill: Default::default(),
+            killers: None,
+            permit: None,
+        }
+    }
+    /// Lets the stream be killed through `killers`.
+    pub fn register(&mut self, killers: &Killers) {
+        killers
+            .0
+            .lock()
+            .unwrap()
+            .insert(self.uuid, self.kill.clone());
+        self.killers = Some(killers.clone());
+    }
+    /// Sends the pending traffic once it's due, waking `cx` then.
+    fn poll_traffic(&mut self, cx: &mut Context<'_>) {
+        loop {
+            if let Some(timer) = &mut self.traffic_timer {
+                if timer.as_mut().poll(cx).is_pending() {
+                    return;
+                }
+                self.traffic_timer = None;
+                if let Some(s) = self.inbound.flush() {
+                    self.send(EventType::Inbound(s));
+                }
+                if let Some(s) = self.outbound.flush() {
+                    self.send(EventType::Outbound(s));
+                }
+            }
+            let deadline = match (self.inbound.deadline(), self.outbound.deadline()) {
+                (Some(a), Some(b)) => a.min(b),
+                (Some(d), None) | (None, Some(d)) => d,
+                (None, None) => return,
+            };
+            self.traffic_timer = Some(Box::pin(sleep_until(deadline.into())));
         }
     }
 }
@@ -91,15 +511,17 @@ impl AsyncRead for TcpStream {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf,
     ) -> Poll<io::Result<()>> {
+        self.kill.check(&self.kill.read, cx)?;
         let before = buf.filled().len();
-        match Pin::new(&mut self.inner).poll_read(cx, buf) {
-            Poll::Ready(Ok(())) => {
-                let s = buf.filled().len() - before;
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = r {
+            let s = buf.filled().len() - before;
+            if let Some(s) = self.inbound.add(s) {
                 self.send(EventType::Inbound(s));
-                Ok(()).into()
             }
-            r => r,
         }
+        self.poll_traffic(cx);
+        r
     }
 }
 impl AsyncWrite for TcpStream {
@@ -108,16 +530,19 @@ impl AsyncWrite for TcpStream {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        match Pin::new(&mut self.inner).poll_write(cx, buf) {
-            Poll::Ready(Ok(s)) => {
+        self.kill.check(&self.kill.write, cx)?;
+        let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(s)) = r {
+            if let Some(s) = self.outbound.add(s) {
                 self.send(EventType::Outbound(s));
-                Ok(s).into()
             }
-            r => r,
         }
+        self.poll_traffic(cx);
+        r
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.kill.check(&self.kill.write, cx)?;
         Pin::new(&mut self.inner).poll_flush(cx)
     }
 
@@ -136,3 +561,664 @@ impl rd_interface::ITcpStream for TcpStream {
         self.inner.local_addr().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rd_interface::{impl_async_read_write, IntoAddress, NOT_IMPLEMENTED};
+    use tokio::{
+        io::{duplex, AsyncWriteExt, DuplexStream},
+        time::sleep,
+    };
+
+    const PEER: &str = "10.0.0.1:12345";
+    const LOCAL: &str = "10.0.0.2:80";
+
+    struct MockTcp(DuplexStream);
+    impl_async_read_write!(MockTcp, 0);
+
+    #[async_trait]
+    impl rd_interface::ITcpStream for MockTcp {
+        async fn peer_addr(&self) -> rd_interface::Result<SocketAddr> {
+            Ok(PEER.parse().unwrap())
+        }
+        async fn local_addr(&self) -> rd_interface::Result<SocketAddr> {
+            Ok(LOCAL.parse().unwrap())
+        }
+    }
+
+    /// Keeps the other end of each accepted stream open.
+    struct MockListener(std::sync::Mutex<Vec<DuplexStream>>);
+
+    #[async_trait]
+    impl rd_interface::ITcpListener for MockListener {
+        async fn accept(&self) -> rd_interface::Result<(rd_interface::TcpStream, SocketAddr)> {
+            let (a, b) = duplex(1024);
+            self.0.lock().unwrap().push(b);
+            Ok((MockTcp(a).into_dyn(), PEER.parse().unwrap()))
+        }
+        async fn local_addr(&self) -> rd_interface::Result<SocketAddr> {
+            Err(NOT_IMPLEMENTED)
+        }
+    }
+
+    struct MockNet;
+
+    #[async_trait]
+    impl INet for MockNet {
+        async fn tcp_connect(
+            &self,
+            _ctx: &mut rd_interface::Context,
+            _addr: Address,
+        ) -> rd_interface::Result<rd_interface::TcpStream> {
+            let (a, _) = duplex(1024);
+            Ok(MockTcp(a).into_dyn())
+        }
+        async fn tcp_bind(
+            &self,
+            _ctx: &mut rd_interface::Context,
+            _addr: Address,
+        ) -> rd_interface::Result<rd_interface::TcpListener> {
+            Ok(MockListener(Default::default()).into_dyn())
+        }
+        async fn udp_bind(
+            &self,
+            _ctx: &mut rd_interface::Context,
+            addr: Address,
+        ) -> rd_interface::Result<rd_interface::UdpSocket> {
+            let udp = tokio::net::UdpSocket::bind(addr.to_socket_addr()?).await?;
+            Ok(MockUdp(udp).into_dyn())
+        }
+        async fn lookup_host(&self, addr: &Address) -> rd_interface::Result<Vec<SocketAddr>> {
+            Ok(vec![SocketAddr::new(
+                [192, 0, 2, 1].into(),
+                addr.port().unwrap(),
+            )])
+        }
+    }
+
+    struct MockUdp(tokio::net::UdpSocket);
+
+    #[async_trait]
+    impl rd_interface::IUdpSocket for MockUdp {
+        async fn recv_from(&self, buf: &mut [u8]) -> rd_interface::Result<(usize, SocketAddr)> {
+            Ok(self.0.recv_from(buf).await?)
+        }
+        async fn send_to(&self, buf: &[u8], addr: Address) -> rd_interface::Result<usize> {
+            Ok(self.0.send_to(buf, addr.to_socket_addr()?).await?)
+        }
+        async fn local_addr(&self) -> rd_interface::Result<SocketAddr> {
+            Ok(self.0.local_addr()?)
+        }
+        async fn recv_batch(
+            &self,
+            buf: &mut BytesMut,
+            max: usize,
+        ) -> rd_interface::Result<Vec<(usize, SocketAddr)>> {
+            let mut datagrams = vec![self.recv_from_buf(buf).await?];
+            let mut datagram = [0u8; 1024];
+            while datagrams.len() < max {
+                match self.0.try_recv_from(&mut datagram) {
+                    Ok((size, addr)) => {
+                        buf.extend_from_slice(&datagram[..size]);
+                        datagrams.push((size, addr));
+                    }
+                    Err(_) => break,
+                }
+            }
+            Ok(datagrams)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_event() {
+        let (sender, mut rx) = mpsc::unbounded_channel();
+        let net = ControllerServerNet {
+            net: MockNet.into_dyn(),
+            sender,
+            killers: Default::default(),
+            limiter: Default::default(),
+        };
+        let listener = net
+            .tcp_bind(
+                &mut rd_interface::Context::new(),
+                "0.0.0.0:0".into_address().unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let (mut a, addr) = listener.accept().await.unwrap();
+        assert_eq!(addr, PEER.parse().unwrap());
+        let (mut b, _) = listener.accept().await.unwrap();
+        a.write_all(b"hello").await.unwrap();
+        drop(a);
+        b.write_all(b"world!").await.unwrap();
+
+        let events = std::iter::from_fn(|| rx.try_recv().ok()).collect::<Vec<_>>();
+        let uuid_a = events[0].uuid;
+        let uuid_b = events[1].uuid;
+        assert_ne!(uuid_a, uuid_b);
+        match &events[0].event_type {
+            EventType::NewTcp(TcpInfo {
+                addr: Address::SocketAddr(addr),
+                source,
+                ..
+            }) => {
+                assert_eq!(addr, &LOCAL.parse().unwrap());
+                assert_eq!(source, &Some(PEER.parse().unwrap()));
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert!(matches!(events[1].event_type, EventType::NewTcp(_)));
+        assert!(matches!(events[2].event_type, EventType::Outbound(5)));
+        assert_eq!(events[2].uuid, uuid_a);
+        assert!(matches!(events[3].event_type, EventType::CloseConnection));
+        assert_eq!(events[3].uuid, uuid_a);
+        assert!(matches!(events[4].event_type, EventType::Outbound(6)));
+        assert_eq!(events[4].uuid, uuid_b);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_host() {
+        let (sender, _rx) = mpsc::unbounded_channel();
+        let net = ControllerServerNet {
+            net: MockNet.into_dyn(),
+            sender,
+            killers: Default::default(),
+            limiter: Default::default(),
+        };
+        let addrs = net
+            .lookup_host(&"example.com:443".into_address().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec!["192.0.2.1:443".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_unix_destination() {
+        let (sender, mut rx) = mpsc::unbounded_channel();
+        let net = ControllerServerNet {
+            net: MockNet.into_dyn(),
+            sender,
+            killers: Default::default(),
+            limiter: Default::default(),
+        };
+        let addr = Address::Unix("/run/docker.sock".into());
+        match net
+            .tcp_connect(&mut rd_interface::Context::new(), addr)
+            .await
+        {
+            Err(rd_interface::Error::IO(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::PermissionDenied)
+            }
+            _ => panic!("connected to a Unix socket"),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_udp_events() {
+        let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (size, addr) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..size], addr).await.unwrap();
+        });
+
+        let (sender, mut rx) = mpsc::unbounded_channel();
+        let net = ControllerServerNet {
+            net: MockNet.into_dyn(),
+            sender,
+            killers: Default::default(),
+            limiter: Default::default(),
+        };
+        let bind_addr = "127.0.0.1:0".into_address().unwrap();
+        let udp = net
+            .udp_bind(&mut rd_interface::Context::new(), bind_addr.clone())
+            .await
+            .unwrap();
+
+        udp.send_to(b"hello", echo_addr.into()).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let (size, addr) = udp.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"hello");
+        assert_eq!(addr, echo_addr);
+        drop(udp);
+
+        let events = std::iter::from_fn(|| rx.try_recv().ok()).collect::<Vec<_>>();
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|e| e.uuid == events[0].uuid));
+        assert!(matches!(&events[0].event_type, EventType::NewUdp(a) if a == &bind_addr));
+        assert!(matches!(
+            &events[1].event_type,
+            EventType::UdpOutbound(Address::SocketAddr(a), 5) if a == &echo_addr
+        ));
+        assert!(matches!(
+            &events[2].event_type,
+            EventType::UdpInbound(Address::SocketAddr(a), 5) if a == &echo_addr
+        ));
+        assert!(matches!(events[3].event_type, EventType::CloseConnection));
+    }
+
+    #[tokio::test]
+    async fn test_udp_recv_batch() {
+        let (sender, mut rx) = mpsc::unbounded_channel();
+        let net = ControllerServerNet {
+            net: MockNet.into_dyn(),
+            sender,
+            killers: Default::default(),
+            limiter: Default::default(),
+        };
+        let udp = net
+            .udp_bind(
+                &mut rd_interface::Context::new(),
+                "127.0.0.1:0".into_address().unwrap(),
+            )
+            .await
+            .unwrap();
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let udp_addr = udp.local_addr().await.unwrap();
+        peer.send_to(b"hello", udp_addr).await.unwrap();
+        peer.send_to(b"world!", udp_addr).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+
+        // the inner socket's batch is kept
+        let mut buf = BytesMut::new();
+        let datagrams = udp.recv_batch(&mut buf, 8).await.unwrap();
+        assert_eq!(datagrams, vec![(5, peer_addr), (6, peer_addr)]);
+        assert_eq!(&buf[..], b"helloworld!");
+
+        let sizes = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|e| match e.event_type {
+                EventType::UdpInbound(_, size) => Some(size),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_rule_matched_event() {
+        let mut registry = crate::Registry::new();
+        crate::builtin::load_builtin(&mut registry).unwrap();
+        let mut nets = rd_interface::registry::NetMap::new();
+        nets.insert("mock".to_string(), MockNet.into_dyn());
+        let rule = registry
+            .get_net("rule")
+            .unwrap()
+            .build(
+                &nets,
+                serde_json::json!({
+                    "rule": [
+                        { "type": "domain", "method": "suffix", "domain": "example.org", "target": "mock" },
+                        { "type": "any", "target": "mock" },
+                    ]
+                }),
+            )
+            .unwrap();
+
+        let (sender, mut rx) = mpsc::unbounded_channel();
+        let net = ControllerServerNet {
+            net: rule,
+            sender,
+            killers: Default::default(),
+            limiter: Default::default(),
+        };
+        let _tcp = net
+            .tcp_connect(
+                &mut rd_interface::Context::new(),
+                "example.com:80".into_address().unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let event = rx.try_recv().unwrap();
+        match &event.event_type {
+            EventType::NewTcp(info) => {
+                assert_eq!(info.rule.as_deref(), Some("#1 any"));
+                assert_eq!(info.net.as_deref(), Some("mock"));
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        // the payload is still the address, the rest is next to it
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event_type"]["NewTcp"], "example.com:80");
+        assert_eq!(json["rule"], "#1 any");
+        assert_eq!(json["net"], "mock");
+        assert!(json.get("source").is_none());
+
+        let close = Event::new(event.uuid, EventType::CloseConnection);
+        let close = serde_json::to_value(&close).unwrap();
+        assert_eq!(close["event_type"], "CloseConnection");
+        assert!(close.get("net").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_close_event() {
+        let controller = crate::controller::Controller::new();
+        let mut subscriber = controller.get_subscriber().await;
+        let net = controller.get_server_net(MockNet.into_dyn());
+
+        let tcp = net
+            .tcp_connect(
+                &mut rd_interface::Context::new(),
+                "example.com:80".into_address().unwrap(),
+            )
+            .await
+            .unwrap();
+        drop(tcp);
+
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            events.extend(subscriber.recv().await.unwrap());
+        }
+        assert!(matches!(events[0].event_type, EventType::NewTcp(_)));
+        assert!(matches!(events[1].event_type, EventType::CloseConnection));
+        assert_eq!(events[0].uuid, events[1].uuid);
+    }
+
+    #[tokio::test]
+    async fn test_kill_connection() {
+        use tokio::io::AsyncReadExt;
+
+        let controller = crate::controller::Controller::new();
+        let mut subscriber = controller.get_subscriber().await;
+        let net = controller.get_server_net(MockNet.into_dyn());
+        let listener = net
+            .tcp_bind(
+                &mut rd_interface::Context::new(),
+                "0.0.0.0:0".into_address().unwrap(),
+            )
+            .await
+            .unwrap();
+        let (tcp, _) = listener.accept().await.unwrap();
+        let (mut other, _) = listener.accept().await.unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            events.extend(subscriber.recv().await.unwrap());
+        }
+        let uuid = events[0].uuid;
+
+        // a pending read is woken up
+        let read = tokio::spawn(async move {
+            let mut tcp = tcp;
+            let mut buf = [0u8; 8];
+            let result = tcp.read(&mut buf).await;
+            (tcp, result)
+        });
+        sleep(Duration::from_millis(50)).await;
+        assert!(controller.kill_connection(uuid));
+        let (mut tcp, result) = read.await.unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+        assert!(tcp.write_all(b"hello").await.is_err());
+        assert!(!controller.kill_connection(uuid));
+        // the others are left alone
+        other.write_all(b"hello").await.unwrap();
+
+        drop(tcp);
+        let closed = loop {
+            let batch = subscriber.recv().await.unwrap();
+            if let Some(e) = batch
+                .iter()
+                .find(|e| matches!(e.event_type, EventType::CloseConnection))
+            {
+                break e.uuid;
+            }
+        };
+        assert_eq!(closed, uuid);
+    }
+
+    async fn connect(net: &ControllerServerNet) -> rd_interface::Result<rd_interface::TcpStream> {
+        net.tcp_connect(
+            &mut rd_interface::Context::new(),
+            "example.com:80".into_address().unwrap(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let (sender, mut rx) = mpsc::unbounded_channel();
+        let net = Arc::new(ControllerServerNet {
+            net: MockNet.into_dyn(),
+            sender,
+            killers: Default::default(),
+            limiter: Default::default(),
+        });
+        net.limiter.set(Some(ConnectionLimit {
+            max: 2,
+            on_exceed: Exceed::Reject,
+        }));
+
+        let a = connect(&net).await.unwrap();
+        let b = connect(&net).await.unwrap();
+        match connect(&net).await {
+            Err(rd_interface::Error::IO(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused)
+            }
+            _ => panic!("the third connection is not rejected"),
+        }
+        drop(a);
+        let c = connect(&net).await.unwrap();
+        let events = std::iter::from_fn(|| rx.try_recv().ok()).collect::<Vec<_>>();
+        let new_tcp = events
+            .iter()
+            .filter(|e| matches!(e.event_type, EventType::NewTcp(_)))
+            .count();
+        assert_eq!(new_tcp, 3);
+        drop((b, c));
+
+        net.limiter.set(Some(ConnectionLimit {
+            max: 1,
+            on_exceed: Exceed::Delay,
+        }));
+        let d = connect(&net).await.unwrap();
+        let delayed = tokio::spawn({
+            let net = net.clone();
+            async move { connect(&net).await.map(drop) }
+        });
+        sleep(Duration::from_millis(50)).await;
+        assert!(!delayed.is_finished());
+        drop(d);
+        delayed.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_reload() {
+        let (sender, _rx) = mpsc::unbounded_channel();
+        let net = Arc::new(ControllerServerNet {
+            net: MockNet.into_dyn(),
+            sender,
+            killers: Default::default(),
+            limiter: Default::default(),
+        });
+        let limit = |max, on_exceed| Some(ConnectionLimit { max, on_exceed });
+        net.limiter.set(limit(2, Exceed::Reject));
+        let a = connect(&net).await.unwrap();
+        let b = connect(&net).await.unwrap();
+
+        // the open connections still count after on_exceed changes
+        net.limiter.set(limit(2, Exceed::Delay));
+        let delayed = tokio::spawn({
+            let net = net.clone();
+            async move { connect(&net).await.map(drop) }
+        });
+        sleep(Duration::from_millis(50)).await;
+        assert!(!delayed.is_finished());
+        drop(a);
+        delayed.await.unwrap().unwrap();
+
+        // shrinking below the open ones waits for them to close
+        let c = connect(&net).await.unwrap();
+        net.limiter.set(limit(1, Exceed::Reject));
+        assert!(connect(&net).await.is_err());
+        drop(b);
+        assert!(connect(&net).await.is_err());
+        drop(c);
+        let d = connect(&net).await.unwrap();
+        assert!(connect(&net).await.is_err());
+
+        // growing adds the difference
+        net.limiter.set(limit(3, Exceed::Reject));
+        let _e = connect(&net).await.unwrap();
+        let _f = connect(&net).await.unwrap();
+        assert!(connect(&net).await.is_err());
+        drop(d);
+    }
+
+    #[test]
+    fn test_close_without_receiver() {
+        let (sender, rx) = mpsc::unbounded_channel();
+        drop(rx);
+        let (a, _) = duplex(1024);
+        // dropping must not panic even though nobody is listening.
+        drop(TcpStream::new(
+            MockTcp(a).into_dyn(),
+            sender,
+            Uuid::new_v4(),
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_traffic_batched() {
+        use tokio::io::AsyncReadExt;
+
+        const SIZE: usize = 1024 * 1024;
+        let (sender, mut rx) = mpsc::unbounded_channel();
+        let (a, mut b) = duplex(1024);
+        let reader = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            b.read_to_end(&mut buf).await.unwrap();
+            buf.len()
+        });
+
+        let mut tcp = TcpStream::new(MockTcp(a).into_dyn(), sender, Uuid::new_v4());
+        tcp.write_all(&vec![0u8; SIZE]).await.unwrap();
+        drop(tcp);
+        assert_eq!(reader.await.unwrap(), SIZE);
+
+        let events = std::iter::from_fn(|| rx.try_recv().ok()).collect::<Vec<_>>();
+        let outbound: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e.event_type {
+                EventType::Outbound(s) => Some(s),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(outbound.iter().sum::<usize>(), SIZE);
+        // about one per TRAFFIC_EVENT_BYTES instead of one per 1 KiB write
+        assert!(outbound.len() <= 2 * SIZE / TRAFFIC_EVENT_BYTES);
+        assert!(matches!(
+            events.last().unwrap().event_type,
+            EventType::CloseConnection
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_traffic_flushed_when_idle() {
+        use tokio::io::AsyncReadExt;
+
+        let (sender, mut rx) = mpsc::unbounded_channel();
+        let (a, mut b) = duplex(1024);
+        let mut tcp = TcpStream::new(MockTcp(a).into_dyn(), sender, Uuid::new_v4());
+
+        b.write_all(b"1").await.unwrap();
+        let mut buf = [0u8; 16];
+        tcp.read_exact(&mut buf[..1]).await.unwrap();
+        // held back, it's within the interval of the first
+        b.write_all(b"23").await.unwrap();
+        tcp.read_exact(&mut buf[..2]).await.unwrap();
+
+        // no more bytes, but the pending ones are sent while waiting for them
+        let idle = tokio::time::timeout(TRAFFIC_EVENT_INTERVAL * 2, tcp.read(&mut buf)).await;
+        assert!(idle.is_err());
+        let inbound: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|e| match e.event_type {
+                EventType::Inbound(s) => Some(s),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(inbound, [1, 2]);
+    }
+
+    type SpanFields = (&'static str, Vec<(String, String)>);
+
+    /// Records the fields of new spans, and the span each event is in.
+    #[derive(Default)]
+    struct SpanCapture {
+        spans: std::sync::Mutex<Vec<SpanFields>>,
+        stack: std::sync::Mutex<Vec<u64>>,
+        events_in: std::sync::Mutex<Vec<Option<u64>>>,
+    }
+
+    struct Fields<'a>(&'a mut Vec<(String, String)>);
+
+    impl tracing::field::Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    impl tracing::Subscriber for SpanCapture {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = Vec::new();
+            span.record(&mut Fields(&mut fields));
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((span.metadata().name(), fields));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, _: &tracing::Event<'_>) {
+            let current = self.stack.lock().unwrap().last().copied();
+            self.events_in.lock().unwrap().push(current);
+        }
+        fn enter(&self, span: &tracing::span::Id) {
+            self.stack.lock().unwrap().push(span.into_u64());
+        }
+        fn exit(&self, _: &tracing::span::Id) {
+            self.stack.lock().unwrap().pop();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_span() {
+        let capture = std::sync::Arc::new(SpanCapture::default());
+        let _guard = tracing::subscriber::set_default(capture.clone());
+
+        let (sender, mut rx) = mpsc::unbounded_channel();
+        let net = ControllerServerNet {
+            net: MockNet.into_dyn(),
+            sender,
+            killers: Default::default(),
+            limiter: Default::default(),
+        };
+        let _tcp = net
+            .tcp_connect(
+                &mut rd_interface::Context::new(),
+                "example.com:80".into_address().unwrap(),
+            )
+            .await
+            .unwrap();
+        let uuid = rx.try_recv().unwrap().uuid;
+
+        let spans = capture.spans.lock().unwrap();
+        let (id, (_, fields)) = spans
+            .iter()
+            .enumerate()
+            .find(|(_, (name, _))| *name == "tcp")
+            .unwrap();
+        assert!(fields.contains(&("uuid".to_string(), uuid.to_string())));
+        assert!(fields.contains(&("addr".to_string(), "example.com:80".to_string())));
+        // the connect log is in the span
+        let span_id = id as u64 + 1;
+        assert!(capture.events_in.lock().unwrap().contains(&Some(span_id)));
+    }
+}
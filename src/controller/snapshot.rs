@@ -0,0 +1,31 @@
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use super::{event::ConnectionInfo, stats::NetStats};
+use crate::config;
+use serde_derive::Serialize;
+
+/// What the controller is doing at one moment, for debugging. Serialize it
+/// to dump it as JSON.
+#[derive(Debug, Serialize)]
+pub struct StateSnapshot {
+    /// `Idle` or `Running`.
+    pub state: &'static str,
+    /// The running config, `None` when idle.
+    pub config: Option<config::Config>,
+    /// The address each server listens on, `None` until it's bound.
+    pub servers: BTreeMap<String, Option<SocketAddr>>,
+    /// The open connections, oldest first.
+    pub connections: Vec<ConnectionSnapshot>,
+    /// Totals of each net since the controller started.
+    pub nets: BTreeMap<String, NetStats>,
+    pub dropped_events: u64,
+}
+
+/// An open connection and its bytes so far.
+#[derive(Debug, Serialize)]
+pub struct ConnectionSnapshot {
+    #[serde(flatten)]
+    pub info: ConnectionInfo,
+    pub inbound: u64,
+    pub outbound: u64,
+}
@@ -0,0 +1,311 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use super::{
+    event::{ConnectionInfo, Event, EventType},
+    snapshot::ConnectionSnapshot,
+};
+use serde_derive::Serialize;
+use uuid::Uuid;
+
+/// The net label of connections not routed by a rule net.
+const UNKNOWN_NET: &str = "unknown";
+
+/// Totals of the connections through a net, since the controller started.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct NetStats {
+    /// Connections open now.
+    pub active: u64,
+    pub connections: u64,
+    pub inbound: u64,
+    pub outbound: u64,
+}
+
+/// Running byte totals of each open connection, as `(inbound, outbound)`,
+/// and the totals of each net.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    connections: HashMap<Uuid, (u64, u64)>,
+    open: HashMap<Uuid, ConnectionInfo>,
+    connection_net: HashMap<Uuid, String>,
+    nets: BTreeMap<String, NetStats>,
+}
+
+impl ConnectionStats {
+    pub fn update(&mut self, event: &Event) {
+        match &event.event_type {
+            EventType::NewTcp(info) => {
+                self.connections.entry(event.uuid).or_default();
+                self.open(event.uuid, info.net.as_deref().unwrap_or(UNKNOWN_NET));
+                self.open.insert(
+                    event.uuid,
+                    ConnectionInfo {
+                        uuid: event.uuid,
+                        protocol: "tcp",
+                        destination: info.addr.clone(),
+                        source: info.source,
+                        net: info.net.clone(),
+                        start: event.time,
+                    },
+                );
+            }
+            EventType::NewUdp(addr) => {
+                self.connections.entry(event.uuid).or_default();
+                self.open(event.uuid, UNKNOWN_NET);
+                self.open.insert(
+                    event.uuid,
+                    ConnectionInfo {
+                        uuid: event.uuid,
+                        protocol: "udp",
+                        destination: addr.clone(),
+                        source: None,
+                        net: None,
+                        start: event.time,
+                    },
+                );
+            }
+            EventType::Inbound(size) | EventType::UdpInbound(_, size) => {
+                // the traffic of a closed connection isn't counted again
+                match self.connections.get_mut(&event.uuid) {
+                    Some(c) => c.0 += *size as u64,
+                    None => return,
+                }
+                if let Some(net) = self.net_of(&event.uuid) {
+                    net.inbound += *size as u64;
+                }
+            }
+            EventType::Outbound(size) | EventType::UdpOutbound(_, size) => {
+                match self.connections.get_mut(&event.uuid) {
+                    Some(c) => c.1 += *size as u64,
+                    None => return,
+                }
+                if let Some(net) = self.net_of(&event.uuid) {
+                    net.outbound += *size as u64;
+                }
+            }
+            EventType::CloseConnection => {
+                self.connections.remove(&event.uuid);
+                self.open.remove(&event.uuid);
+                if let Some(net) = self.net_of(&event.uuid) {
+                    net.active = net.active.saturating_sub(1);
+                }
+                self.connection_net.remove(&event.uuid);
+            }
+            EventType::ConfigChanged(_) => {}
+        }
+    }
+    fn open(&mut self, uuid: Uuid, net: &str) {
+        let stats = self.nets.entry(net.to_string()).or_default();
+        stats.active += 1;
+        stats.connections += 1;
+        self.connection_net.insert(uuid, net.to_string());
+    }
+    fn net_of(&mut self, uuid: &Uuid) -> Option<&mut NetStats> {
+        let net = self.connection_net.get(uuid)?;
+        self.nets.get_mut(net)
+    }
+    pub fn snapshot(&self) -> HashMap<Uuid, (u64, u64)> {
+        self.connections.clone()
+    }
+    /// The open connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut list: Vec<_> = self.open.values().cloned().collect();
+        list.sort_by_key(|c| (c.start, c.uuid));
+        list
+    }
+    /// The open connections with their bytes, oldest first.
+    pub fn list_with_bytes(&self) -> Vec<ConnectionSnapshot> {
+        self.list()
+            .into_iter()
+            .map(|info| {
+                let (inbound, outbound) = self
+                    .connections
+                    .get(&info.uuid)
+                    .copied()
+                    .unwrap_or_default();
+                ConnectionSnapshot {
+                    info,
+                    inbound,
+                    outbound,
+                }
+            })
+            .collect()
+    }
+    pub fn nets(&self) -> BTreeMap<String, NetStats> {
+        self.nets.clone()
+    }
+    /// Writes the totals of each net in the Prometheus text format.
+    /// Connections are labeled with the net chosen by the rule net, or
+    /// `unknown`.
+    pub fn metrics_text(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, ty: &str, help: &str, values: Vec<(String, u64)>| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, ty).unwrap();
+            for (labels, value) in values {
+                writeln!(out, "{}{{{}}} {}", name, labels, value).unwrap();
+            }
+        };
+        let per_net = |f: fn(&NetStats) -> u64| {
+            self.nets
+                .iter()
+                .map(|(net, stats)| (format!("net=\"{}\"", escape(net)), f(stats)))
+                .collect()
+        };
+
+        metric(
+            "rabbit_digger_active_connections",
+            "gauge",
+            "Connections open now.",
+            per_net(|s| s.active),
+        );
+        metric(
+            "rabbit_digger_connections_total",
+            "counter",
+            "Connections opened.",
+            per_net(|s| s.connections),
+        );
+        let bytes = self
+            .nets
+            .iter()
+            .flat_map(|(net, stats)| {
+                let net = escape(net);
+                vec![
+                    (
+                        format!("net=\"{}\",direction=\"inbound\"", net),
+                        stats.inbound,
+                    ),
+                    (
+                        format!("net=\"{}\",direction=\"outbound\"", net),
+                        stats.outbound,
+                    ),
+                ]
+            })
+            .collect();
+        metric(
+            "rabbit_digger_bytes_total",
+            "counter",
+            "Bytes transferred.",
+            bytes,
+        );
+        out
+    }
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::TcpInfo;
+    use rd_interface::IntoAddress;
+
+    #[test]
+    fn test_connection_stats() {
+        let mut stats = ConnectionStats::default();
+        let a = Uuid::new_v4();
+        let b = Uuid::new_v4();
+        let events = vec![
+            Event::new(
+                a,
+                EventType::NewTcp("1.2.3.4:80".into_address().unwrap().into()),
+            ),
+            Event::new(a, EventType::Outbound(100)),
+            Event::new(
+                b,
+                EventType::NewTcp("1.2.3.4:443".into_address().unwrap().into()),
+            ),
+            Event::new(a, EventType::Inbound(1000)),
+            Event::new(b, EventType::Outbound(10)),
+            Event::new(a, EventType::Inbound(24)),
+            Event::new(a, EventType::Outbound(1)),
+        ];
+        for e in &events {
+            stats.update(e);
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[&a], (1024, 101));
+        assert_eq!(snapshot[&b], (0, 10));
+
+        let list = stats.list();
+        assert_eq!(list.len(), 2);
+        let info_b = list.iter().find(|c| c.uuid == b).unwrap();
+        assert_eq!(info_b.protocol, "tcp");
+        assert_eq!(info_b.destination.to_string(), "1.2.3.4:443");
+
+        stats.update(&Event::new(a, EventType::CloseConnection));
+        // a late event of the closed connection
+        stats.update(&Event::new(a, EventType::Inbound(5)));
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert!(!snapshot.contains_key(&a));
+        let list = stats.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].uuid, b);
+    }
+
+    #[test]
+    fn test_metrics_text() {
+        let mut stats = ConnectionStats::default();
+        let tcp = |net: Option<&str>| {
+            let mut info: TcpInfo = "1.2.3.4:80".into_address().unwrap().into();
+            info.net = net.map(ToString::to_string);
+            EventType::NewTcp(info)
+        };
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let events = vec![
+            Event::new(a, tcp(Some("proxy"))),
+            Event::new(a, EventType::Outbound(100)),
+            Event::new(a, EventType::Inbound(1000)),
+            Event::new(a, EventType::CloseConnection),
+            Event::new(b, tcp(Some("proxy"))),
+            Event::new(b, EventType::Inbound(24)),
+            Event::new(c, tcp(None)),
+            Event::new(c, EventType::Outbound(5)),
+        ];
+        for e in &events {
+            stats.update(e);
+        }
+
+        let text = stats.metrics_text();
+        let lines: Vec<&str> = text.lines().collect();
+        for name in &[
+            "rabbit_digger_active_connections gauge",
+            "rabbit_digger_connections_total counter",
+            "rabbit_digger_bytes_total counter",
+        ] {
+            assert!(lines.contains(&format!("# TYPE {}", name).as_str()));
+        }
+        for line in &[
+            r#"rabbit_digger_active_connections{net="proxy"} 1"#,
+            r#"rabbit_digger_active_connections{net="unknown"} 1"#,
+            r#"rabbit_digger_connections_total{net="proxy"} 2"#,
+            r#"rabbit_digger_bytes_total{net="proxy",direction="inbound"} 1024"#,
+            r#"rabbit_digger_bytes_total{net="proxy",direction="outbound"} 100"#,
+            r#"rabbit_digger_bytes_total{net="unknown",direction="outbound"} 5"#,
+        ] {
+            assert!(lines.contains(line), "missing {}", line);
+        }
+        // every sample follows the TYPE line of its metric
+        let mut metric = "";
+        for line in lines {
+            match line.strip_prefix("# TYPE ") {
+                Some(ty) => metric = ty.split(' ').next().unwrap(),
+                None if line.starts_with('#') => {}
+                None => assert!(line.starts_with(&format!("{}{{", metric)), "{}", line),
+            }
+        }
+
+        assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+    }
+}
@@ -1,10 +1,12 @@
 use super::event::Event;
 use rd_interface::{async_trait, Address, INet, Net, TcpListener, UdpSocket};
+use std::net::SocketAddr;
 use tokio::sync::mpsc;
 
 pub struct ControllerNet {
     pub net_name: String,
     pub net: Net,
+    #[allow(dead_code)]
     pub sender: mpsc::UnboundedSender<Event>,
 }
 
@@ -38,4 +40,84 @@ impl INet for ControllerNet {
         ctx.append_net(&self.net_name);
         self.net.udp_bind(ctx, addr).await
     }
+
+    async fn lookup_host(&self, addr: &Address) -> rd_interface::Result<Vec<SocketAddr>> {
+        self.net.lookup_host(addr).await
+    }
+
+    fn id(&self) -> *const () {
+        self.net.id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::controller::Controller;
+    use rd_interface::{Context, INet, IntoAddress, IntoDyn};
+    use rd_std::{
+        builtin::local::{LocalConfig, LocalNet},
+        socks5::{Socks5Client, Socks5Server},
+        tests::RecordNet,
+    };
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_source_addr() {
+        let controller = Controller::new();
+        let inner = Arc::new(RecordNet::default());
+        let net =
+            controller.get_server_net(controller.get_net("record".to_string(), inner.clone()));
+
+        let local = LocalNet::new(LocalConfig::default()).into_dyn();
+        let listener = local
+            .tcp_bind(&mut Context::new(), "127.0.0.1:0".into_address().unwrap())
+            .await
+            .unwrap();
+        let port = listener.local_addr().await.unwrap().port();
+        let server = Socks5Server::new(local.clone(), net, Default::default());
+        let handle = tokio::spawn(async move {
+            let (socket, addr) = listener.accept().await.unwrap();
+            server.serve_connection(socket, addr).await.unwrap();
+            addr
+        });
+
+        let client = Socks5Client::new(local, "127.0.0.1".to_string(), port, None);
+        assert!(client
+            .tcp_connect(&mut Context::new(), "127.0.0.1:80".into_address().unwrap())
+            .await
+            .is_err());
+
+        let addr = handle.await.unwrap();
+        assert_eq!(*inner.source.lock().unwrap(), Some(addr));
+    }
+
+    #[tokio::test]
+    async fn test_is_same() {
+        let controller = Controller::new();
+        let inner = RecordNet::default().into_dyn();
+        let wrapped = controller.get_net("record".to_string(), inner.clone());
+
+        let mut registry = crate::Registry::new();
+        crate::builtin::load_builtin(&mut registry).unwrap();
+        let mut nets = rd_interface::registry::NetMap::new();
+        nets.insert("record".to_string(), wrapped.clone());
+        let alias = registry
+            .get_net("alias")
+            .unwrap()
+            .build(&nets, serde_json::json!({ "net": "record" }))
+            .unwrap();
+        let alias = controller.get_net("alias".to_string(), alias);
+
+        let same = [&inner, &wrapped, &alias];
+        for a in same {
+            for b in same {
+                assert!(a.is_same(b));
+            }
+        }
+        let other = controller.get_net("other".to_string(), RecordNet::default().into_dyn());
+        for a in same {
+            assert!(!a.is_same(&other));
+            assert!(!other.is_same(a));
+        }
+    }
 }
@@ -5,6 +5,7 @@ pub mod controller;
 pub mod rabbit_digger;
 pub mod registry;
 pub mod util;
+pub mod validate;
 
 pub use config::Config;
 pub use registry::Registry;
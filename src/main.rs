@@ -1,7 +1,7 @@
 use std::path::PathBuf;
 
 use anyhow::Result;
-use rabbit_digger::{config::Config, controller};
+use rabbit_digger::{builtin::load_builtin, config::Config, controller, Registry};
 use structopt::StructOpt;
 use tokio::fs::read_to_string;
 
@@ -16,6 +16,15 @@ struct Args {
         default_value = "config.yaml"
     )]
     config: PathBuf,
+
+    /// Append a JSON line for each connection to this file, or to stdout if
+    /// it's `-`
+    #[structopt(long, env = "RD_ACCESS_LOG", parse(from_os_str))]
+    access_log: Option<PathBuf>,
+
+    /// Write the JSON schema of the config file to this path and exit
+    #[structopt(long, parse(from_os_str))]
+    write_schema: Option<PathBuf>,
 }
 
 async fn real_main(args: Args) -> Result<()> {
@@ -24,10 +33,21 @@ async fn real_main(args: Args) -> Result<()> {
     }
     tracing_subscriber::fmt::init();
 
+    if let Some(path) = args.write_schema {
+        let mut registry = Registry::new();
+        load_builtin(&mut registry)?;
+        let schema = serde_json::to_string_pretty(&registry.schema()?)?;
+        tokio::fs::write(path, schema).await?;
+        return Ok(());
+    }
+
     let content = read_to_string(args.config).await?;
     let config: Config = serde_yaml::from_str(&content)?;
 
     let controller = controller::Controller::new();
+    if let Some(path) = args.access_log {
+        controller.set_access_log(Some(controller::AccessLog::open(path)?));
+    }
 
     controller.run(config).await?;
 
@@ -1,4 +1,9 @@
-use std::{collections::HashMap, fmt};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+};
 
 use crate::builtin::load_builtin;
 use crate::config;
@@ -7,9 +12,9 @@ use crate::registry::Registry;
 use crate::util::topological_sort;
 use anyhow::{anyhow, Context, Result};
 use config::AllNet;
-use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
-use rd_interface::{Arc, Net, Server, Value};
+use rd_interface::{registry::with_default_net, Arc, Net, Server, Value};
 use serde_json::Map;
+use tokio::task::JoinHandle;
 
 pub type PluginLoader =
     Arc<dyn Fn(&config::Config, &mut Registry) -> Result<()> + Send + Sync + 'static>;
@@ -33,23 +38,20 @@ impl fmt::Debug for RabbitDigger {
 }
 
 impl RabbitDigger {
-    pub async fn run(servers: Vec<ServerInfo>) -> Result<()> {
+    /// Spawns every server in its own task, keyed by the server name.
+    pub fn spawn(servers: Vec<ServerInfo>) -> HashMap<String, ServerTask> {
         tracing::info!("Server:\n{}", ServerList(&servers));
 
-        let mut server_tasks: FuturesUnordered<_> = servers
-            .iter()
-            .map(|i| {
-                let name = i.name.clone();
-                start_server(&i.server).map(|r| (name, r)).boxed()
-            })
-            .collect();
-
-        while let Some((name, r)) = server_tasks.next().await {
-            tracing::info!("Server {} is stopped. Return: {:?}", name, r)
-        }
+        servers
+            .into_iter()
+            .map(|i| (i.name.clone(), i.spawn()))
+            .collect()
+    }
+}
 
-        tracing::info!("all servers are down, exit.");
-        Ok(())
+impl Default for RabbitDiggerBuilder {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -62,8 +64,15 @@ impl RabbitDiggerBuilder {
     pub fn build(
         &self,
         ctl: &controller::Controller,
-        config: config::Config,
+        mut config: config::Config,
     ) -> Result<RabbitDigger> {
+        config.resolve_extends()?;
+        if let Some(limit) = &config.connection_limit {
+            limit.validate()?;
+        }
+        // the running config keeps the `${VAR}`s, not to expose secrets
+        let mut expanded = config.clone();
+        expanded.expand_env()?;
         let wrap_net = {
             let c = ctl.clone();
             move |net_name: String, net: Net| c.get_net(net_name, net)
@@ -75,16 +84,28 @@ impl RabbitDiggerBuilder {
         let mut registry = Registry::new();
 
         load_builtin(&mut registry)?;
-        (self.plugin_loader)(&config, &mut registry)?;
+        (self.plugin_loader)(&expanded, &mut registry)?;
         tracing::debug!("Registry:\n{}", registry);
 
-        let all_net = config
+        if let Err(errors) = registry.validate(&expanded) {
+            let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+            return Err(anyhow!("Invalid config:\n{}", errors.join("\n")));
+        }
+
+        let all_net = expanded
             .net
             .iter()
             .map(|(k, v)| (k.to_string(), AllNet::Net(v.clone())))
             .collect();
-        let nets = build_net(&registry, all_net, &config.server, wrap_net)?;
-        let servers = build_server(&registry, &nets, &config.server, wrap_server_net)?;
+        let build = || -> Result<_> {
+            let nets = build_net(&registry, all_net, &expanded.server, wrap_net)?;
+            let servers = build_server(&registry, &expanded, &nets, wrap_server_net)?;
+            Ok((nets, servers))
+        };
+        let (nets, servers) = match &expanded.default_net {
+            Some(name) => with_default_net(name, build)?,
+            None => build()?,
+        };
         tracing::debug!(
             "net and server are built. net count: {}, server count: {}",
             nets.len(),
@@ -110,7 +131,104 @@ pub struct ServerInfo {
     listen: String,
     net: String,
     server: Server,
+    /// The net passed to `server`, for reloading.
+    server_net: Net,
     config: Value,
+    hash: u64,
+    listen_hash: u64,
+}
+
+impl ServerInfo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Hash of the server config and the config of the nets it depends on.
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+    /// Hash of the server type and the config of its listen net. A running
+    /// server can only be reloaded in place when it's unchanged.
+    pub fn listen_hash(&self) -> u64 {
+        self.listen_hash
+    }
+    pub fn spawn(self) -> ServerTask {
+        let ServerInfo {
+            name,
+            server,
+            hash,
+            listen_hash,
+            ..
+        } = self;
+        let server = Arc::new(server);
+        let handle = {
+            let server = server.clone();
+            tokio::spawn(async move {
+                let r = start_server(&server).await;
+                tracing::info!("Server {} is stopped. Return: {:?}", name, r)
+            })
+        };
+        ServerTask {
+            hash,
+            listen_hash,
+            server,
+            handle,
+        }
+    }
+}
+
+/// A spawned server. It's aborted when dropped.
+pub struct ServerTask {
+    hash: u64,
+    listen_hash: u64,
+    server: Arc<Server>,
+    handle: JoinHandle<()>,
+}
+
+impl fmt::Debug for ServerTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerTask")
+            .field("hash", &self.hash)
+            .finish()
+    }
+}
+
+impl ServerTask {
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+    pub fn listen_hash(&self) -> u64 {
+        self.listen_hash
+    }
+    /// Applies the config of `info` to the running server without
+    /// restarting it. Fails if the server can't reload in place.
+    pub async fn reload(&mut self, info: &ServerInfo) -> rd_interface::Result<()> {
+        if info.listen_hash != self.listen_hash {
+            return Err(rd_interface::NOT_IMPLEMENTED);
+        }
+        self.server
+            .reload(info.server_net.clone(), info.config.clone())
+            .await?;
+        self.hash = info.hash;
+        Ok(())
+    }
+    /// The address the server listens on, once it's bound.
+    pub async fn local_addr(&self) -> rd_interface::Result<SocketAddr> {
+        self.server.local_addr().await
+    }
+    /// Stops the server and waits until it's gone. Servers that can't stop
+    /// themselves are aborted.
+    pub async fn stop(mut self) {
+        if self.server.stop().await.is_err() {
+            self.handle.abort();
+        }
+        (&mut self.handle).await.ok();
+    }
+}
+
+impl Drop for ServerTask {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
 }
 
 struct ServerList<'a>(&'a Vec<ServerInfo>);
@@ -165,8 +283,11 @@ fn build_net(
         AllNet::Root(server.values().map(|i| i.net.clone()).collect()),
     );
 
-    let all_net = topological_sort(all_net, |n| n.get_dependency(registry))?
-        .ok_or(anyhow!("There is cyclic dependencies in net",))?;
+    let all_net =
+        topological_sort(all_net, |n| n.get_dependency(registry))?.map_err(|mut cycle| {
+            cycle.sort();
+            anyhow!("Cycle detected among nets: {}", cycle.join(", "))
+        })?;
 
     for (name, i) in all_net {
         match i {
@@ -191,14 +312,66 @@ fn build_net(
     Ok(net_map)
 }
 
+fn server_hash(
+    registry: &Registry,
+    config: &config::Config,
+    server: &config::Server,
+) -> Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(server)?.hash(&mut hasher);
+    hash_nets(
+        registry,
+        config,
+        vec![server.listen.clone(), server.net.clone()],
+        &mut hasher,
+    )?;
+    Ok(hasher.finish())
+}
+
+fn listen_hash(
+    registry: &Registry,
+    config: &config::Config,
+    server: &config::Server,
+) -> Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    server.server_type.hash(&mut hasher);
+    hash_nets(registry, config, vec![server.listen.clone()], &mut hasher)?;
+    Ok(hasher.finish())
+}
+
+/// Hashes the config of `roots` and the nets they depend on.
+fn hash_nets(
+    registry: &Registry,
+    config: &config::Config,
+    roots: Vec<String>,
+    hasher: &mut impl Hasher,
+) -> Result<()> {
+    let mut nets = BTreeSet::new();
+    let mut stack = roots;
+    while let Some(name) = stack.pop() {
+        if !nets.insert(name.clone()) {
+            continue;
+        }
+        if let Some(net) = config.net.get(&name) {
+            stack.extend(AllNet::Net(net.clone()).get_dependency(registry)?);
+        }
+    }
+    for name in nets {
+        name.hash(hasher);
+        serde_json::to_string(&config.net.get(&name))?.hash(hasher);
+    }
+
+    Ok(())
+}
+
 fn build_server(
     registry: &Registry,
+    all_config: &config::Config,
     net: &HashMap<String, Net>,
-    config: &config::ConfigServer,
     wrapper: impl Fn(Net) -> Net,
 ) -> Result<Vec<ServerInfo>> {
     let mut servers: Vec<ServerInfo> = Vec::new();
-    let config = config.clone();
+    let config = all_config.server.clone();
 
     for (name, i) in config {
         let name = &name;
@@ -215,15 +388,21 @@ fn build_server(
                 &name
             ))?;
 
+            let server_net = wrapper(net.clone());
             let server = server_item
-                .build(listen.clone(), wrapper(net.clone()), i.opt.clone())
+                .build(listen.clone(), server_net.clone(), i.opt.clone())
                 .context(format!(
                     "Failed to build server {:?}. Please check your config.",
                     name
                 ))?;
+            let hash = server_hash(registry, all_config, &i)?;
+            let listen_hash = listen_hash(registry, all_config, &i)?;
             servers.push(ServerInfo {
                 name: name.to_string(),
+                hash,
+                listen_hash,
                 server,
+                server_net,
                 config: i.opt,
                 listen: i.listen,
                 net: i.net,
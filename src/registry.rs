@@ -1,10 +1,16 @@
 //! A registry with plugin name
 
+use crate::{
+    config,
+    validate::{validate, ValidationError},
+};
 use anyhow::{anyhow, Context, Result};
 use rd_interface::{
-    registry::{NetMap, NetResolver, ServerResolver},
+    registry::{with_default_net, NetMap, NetResolver, ServerResolver},
+    schemars::schema::{InstanceType, RootSchema},
     Net, Server, Value,
 };
+use serde_json::{json, Map};
 use std::{collections::HashMap, fmt};
 
 pub struct NetItem {
@@ -21,23 +27,21 @@ pub struct ServerItem {
 
 impl NetItem {
     pub fn build(&self, nets: &NetMap, config: Value) -> Result<Net> {
-        Ok(self
-            .resolver
+        self.resolver
             .build(nets, config)
-            .with_context(|| format!("Failed to build net: {}", self.id))?)
+            .with_context(|| format!("Failed to build net: {}", self.id))
     }
 }
 
 impl ServerItem {
     pub fn build(&self, listen: Net, net: Net, config: Value) -> Result<Server> {
-        Ok(self
-            .resolver
+        self.resolver
             .build(listen, net, config)
-            .with_context(|| format!("Failed to build server: {}", self.id))?)
+            .with_context(|| format!("Failed to build server: {}", self.id))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Registry {
     pub net: HashMap<String, NetItem>,
     pub server: HashMap<String, ServerItem>,
@@ -90,6 +94,53 @@ impl Registry {
         self.add_registry(name.into(), r);
         Ok(())
     }
+    /// Loads the nets and servers of the plugin at `path`, named after the
+    /// file. See [`rd_interface::plugin`].
+    ///
+    /// # Safety
+    ///
+    /// The library runs in this process, and must be built with the same
+    /// rustc and `rd-interface`, there is no stable ABI. It's never unloaded, the nets built from
+    /// it run its code.
+    #[cfg(feature = "plugin")]
+    pub unsafe fn load_plugin(&mut self, path: impl AsRef<std::path::Path>) -> Result<()> {
+        use rd_interface::plugin::{
+            PluginAbiVersion, PluginInit, PLUGIN_ABI_VERSION, PLUGIN_ABI_VERSION_SYMBOL,
+            PLUGIN_INIT_SYMBOL,
+        };
+        use std::ffi::CStr;
+
+        let path = path.as_ref();
+        let library = libloading::Library::new(path)
+            .with_context(|| format!("Failed to load plugin: {}", path.display()))?;
+        let abi_version = library.get::<PluginAbiVersion>(PLUGIN_ABI_VERSION_SYMBOL)?;
+        let abi_version = CStr::from_ptr(abi_version()).to_string_lossy();
+        let expected = PLUGIN_ABI_VERSION.trim_end_matches('\0');
+        if abi_version != expected {
+            return Err(anyhow!(
+                "Plugin {} is built with {}, expected {}",
+                path.display(),
+                abi_version,
+                expected
+            ));
+        }
+        let init = *library.get::<PluginInit>(PLUGIN_INIT_SYMBOL)?;
+        std::mem::forget(library);
+
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.init_with_registry(name, |registry| {
+            let error = init(registry);
+            if error.is_null() {
+                Ok(())
+            } else {
+                Err(*Box::from_raw(error))
+            }
+        })?;
+        Ok(())
+    }
     fn add_registry(&mut self, plugin_name: String, registry: rd_interface::Registry) {
         for (k, v) in registry.net {
             self.net.insert(
@@ -122,4 +173,451 @@ impl Registry {
             .get(server_type)
             .ok_or(anyhow!("Server type is not loaded: {}", server_type))
     }
+    /// Builds the JSON schema of a config file. Nets and servers are `oneOf`
+    /// the registered types, told apart by their `type` field.
+    pub fn schema(&self) -> Result<Value> {
+        let mut definitions = Map::new();
+
+        let nets = self.net.iter().map(|(k, v)| (k, v.resolver.schema()));
+        let net = one_of("net", nets, &mut definitions)?;
+        let servers = self.server.iter().map(|(k, v)| (k, v.resolver.schema()));
+        let server = one_of("server", servers, &mut definitions)?;
+        definitions.insert("AnyNet".to_string(), net);
+        definitions.insert("AnyServer".to_string(), server);
+
+        Ok(json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "title": "Config",
+            "type": "object",
+            "properties": {
+                "net": {
+                    "type": "object",
+                    "additionalProperties": { "$ref": "#/definitions/AnyNet" },
+                },
+                "server": {
+                    "type": "object",
+                    "additionalProperties": { "$ref": "#/definitions/AnyServer" },
+                },
+            },
+            "definitions": definitions,
+        }))
+    }
+    /// Returns the nets each net in `config` depends on, without building
+    /// them. The nets left out of the options are `default_net`, as when
+    /// they are built.
+    pub fn net_dependencies(
+        &self,
+        config: &config::Config,
+    ) -> Result<HashMap<String, Vec<String>>> {
+        let mut config = config.clone();
+        config.resolve_extends()?;
+        let default_net = config.default_net.take();
+        let dependencies = || {
+            config
+                .net
+                .into_iter()
+                .map(|(name, net)| {
+                    let deps = config::AllNet::Net(net)
+                        .get_dependency(self)
+                        .with_context(|| format!("Getting the dependencies of net {}", name))?;
+                    Ok((name, deps))
+                })
+                .collect()
+        };
+        match &default_net {
+            Some(name) => with_default_net(name, dependencies),
+            None => dependencies(),
+        }
+    }
+    /// Checks the type and the options of every net and server in `config`,
+    /// returning all the errors found.
+    pub fn validate(&self, config: &config::Config) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+
+        let mut nets: Vec<_> = config.net.iter().collect();
+        nets.sort_by_key(|(name, _)| *name);
+        for (name, net) in nets {
+            let path = format!("net.{}", name);
+            match self.net.get(&net.net_type) {
+                Some(item) => validate_opt(item.resolver.schema(), &net.opt, &path, &mut errors),
+                None => errors.push(ValidationError {
+                    path: format!("{}.type", path),
+                    message: format!("Net type is not loaded: {}", net.net_type),
+                }),
+            }
+        }
+
+        let mut servers: Vec<_> = config.server.iter().collect();
+        servers.sort_by_key(|(name, _)| *name);
+        for (name, server) in servers {
+            let path = format!("server.{}", name);
+            match self.server.get(&server.server_type) {
+                Some(item) => {
+                    // `listen` and `net` are taken out of the options by serde
+                    let mut opt = server.opt.clone();
+                    if let Some(map) = opt.as_object_mut() {
+                        map.insert("listen".to_string(), server.listen.clone().into());
+                        map.insert("net".to_string(), server.net.clone().into());
+                    }
+                    validate_opt(item.resolver.schema(), &opt, &path, &mut errors)
+                }
+                None => errors.push(ValidationError {
+                    path: format!("{}.type", path),
+                    message: format!("Server type is not loaded: {}", server.server_type),
+                }),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn validate_opt(schema: &RootSchema, opt: &Value, path: &str, errors: &mut Vec<ValidationError>) {
+    // EmptyConfig takes anything
+    let is_empty = schema
+        .schema
+        .instance_type
+        .as_ref()
+        .map(|t| t.contains(&InstanceType::Null))
+        .unwrap_or_default();
+    if !is_empty {
+        validate(schema, opt, path, errors);
+    }
+}
+
+/// Merges the schemas of the `kind` types into a `oneOf`, and their
+/// definitions into `definitions`. A definition named like another but
+/// different, or referring to one, is renamed to `{kind}.{type}.{name}`.
+fn one_of<'a>(
+    kind: &str,
+    schemas: impl Iterator<Item = (&'a String, &'a RootSchema)>,
+    definitions: &mut Map<String, Value>,
+) -> Result<Value> {
+    let mut schemas: Vec<_> = schemas.collect();
+    schemas.sort_by_key(|(name, _)| *name);
+
+    let mut variants = Vec::with_capacity(schemas.len());
+    for (name, schema) in schemas {
+        let mut own = Map::new();
+        for (k, v) in &schema.definitions {
+            own.insert(k.clone(), serde_json::to_value(v)?);
+        }
+        let mut renamed: HashMap<String, String> = own
+            .iter()
+            .filter(|(k, v)| definitions.get(*k).is_some_and(|d| d != *v))
+            .map(|(k, _)| (k.clone(), format!("{}.{}.{}", kind, name, k)))
+            .collect();
+        // the ones referring to a renamed one differ once it's renamed
+        loop {
+            let referring: Vec<_> = own
+                .iter()
+                .filter(|(k, v)| !renamed.contains_key(*k) && refers_to(v, &renamed))
+                .map(|(k, _)| k.clone())
+                .collect();
+            if referring.is_empty() {
+                break;
+            }
+            for k in referring {
+                let new_name = format!("{}.{}.{}", kind, name, k);
+                renamed.insert(k, new_name);
+            }
+        }
+
+        for (k, mut v) in own {
+            rename_refs(&mut v, &renamed);
+            let k = renamed.get(&k).cloned().unwrap_or(k);
+            definitions.entry(k).or_insert(v);
+        }
+        let mut schema = serde_json::to_value(&schema.schema)?;
+        rename_refs(&mut schema, &renamed);
+        variants.push(with_type(name, schema));
+    }
+
+    Ok(json!({ "oneOf": variants }))
+}
+
+/// Whether `schema` has a `$ref` to any of the definitions in `names`.
+fn refers_to(schema: &Value, names: &HashMap<String, String>) -> bool {
+    match schema {
+        Value::Object(map) => map.iter().any(|(k, v)| match (k.as_str(), v) {
+            ("$ref", Value::String(r)) => r
+                .strip_prefix("#/definitions/")
+                .is_some_and(|name| names.contains_key(name)),
+            _ => refers_to(v, names),
+        }),
+        Value::Array(items) => items.iter().any(|v| refers_to(v, names)),
+        _ => false,
+    }
+}
+
+/// Points the `$ref`s in `schema` to the new names of the definitions.
+fn rename_refs(schema: &mut Value, renamed: &HashMap<String, String>) {
+    match schema {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                match (k.as_str(), v) {
+                    ("$ref", Value::String(r)) => {
+                        let new_name = r
+                            .strip_prefix("#/definitions/")
+                            .and_then(|name| renamed.get(name));
+                        if let Some(new_name) = new_name {
+                            *r = format!("#/definitions/{}", new_name);
+                        }
+                    }
+                    (_, v) => rename_refs(v, renamed),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| rename_refs(v, renamed)),
+        _ => {}
+    }
+}
+
+/// Adds the `type` field to the schema of a config.
+fn with_type(name: &str, mut schema: Value) -> Value {
+    let tag = json!({
+        "type": "object",
+        "properties": { "type": { "const": name } },
+        "required": ["type"],
+    });
+    match schema.get("type").and_then(Value::as_str) {
+        Some("object") => {
+            schema["properties"]["type"] = tag["properties"]["type"].clone();
+            match schema["required"].as_array_mut() {
+                Some(required) => required.push("type".into()),
+                None => schema["required"] = json!(["type"]),
+            }
+            schema
+        }
+        // EmptyConfig
+        Some("null") => tag,
+        _ => json!({ "allOf": [tag, schema] }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builtin::load_builtin;
+
+    #[test]
+    fn test_schema_definition_conflict() {
+        #[allow(dead_code)]
+        mod first {
+            use rd_interface::schemars::{self, JsonSchema};
+            #[derive(JsonSchema)]
+            pub struct Inner {
+                pub a: u8,
+            }
+            #[derive(JsonSchema)]
+            pub struct Outer {
+                pub inner: Inner,
+            }
+            #[derive(JsonSchema)]
+            pub struct Config {
+                pub outer: Outer,
+            }
+        }
+        #[allow(dead_code)]
+        mod second {
+            use rd_interface::schemars::{self, JsonSchema};
+            #[derive(JsonSchema)]
+            pub struct Inner {
+                pub b: String,
+            }
+            #[derive(JsonSchema)]
+            pub struct Outer {
+                pub inner: Inner,
+            }
+            #[derive(JsonSchema)]
+            pub struct Config {
+                pub outer: Outer,
+            }
+        }
+
+        let schemas = [
+            (
+                "first".to_string(),
+                rd_interface::schemars::schema_for!(first::Config),
+            ),
+            (
+                "second".to_string(),
+                rd_interface::schemars::schema_for!(second::Config),
+            ),
+        ];
+        let mut definitions = Map::new();
+        let net = one_of("net", schemas.iter().map(|(k, v)| (k, v)), &mut definitions).unwrap();
+
+        // the first keeps the names
+        assert!(definitions["Inner"]["properties"].get("a").is_some());
+        assert_eq!(
+            definitions["Outer"]["properties"]["inner"]["$ref"],
+            "#/definitions/Inner"
+        );
+        assert!(definitions["net.second.Inner"]["properties"]
+            .get("b")
+            .is_some());
+        assert_eq!(
+            definitions["net.second.Outer"]["properties"]["inner"]["$ref"],
+            "#/definitions/net.second.Inner"
+        );
+        assert_eq!(
+            net["oneOf"][1]["properties"]["outer"]["$ref"],
+            "#/definitions/net.second.Outer"
+        );
+    }
+
+    #[test]
+    fn test_schema() {
+        let mut registry = Registry::new();
+        load_builtin(&mut registry).unwrap();
+        let schema = registry.schema().unwrap();
+
+        let types = |def: &str| -> Vec<String> {
+            schema["definitions"][def]["oneOf"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| {
+                    let tag = match v.get("allOf") {
+                        Some(all) => &all[0],
+                        None => v,
+                    };
+                    tag["properties"]["type"]["const"]
+                        .as_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect()
+        };
+        let nets = types("AnyNet");
+        for name in registry.net.keys() {
+            assert!(nets.contains(name), "missing net {}", name);
+        }
+        let servers = types("AnyServer");
+        for name in registry.server.keys() {
+            assert!(servers.contains(name), "missing server {}", name);
+        }
+
+        // servers take `net` and `listen` like in ServerResolver
+        let socks5 = schema["definitions"]["AnyServer"]["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|v| v["properties"]["type"]["const"] == "socks5")
+            .unwrap();
+        assert!(socks5["properties"].get("listen").is_some());
+        assert!(socks5["properties"].get("net").is_some());
+        assert!(socks5["required"]
+            .as_array()
+            .unwrap()
+            .contains(&"type".into()));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut registry = Registry::new();
+        load_builtin(&mut registry).unwrap();
+        let config = |value: Value| -> config::Config { serde_json::from_value(value).unwrap() };
+
+        let valid = config(json!({
+            "net": {
+                "proxy": { "type": "socks5", "address": "127.0.0.1", "port": 1080 },
+                "none": { "type": "noop" },
+            },
+            "server": {
+                "socks5": { "type": "socks5", "bind": "127.0.0.1:1080", "net": "proxy" },
+            },
+        }));
+        registry.validate(&valid).unwrap();
+
+        let invalid = config(json!({
+            "net": {
+                "proxy": { "type": "socks5", "address": "127.0.0.1", "port": "1080" },
+                "typo": { "type": "sock5", "address": "127.0.0.1", "port": 1080 },
+            },
+            "server": {
+                "socks5": { "type": "socks5", "net": "proxy" },
+            },
+        }));
+        let errors = registry.validate(&invalid).unwrap_err();
+        let paths: Vec<_> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["net.proxy.port", "net.typo.type", "server.socks5"]);
+        assert!(errors[2].message.contains("`bind`"));
+    }
+
+    #[test]
+    fn test_net_dependencies() {
+        let mut registry = Registry::new();
+        load_builtin(&mut registry).unwrap();
+        let config: config::Config = serde_json::from_value(json!({
+            "net": {
+                "a": { "type": "socks5", "address": "127.0.0.1", "port": 1080, "net": "b" },
+                "b": { "type": "socks5", "address": "127.0.0.1", "port": 1081, "net": "c" },
+                "c": { "type": "noop" },
+            },
+        }))
+        .unwrap();
+
+        let deps = registry.net_dependencies(&config).unwrap();
+        let expected: HashMap<String, Vec<String>> = vec![
+            ("a".to_string(), vec!["b".to_string()]),
+            ("b".to_string(), vec!["c".to_string()]),
+            ("c".to_string(), vec![]),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(deps, expected);
+
+        let config: config::Config = serde_json::from_value(json!({
+            "net": {
+                "a": { "type": "socks5", "address": "127.0.0.1", "port": 1080 },
+                "b": { "type": "noop" },
+            },
+            "default_net": "b",
+        }))
+        .unwrap();
+        let deps = registry.net_dependencies(&config).unwrap();
+        assert_eq!(deps["a"], ["b"]);
+    }
+
+    /// The example plugin, built along with the tests.
+    #[cfg(feature = "plugin")]
+    #[tokio::test]
+    async fn test_load_plugin() {
+        use rd_interface::{Context, IntoAddress};
+
+        let mut path = std::env::current_exe().unwrap();
+        path.pop();
+        if path.ends_with("deps") {
+            path.pop();
+        }
+        path.push("examples");
+        path.push(libloading::library_filename("example_plugin"));
+
+        let mut registry = Registry::new();
+        unsafe { registry.load_plugin(&path) }.unwrap();
+        let item = registry.get_net("reject").unwrap();
+        assert_eq!(
+            item.plugin_name,
+            path.file_stem().unwrap().to_str().unwrap()
+        );
+
+        let net = item
+            .build(&NetMap::new(), json!({ "message": "from plugin" }))
+            .unwrap();
+        let result = net
+            .tcp_connect(
+                &mut Context::new(),
+                ("127.0.0.1", 80).into_address().unwrap(),
+            )
+            .await;
+        assert!(result.err().unwrap().to_string().contains("from plugin"));
+
+        assert!(unsafe { registry.load_plugin(path.with_file_name("missing")) }.is_err());
+    }
 }
@@ -1,20 +1,30 @@
-use std::{collections::HashMap, hash::Hash};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
 
 use topological_sort::TopologicalSort;
 
+/// Sorted items, or the keys forming a cycle.
+pub type SortResult<K, V> = Result<Vec<(K, V)>, Vec<K>>;
+
+/// Sorts `map` so that every item comes after its dependencies. If there is a
+/// cycle, `Err` with the keys forming it is returned.
 pub fn topological_sort<K, V, D, E>(
     mut map: HashMap<K, V>,
     get_deps: D,
-) -> Result<Option<Vec<(K, V)>>, E>
+) -> Result<SortResult<K, V>, E>
 where
     K: Hash + Eq + Clone,
     D: Fn(&V) -> Result<Vec<K>, E>,
 {
     let mut ts = TopologicalSort::<K>::new();
+    let mut edges = Vec::new();
 
     for (k, v) in map.iter() {
         for d in get_deps(v)?.into_iter() {
-            ts.add_dependency(d, k.clone());
+            ts.add_dependency(d.clone(), k.clone());
+            edges.push((d, k.clone()));
         }
     }
 
@@ -23,17 +33,82 @@ where
         list.push(k.clone());
     }
 
-    if ts.len() > 0 {
-        return Ok(None);
+    if !ts.is_empty() {
+        return Ok(Err(find_cycle(edges, &list)));
+    }
+
+    Ok(Ok(list
+        .into_iter()
+        .filter_map(|k| {
+            let v = map.remove(&k);
+            v.map(|v| (k, v))
+        })
+        .collect()))
+}
+
+/// Returns the keys left unsorted, without those only depending on a cycle.
+fn find_cycle<K: Hash + Eq + Clone>(edges: Vec<(K, K)>, sorted: &[K]) -> Vec<K> {
+    let sorted: HashSet<&K> = sorted.iter().collect();
+    let mut edges: Vec<(K, K)> = edges
+        .into_iter()
+        .filter(|(d, k)| !sorted.contains(d) && !sorted.contains(k))
+        .collect();
+
+    // Drop the keys nothing left depends on until only cycles remain.
+    loop {
+        let required: HashSet<&K> = edges.iter().map(|(d, _)| d).collect();
+        let keep: Vec<bool> = edges.iter().map(|(_, k)| required.contains(k)).collect();
+        if keep.iter().all(|k| *k) {
+            break;
+        }
+        let mut keep = keep.into_iter();
+        edges.retain(|_| keep.next().unwrap());
+    }
+
+    let mut keys = Vec::new();
+    for (d, _) in edges {
+        if !keys.contains(&d) {
+            keys.push(d);
+        }
     }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(Some(
-        list.into_iter()
-            .map(|k| {
-                let v = map.remove(&k);
-                v.map(|v| (k, v))
-            })
-            .filter_map(|i| i)
-            .collect(),
-    ))
+    fn sort(
+        deps: &[(&'static str, &[&'static str])],
+    ) -> Result<Vec<&'static str>, Vec<&'static str>> {
+        let map: HashMap<_, _> = deps.iter().map(|(k, v)| (*k, v.to_vec())).collect();
+        let result = topological_sort(map, |v| Ok::<_, ()>(v.clone())).unwrap();
+        match result {
+            Ok(list) => Ok(list.into_iter().map(|(k, _)| k).collect()),
+            Err(mut cycle) => {
+                cycle.sort();
+                Err(cycle)
+            }
+        }
+    }
+
+    #[test]
+    fn test_topological_sort() {
+        let list = sort(&[("a", &["b"]), ("b", &["c"]), ("c", &[])]).unwrap();
+        assert_eq!(list, vec!["c", "b", "a"]);
+    }
+
+    #[test]
+    fn test_topological_sort_cycle() {
+        let cycle = sort(&[
+            ("a", &["b"]),
+            ("b", &["c"]),
+            ("c", &["a", "e"]),
+            // depends on the cycle, but isn't part of it
+            ("d", &["a"]),
+            ("e", &[]),
+        ])
+        .unwrap_err();
+        assert_eq!(cycle, vec!["a", "b", "c"]);
+    }
 }
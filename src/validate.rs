@@ -0,0 +1,203 @@
+//! Checks config values against the `RootSchema` of their types. Only the
+//! keywords schemars generates are supported.
+
+use std::fmt;
+
+use rd_interface::{
+    schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec},
+    Value,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    /// Where the error is, e.g. `net.proxy.port`.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Validates `value` against `root`, pushing every error found to `errors`.
+pub fn validate(root: &RootSchema, value: &Value, path: &str, errors: &mut Vec<ValidationError>) {
+    Validator { root }.schema(&root.schema, value, path, errors)
+}
+
+struct Validator<'a> {
+    root: &'a RootSchema,
+}
+
+impl<'a> Validator<'a> {
+    fn matches(&self, schema: &Schema, value: &Value) -> bool {
+        let mut errors = Vec::new();
+        self.any(schema, value, "", &mut errors);
+        errors.is_empty()
+    }
+
+    fn any(&self, schema: &Schema, value: &Value, path: &str, errors: &mut Vec<ValidationError>) {
+        match schema {
+            Schema::Bool(true) => {}
+            Schema::Bool(false) => errors.push(error(path, "no value is allowed")),
+            Schema::Object(schema) => self.schema(schema, value, path, errors),
+        }
+    }
+
+    fn schema(
+        &self,
+        schema: &SchemaObject,
+        value: &Value,
+        path: &str,
+        errors: &mut Vec<ValidationError>,
+    ) {
+        if let Some(reference) = &schema.reference {
+            let name = reference.trim_start_matches("#/definitions/");
+            match self.root.definitions.get(name) {
+                Some(def) => self.any(def, value, path, errors),
+                None => errors.push(error(path, format!("unknown reference {}", reference))),
+            }
+        }
+
+        if let Some(types) = &schema.instance_type {
+            if !matches_type(types, value) {
+                errors.push(error(
+                    path,
+                    format!("expected {}, got {}", type_names(types), value),
+                ));
+                return;
+            }
+        }
+        if let Some(values) = &schema.enum_values {
+            if !values.contains(value) {
+                let values: Vec<_> = values.iter().map(ToString::to_string).collect();
+                errors.push(error(
+                    path,
+                    format!("expected one of {}, got {}", values.join(", "), value),
+                ));
+            }
+        }
+        if let Some(expected) = &schema.const_value {
+            if expected != value {
+                errors.push(error(path, format!("expected {}, got {}", expected, value)));
+            }
+        }
+
+        if let Some(sub) = &schema.subschemas {
+            for s in sub.all_of.iter().flatten() {
+                self.any(s, value, path, errors);
+            }
+            if let Some(any_of) = &sub.any_of {
+                if !any_of.iter().any(|s| self.matches(s, value)) {
+                    errors.push(error(path, "doesn't match any of the allowed schemas"));
+                }
+            }
+            if let Some(one_of) = &sub.one_of {
+                match one_of.iter().filter(|s| self.matches(s, value)).count() {
+                    1 => {}
+                    0 => errors.push(error(path, "doesn't match any of the allowed schemas")),
+                    _ => errors.push(error(path, "matches more than one of the schemas")),
+                }
+            }
+            if let Some(not) = &sub.not {
+                if self.matches(not, value) {
+                    errors.push(error(path, "matches a disallowed schema"));
+                }
+            }
+        }
+
+        if let (Some(number), Some(n)) = (&schema.number, value.as_f64()) {
+            match (number.minimum, number.maximum) {
+                (Some(min), _) if n < min => {
+                    errors.push(error(path, format!("{} is less than {}", n, min)))
+                }
+                (_, Some(max)) if n > max => {
+                    errors.push(error(path, format!("{} is more than {}", n, max)))
+                }
+                _ => {}
+            }
+        }
+
+        if let (Some(array), Some(items)) = (&schema.array, value.as_array()) {
+            match &array.items {
+                Some(SingleOrVec::Single(s)) => {
+                    for (i, item) in items.iter().enumerate() {
+                        self.any(s, item, &join(path, &i.to_string()), errors);
+                    }
+                }
+                Some(SingleOrVec::Vec(s)) => {
+                    for (i, (s, item)) in s.iter().zip(items).enumerate() {
+                        self.any(s, item, &join(path, &i.to_string()), errors);
+                    }
+                }
+                None => {}
+            }
+        }
+
+        if let (Some(object), Some(map)) = (&schema.object, value.as_object()) {
+            for key in &object.required {
+                if !map.contains_key(key) {
+                    errors.push(error(path, format!("missing field `{}`", key)));
+                }
+            }
+            for (key, v) in map {
+                let path = join(path, key);
+                match (&object.properties.get(key), &object.additional_properties) {
+                    (Some(s), _) => self.any(s, v, &path, errors),
+                    (None, Some(s)) if **s == Schema::Bool(false) => {
+                        errors.push(error(&path, "unknown field"))
+                    }
+                    (None, Some(s)) => self.any(s, v, &path, errors),
+                    (None, None) => {}
+                }
+            }
+        }
+    }
+}
+
+fn error(path: &str, message: impl Into<String>) -> ValidationError {
+    ValidationError {
+        path: path.to_string(),
+        message: message.into(),
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn matches_type(types: &SingleOrVec<InstanceType>, value: &Value) -> bool {
+    let is = |t: &InstanceType| match t {
+        InstanceType::Null => value.is_null(),
+        InstanceType::Boolean => value.is_boolean(),
+        InstanceType::Object => value.is_object(),
+        InstanceType::Array => value.is_array(),
+        InstanceType::Number => value.is_number(),
+        InstanceType::String => value.is_string(),
+        InstanceType::Integer => value.is_i64() || value.is_u64(),
+    };
+    match types {
+        SingleOrVec::Single(t) => is(t),
+        SingleOrVec::Vec(ts) => ts.iter().any(is),
+    }
+}
+
+fn type_names(types: &SingleOrVec<InstanceType>) -> String {
+    let name = |t: &InstanceType| {
+        serde_json::to_value(t)
+            .ok()
+            .and_then(|v| v.as_str().map(ToString::to_string))
+            .unwrap_or_default()
+    };
+    match types {
+        SingleOrVec::Single(t) => name(t),
+        SingleOrVec::Vec(ts) => ts.iter().map(name).collect::<Vec<_>>().join(" or "),
+    }
+}